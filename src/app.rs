@@ -0,0 +1,3138 @@
+//! The calculator state and the actions that change it.
+
+use crate::{
+    dates::*, engine::*, lint::lint, parser::*, perf::FrameStats, rates::*, session::Autosave, shared::Share,
+    tasks::{TaskOutput, Tasks}, ui::{color_disabled, Capabilities, Theme}, units::unit,
+};
+use crossterm::event::{KeyCode, KeyModifiers};
+use ratatui::layout::Rect;
+use serde::Deserialize;
+use std::{
+    collections::{BTreeMap, HashMap}, io::{self, Write}, path::PathBuf, process::{Command, Stdio},
+    sync::{atomic::{AtomicBool, Ordering}, mpsc, Arc}, thread, time::{Duration, Instant},
+};
+
+/// How an error is signalled besides its message: not at all, with the terminal bell, or by flashing the display border.
+#[derive(Clone, Copy, PartialEq, Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum ErrorFeedback { #[default] Off, Bell, Flash }
+
+impl ErrorFeedback {
+    pub(crate) fn name(self) -> &'static str {
+        match self { ErrorFeedback::Off => "Off", ErrorFeedback::Bell => "Bell", ErrorFeedback::Flash => "Flash" }
+    }
+
+    pub(crate) fn next(self, up: bool) -> Self {
+        const ALL: [ErrorFeedback; 3] = [ErrorFeedback::Off, ErrorFeedback::Bell, ErrorFeedback::Flash];
+        let i = ALL.iter().position(|&m| m == self).unwrap_or(0);
+        ALL[if up { (i + 1) % ALL.len() } else { (i + ALL.len() - 1) % ALL.len() }]
+    }
+}
+
+/// How the edit position on the display is marked: not at all, or by the terminal cursor, steady or blinking.
+#[derive(Clone, Copy, PartialEq, Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum CursorMode { Off, Steady, #[default] Blinking }
+
+impl CursorMode {
+    pub(crate) fn name(self) -> &'static str {
+        match self { CursorMode::Off => "Off", CursorMode::Steady => "Steady", CursorMode::Blinking => "Blinking" }
+    }
+
+    pub(crate) fn next(self, up: bool) -> Self {
+        const ALL: [CursorMode; 3] = [CursorMode::Off, CursorMode::Steady, CursorMode::Blinking];
+        let i = ALL.iter().position(|&m| m == self).unwrap_or(0);
+        ALL[if up { (i + 1) % ALL.len() } else { (i + ALL.len() - 1) % ALL.len() }]
+    }
+}
+
+/// The user-adjustable settings, edited through the settings overlay.
+pub(crate) struct Settings {
+    pub(crate) decimals: usize, pub(crate) rounding: RoundingMode, pub(crate) group_separator: Option<char>,
+    pub(crate) saturate: bool, pub(crate) max_length: usize, pub(crate) max_depth: usize,
+    pub(crate) timeout_secs: u64, pub(crate) osc52: bool, pub(crate) backend: NumberBackend,
+    pub(crate) currency: &'static str, pub(crate) tax_rates: Vec<f64>, pub(crate) tax_index: usize,
+    pub(crate) word_bits: u32, pub(crate) utc_offset_minutes: i32, pub(crate) error_feedback: ErrorFeedback,
+    pub(crate) key_click: bool, pub(crate) idle_minutes: u32, pub(crate) window_title: bool,
+    pub(crate) superscripts: bool, pub(crate) cursor: CursorMode, pub(crate) usage_stats: bool,
+    pub(crate) snap_integers: bool, pub(crate) si_suffixes: bool,
+    /// Whether `sin`, `cos` and `tan` take degrees instead of radians.
+    pub(crate) degrees: bool,
+}
+
+/// The labels of the settings overlay entries, in display order.
+pub(crate) const SETTING_LABELS: [&str; 22] = [
+    "Decimal places",
+    "Rounding",
+    "Digit grouping",
+    "On overflow",
+    "Max length",
+    "Max nesting",
+    "Time limit",
+    "Copy via",
+    "Currency",
+    "Tax rate",
+    "Word size",
+    "Time zone",
+    "Error feedback",
+    "Key click",
+    "Screensaver",
+    "Window title",
+    "Exponents",
+    "Cursor",
+    "Usage stats",
+    "Near integers",
+    "Engineering",
+    "Angles",
+];
+
+/// The idle times after which the screensaver can start, in minutes; 0 never starts it.
+pub(crate) const IDLE_MINUTES: [u32; 7] = [0, 1, 2, 5, 10, 15, 30];
+
+/// The selectable thousands separators; `None` disables grouping.
+pub(crate) const GROUP_SEPARATORS: [Option<char>; 5] = [None, Some(','), Some(' '), Some('\''), Some('_')];
+
+/// The currency symbols money results can be shown with; the empty string shows none.
+pub(crate) const CURRENCY_SYMBOLS: [&str; 5] = ["$", "€", "£", "¥", ""];
+
+impl Settings {
+    // The rows of the settings overlay, as indices into `SETTING_LABELS`.
+    pub(crate) const DECIMALS: usize = 0;
+    pub(crate) const ROUNDING: usize = 1;
+    pub(crate) const GROUPING: usize = 2;
+    pub(crate) const OVERFLOW: usize = 3;
+    pub(crate) const MAX_LENGTH: usize = 4;
+    pub(crate) const MAX_NESTING: usize = 5;
+    pub(crate) const TIME_LIMIT: usize = 6;
+    pub(crate) const COPY_VIA: usize = 7;
+    pub(crate) const CURRENCY: usize = 8;
+    pub(crate) const TAX_RATE: usize = 9;
+    pub(crate) const WORD_SIZE: usize = 10;
+    pub(crate) const TIME_ZONE: usize = 11;
+    pub(crate) const ERROR_FEEDBACK: usize = 12;
+    pub(crate) const KEY_CLICK: usize = 13;
+    pub(crate) const SCREENSAVER: usize = 14;
+    pub(crate) const WINDOW_TITLE: usize = 15;
+    pub(crate) const EXPONENTS: usize = 16;
+    pub(crate) const CURSOR: usize = 17;
+    pub(crate) const USAGE_STATS: usize = 18;
+    pub(crate) const NEAR_INTEGERS: usize = 19;
+    pub(crate) const ENGINEERING: usize = 20;
+    pub(crate) const ANGLES: usize = 21;
+
+    pub(crate) fn default() -> Self {
+        Settings {
+            decimals: 8, rounding: RoundingMode::HalfUp, group_separator: None, saturate: false, max_length: 256,
+            max_depth: 64, timeout_secs: 5, osc52: false, backend: NumberBackend::Float, currency: "$",
+            tax_rates: Vec::new(), tax_index: 0, word_bits: 64, utc_offset_minutes: 0,
+            error_feedback: ErrorFeedback::Off, key_click: false, idle_minutes: 0, window_title: false,
+            superscripts: false, cursor: CursorMode::Blinking, usage_stats: false, snap_integers: false,
+            si_suffixes: false, degrees: false,
+        }
+    }
+
+    /// The evaluator options implied by these settings, with the time budget starting now.
+    pub(crate) fn eval_options(&self) -> EvalOptions {
+        let deadline = Some(Instant::now() + Duration::from_secs(self.timeout_secs));
+        EvalOptions {
+            saturate: self.saturate, max_depth: self.max_depth, cancel: None, deadline, variables: HashMap::new(),
+            backend: self.backend, word_bits: self.word_bits, rates: Arc::new(Rates::builtin()),
+            utc_offset_minutes: self.utc_offset_minutes, si_suffixes: self.si_suffixes, degrees: self.degrees,
+        }
+    }
+
+    /// The display settings, as the formatting functions take them.
+    pub(crate) fn format_options(&self) -> FormatOptions {
+        FormatOptions {
+            decimals: self.decimals, rounding: self.rounding, group_separator: self.group_separator,
+            currency: self.currency, snap_integers: self.snap_integers, superscripts: self.superscripts,
+            si_suffixes: self.si_suffixes,
+        }
+    }
+
+    /// Returns the current value of the entry at `index` as display text.
+    pub(crate) fn value_text(&self, index: usize) -> String {
+        match index {
+            Self::DECIMALS => self.decimals.to_string(),
+            Self::ROUNDING => self.rounding.name().to_string(),
+            Self::GROUPING => match self.group_separator { None => "Off".to_string(), Some(' ') => "Space".to_string(), Some(c) => format!("'{}'", c) },
+            Self::OVERFLOW => if self.saturate { "Show ∞".to_string() } else { "Error".to_string() },
+            Self::MAX_LENGTH => self.max_length.to_string(),
+            Self::MAX_NESTING => self.max_depth.to_string(),
+            Self::TIME_LIMIT => format!("{} s", self.timeout_secs),
+            Self::COPY_VIA => if self.osc52 { "OSC 52".to_string() } else { "System".to_string() },
+            Self::CURRENCY => if self.currency.is_empty() { "None".to_string() } else { self.currency.to_string() },
+            Self::TAX_RATE => self.tax_rates.get(self.tax_index).map_or("None".to_string(), |rate| format!("{}%", rate)),
+            Self::WORD_SIZE => format!("{} bit", self.word_bits),
+            Self::TIME_ZONE => utc_offset_text(self.utc_offset_minutes),
+            Self::ERROR_FEEDBACK => self.error_feedback.name().to_string(),
+            Self::KEY_CLICK => if self.key_click { "On".to_string() } else { "Off".to_string() },
+            Self::SCREENSAVER => if self.idle_minutes == 0 { "Off".to_string() } else { format!("After {} min", self.idle_minutes) },
+            Self::WINDOW_TITLE => if self.window_title { "Result".to_string() } else { "Off".to_string() },
+            Self::EXPONENTS => if self.superscripts { "2³, 1.5×10⁶".to_string() } else { "2^3".to_string() },
+            Self::CURSOR => self.cursor.name().to_string(),
+            Self::USAGE_STATS => if self.usage_stats { "On, kept locally".to_string() } else { "Off".to_string() },
+            Self::NEAR_INTEGERS => if self.snap_integers { "Show as integers".to_string() } else { "As computed".to_string() },
+            Self::ENGINEERING => if self.si_suffixes { "4.7k, 100n".to_string() } else { "Off".to_string() },
+            Self::ANGLES => if self.degrees { "Degrees".to_string() } else { "Radians".to_string() },
+            _ => String::new(),
+        }
+    }
+
+    /// Steps the entry at `index` up or down.
+    pub(crate) fn adjust(&mut self, index: usize, up: bool) {
+        match index {
+            Self::DECIMALS => self.decimals = if up { (self.decimals + 1).min(15) } else { self.decimals.saturating_sub(1) },
+            Self::ROUNDING => self.rounding = self.rounding.next(up),
+            Self::GROUPING => {
+                let i = GROUP_SEPARATORS.iter().position(|&c| c == self.group_separator).unwrap_or(0);
+                let len = GROUP_SEPARATORS.len();
+                self.group_separator = GROUP_SEPARATORS[if up { (i + 1) % len } else { (i + len - 1) % len }];
+            }
+            Self::OVERFLOW => self.saturate = !self.saturate,
+            Self::MAX_LENGTH => self.max_length = if up { (self.max_length + 32).min(1024) } else { (self.max_length - 32).max(32) },
+            Self::MAX_NESTING => self.max_depth = if up { (self.max_depth + 8).min(256) } else { (self.max_depth - 8).max(8) },
+            Self::TIME_LIMIT => self.timeout_secs = if up { (self.timeout_secs + 1).min(60) } else { (self.timeout_secs - 1).max(1) },
+            Self::COPY_VIA => self.osc52 = !self.osc52,
+            Self::CURRENCY => {
+                let i = CURRENCY_SYMBOLS.iter().position(|&c| c == self.currency).unwrap_or(0);
+                let len = CURRENCY_SYMBOLS.len();
+                self.currency = CURRENCY_SYMBOLS[if up { (i + 1) % len } else { (i + len - 1) % len }];
+            }
+            Self::TAX_RATE if !self.tax_rates.is_empty() => {
+                let len = self.tax_rates.len();
+                self.tax_index = if up { (self.tax_index + 1) % len } else { (self.tax_index + len - 1) % len };
+            }
+            Self::WORD_SIZE => {
+                let i = WORD_SIZES.iter().position(|&bits| bits == self.word_bits).unwrap_or(0);
+                let len = WORD_SIZES.len();
+                self.word_bits = WORD_SIZES[if up { (i + 1) % len } else { (i + len - 1) % len }];
+            }
+            Self::TIME_ZONE => {
+                self.utc_offset_minutes = if up { (self.utc_offset_minutes + 30).min(14 * 60) } else { (self.utc_offset_minutes - 30).max(-12 * 60) };
+            }
+            Self::ERROR_FEEDBACK => self.error_feedback = self.error_feedback.next(up),
+            Self::KEY_CLICK => self.key_click = !self.key_click,
+            Self::SCREENSAVER => {
+                let i = IDLE_MINUTES.iter().position(|&minutes| minutes == self.idle_minutes).unwrap_or(0);
+                let len = IDLE_MINUTES.len();
+                self.idle_minutes = IDLE_MINUTES[if up { (i + 1) % len } else { (i + len - 1) % len }];
+            }
+            Self::WINDOW_TITLE => self.window_title = !self.window_title,
+            Self::EXPONENTS => self.superscripts = !self.superscripts,
+            Self::CURSOR => self.cursor = self.cursor.next(up),
+            Self::USAGE_STATS => self.usage_stats = !self.usage_stats,
+            Self::NEAR_INTEGERS => self.snap_integers = !self.snap_integers,
+            Self::ENGINEERING => self.si_suffixes = !self.si_suffixes,
+            Self::ANGLES => self.degrees = !self.degrees,
+            _ => {}
+        }
+    }
+}
+
+/// The tip-split helper: the bill as typed, the tip in percent, how many people share it, and the selected row.
+pub(crate) struct TipSplit { pub(crate) bill: String, pub(crate) tip_percent: u32, pub(crate) people: u32, pub(crate) cursor: usize }
+
+impl TipSplit {
+    pub(crate) fn new(bill: String) -> Self { TipSplit { bill, tip_percent: 15, people: 2, cursor: 0 } }
+
+    /// The tip, the total, and each person's share.
+    pub(crate) fn amounts(&self) -> (f64, f64, f64) {
+        let bill: f64 = self.bill.parse().unwrap_or(0.0);
+        let tip = bill * self.tip_percent as f64 / 100.0;
+        (tip, bill + tip, (bill + tip) / self.people as f64)
+    }
+}
+
+/// The amortization view: the form fields as typed (principal, annual rate in percent, term in months),
+/// the selected field, and how far the table is scrolled.
+pub(crate) struct Amortization { pub(crate) fields: [String; 3], pub(crate) cursor: usize, pub(crate) scroll: usize }
+
+impl Amortization {
+    pub(crate) const LABELS: [&'static str; 3] = ["Principal", "Annual rate %", "Months"];
+    /// How many schedule rows the view shows at its full height, and so how far it can scroll.
+    pub(crate) const PAGE: usize = 17;
+
+    pub(crate) fn schedule(&self) -> Vec<AmortizationRow> {
+        let [principal, rate, months] = self.fields.each_ref().map(|field| field.parse::<f64>().unwrap_or(0.0));
+        if principal <= 0.0 || months < 1.0 { return Vec::new(); }
+        amortization_schedule(principal, rate, months.min(1200.0) as u32)
+    }
+}
+
+/// Writes a schedule as CSV.
+pub(crate) fn write_amortization_csv(rows: &[AmortizationRow], mut out: impl Write) -> io::Result<()> {
+    writeln!(out, "period,payment,interest,principal,balance")?;
+    for row in rows { writeln!(out, "{},{:.2},{:.2},{:.2},{:.2}", row.period, row.payment, row.interest, row.principal, row.balance)?; }
+    Ok(())
+}
+
+/// The mortgage wizard: amount, annual rate in percent, years, and an extra monthly payment, as typed.
+pub(crate) struct Mortgage { pub(crate) fields: [String; 4], pub(crate) cursor: usize }
+
+impl Mortgage {
+    pub(crate) const LABELS: [&'static str; 4] = ["Amount", "Annual rate %", "Years", "Extra per month"];
+
+    pub(crate) fn summary(&self) -> Option<MortgageSummary> {
+        let [amount, rate, years, extra] = self.fields.each_ref().map(|field| field.parse::<f64>().unwrap_or(0.0));
+        if amount <= 0.0 || years <= 0.0 { return None; }
+        mortgage_summary(amount, rate, (years * 12.0).round().clamp(1.0, 1200.0) as u32, extra)
+    }
+}
+
+/// The command palette: the filter typed so far and the selected match.
+#[derive(Default)]
+pub(crate) struct Palette { pub(crate) query: String, pub(crate) cursor: usize }
+
+/// Something the command palette can run.
+pub(crate) type Action = fn(&mut App);
+
+/// The actions the command palette offers.
+pub(crate) const PALETTE_ACTIONS: [(&str, Action); 38] = [
+    ("Settings", |app| app.overlay = Some(Overlay::Settings)),
+    ("Timing panel", |app| app.stats_open = !app.stats_open),
+    ("Switch numeric backend", cycle_backend),
+    ("Finance keypad", |app| app.finance_keypad = !app.finance_keypad),
+    ("Tip split", open_tip_split),
+    ("Loan amortization schedule", open_amortization),
+    ("Mortgage calculator", open_mortgage),
+    ("Quadratic solver", open_quad),
+    ("Snippets", |app| app.overlay = Some(Overlay::SnippetMenu(0))),
+    ("Function picker", |app| app.overlay = Some(Overlay::FunctionPicker(0))),
+    ("Copy result", copy_result),
+    ("Label last result", open_note),
+    ("Export history", export_history),
+    ("Compare results", open_compare),
+    ("Statistics mode", |app| app.stats_mode = !app.stats_mode),
+    ("RPN mode", toggle_rpn),
+    ("Show postfix/prefix notation", cycle_notation),
+    ("Parse tree", |app| app.tree_panel = !app.tree_panel),
+    ("Variables", |app| app.variables_panel = !app.variables_panel),
+    ("Presentation mode", |app| app.presenting = !app.presenting),
+    ("History entry menu", open_history_menu),
+    ("Tutor mode", |app| { app.tutor_mode = !app.tutor_mode; app.tutor_text = None; }),
+    ("Practice mode", toggle_quiz),
+    ("Switch profile", next_profile),
+    ("Clear data points", |app| app.data_points.clear()),
+    ("Base converter", |app| app.base_panel = !app.base_panel),
+    ("Float inspector", |app| app.inspector_open = !app.inspector_open),
+    ("Add tax", |app| apply_tax(app, true)),
+    ("Remove tax", |app| apply_tax(app, false)),
+    ("Refresh exchange rates", |app| refresh_exchange_rates(app, true)),
+    ("Most used", |app| app.overlay = Some(Overlay::Usage)),
+    ("Theme preview", |app| app.overlay = Some(Overlay::ThemePreview)),
+    ("Evaluate and keep expression", |app| evaluate_then(app, Followup::KeepExpression)),
+    ("Evaluate and copy", |app| evaluate_then(app, Followup::Copy)),
+    ("Export variables", export_variables),
+    ("Import variables", import_variables),
+    ("Key help", |app| app.overlay = (!matches!(app.overlay, Some(Overlay::KeyHelp))).then_some(Overlay::KeyHelp)),
+    ("Chart of results", open_chart),
+];
+
+/// The built-in shortcuts: a key as `keys` in the config writes it, the palette action it runs, and how the footer
+/// words it, if it is worth the room there. Keys bound in the config are looked up first, so they can take these over.
+pub(crate) const DEFAULT_KEYS: [(&str, &str, &str); 18] = [
+    ("f1", "Key help", "for all keys"), ("f2", "Settings", "for settings"), ("f3", "Timing panel", "for timing"), ("f4", "Switch numeric backend", ""),
+    ("f5", "Finance keypad", "for finance keys"), ("f6", "Tip split", "to split a tip"), ("f7", "Loan amortization schedule", "for a loan schedule"),
+    ("f8", "Statistics mode", "for statistics"), ("f9", "Base converter", "for bases"), ("f10", "Float inspector", "to inspect floats"),
+    ("shift+f10", "History entry menu", ""), ("ctrl+c", "Copy result", ""), ("ctrl+r", "RPN mode", ""), ("ctrl+t", "Add tax", ""), ("ctrl+n", "Remove tax", ""),
+    ("alt+n", "Show postfix/prefix notation", ""), ("alt+t", "Parse tree", ""), ("alt+p", "Presentation mode", ""),
+];
+
+/// The keys handled outside the binding table, listed in the key help: quitting, the palette, and keys whose meaning
+/// depends on what has the focus.
+pub(crate) const FIXED_KEYS: [(&str, &str); 9] = [
+    ("Ctrl+Q", "Quit"), ("Ctrl+P", "Command palette"), ("Alt+H", "Focus the history tape"),
+    ("Tab", "Complete a name"), ("Esc", "Clear, or cancel an evaluation"),
+    ("{ / }", "Fewer / more decimals"), ("#", "Label last result"), ("←/→", "Scroll the display"), ("F12", "Debug overlay"),
+];
+
+/// A key as the help shows it: `ctrl+t` is `Ctrl+T`.
+pub(crate) fn key_label(key: &str) -> String {
+    key.split('+').map(|part| {
+        let mut chars = part.trim().chars();
+        chars.next().map_or(String::new(), |first| first.to_uppercase().chain(chars).collect())
+    }).collect::<Vec<_>>().join("+")
+}
+
+/// The palette action with this exact name, ignoring case.
+pub(crate) fn palette_action(name: &str) -> Option<Action> {
+    PALETTE_ACTIONS.iter().find(|(action, _)| action.eq_ignore_ascii_case(name)).map(|&(_, action)| action)
+}
+
+/// Runs a configured button: its action, then its snippet typed key by key, so it goes through the same input checks as the keyboard.
+pub(crate) fn press_custom_button(app: &mut App, index: usize) {
+    let Some(button) = app.custom_buttons.get(index).cloned() else { return };
+    if let Some(action) = button.action.as_deref().and_then(palette_action) { action(app); }
+    for c in button.insert.iter().flat_map(|snippet| snippet.chars()) { on_click(app, &c.to_string()); }
+    app.set_active_button(&format!("user:{}", index));
+}
+
+/// The palette actions whose names contain `query`, ignoring case.
+pub(crate) fn palette_matches(query: &str) -> Vec<usize> {
+    let query = query.to_lowercase();
+    (0..PALETTE_ACTIONS.len()).filter(|&i| PALETTE_ACTIONS[i].0.to_lowercase().contains(&query)).collect()
+}
+
+/// What a worker thread sends back: the outcome and how long each stage took.
+pub(crate) type Evaluated = (Result<Outcome, EvalError>, EvalTimings);
+
+/// An evaluation waiting for a worker thread or running on one; the result arrives through `receiver`.
+pub(crate) struct PendingEvaluation {
+    pub(crate) expression: String, pub(crate) started: Instant, pub(crate) receiver: mpsc::Receiver<Evaluated>, pub(crate) cancel: Arc<AtomicBool>,
+    pub(crate) followup: Followup,
+    /// The options and the sending end for the worker, until `Effect::Evaluate` hands them over.
+    pub(crate) job: Option<(EvalOptions, mpsc::Sender<Evaluated>)>,
+}
+
+/// What happens once an evaluation succeeds, besides showing its result: `Shift+Enter` keeps the expression
+/// on the display for more editing, `Ctrl+Enter` copies the result.
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub(crate) enum Followup { #[default] Show, KeepExpression, Copy }
+
+/// A single evaluated calculation, kept at full precision.
+/// `exact` holds the result text of a non-float backend, and `note` the user's label for the entry;
+/// `reused` counts how often its value went into later calculations, while usage stats are on.
+pub(crate) struct HistoryEntry {
+    pub(crate) expression: String, pub(crate) value: f64, pub(crate) exact: Option<String>,
+    pub(crate) backend: NumberBackend, pub(crate) timings: EvalTimings, pub(crate) note: Option<String>,
+    pub(crate) pinned: bool, pub(crate) reused: u32,
+}
+
+/// What the display is showing, which decides how the next key treats it: typing replaces a shown result,
+/// while after an error the expression stays on the display to be corrected.
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub(crate) enum Mode { #[default] Editing, ResultShown, ErrorShown }
+
+/// The overlay that has the keyboard; only one is open at a time, and opening another replaces it.
+pub(crate) enum Overlay {
+    Palette(Palette), Settings, KeyHelp, Usage, ThemePreview, TipSplit(TipSplit), Amortization(Amortization), Mortgage(Mortgage),
+    /// The snippet menu and its cursor.
+    SnippetMenu(usize),
+    /// The function picker and its cursor, an index into `picker_functions()`.
+    FunctionPicker(usize),
+    /// The note being typed for the newest history entry.
+    NoteEditor(String),
+    /// The history entry being bound to a name, and the name typed so far.
+    VariablePrompt(usize, String),
+    Compare(Compare),
+    /// The chart of results and the history entry it highlights.
+    Chart(usize),
+    /// The context menu of a history entry.
+    HistoryMenu(HistoryMenu),
+}
+
+/// Which part of the app receives keys: the topmost open overlay, or else the display in its current mode.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub(crate) enum Focus {
+    Display(Mode), Palette, Settings, KeyHelp, Usage, ThemePreview, TipSplit, Amortization, Mortgage, SnippetMenu,
+    FunctionPicker, NoteEditor, VariablePrompt, Compare, Chart, HistoryMenu, History,
+}
+
+/// A short message in the corner of the screen that goes away by itself, for things that happen without a key press.
+pub(crate) struct Toast { pub(crate) text: String, pub(crate) error: bool, pub(crate) until: Instant }
+
+/// How long a toast stays up.
+pub(crate) const TOAST_TIME: Duration = Duration::from_secs(3);
+
+/// Work that reaches outside the app, queued by actions and carried out by the event loop: `Evaluate` starts the
+/// pending evaluation on a worker thread, and the others run a program, touch a file or the network, or resize.
+#[derive(Clone, PartialEq, Debug)]
+pub(crate) enum Effect {
+    Copy(String), WriteFile { path: String, contents: String, notice: String }, Resize(u16, u16), Evaluate,
+    FetchRates { url: String, ttl_hours: u64, force: bool }, ReadVariables,
+}
+
+/// The main application struct.
+pub(crate) struct App {
+    pub(crate) display_value: String, mode: Mode, pub(crate) active_button: Option<(String, Instant)>,
+    pub(crate) button_rects: Vec<(Rect, String)>, pub(crate) should_quit: bool, pub(crate) effects: Vec<Effect>,
+    pub(crate) tasks: Tasks, pub(crate) rates: Arc<Rates>, pub(crate) theme: Theme,
+    pub(crate) last_timings: Option<EvalTimings>, pub(crate) error_message: Option<String>,
+    pub(crate) error_span: Option<SourceSpan>, pub(crate) notice: Option<String>,
+    pub(crate) last_expression: Option<String>, pub(crate) last_result: Option<f64>,
+    pub(crate) last_exact: Option<String>,
+    /// What the last result shows instead of its value, like the character of `char(65)`.
+    pub(crate) last_label: Option<String>, pub(crate) history: Vec<HistoryEntry>, pub(crate) settings: Settings,
+    pub(crate) settings_cursor: usize, pub(crate) display_scroll: usize,
+    pub(crate) pending: Option<PendingEvaluation>, pub(crate) stats_open: bool, pub(crate) finance_keypad: bool,
+    /// The rate and direction of a pending `+TAX`/`−TAX` evaluation, used to describe its result.
+    pub(crate) tax_step: Option<(f64, bool)>,
+    /// The open overlay, if any.
+    pub(crate) overlay: Option<Overlay>,
+    /// In statistics mode `=` adds the value to `data_points` instead of showing it as a result; `x, y` adds a pair.
+    pub(crate) stats_mode: bool, pub(crate) data_points: Vec<(Option<f64>, f64)>,
+    /// The last matrix result, shown as a grid in the side panel.
+    pub(crate) matrix_result: Option<Matrix>,
+    /// The roots from `quad(a, b, c)`, shown on the two display lines until the next key.
+    pub(crate) quad_roots: Option<QuadRoots>,
+    /// Whether the side panel shows the base converter.
+    pub(crate) base_panel: bool,
+    /// Whether the IEEE-754 inspector overlay is showing.
+    pub(crate) inspector_open: bool,
+    /// The keys from the config file, shown in the user row of the keypad.
+    pub(crate) custom_buttons: Vec<CustomButton>,
+    /// The snippet being filled in; while set, typed text goes into its current field.
+    pub(crate) snippet: Option<SnippetFill>,
+    /// Results bound to names from the history tape, usable in expressions and listed in the variables panel.
+    pub(crate) variables: BTreeMap<String, f64>, pub(crate) variables_panel: bool,
+    /// Where each visible history entry was drawn, for right-clicks.
+    pub(crate) history_rows: Vec<(Rect, usize)>,
+    /// The selected entry while the history tape has the keyboard focus.
+    pub(crate) history_cursor: Option<usize>, pub(crate) usage: BTreeMap<String, u32>,
+    /// Whether the hidden F12 overlay with frame and evaluation costs is open, and the numbers it shows.
+    pub(crate) debug_overlay: bool, pub(crate) frames: FrameStats,
+    /// Whether something on screen may have changed since the last frame, so the event loop has to draw again.
+    pub(crate) dirty: bool,
+    /// What the terminal can show; everything until `main` detects otherwise.
+    pub(crate) capabilities: Capabilities,
+    /// Whether to spend as few bytes per frame as possible, for slow links: no key highlights and no spinner animation.
+    pub(crate) low_bandwidth: bool,
+    /// The link to the history and variables shared with other running instances, when sharing is on.
+    pub(crate) share: Option<Share>,
+    /// Where and when the session is saved for `--resume`, outside of tests.
+    pub(crate) autosave: Option<Autosave>,
+    /// In RPN mode `=` pushes the entry onto `rpn_stack`, whose last value is X, and operators work on the stack.
+    pub(crate) rpn_mode: bool, pub(crate) rpn_stack: Vec<f64>,
+    /// The notation the expression line shows the parsed expression in, when not infix.
+    pub(crate) notation: Option<Notation>,
+    /// Whether the side panel shows the parse tree of the expression.
+    pub(crate) tree_panel: bool,
+    /// Presentation mode: only the expression and value, in large glyphs.
+    pub(crate) presenting: bool,
+    /// Set by the `2nd` key: the next button press uses its secondary function.
+    pub(crate) second: bool,
+    /// In tutor mode the side panel tells the order of the last evaluation, kept in `tutor_text`.
+    pub(crate) tutor_mode: bool, pub(crate) tutor_text: Option<String>,
+    /// The practice session, while one is running; `=` then checks the answer.
+    pub(crate) quiz: Option<Quiz>,
+    /// Until when the display border flashes for an error.
+    pub(crate) flash_until: Option<Instant>, pub(crate) toast: Option<Toast>,
+    /// When the last key or click came in, for the screensaver.
+    pub(crate) last_input: Instant,
+    /// The active profile's name, the sessions of the others, and the config they are made from.
+    pub(crate) profile: String, pub(crate) profiles: HashMap<String, ProfileSession>, pub(crate) config: Config,
+}
+
+impl App {
+    pub(crate) fn new() -> App {
+        App {
+            display_value: String::from("0"), mode: Mode::Editing, active_button: None, button_rects: Vec::new(),
+            should_quit: false, effects: Vec::new(), tasks: Tasks::new(), rates: Arc::new(Rates::builtin()),
+            theme: Theme::default(), last_timings: None, error_message: None, error_span: None, notice: None,
+            last_expression: None, last_result: None, last_exact: None, last_label: None, history: Vec::new(),
+            settings: Settings::default(), settings_cursor: 0, display_scroll: 0, pending: None, stats_open: false,
+            finance_keypad: false, tax_step: None, overlay: None, stats_mode: false, data_points: Vec::new(),
+            matrix_result: None, quad_roots: None, base_panel: false, inspector_open: false,
+            custom_buttons: Vec::new(), snippet: None, variables: BTreeMap::new(), variables_panel: false,
+            history_rows: Vec::new(), history_cursor: None, usage: BTreeMap::new(), debug_overlay: false,
+            frames: FrameStats::default(), dirty: true, capabilities: Capabilities::FULL, low_bandwidth: false,
+            share: None, autosave: None, rpn_mode: false, rpn_stack: Vec::new(), notation: None, tree_panel: false,
+            presenting: false, second: false, tutor_mode: false, tutor_text: None, quiz: None, flash_until: None,
+            toast: None, last_input: Instant::now(), profile: String::from("default"), profiles: HashMap::new(),
+            config: Config::default(),
+        }
+    }
+
+    /// Re-formats the displayed result after a display setting changed.
+    pub(crate) fn refresh_result(&mut self) {
+        if let (true, Some(value), None) = (self.mode == Mode::ResultShown, self.last_result, &self.last_label) {
+            self.display_value = operand_text(value, self.last_exact.as_deref(), self.settings.backend, &self.settings.format_options());
+        }
+    }
+
+    /// What the display is showing: an expression being edited, a result, or a failed expression.
+    pub(crate) fn mode(&self) -> Mode { self.mode }
+
+    /// Puts `text` on the display to be edited further.
+    pub(crate) fn edit(&mut self, text: impl Into<String>) { self.display_value = text.into(); self.mode = Mode::Editing; }
+
+    /// Puts a result on the display; the next digit starts a new expression.
+    pub(crate) fn show_result(&mut self, text: impl Into<String>) { self.display_value = text.into(); self.mode = Mode::ResultShown; }
+
+    /// Clears the error and notice lines; called on every keystroke. A failed expression goes back to being edited.
+    pub(crate) fn clear_messages(&mut self) {
+        self.error_message = None;
+        self.error_span = None;
+        self.notice = None;
+        if self.mode == Mode::ErrorShown { self.mode = Mode::Editing; }
+    }
+
+    /// Shows why the expression on the display failed, leaving it there to be corrected.
+    pub(crate) fn show_error(&mut self, error: &EvalError, expression: &str) {
+        self.error_message = Some(error.describe(expression));
+        self.error_span = error.span;
+        self.mode = Mode::ErrorShown;
+    }
+
+    /// Takes up a changed config file: custom buttons, key bindings, and the settings and theme it gives for the current profile.
+    pub(crate) fn reload_config(&mut self, config: Config) {
+        self.settings = config.settings(&self.profile);
+        self.theme = config.theme(&self.profile);
+        self.custom_buttons = config.buttons.clone();
+        self.config = config;
+        self.refresh_result();
+        self.show_toast("Reloaded config.toml", false);
+    }
+
+    /// Shows a short message in a corner for a few seconds, over whatever is open.
+    pub(crate) fn show_toast(&mut self, text: &str, error: bool) {
+        self.toast = Some(Toast { text: text.to_string(), error, until: Instant::now() + TOAST_TIME });
+    }
+
+    /// Which part of the app receives keys. Overlays are listed from the topmost down.
+    pub(crate) fn focus(&self) -> Focus {
+        match &self.overlay {
+            Some(Overlay::Palette(_)) => Focus::Palette,
+            Some(Overlay::Settings) => Focus::Settings,
+            Some(Overlay::KeyHelp) => Focus::KeyHelp,
+            Some(Overlay::Usage) => Focus::Usage,
+            Some(Overlay::ThemePreview) => Focus::ThemePreview,
+            Some(Overlay::TipSplit(_)) => Focus::TipSplit,
+            Some(Overlay::Amortization(_)) => Focus::Amortization,
+            Some(Overlay::Mortgage(_)) => Focus::Mortgage,
+            Some(Overlay::SnippetMenu(_)) => Focus::SnippetMenu,
+            Some(Overlay::FunctionPicker(_)) => Focus::FunctionPicker,
+            Some(Overlay::NoteEditor(_)) => Focus::NoteEditor,
+            Some(Overlay::VariablePrompt(..)) => Focus::VariablePrompt,
+            Some(Overlay::Compare(_)) => Focus::Compare,
+            Some(Overlay::Chart(_)) => Focus::Chart,
+            Some(Overlay::HistoryMenu(_)) => Focus::HistoryMenu,
+            None if self.history_cursor.is_some() => Focus::History,
+            None => Focus::Display(self.mode),
+        }
+    }
+
+    pub(crate) fn set_active_button(&mut self, label: &str) {
+        if self.low_bandwidth { return; }
+        self.active_button = Some((label.to_string(), Instant::now()));
+    }
+}
+
+/// The secondary functions of keypad buttons, reached with a right-click or after `2nd`: the button, what it types, and its corner label.
+pub(crate) const SECOND_FUNCTIONS: [(&str, &str, &str); 9] = [
+    ("^", "sqrt(", "√"), ("/", "mod(", "mod"), ("*", "^2", "x²"), ("-", "abs(", "|x|"), ("+", "sum(", "Σ"),
+    ("%", "pctchange(", "Δ%"), ("(", "ln(", "ln"), (")", "exp(", "eˣ"), (".", "pi", "π"),
+];
+
+/// The text the secondary function of button `value` types, and its corner label.
+pub(crate) fn second_function(value: &str) -> Option<(&'static str, &'static str)> {
+    SECOND_FUNCTIONS.iter().find(|(button, _, _)| *button == value).map(|&(_, text, label)| (text, label))
+}
+
+/// Types the secondary function of button `value`, if it has one.
+pub(crate) fn press_second(app: &mut App, value: &str) {
+    app.second = false;
+    let Some((text, _)) = second_function(value) else { return };
+    for c in text.chars() { on_click(app, &c.to_string()); }
+    app.set_active_button(value);
+}
+
+/// The logic executed when a button is clicked.
+pub(crate) fn on_click(app: &mut App, value: &str) {
+    if app.pending.is_some() { return; }
+    if value == "2nd" { app.second = !app.second; return app.set_active_button(value); }
+    // After `2nd` a button with a secondary function uses it; any other key just cancels `2nd`.
+    if app.second {
+        app.second = false;
+        if second_function(value).is_some() { return press_second(app, value); }
+    }
+    if app.snippet.is_some() { return on_snippet_input(app, value); }
+    if app.quiz.is_some() && value == "=" {
+        app.set_active_button(value);
+        app.clear_messages();
+        return check_answer(app);
+    }
+    // A negative answer starts with `-` on the empty display, rather than continuing from the last result.
+    if app.quiz.is_some() && value == "-" && app.display_value == "0" { return app.edit("-"); }
+    // `+/-` only works on the stack when nothing was typed; otherwise it edits the entry.
+    let stack_key = matches!(value, "=" | "+" | "-" | "*" | "/" | "^" | "C" | "x⇄y" | "Drop" | "R↓") || value == "+/-" && app.mode() == Mode::ResultShown;
+    if app.rpn_mode && stack_key {
+        app.set_active_button(value);
+        app.clear_messages();
+        return rpn_key(app, value);
+    }
+    app.set_active_button(value);
+    app.clear_messages();
+    app.display_scroll = 0;
+    app.quad_roots = None;
+    let before = app.display_value.clone();
+    // `C` is the clear key, so no unit starts with it.
+    let letter = value.len() == 1 && value != "C" && value.bytes().all(|b| b.is_ascii_alphabetic());
+
+    match value {
+        "+TAX" | "−TAX" => return apply_tax(app, value == "+TAX"),
+        _ if let Some(index) = value.strip_prefix("user:").and_then(|index| index.parse().ok()) => return press_custom_button(app, index),
+        // Inside a character literal any key is text, including the closing quote.
+        _ if in_char_literal(&app.display_value) && value.chars().count() == 1 => app.display_value.push_str(value),
+        "\"" if app.mode() == Mode::ResultShown || app.display_value == "0" => { app.edit("\""); }
+        "\"" if validate_input(&app.display_value, '(', app.settings.si_suffixes) == InputAction::Append => app.display_value.push('"'),
+        _ if let Some(bit) = value.strip_prefix("bit:").and_then(|bit| bit.parse().ok()) => toggle_bit(app, bit),
+        "0" | "1" | "2" | "3" | "4" | "5" | "6" | "7" | "8" | "9" | "(" | "[" if app.mode() == Mode::ResultShown || app.display_value == "0" => {
+            app.edit(value);
+        }
+        "." if app.mode() == Mode::ResultShown => { app.edit("0."); }
+        _ if letter && (app.mode() == Mode::ResultShown || app.display_value == "0") => {
+            app.edit(value);
+        }
+        _ if letter && validate_input(
+            &app.display_value, value.chars().next().unwrap_or(' '), app.settings.si_suffixes
+        ) == InputAction::Append => app.display_value.push_str(value),
+        "_" if app.mode() == Mode::Editing && validate_input(
+            &app.display_value, '_', app.settings.si_suffixes
+        ) == InputAction::Append => app.display_value.push('_'),
+        "pmt" | "fv" | "pv" | "npv" | "irr" if app.mode() == Mode::ResultShown || app.display_value == "0" => {
+            app.edit(format!("{}(", value));
+        }
+        "pmt" | "fv" | "pv" | "npv" | "irr" if validate_input(
+            &app.display_value, '(', app.settings.si_suffixes
+        ) == InputAction::Append => app.display_value.push_str(&format!("{}(", value)),
+        "," if validate_input(&app.display_value, ',', app.settings.si_suffixes) == InputAction::Append => app.display_value.push_str(", "),
+        ":" if validate_input(&app.display_value, ':', app.settings.si_suffixes) == InputAction::Append => app.display_value.push(':'),
+        "$" | "€" | "£" | "¥" | "@" if app.mode() == Mode::ResultShown || app.display_value == "0" => { app.edit(value); }
+        "$" | "€" | "£" | "¥" | "@" if validate_input(
+            &app.display_value, '$', app.settings.si_suffixes
+        ) == InputAction::Append => app.display_value.push_str(value),
+        // In statistics mode a top-level comma separates the x and y of a data pair.
+        "," if app.stats_mode && app.mode() != Mode::ResultShown && top_level_comma(&app.display_value).is_none() && unclosed_parens(&app.display_value) == 0
+            && validate_input(&app.display_value, '%', app.settings.si_suffixes) == InputAction::Append => app.display_value.push_str(", "),
+        "[" | "]" => app.display_value.push_str(value),
+        ";" => app.display_value.push_str("; "),
+        "0" | "1" | "2" | "3" | "4" | "5" | "6" | "7" | "8" | "9" | "(" | ")" | "." | "%" => {
+            let c = value.chars().next().unwrap_or(' ');
+            match validate_input(&app.display_value, c, app.settings.si_suffixes) {
+                InputAction::Append => app.edit(format!("{}{}", app.display_value, c)),
+                InputAction::Replace(start) => app.edit(format!("{}{}", &app.display_value[..start], c)),
+                InputAction::Reject => {}
+            }
+        }
+        "C" => {
+            app.edit("0");
+            (app.last_timings, app.last_expression, app.last_result, app.last_exact, app.last_label) = (None, None, None, None, None);
+            app.matrix_result = None;
+        }
+        "+/-" => {
+             let text = if let Some(last_num_start) = app.display_value.rfind(|c: char| !c.is_ascii_digit() && c != '.') {
+                 let (before, after) = app.display_value.split_at(last_num_start + 1);
+                 if let Some(stripped) = after.strip_prefix('-') { format!("{}{}", before, stripped) } else { format!("{}-{}", before, after) }
+             } else if let Some(stripped) = app.display_value.strip_prefix('-') { stripped.to_string() }
+             else if app.display_value != "0" { format!("-{}", app.display_value) }
+             else { app.display_value.clone() };
+             app.edit(text);
+        }
+        // On a fresh display an operator continues from the previous answer, like a desk calculator.
+        "+" | "-" | "*" | "/" | "^" if app.display_value == "0" && app.last_result.is_some() => {
+            app.display_value = format!("ans {} ", value);
+        }
+        // The result becomes the first operand of the next calculation, at full precision rather than as rounded
+        // for display, so `2 / 3 =` and then `* 3 =` gives 2. A matrix has no single value and stays as shown.
+        "+" | "-" | "*" | "/" | "^" if app.mode() == Mode::ResultShown => {
+            let operand = app.last_result.map_or_else(|| app.display_value.clone(), |result| full_operand_text(result, app.last_exact.as_deref()));
+            app.edit(format!("{} {} ", operand, value));
+        }
+        "+" | "-" | "*" | "/" | "^" => {
+            let trimmed = app.display_value.trim();
+            let last = trimmed.chars().last();
+            // A minus right after `*`, `/`, `^` or `(` is a sign; any other operator replaces a trailing one.
+            let base = trimmed.trim_end_matches(['+', '-', '*', '/', '^', ' ']);
+            let text = if value == "-" && matches!(last, Some('*' | '/' | '^')) { format!("{} -", trimmed) }
+            else if value == "-" && last == Some('(') { format!("{}-", trimmed) }
+            else if !base.is_empty() && !base.ends_with('(') { format!("{} {} ", base, value) }
+            else { app.display_value.clone() };
+            app.edit(text);
+        }
+        "=" if app.display_value.contains('[') => evaluate_matrix_input(app),
+        " " if app.mode() != Mode::ResultShown && !app.display_value.ends_with(' ') => app.display_value.push(' '),
+        "=" if app.stats_mode && top_level_comma(&app.display_value).is_some() => add_pair(app),
+        "=" => start_evaluation(app),
+        _ => {}
+    }
+
+    let length = app.display_value.chars().count();
+    if app.mode() != Mode::ResultShown && length > before.chars().count() && length > app.settings.max_length {
+        app.display_value = before;
+        app.error_message = Some(format!("Expression is limited to {} characters", app.settings.max_length));
+    }
+}
+
+/// The evaluator options for the next calculation, with the named results, `ans`, the numbered history results
+/// `ans1`, `ans2`, … and the regression line as variables.
+pub(crate) fn evaluation_options(app: &App) -> EvalOptions {
+    let mut options = EvalOptions { rates: app.rates.clone(), ..app.settings.eval_options() };
+    options.variables.extend(app.variables.iter().map(|(name, &value)| (name.clone(), value)));
+    if let Some(ans) = app.last_result { options.variables.insert("ans".to_string(), ans); }
+    options.variables.extend(app.history.iter().enumerate().map(|(i, entry)| (format!("ans{}", i + 1), entry.value)));
+    if let Some(fit) = linear_fit(&data_pairs(app)) {
+        options.variables.extend([("slope".to_string(), fit.slope), ("intercept".to_string(), fit.intercept), ("r2".to_string(), fit.r_squared)]);
+    }
+    options
+}
+
+/// Queues the current expression for evaluation on a worker thread, so the UI stays responsive.
+/// Missing closing parentheses are appended first, like most desk calculators do.
+pub(crate) fn start_evaluation(app: &mut App) {
+    app.tax_step = None;
+    app.matrix_result = None;
+    let missing = unclosed_parens(&app.display_value);
+    if missing > 0 {
+        let added = ")".repeat(missing);
+        app.display_value = format!("{}{}", app.display_value.trim_end(), added);
+        app.notice = Some(format!("Added missing `{}`", added));
+    }
+    let expression = app.display_value.clone();
+    let cancel = Arc::new(AtomicBool::new(false));
+    let options = EvalOptions { cancel: Some(cancel.clone()), ..evaluation_options(app) };
+    let (sender, receiver) = mpsc::channel();
+    app.pending = Some(PendingEvaluation { expression, started: Instant::now(), receiver, cancel, followup: Followup::Show, job: Some((options, sender)) });
+    app.effects.push(Effect::Evaluate);
+}
+
+/// Carries out `Effect::Evaluate`: hands the pending evaluation to a worker thread, unless it was cancelled first.
+pub(crate) fn spawn_evaluation(app: &mut App) {
+    let Some(pending) = &mut app.pending else { return };
+    let Some((options, sender)) = pending.job.take() else { return };
+    let source = pending.expression.clone();
+    thread::spawn(move || { let _ = sender.send(evaluate_timed(&source, &options)); });
+}
+
+
+/// The byte offset of the first comma outside any parentheses.
+pub(crate) fn top_level_comma(expression: &str) -> Option<usize> {
+    let mut depth = 0usize;
+    expression.char_indices().find_map(|(i, c)| {
+        match c { '(' => depth += 1, ')' => depth = depth.saturating_sub(1), ',' if depth == 0 => return Some(i), _ => {} }
+        None
+    })
+}
+
+/// Evaluates a matrix expression right away; small systems are quick enough not to need the worker thread.
+pub(crate) fn evaluate_matrix_input(app: &mut App) {
+    let expression = app.display_value.trim().to_string();
+    match evaluate_matrix(&app.display_value, 0, &app.settings.eval_options()) {
+        Ok(MatrixValue::Scalar(value)) => {
+            app.history.push(HistoryEntry {
+                expression: expression.clone(), value, exact: None, backend: app.settings.backend,
+                timings: EvalTimings::default(), note: None, pinned: false, reused: 0,
+            });
+            app.show_result(format_result(value, &app.settings.format_options()));
+            app.last_result = Some(value);
+            (app.last_exact, app.last_label) = (None, None);
+            app.matrix_result = None;
+        }
+        Ok(MatrixValue::Matrix(matrix)) => {
+            app.show_result(matrix.literal());
+            app.last_result = None;
+            app.last_exact = None;
+            app.notice = Some(format!("{}×{} matrix", matrix.rows, matrix.cols));
+            app.matrix_result = Some(matrix);
+        }
+        Err(e) => { let expression = app.display_value.clone(); return app.show_error(&e, &expression); }
+    }
+    app.last_expression = Some(expression);
+}
+
+/// Whether `expression` ends inside an unclosed `"` literal.
+pub(crate) fn in_char_literal(expression: &str) -> bool { expression.matches('"').count() % 2 == 1 }
+
+/// Refreshes the exchange rates in the background from the configured endpoint. At startup the cache is used while
+/// it is fresh; asking from the palette always downloads.
+pub(crate) fn refresh_exchange_rates(app: &mut App, force: bool) {
+    let Some(url) = app.config.rates_url.clone() else {
+        if force { app.error_message = Some("Set rates_url in config.toml to fetch exchange rates".to_string()); }
+        return;
+    };
+    app.effects.push(Effect::FetchRates { url, ttl_hours: app.config.rates_ttl_hours.unwrap_or(12), force });
+}
+
+/// Starts `quad(` on a fresh display.
+pub(crate) fn open_quad(app: &mut App) {
+    app.edit(String::from("quad("));
+    app.notice = Some("Enter a, b, c and press =".to_string());
+}
+
+/// The formula templates of the snippet menu; each `{name}` is a field to fill in.
+pub(crate) const SNIPPETS: [(&str, &str); 7] = [
+    ("Compound growth", "{a}*(1+{r})^{n}"),
+    ("Simple interest", "{p}*(1+{r}*{t})"),
+    ("Percent of", "{x}*{pct}/100"),
+    ("Loan payment", "pmt({rate}, {periods}, {pv})"),
+    ("Hypotenuse", "sqrt({a}^2+{b}^2)"),
+    ("Circle area", "pi*{r}^2"),
+    ("Celsius to Fahrenheit", "{c}*9/5+32"),
+];
+
+/// A snippet being filled in: its template, the text typed into each field, and the field being edited.
+pub(crate) struct SnippetFill { pub(crate) template: &'static str, pub(crate) values: Vec<String>, pub(crate) field: usize }
+
+impl SnippetFill {
+    pub(crate) fn new(template: &'static str) -> Self { SnippetFill { template, values: vec![String::new(); SnippetFill::names(template).len()], field: 0 } }
+
+    pub(crate) fn names(template: &str) -> Vec<&str> { template.split('{').skip(1).filter_map(|part| part.split_once('}')).map(|(name, _)| name).collect() }
+
+    /// The expression with each field's text in place, or its name while empty, and the span of the current field.
+    /// Anything but a plain number goes in parentheses so that, say, `-3` squared stays 9.
+    pub(crate) fn text(&self) -> (String, SourceSpan) {
+        let (mut text, mut span, mut rest) = (String::new(), SourceSpan { start: 0, end: 0 }, self.template);
+        for (i, (name, value)) in SnippetFill::names(self.template).into_iter().zip(&self.values).enumerate() {
+            let (before, after) = rest.split_once('{').unwrap_or((rest, ""));
+            text.push_str(before);
+            let start = text.len();
+            if value.is_empty() { text.push_str(name); }
+            else if value.chars().all(|c| c.is_ascii_alphanumeric() || c == '.') { text.push_str(value); }
+            else { text.push_str(&format!("({})", value)); }
+            if i == self.field { span = SourceSpan { start, end: text.len() }; }
+            rest = after.split_once('}').map_or("", |(_, after)| after);
+        }
+        text.push_str(rest);
+        (text, span)
+    }
+}
+
+/// Starts filling in the snippet at `index` on the display.
+pub(crate) fn start_snippet(app: &mut App, index: usize) {
+    let fill = SnippetFill::new(SNIPPETS[index].1);
+    app.edit(fill.text().0);
+    app.clear_messages();
+    app.snippet = Some(fill);
+}
+
+/// Handles a keypad key while a snippet is being filled in: text goes into the current field,
+/// `=` evaluates once every field has a value, and `C` gives up on the snippet.
+pub(crate) fn on_snippet_input(app: &mut App, value: &str) {
+    app.clear_messages();
+    let Some(fill) = &mut app.snippet else { return };
+    match value {
+        "=" => {
+            if let Some(empty) = fill.values.iter().position(String::is_empty) {
+                fill.field = empty;
+                app.error_message = Some(format!("Fill in `{}` first", SnippetFill::names(fill.template)[empty]));
+                return;
+            }
+            app.snippet = None;
+            on_click(app, "=");
+            return;
+        }
+        "C" => { app.snippet = None; app.display_value = String::from("0"); return; }
+        _ if value.chars().count() == 1 && value != " " => fill.values[fill.field].push_str(value),
+        _ => {}
+    }
+    app.display_value = fill.text().0;
+}
+
+/// What each profile keeps to itself while another one is active: its settings, theme and modes, and the work done in it.
+pub(crate) struct ProfileSession {
+    pub(crate) settings: Settings, pub(crate) theme: Theme, pub(crate) history: Vec<HistoryEntry>,
+    pub(crate) last_expression: Option<String>, pub(crate) last_result: Option<f64>, pub(crate) last_exact: Option<String>,
+    pub(crate) data_points: Vec<(Option<f64>, f64)>, pub(crate) rpn_stack: Vec<f64>, pub(crate) rpn_mode: bool,
+    pub(crate) stats_mode: bool, pub(crate) finance_keypad: bool, pub(crate) variables: BTreeMap<String, f64>,
+}
+
+impl ProfileSession {
+    /// A fresh session in profile `name`, set up from the config.
+    pub(crate) fn new(config: &Config, name: &str) -> Self {
+        let profile = config.profiles.get(name).cloned().unwrap_or_default();
+        ProfileSession {
+            settings: config.settings(name), theme: config.theme(name),
+            history: Vec::new(), last_expression: None, last_result: None, last_exact: None,
+            data_points: Vec::new(), rpn_stack: Vec::new(), rpn_mode: profile.rpn, stats_mode: profile.stats, finance_keypad: profile.finance_keypad,
+            variables: profile.variables.into_iter().filter(|(name, _)| variable_name_error(name).is_none()).collect(),
+        }
+    }
+
+    /// Trades this session for the one the app is showing.
+    pub(crate) fn exchange(&mut self, app: &mut App) {
+        std::mem::swap(&mut self.settings, &mut app.settings);
+        std::mem::swap(&mut self.theme, &mut app.theme);
+        std::mem::swap(&mut self.history, &mut app.history);
+        std::mem::swap(&mut self.last_expression, &mut app.last_expression);
+        std::mem::swap(&mut self.last_result, &mut app.last_result);
+        std::mem::swap(&mut self.last_exact, &mut app.last_exact);
+        std::mem::swap(&mut self.data_points, &mut app.data_points);
+        std::mem::swap(&mut self.rpn_stack, &mut app.rpn_stack);
+        std::mem::swap(&mut self.rpn_mode, &mut app.rpn_mode);
+        std::mem::swap(&mut self.stats_mode, &mut app.stats_mode);
+        std::mem::swap(&mut self.finance_keypad, &mut app.finance_keypad);
+        std::mem::swap(&mut self.variables, &mut app.variables);
+    }
+}
+
+/// Makes `name` the active profile, resuming its session or starting a fresh one, and parks the current one.
+pub(crate) fn switch_profile(app: &mut App, name: &str) {
+    if app.profile == name { return; }
+    let mut session = app.profiles.remove(name).unwrap_or_else(|| ProfileSession::new(&app.config, name));
+    session.exchange(app);
+    // The history of the other profile is not new, and is not shared for switching to it.
+    if let Some(share) = &mut app.share { (share.published, share.variables) = (app.history.len(), app.variables.clone()); }
+    let previous = std::mem::replace(&mut app.profile, name.to_string());
+    app.profiles.insert(previous, session);
+    app.edit("0");
+    app.matrix_result = None;
+    app.notice = Some(format!("Profile {}", name));
+}
+
+/// Switches to the next profile in the config, after `default`.
+pub(crate) fn next_profile(app: &mut App) {
+    let mut names: Vec<String> = std::iter::once("default".to_string()).chain(app.config.profiles.keys().cloned()).collect();
+    if !names.contains(&app.profile) { names.push(app.profile.clone()); }
+    let i = names.iter().position(|name| *name == app.profile).unwrap_or(0);
+    let next = names[(i + 1) % names.len()].clone();
+    switch_profile(app, &next);
+}
+
+/// A practice session: the current problem and its answer, the difficulty level, and the score so far.
+pub(crate) struct Quiz {
+    pub(crate) level: u32, pub(crate) problem: String, pub(crate) answer: f64, pub(crate) correct: u32,
+    pub(crate) asked: u32, pub(crate) streak: u32, pub(crate) best_streak: u32, pub(crate) seed: u64,
+}
+
+impl Quiz {
+    pub(crate) const LEVELS: u32 = 3;
+
+    pub(crate) fn new(level: u32, seed: u64) -> Self {
+        let mut quiz = Quiz { level, problem: String::new(), answer: 0.0, correct: 0, asked: 0, streak: 0, best_streak: 0, seed: seed | 1 };
+        quiz.next_problem();
+        quiz
+    }
+
+    /// The next number of an xorshift64* sequence, drawn from `low..=high`.
+    pub(crate) fn random(&mut self, low: i64, high: i64) -> i64 {
+        self.seed ^= self.seed >> 12;
+        self.seed ^= self.seed << 25;
+        self.seed ^= self.seed >> 27;
+        low + (self.seed.wrapping_mul(0x2545_F491_4F6C_DD1D) % (high - low + 1) as u64) as i64
+    }
+
+    /// Makes up a problem for the level: sums at 1, products and exact quotients at 2, mixed precedence at 3.
+    /// The calculator's own evaluator works out the answer.
+    pub(crate) fn next_problem(&mut self) {
+        let (a, b, c) = (self.random(2, 12), self.random(2, 12), self.random(2, 12));
+        self.problem = match (self.level, self.random(0, 3)) {
+            (1, 0 | 1) => format!("{} + {}", self.random(1, 20), self.random(1, 20)),
+            (1, _) => { let (x, y) = (self.random(1, 20), self.random(1, 20)); format!("{} - {}", x.max(y), x.min(y)) }
+            (2, 0) => format!("{} * {}", a, b),
+            (2, 1) => format!("{} / {}", a * b, b),
+            (2, 2) => format!("{} + {}", self.random(10, 99), self.random(10, 99)),
+            (2, _) => format!("{} - {}", self.random(10, 99), self.random(10, 99)),
+            (_, 0) => format!("{} + {} * {}", a, b, c),
+            (_, 1) => format!("({} + {}) * {}", a, b, c),
+            (_, 2) => format!("{} ^ 2 - {}", a, b * c),
+            (_, _) => format!("{} * {} - {}", a, b, c * 5),
+        };
+        self.answer = evaluate_timed(&self.problem, &EvalOptions::default()).0.map_or(f64::NAN, |outcome| outcome.value);
+    }
+}
+
+/// Starts a practice session at level 1, or ends the running one.
+pub(crate) fn toggle_quiz(app: &mut App) {
+    if app.quiz.take().is_some() { return; }
+    let seed = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map_or(1, |elapsed| elapsed.as_nanos() as u64);
+    app.quiz = Some(Quiz::new(1, seed));
+    app.edit("0");
+}
+
+/// Checks the typed number against the answer to the current problem, keeps score, and moves on to the next problem.
+pub(crate) fn check_answer(app: &mut App) {
+    // The answer has to be worked out, not typed in as the problem itself.
+    let Ok(given) = app.display_value.trim().replace(' ', "").parse::<f64>() else {
+        app.error_message = Some("Type the answer as a number".to_string());
+        return;
+    };
+    let Some(quiz) = &mut app.quiz else { return };
+    quiz.asked += 1;
+    if (given - quiz.answer).abs() <= 1e-9 * quiz.answer.abs().max(1.0) {
+        quiz.correct += 1;
+        quiz.streak += 1;
+        quiz.best_streak = quiz.best_streak.max(quiz.streak);
+        app.notice = Some(format!("✓ Correct! Streak {}", quiz.streak));
+    } else {
+        quiz.streak = 0;
+        app.error_message = Some(format!("✗ {} = {}", quiz.problem, format_result(quiz.answer, &app.settings.format_options())));
+    }
+    quiz.next_problem();
+    app.edit("0");
+}
+
+/// Changes the practice level by one step, starting a fresh streak on a new problem.
+pub(crate) fn change_quiz_level(app: &mut App, up: bool) {
+    let Some(quiz) = &mut app.quiz else { return };
+    quiz.level = if up { (quiz.level + 1).min(Quiz::LEVELS) } else { quiz.level.saturating_sub(1).max(1) };
+    quiz.streak = 0;
+    quiz.next_problem();
+}
+
+/// Narrates how `expression` is worked out, step by step, with the options its evaluation used.
+pub(crate) fn tutor_narration(app: &App, expression: &str) -> Option<String> {
+    let tree = parse_tokens(tokenize(expression, app.settings.si_suffixes).ok()?, expression.len(), app.settings.max_depth).ok()?;
+    let mut recorder = Recorder { source: expression, steps: Vec::new() };
+    eval_expr::<f64>(&tree, &evaluation_options(app), Some(&mut recorder)).ok()?;
+    Some(narrate(&recorder.steps, |x| format_result(x, &app.settings.format_options())))
+}
+
+/// Cycles the expression line through infix, postfix and prefix notation.
+pub(crate) fn cycle_notation(app: &mut App) {
+    app.notation = match app.notation { None => Some(Notation::Postfix), Some(Notation::Postfix) => Some(Notation::Prefix), Some(Notation::Prefix) => None };
+}
+
+/// Switches RPN mode on or off, starting from an empty stack and entry.
+pub(crate) fn toggle_rpn(app: &mut App) {
+    app.rpn_mode = !app.rpn_mode;
+    app.rpn_stack.clear();
+    app.edit("0");
+    app.last_expression = None;
+    app.last_result = None;
+    app.last_exact = None;
+}
+
+/// Handles a stack key in RPN mode. `=` pushes the entry, or duplicates X when nothing was typed;
+/// operators first push a typed entry, then replace Y and X with `Y op X`. `C` drops the entry back to X, then clears the stack.
+pub(crate) fn rpn_key(app: &mut App, value: &str) {
+    let typed = app.mode() != Mode::ResultShown;
+    if value == "C" && !typed {
+        app.rpn_stack.clear();
+    } else if typed && matches!(value, "=" | "+" | "-" | "*" | "/" | "^") {
+        let entry = app.display_value.trim().to_string();
+        match evaluate_timed(&entry, &evaluation_options(app)).0 {
+            Ok(outcome) => app.rpn_stack.push(outcome.value),
+            Err(e) => return app.show_error(&e, &entry),
+        }
+    } else if value == "=" && let Some(&x) = app.rpn_stack.last() {
+        app.rpn_stack.push(x);
+    }
+    let stack = &mut app.rpn_stack;
+    let len = stack.len();
+    match value {
+        "+" | "-" | "*" | "/" | "^" if len < 2 => app.error_message = Some("The stack needs two values".to_string()),
+        "+" | "-" | "*" | "/" | "^" => {
+            let op = value.chars().next().unwrap_or('+');
+            match apply_op(op, stack[len - 2], stack[len - 1]).and_then(|result| check_finite(result, &app.settings.eval_options())) {
+                Ok(result) => { stack.truncate(len - 2); stack.push(result); }
+                Err(message) => app.error_message = Some(message.to_string()),
+            }
+        }
+        "x⇄y" if len >= 2 => stack.swap(len - 2, len - 1),
+        "Drop" => { stack.pop(); }
+        "+/-" => if let Some(x) = stack.last_mut() { *x = -*x },
+        "R↓" => stack.rotate_right(1.min(len)),
+        _ => {}
+    }
+    app.last_expression = None;
+    (app.last_exact, app.last_label) = (None, None);
+    app.last_result = app.rpn_stack.last().copied();
+    match app.last_result {
+        Some(x) => app.show_result(operand_text(x, None, app.settings.backend, &app.settings.format_options())),
+        None => app.edit("0"),
+    }
+}
+
+/// Evaluates an `x, y` expression and adds it to the statistics data as a pair.
+pub(crate) fn add_pair(app: &mut App) {
+    let Some(comma) = top_level_comma(&app.display_value) else { return };
+    let options = evaluation_options(app);
+    let (x_text, y_text) = (app.display_value[..comma].to_string(), app.display_value[comma + 1..].to_string());
+    let x = evaluate_timed(&x_text, &options).0;
+    let y = evaluate_timed(&y_text, &options).0;
+    match (x, y) {
+        (Ok(x), Ok(y)) => {
+            app.data_points.push((Some(x.value), y.value));
+            app.display_value = String::from("0");
+            let pairs = app.data_points.iter().filter(|p| p.0.is_some()).count();
+            app.notice = Some(format!(
+                "Added ({}, {}) as pair {}", format_result(x.value, &app.settings.format_options()),
+                format_result(y.value, &app.settings.format_options()), pairs
+            ));
+        }
+        (Err(e), _) => app.error_message = Some(e.describe(&x_text)),
+        (_, Err(e)) => app.error_message = Some(e.describe(&y_text)),
+    }
+}
+
+/// The `(x, y)` pairs entered in statistics mode.
+pub(crate) fn data_pairs(app: &App) -> Vec<(f64, f64)> {
+    app.data_points.iter().filter_map(|&(x, y)| x.map(|x| (x, y))).collect()
+}
+
+/// Counts the `(` that have no matching `)`.
+pub(crate) fn unclosed_parens(expression: &str) -> usize {
+    expression.chars().fold(0usize, |open, c| match c { '(' => open + 1, ')' => open.saturating_sub(1), _ => open })
+}
+
+/// Abandons the pending evaluation, leaving the expression as it was before `=`.
+pub(crate) fn cancel_evaluation(app: &mut App) {
+    if let Some(pending) = app.pending.take() {
+        pending.cancel.store(true, Ordering::Relaxed);
+        app.tax_step = None;
+        app.error_message = Some("Calculation cancelled".to_string());
+    }
+}
+
+/// Applies the selected tax rate to the current value: `+TAX` adds it, `−TAX` takes it back out of a gross amount.
+pub(crate) fn apply_tax(app: &mut App, add: bool) {
+    if app.pending.is_some() { return; }
+    app.clear_messages();
+    let Some(&rate) = app.settings.tax_rates.get(app.settings.tax_index) else {
+        app.error_message = Some("No tax rate configured (add tax_rates to config.toml)".to_string());
+        return;
+    };
+    let mut base = app.display_value.trim().to_string();
+    base.push_str(&")".repeat(unclosed_parens(&base)));
+    if !tokenize(&base, app.settings.si_suffixes).is_ok_and(|tokens| tokens.len() == 1) && !is_parenthesized(&base) { base = format!("({})", base); }
+    app.edit(if add { format!("{} + {}%", base, rate) } else { format!("{} / (1 + {}%)", base, rate) });
+    start_evaluation(app);
+    app.tax_step = Some((rate, add));
+}
+
+/// Applies the result of the pending evaluation once the worker has delivered it.
+pub(crate) fn poll_evaluation(app: &mut App) {
+    let Some(pending) = &app.pending else { return };
+    match pending.receiver.try_recv() {
+        Ok((result, timings)) => {
+            let pending = app.pending.take().unwrap();
+            finish_evaluation(app, &pending.expression, result, timings);
+            follow_up(app, &pending.expression, pending.followup);
+        }
+        Err(mpsc::TryRecvError::Empty) => {}
+        Err(mpsc::TryRecvError::Disconnected) => { app.pending = None; app.error_message = Some("Evaluation failed".to_string()); }
+    }
+}
+
+/// Evaluates the display like `=`, then does `followup` with the result, right away or once the worker is done.
+pub(crate) fn evaluate_then(app: &mut App, followup: Followup) {
+    let expression = app.display_value.clone();
+    on_click(app, "=");
+    match &mut app.pending {
+        Some(pending) => pending.followup = followup,
+        None => follow_up(app, &expression, followup),
+    }
+}
+
+/// Carries out what was asked for after evaluating `expression`, if it succeeded.
+pub(crate) fn follow_up(app: &mut App, expression: &str, followup: Followup) {
+    if app.mode() != Mode::ResultShown { return; }
+    match followup {
+        Followup::Show => {}
+        Followup::KeepExpression => {
+            app.notice = Some(format!(
+                "= {}",
+                format_display(app.last_result.unwrap_or_default(), app.last_exact.as_deref(), app.settings.backend, &app.settings.format_options())
+            ));
+            app.edit(expression.trim());
+        }
+        Followup::Copy => copy_result(app),
+    }
+}
+
+/// Shows the outcome of an evaluation of `expression`.
+pub(crate) fn finish_evaluation(app: &mut App, expression: &str, result: Result<Outcome, EvalError>, timings: EvalTimings) {
+    app.last_timings = Some(timings);
+    match result {
+        Ok(Outcome { value, .. }) if app.stats_mode => {
+            app.data_points.push((None, value));
+            app.display_value = String::from("0");
+            app.notice = Some(format!("Added {} as point {}", format_result(value, &app.settings.format_options()), app.data_points.len()));
+        }
+        Ok(Outcome { value, exact, note, roots, label }) => {
+            let warnings = lint(expression, app.settings.backend, app.settings.max_depth, app.settings.si_suffixes);
+            let expression = expression.trim().to_string();
+            if app.tutor_mode { app.tutor_text = tutor_narration(app, &expression); }
+            if app.settings.usage_stats { record_usage(app, &expression); }
+            let stored = resolve_history_references(&expression, &app.history, app.settings.si_suffixes);
+            app.history.push(HistoryEntry {
+                expression: stored, value, exact: exact.clone(), backend: app.settings.backend, timings, note: None,
+                pinned: false, reused: 0,
+            });
+            app.last_expression = Some(expression);
+            app.last_result = Some(value);
+            app.show_result(label.clone().unwrap_or_else(|| operand_text(value, exact.as_deref(), app.settings.backend, &app.settings.format_options())));
+            if let Some(note) = note { app.notice = Some(note); }
+            app.quad_roots = roots;
+            if let Some((rate, add)) = app.tax_step.take() {
+                let tax = if add { value - value / (1.0 + rate / 100.0) } else { value * rate / 100.0 };
+                let kind = if add { "Total" } else { "Net" };
+                app.notice = Some(format!(
+                    "Tax {}%: {} · {}: {}", rate, format_result(tax, &app.settings.format_options()), kind,
+                    format_display(value, exact.as_deref(), app.settings.backend, &app.settings.format_options())
+                ));
+            }
+            (app.last_exact, app.last_label) = (exact, label);
+            if !warnings.is_empty() { app.notice = Some(app.notice.iter().cloned().chain(warnings).collect::<Vec<_>>().join(" · ")); }
+        }
+        Err(e) => {
+            app.show_error(&e, expression);
+            // A `*` left out is a likely reason for a syntax error, so it is said next to it.
+            let warnings = lint(expression, app.settings.backend, app.settings.max_depth, app.settings.si_suffixes);
+            if let (Some(message), Some(warning)) = (&mut app.error_message, warnings.first()) { message.push_str(&format!(" · {}", warning)); }
+        }
+    }
+}
+
+/// How a typed character should be applied to the expression.
+#[derive(Debug, PartialEq)]
+pub(crate) enum InputAction { Append, Replace(usize), Reject }
+
+/// The input-validation layer: decides from the expression's last token whether typing `c` makes sense.
+/// `Replace(start)` swaps out the last token from byte `start`, e.g. a lone leading zero. A suffix like the `k` of
+/// `4.7k` may follow the digits only with `si_suffixes` on.
+pub(crate) fn validate_input(expression: &str, c: char, si_suffixes: bool) -> InputAction {
+    let Ok(tokens) = tokenize(expression, si_suffixes) else { return InputAction::Append };
+    let last = tokens.last();
+    let last_text = last.map_or("", |t| &expression[t.span.start..t.span.end]);
+    let closes_value = matches!(last.map(|t| &t.kind), Some(TokenKind::RParen | TokenKind::Percent));
+    let ends_operand = closes_value || matches!(
+        last.map(|t| &t.kind),
+        Some(TokenKind::Number(..) | TokenKind::Duration(_) | TokenKind::Ident(_) | TokenKind::Infinity)
+    );
+    let verdict = |ok: bool| if ok { InputAction::Append } else { InputAction::Reject };
+    let number = matches!(last.map(|t| &t.kind), Some(TokenKind::Number(..)))
+        && last_text.starts_with(|c: char| c.is_ascii_digit() || c == '.') && !expression.ends_with(' ');
+    // An engineering suffix like the `k` of `4.7k` ends its number.
+    let suffixed = number && last_text.ends_with(|c: char| c.is_ascii_alphabetic());
+    match c {
+        '0'..='9' if last_text == "0" => if c == '0' { InputAction::Reject } else { InputAction::Replace(last.unwrap().span.start) },
+        '0'..='9' => verdict(!closes_value && !suffixed),
+        '.' => verdict(!closes_value && !suffixed && !last_text.contains('.')),
+        _ if si_suffixes && number && !suffixed && SI_SUFFIXES.iter().any(|(suffix, _)| *suffix == c) => InputAction::Append,
+        '(' => verdict(!ends_operand || matches!(last.map(|t| &t.kind), Some(TokenKind::Ident(_)))),
+        ')' | ',' => verdict(ends_operand && unclosed_parens(expression) > 0),
+        '%' => verdict(ends_operand),
+        // After a number a letter needs a space first, and starts a unit: `5 m`.
+        'a'..='z' | 'A'..='Z' => verdict(!closes_value && (expression.ends_with(' ') || !matches!(
+            last.map(|t| &t.kind), Some(TokenKind::Number(..) | TokenKind::Duration(_) | TokenKind::Infinity)
+        ))),
+        // A currency symbol starts an amount and `@` a history reference, so neither can follow an operand.
+        '$' | '€' | '£' | '¥' | '@' => verdict(!ends_operand),
+        // An underscore only joins the parts of a name, as in `is_int`.
+        '_' => verdict(matches!(last.map(|t| &t.kind), Some(TokenKind::Ident(_))) && !expression.ends_with(' ')),
+        ':' => verdict(matches!(last.map(|t| &t.kind), Some(TokenKind::Number(..) | TokenKind::Duration(_)))
+            && !suffixed && !last_text.contains('.') && last_text.matches(':').count() < 2),
+        _ => InputAction::Append,
+    }
+}
+
+/// Where the identifier-like run of characters at the end of `expression` starts.
+pub(crate) fn trailing_word_start(expression: &str) -> usize {
+    expression.rfind(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).map_or(0, |i| i + 1)
+}
+
+/// The built-in names that complete the identifier being typed at the end of `expression`.
+pub(crate) fn completions(expression: &str) -> Vec<(&'static str, &'static str)> {
+    let prefix = &expression[trailing_word_start(expression)..];
+    if !prefix.starts_with(|c: char| c.is_ascii_alphabetic()) { return Vec::new(); }
+    COMPLETIONS.iter().copied().filter(|(name, _)| name.starts_with(prefix)).collect()
+}
+
+/// Completes the identifier being typed to the first matching name; functions get their `(`.
+pub(crate) fn complete(app: &mut App) {
+    if app.pending.is_some() || app.mode() == Mode::ResultShown { return; }
+    let Some(&(name, hint)) = completions(&app.display_value).first() else { return };
+    app.display_value.truncate(trailing_word_start(&app.display_value));
+    app.display_value.push_str(name);
+    if !hint.is_empty() { app.display_value.push('('); }
+}
+
+/// The functions in the order the picker lists them, each with its section.
+pub(crate) fn picker_functions() -> Vec<(&'static str, &'static str)> {
+    FUNCTION_GROUPS.iter().flat_map(|&(group, names)| names.iter().map(move |&name| (group, name))).collect()
+}
+
+/// Types the function at `index` of the picker as if from the keyboard; functions get their `(`.
+pub(crate) fn insert_function(app: &mut App, index: usize) {
+    let Some(&(_, name)) = picker_functions().get(index) else { return };
+    let takes_arguments = COMPLETIONS.iter().any(|&(function, hint)| function == name && !hint.is_empty());
+    for c in name.chars().chain(takes_arguments.then_some('(')) { on_click(app, &c.to_string()); }
+}
+
+/// Handles the Backspace key press.
+pub(crate) fn on_backspace(app: &mut App) {
+    if app.pending.is_some() { return; }
+    app.clear_messages();
+    app.display_scroll = 0;
+    app.quad_roots = None;
+    if app.mode() == Mode::ResultShown {
+        app.edit("0");
+    } else if app.display_value.len() > 1 {
+        let last_char = app.display_value.pop();
+        // If the last character was a space, pop again to remove the operator
+        if last_char == Some(' ') {
+            app.display_value.pop();
+        }
+    } else {
+        app.display_value = String::from("0");
+    }
+}
+
+
+/// Changes the number of displayed decimals, re-rendering the current result.
+pub(crate) fn change_decimals(app: &mut App, up: bool) {
+    app.settings.adjust(Settings::DECIMALS, up);
+    app.refresh_result();
+}
+
+/// Switches to the next numeric backend, re-evaluating a displayed result so both appear in the history.
+pub(crate) fn cycle_backend(app: &mut App) {
+    if app.pending.is_some() { return; }
+    app.settings.backend = app.settings.backend.next();
+    app.notice = Some(format!("Numeric backend: {}", app.settings.backend.name()));
+    if let (true, Some(expression)) = (app.mode() == Mode::ResultShown, app.last_expression.clone()) {
+        app.edit(expression);
+        start_evaluation(app);
+    }
+}
+
+/// The number the base converter shows: the result on display, or the number being typed.
+pub(crate) fn current_value(app: &App) -> Option<f64> {
+    if app.mode() == Mode::ResultShown { return app.last_result; }
+    app.display_value.trim().parse().ok().or(app.last_result)
+}
+
+/// The integer part of `value` as an unsigned `bits`-wide word, or `None` when it does not fit.
+pub(crate) fn to_word(value: f64, bits: u32) -> Option<i128> {
+    let n = value.trunc();
+    if !n.is_finite() || n < -(2f64.powi(bits as i32 - 1)) || n >= 2f64.powi(bits as i32) { return None; }
+    Some((n as i128) & ((1i128 << bits) - 1))
+}
+
+/// The integer part of `value` written in each base, or `None` when it does not fit in a `bits`-wide word.
+/// Negative numbers are shown as their two's complement, with binary padded to the full word.
+pub(crate) fn base_representations(value: f64, bits: u32) -> Option<[(&'static str, String); 4]> {
+    let (n, word) = (value.trunc() as i128, to_word(value, bits)?);
+    let binary = if n < 0 { format!("{:0width$b}", word, width = bits as usize) } else { format!("{:b}", word) };
+    Some([("dec", n.to_string()), ("hex", format!("0x{:X}", word)), ("bin", format!("0b{}", binary)), ("oct", format!("0o{:o}", word))])
+}
+
+/// Spaces the digits after the sign and base prefix into groups: nibbles for hex and binary, threes otherwise.
+pub(crate) fn group_base_digits(text: &str) -> String {
+    let sign = usize::from(text.starts_with('-'));
+    let prefixed = ["0x", "0b", "0o"].iter().any(|prefix| text[sign..].starts_with(prefix));
+    let (head, digits) = text.split_at(if prefixed { sign + 2 } else { sign });
+    let size = if head.ends_with('x') || head.ends_with('b') { 4 } else { 3 };
+    let chars: Vec<char> = digits.chars().collect();
+    let groups: Vec<String> = chars.rchunks(size).rev().map(|chunk| chunk.iter().collect()).collect();
+    format!("{}{}", head, groups.join(" "))
+}
+
+/// Copies the current result (or the expression, if there is none) to the clipboard.
+pub(crate) fn copy_result(app: &mut App) {
+    let text = match (app.mode() == Mode::ResultShown, app.last_result) {
+        (true, Some(value)) => format_outcome(value, app.last_exact.as_deref(), app.settings.backend, &app.settings.format_options()),
+        _ => app.display_value.trim().to_string(),
+    };
+    copy_text(app, text);
+}
+
+/// The compare view: a cursor over the history and the indices of up to two marked entries, A first.
+pub(crate) struct Compare { pub(crate) cursor: usize, pub(crate) marked: Vec<usize> }
+
+/// How `b` relates to `a`: the absolute difference, the ratio `b / a`, and the change from `a` to `b` in percent.
+/// The last two are undefined when `a` is zero.
+pub(crate) fn compare_values(a: f64, b: f64) -> (f64, Option<f64>, Option<f64>) {
+    if a == 0.0 { return ((b - a).abs(), None, None); }
+    ((b - a).abs(), Some(b / a), Some((b - a) / a.abs() * 100.0))
+}
+
+/// Opens the compare view with the two newest entries marked.
+pub(crate) fn open_compare(app: &mut App) {
+    let len = app.history.len();
+    if len < 2 { app.error_message = Some("Compare needs at least two results in the history".to_string()); return; }
+    app.overlay = Some(Overlay::Compare(Compare { cursor: len - 1, marked: vec![len - 2, len - 1] }));
+}
+
+/// The context menu of a history entry: the entry, the selected item, and the cell it was opened at (none from the keyboard).
+pub(crate) struct HistoryMenu { pub(crate) entry: usize, pub(crate) cursor: usize, pub(crate) at: Option<(u16, u16)> }
+
+/// The history menu items; each one's first letter is its key.
+pub(crate) const HISTORY_MENU_ITEMS: [&str; 5] = ["Copy result", "Copy expression", "Insert", "Pin", "Delete"];
+
+/// The history entries the chart of results plots: those with a finite value, in order.
+pub(crate) fn chart_entries(history: &[HistoryEntry]) -> Vec<usize> { (0..history.len()).filter(|&i| history[i].value.is_finite()).collect() }
+
+/// Opens the chart of results on the newest one.
+pub(crate) fn open_chart(app: &mut App) {
+    match chart_entries(&app.history).last() {
+        Some(&index) => app.overlay = Some(Overlay::Chart(index)),
+        None => app.notice = Some("No results to chart yet".to_string()),
+    }
+}
+
+/// Opens the history menu on the newest entry, for the keyboard.
+pub(crate) fn open_history_menu(app: &mut App) {
+    match app.history.len() {
+        0 => app.error_message = Some("No history yet".to_string()),
+        len => app.overlay = Some(Overlay::HistoryMenu(HistoryMenu { entry: len - 1, cursor: 0, at: None })),
+    }
+}
+
+/// Runs history menu item `item` on entry `index` and closes the menu.
+pub(crate) fn run_history_menu(app: &mut App, index: usize, item: usize) {
+    app.overlay = None;
+    let Some(entry) = app.history.get(index) else { return };
+    let value = format_outcome(entry.value, entry.exact.as_deref(), entry.backend, &app.settings.format_options());
+    match item {
+        0 => copy_text(app, value),
+        1 => copy_text(app, entry.expression.clone()),
+        // Typed like a custom button, so it replaces a shown result and follows an operator.
+        2 => {
+            if app.settings.usage_stats { app.history[index].reused += 1; }
+            let text = if value.starts_with('-') { format!("({})", value) } else { value };
+            for c in text.chars() { on_click(app, &c.to_string()); }
+        }
+        3 => app.history[index].pinned = !app.history[index].pinned,
+        4 => {
+            app.history.remove(index);
+            app.history_cursor = app.history_cursor.filter(|_| !app.history.is_empty())
+                .map(|cursor| if cursor > index { cursor - 1 } else { cursor.min(app.history.len() - 1) });
+        }
+        _ => {}
+    }
+}
+
+/// The history entries in the order the tape shows them: pinned ones first, then the rest, each in history order.
+pub(crate) fn history_order(history: &[HistoryEntry]) -> Vec<usize> {
+    let (pinned, rest): (Vec<usize>, Vec<usize>) = (0..history.len()).partition(|&i| history[i].pinned);
+    pinned.into_iter().chain(rest).collect()
+}
+
+/// Gives the history tape the keyboard focus with the newest entry selected, or takes it away.
+pub(crate) fn focus_history(app: &mut App) {
+    app.history_cursor = match (app.history_cursor, app.history.is_empty()) {
+        (None, false) => history_order(&app.history).last().copied(),
+        _ => None,
+    };
+}
+
+/// The longest note a history entry can carry, in characters.
+pub(crate) const NOTE_MAX_LENGTH: usize = 40;
+
+/// Starts editing the note of the newest history entry.
+pub(crate) fn open_note(app: &mut App) {
+    match app.history.last() {
+        Some(entry) => app.overlay = Some(Overlay::NoteEditor(entry.note.clone().unwrap_or_default())),
+        None => app.error_message = Some("No result to label yet".to_string()),
+    }
+}
+
+/// Starts naming the result of history entry `entry`, so it can be used as a variable.
+pub(crate) fn open_variable_prompt(app: &mut App, entry: usize) {
+    if entry < app.history.len() { app.overlay = Some(Overlay::VariablePrompt(entry, String::new())); }
+}
+
+/// Why `name` cannot name a result: it is not an identifier, or the calculator already gives it a meaning.
+pub(crate) fn variable_name_error(name: &str) -> Option<String> {
+    if !name.starts_with(|c: char| c.is_ascii_alphabetic()) || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+        return Some("A name starts with a letter and has only letters, digits and `_`".to_string());
+    }
+    let taken = matches!(name, "ans" | "slope" | "intercept" | "r2") || history_reference(name).is_some() || constant(name).is_some() || unit(name).is_some()
+        || COMPLETIONS.iter().any(|(function, _)| *function == name);
+    taken.then(|| format!("`{}` is a built-in name", name))
+}
+
+/// Binds the entry being named to the typed name, replacing any earlier value of it, and leaves the history tape
+/// for the variables panel.
+pub(crate) fn assign_variable(app: &mut App) {
+    let Some(Overlay::VariablePrompt(entry, name)) = app.overlay.take() else { return };
+    let Some(value) = app.history.get(entry).map(|entry| entry.value) else { return };
+    if let Some(error) = variable_name_error(&name) { app.error_message = Some(error); app.overlay = Some(Overlay::VariablePrompt(entry, name)); return; }
+    (app.error_message, app.notice) = (None, Some(format!("{} = {}", name, format_result(value, &app.settings.format_options()))));
+    app.variables.insert(name, value);
+    (app.variables_panel, app.history_cursor) = (true, None);
+}
+
+/// Where the variables are exported to and imported from, in the current directory.
+pub(crate) const VARIABLES_FILE: &str = "variables.json";
+
+/// The variables as JSON, `{"variables": {"rate": 0.07, …}}`. JSON has no infinity, so infinite values are left out.
+pub(crate) fn variables_json(variables: &BTreeMap<String, f64>) -> String {
+    let pairs: Vec<String> = variables.iter().filter(|(_, value)| value.is_finite()).map(|(name, value)| format!("    \"{}\": {}", name, value)).collect();
+    format!("{{\n  \"variables\": {{\n{}\n  }}\n}}\n", pairs.join(",\n"))
+}
+
+/// Writes the variables of the current profile to `variables.json`.
+pub(crate) fn export_variables(app: &mut App) {
+    if app.variables.is_empty() { app.error_message = Some("No variables to export".to_string()); return; }
+    let notice = format!("Saved {} variables to {}", app.variables.len(), VARIABLES_FILE);
+    app.effects.push(Effect::WriteFile { path: VARIABLES_FILE.to_string(), contents: variables_json(&app.variables), notice });
+}
+
+/// Asks for `variables.json` to be read in the background; the variables arrive through `merge_variables`.
+pub(crate) fn import_variables(app: &mut App) { app.effects.push(Effect::ReadVariables); }
+
+/// The contents of `variables.json`, `{"variables": {"subtotal": 150, …}}`.
+#[derive(Deserialize)]
+struct VariablesFile { variables: BTreeMap<String, f64> }
+
+/// Reads the variables out of the text of a `variables.json`.
+pub(crate) fn parse_variables(text: &str) -> Result<BTreeMap<String, f64>, serde_json::Error> {
+    serde_json::from_str::<VariablesFile>(text).map(|file| file.variables)
+}
+
+/// Reads the variables of `variables.json`. It blocks, so call it from a task.
+pub(crate) fn read_variables() -> TaskOutput {
+    match std::fs::read_to_string(VARIABLES_FILE) {
+        Ok(text) => parse_variables(&text).map_or_else(|e| TaskOutput::Error(format!("Cannot read {}: {}", VARIABLES_FILE, e)), TaskOutput::Variables),
+        Err(e) => TaskOutput::Error(format!("Cannot read {}: {}", VARIABLES_FILE, e)),
+    }
+}
+
+/// Adds imported variables to the current profile, replacing those with the same names. Names the calculator
+/// already uses are left out.
+pub(crate) fn merge_variables(app: &mut App, variables: BTreeMap<String, f64>) {
+    let (valid, refused): (Vec<_>, Vec<_>) = variables.into_iter().partition(|(name, _)| variable_name_error(name).is_none());
+    let mut notice = format!("Loaded {} variables from {}", valid.len(), VARIABLES_FILE);
+    if !refused.is_empty() { notice.push_str(&format!(", skipped {}", refused.iter().map(|(name, _)| name.as_str()).collect::<Vec<_>>().join(", "))); }
+    app.variables.extend(valid);
+    (app.notice, app.variables_panel) = (Some(notice), true);
+}
+
+/// The history as a plain-text worksheet: one `expression = result` line per entry, its note as a `#` line above it.
+pub(crate) fn history_text(app: &App) -> String {
+    let mut text = String::new();
+    for entry in &app.history {
+        if let Some(note) = &entry.note { text.push_str(&format!("# {}\n", note)); }
+        text.push_str(&format!(
+            "{} = {}\n", entry.expression,
+            format_outcome(entry.value, entry.exact.as_deref(), entry.backend, &app.settings.format_options())
+        ));
+    }
+    text
+}
+
+/// What `--print-on-exit` writes to stdout when the calculator quits.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub(crate) enum PrintOnExit { Result, Tape }
+
+/// The text `--print-on-exit` leaves on stdout: the last result, or the whole tape like `history_text`. `None` when
+/// there is nothing to print, so a script can tell quitting without a result apart from a result.
+pub(crate) fn exit_text(app: &App, print: PrintOnExit) -> Option<String> {
+    match print {
+        PrintOnExit::Result => app.last_result.map(|value| format!(
+            "{}\n",
+            format_outcome(value, app.last_exact.as_deref(), app.settings.backend, &app.settings.format_options())
+        )),
+        PrintOnExit::Tape => Some(history_text(app)).filter(|text| !text.is_empty()),
+    }
+}
+
+// --- Usage Section ---
+
+/// How often an unpinned result has to be reused before the usage view suggests pinning it.
+pub(crate) const PIN_SUGGESTION_USES: u32 = 3;
+
+/// Counts the functions, operators and names in an evaluated expression. Using `ans` counts as reusing the
+/// previous result, and `ans3` or `@3` as reusing the third.
+pub(crate) fn record_usage(app: &mut App, expression: &str) {
+    let Ok(tokens) = tokenize(expression, app.settings.si_suffixes) else { return };
+    for (i, token) in tokens.iter().enumerate() {
+        let name = match &token.kind {
+            TokenKind::Ident(name) if matches!(tokens.get(i + 1).map(|t| &t.kind), Some(TokenKind::LParen)) => format!("{}()", name),
+            TokenKind::Ident(name) => name.clone(),
+            TokenKind::Op(op) => op.to_string(),
+            TokenKind::Percent => "%".to_string(),
+            _ => continue,
+        };
+        if name == "ans" && let Some(entry) = app.history.last_mut() { entry.reused += 1; }
+        if let Some(n) = history_reference(&name) && let Some(entry) = app.history.get_mut(n - 1) { entry.reused += 1; }
+        *app.usage.entry(name).or_insert(0) += 1;
+    }
+}
+
+/// The history number a name like `ans3` refers to, counting from 1.
+pub(crate) fn history_reference(name: &str) -> Option<usize> {
+    name.strip_prefix("ans").filter(|digits| !digits.starts_with('0')).and_then(|digits| digits.parse().ok())
+}
+
+/// `expression` with each `@N` or `ansN` replaced by that result at full precision, as it goes into the history:
+/// deleting or moving entries renumbers them, and the stored expression has to keep meaning what was evaluated.
+pub(crate) fn resolve_history_references(expression: &str, history: &[HistoryEntry], si_suffixes: bool) -> String {
+    let Ok(tokens) = tokenize(expression, si_suffixes) else { return expression.to_string() };
+    let mut resolved = expression.to_string();
+    for token in tokens.iter().rev() {
+        let TokenKind::Ident(name) = &token.kind else { continue };
+        let Some(entry) = history_reference(name).and_then(|n| history.get(n - 1)) else { continue };
+        let text = full_operand_text(entry.value, entry.exact.as_deref());
+        let text = if text.starts_with('-') { format!("({})", text) } else { text };
+        resolved.replace_range(token.span.start..token.span.end, &text);
+    }
+    resolved
+}
+
+/// The most used names, most used first, at most `count` of them.
+pub(crate) fn most_used(app: &App, count: usize) -> Vec<(&str, u32)> {
+    let mut used: Vec<(&str, u32)> = app.usage.iter().map(|(name, &uses)| (name.as_str(), uses)).collect();
+    used.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(b.0)));
+    used.truncate(count);
+    used
+}
+
+/// Suggestions drawn from the usage counts: results worth pinning because they keep being reused.
+pub(crate) fn usage_suggestions(app: &App) -> Vec<String> {
+    app.history.iter().filter(|entry| !entry.pinned && entry.reused >= PIN_SUGGESTION_USES).map(|entry| {
+        let value = format_display(entry.value, entry.exact.as_deref(), entry.backend, &app.settings.format_options());
+        format!("Pin {} = {}? Reused {} times (Alt+H, select it, p)", entry.expression, value, entry.reused)
+    }).collect()
+}
+
+/// Where the usage counts are kept, as `name = uses` lines like the exported history.
+pub(crate) fn usage_path() -> Option<PathBuf> { dirs::data_dir().map(|dir| dir.join("rust-calculator-tui").join("usage.txt")) }
+
+/// The usage counts as `name = uses` lines.
+pub(crate) fn usage_text(usage: &BTreeMap<String, u32>) -> String { usage.iter().map(|(name, uses)| format!("{} = {}\n", name, uses)).collect() }
+
+/// Reads `name = uses` lines, skipping `#` comments and anything else that does not fit.
+pub(crate) fn parse_usage(text: &str) -> BTreeMap<String, u32> {
+    text.lines().filter(|line| !line.starts_with('#')).filter_map(|line| line.rsplit_once(" = "))
+        .filter_map(|(name, uses)| Some((name.to_string(), uses.trim().parse().ok()?))).collect()
+}
+
+/// Loads the usage counts saved by earlier sessions, if usage stats are on.
+pub(crate) fn load_usage(app: &mut App) {
+    if !app.settings.usage_stats { return; }
+    if let Some(text) = usage_path().and_then(|path| std::fs::read_to_string(path).ok()) { app.usage = parse_usage(&text); }
+}
+
+/// Queues saving the usage counts; called on quit.
+pub(crate) fn save_usage(app: &mut App) {
+    let Some(path) = usage_path().filter(|_| app.settings.usage_stats && !app.usage.is_empty()) else { return };
+    app.effects.push(Effect::WriteFile { path: path.display().to_string(), contents: usage_text(&app.usage), notice: String::new() });
+}
+
+/// Writes the history to `history.txt` in the current directory.
+pub(crate) fn export_history(app: &mut App) {
+    if app.history.is_empty() { app.error_message = Some("The history is empty".to_string()); return; }
+    let path = "history.txt";
+    let notice = format!("Saved {} entries to {}", app.history.len(), path);
+    app.effects.push(Effect::WriteFile { path: path.to_string(), contents: history_text(app), notice });
+}
+
+/// Copies one row of the base converter, picked by the first letter of its label.
+pub(crate) fn copy_base(app: &mut App, key: char) {
+    let Some(value) = current_value(app) else { return };
+    match base_representations(value, app.settings.word_bits).and_then(|rows| rows.into_iter().find(|(label, _)| label.starts_with(key))) {
+        Some((_, text)) => copy_text(app, text),
+        None => app.error_message = Some(format!("Out of range for a {}-bit word", app.settings.word_bits)),
+    }
+}
+
+/// Puts `text` on the clipboard once the event loop gets to it.
+pub(crate) fn copy_text(app: &mut App, text: String) { app.effects.push(Effect::Copy(text)); }
+
+/// Puts `text` on the Windows clipboard as UTF-16 through the Windows API; `clip.exe` would read it in the console's code page.
+#[cfg(windows)]
+fn copy_windows(text: &str) -> io::Result<()> {
+    use std::ffi::c_void;
+    #[link(name = "user32")]
+    unsafe extern "system" {
+        fn OpenClipboard(owner: *mut c_void) -> i32;
+        fn EmptyClipboard() -> i32;
+        fn SetClipboardData(format: u32, memory: *mut c_void) -> *mut c_void;
+        fn CloseClipboard() -> i32;
+    }
+    #[link(name = "kernel32")]
+    unsafe extern "system" {
+        fn GlobalAlloc(flags: u32, bytes: usize) -> *mut c_void;
+        fn GlobalLock(memory: *mut c_void) -> *mut c_void;
+        fn GlobalUnlock(memory: *mut c_void) -> i32;
+        fn GlobalFree(memory: *mut c_void) -> *mut c_void;
+    }
+    const CF_UNICODETEXT: u32 = 13;
+    const GMEM_MOVEABLE: u32 = 2;
+    let wide: Vec<u16> = text.encode_utf16().chain([0]).collect();
+    unsafe {
+        let memory = GlobalAlloc(GMEM_MOVEABLE, wide.len() * 2);
+        if memory.is_null() { return Err(io::Error::last_os_error()); }
+        let target = GlobalLock(memory) as *mut u16;
+        if target.is_null() { let error = io::Error::last_os_error(); GlobalFree(memory); return Err(error); }
+        std::ptr::copy_nonoverlapping(wide.as_ptr(), target, wide.len());
+        GlobalUnlock(memory);
+        if OpenClipboard(std::ptr::null_mut()) == 0 { let error = io::Error::last_os_error(); GlobalFree(memory); return Err(error); }
+        EmptyClipboard();
+        // Once set, the memory belongs to the clipboard; only a failed handover leaves it to us to free.
+        let result = if SetClipboardData(CF_UNICODETEXT, memory).is_null() {
+            let error = io::Error::last_os_error();
+            GlobalFree(memory);
+            Err(error)
+        } else { Ok(()) };
+        CloseClipboard();
+        result
+    }
+}
+
+/// Hands `text` to the system clipboard: the Windows API on Windows, else the first clipboard tool available.
+pub(crate) fn copy_system(text: &str) -> io::Result<()> {
+    #[cfg(windows)]
+    if copy_windows(text).is_ok() { return Ok(()); }
+    const TOOLS: [(&str, &[&str]); 5] = [
+        ("pbcopy", &[]), ("wl-copy", &[]), ("xclip", &["-selection", "clipboard"]), ("xsel", &["--clipboard", "--input"]), ("clip.exe", &[]),
+    ];
+    for (tool, args) in TOOLS {
+        let Ok(mut child) = Command::new(tool).args(args).stdin(Stdio::piped()).stdout(Stdio::null()).stderr(Stdio::null()).spawn() else { continue };
+        child.stdin.take().ok_or(io::ErrorKind::BrokenPipe)?.write_all(text.as_bytes())?;
+        if child.wait()?.success() { return Ok(()); }
+    }
+    Err(io::Error::new(io::ErrorKind::NotFound, "no clipboard tool found (try OSC 52 in settings)"))
+}
+
+/// Builds the OSC 52 "set clipboard" escape sequence for `text`.
+pub(crate) fn osc52_sequence(text: &str) -> String { format!("\x1b]52;c;{}\x07", base64(text.as_bytes())) }
+
+/// Standard base64 encoding with padding.
+pub(crate) fn base64(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::new();
+    for chunk in bytes.chunks(3) {
+        let n = chunk.iter().enumerate().fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            encoded.push(if i <= chunk.len() { ALPHABET[(n >> (18 - 6 * i) & 63) as usize] as char } else { '=' });
+        }
+    }
+    encoded
+}
+
+/// Opens the tip-split helper, starting from the displayed number when there is one.
+pub(crate) fn open_tip_split(app: &mut App) {
+    let bill = app.display_value.trim().parse::<f64>().ok().filter(|&bill| bill >= 0.0).map_or("0".to_string(), |bill| bill.to_string());
+    app.overlay = Some(Overlay::TipSplit(TipSplit::new(bill)));
+}
+
+/// Opens the amortization view, taking the principal from the displayed number when there is one.
+pub(crate) fn open_amortization(app: &mut App) {
+    let principal = app.display_value.trim().parse::<f64>().ok().filter(|&p| p > 0.0).map_or("0".to_string(), |p| p.to_string());
+    app.overlay = Some(Overlay::Amortization(Amortization { fields: [principal, "5".to_string(), "360".to_string()], cursor: 0, scroll: 0 }));
+}
+
+/// Opens the mortgage wizard, taking the amount from the displayed number when there is one.
+pub(crate) fn open_mortgage(app: &mut App) {
+    let amount = app.display_value.trim().parse::<f64>().ok().filter(|&a| a > 0.0).map_or("0".to_string(), |a| a.to_string());
+    app.overlay = Some(Overlay::Mortgage(Mortgage { fields: [amount, "5".to_string(), "30".to_string(), "0".to_string()], cursor: 0 }));
+}
+
+// --- Config Section ---
+
+/// Reads a key like `ctrl+e`, `alt+shift+x` or `f11`, ignoring case. Plain letters and digits are left to typing.
+pub(crate) fn parse_key(text: &str) -> Option<(KeyCode, KeyModifiers)> {
+    let text = text.to_lowercase();
+    let mut parts: Vec<&str> = text.split('+').map(str::trim).collect();
+    let key = parts.pop()?;
+    let mut modifiers = KeyModifiers::NONE;
+    for part in parts {
+        modifiers |= match part { "ctrl" => KeyModifiers::CONTROL, "alt" => KeyModifiers::ALT, "shift" => KeyModifiers::SHIFT, _ => return None };
+    }
+    let code = match key.strip_prefix('f').and_then(|n| n.parse().ok()) {
+        Some(n @ 1..=12) => KeyCode::F(n),
+        _ if key.chars().count() == 1 && !modifiers.is_empty() => KeyCode::Char(key.chars().next()?),
+        _ => return None,
+    };
+    Some((code, modifiers))
+}
+
+/// A key event the way `parse_key` reads a binding: a letter in lower case, with Shift held when it is a capital.
+/// Terminals report Shift+E as `E` with or without the Shift flag, so both match a `shift+e` binding.
+fn normalize_key(code: KeyCode, modifiers: KeyModifiers) -> (KeyCode, KeyModifiers) {
+    match code {
+        KeyCode::Char(c) if c.is_uppercase() => (KeyCode::Char(c.to_lowercase().next().unwrap_or(c)), modifiers | KeyModifiers::SHIFT),
+        _ => (code, modifiers),
+    }
+}
+
+/// What an entry in `operator_keys` can type.
+pub(crate) const OPERATOR_KEYS: [&str; 8] = ["+", "-", "*", "/", "^", "%", "(", ")"];
+
+/// The rows of the inline viewport unless the config says otherwise, and the fewest the keypad fits in.
+pub(crate) const INLINE_HEIGHT: u16 = 22;
+pub(crate) const MIN_INLINE_HEIGHT: u16 = 12;
+
+/// Options read from `config.toml` in the user's config directory; anything left out keeps its default.
+#[derive(Deserialize, Clone, Default, Debug, PartialEq)]
+#[serde(default)]
+pub(crate) struct Config {
+    /// Tax rates in percent for the `+TAX`/`−TAX` keys; the first one is selected at startup.
+    pub(crate) tax_rates: Vec<f64>,
+    /// Extra keys for the user row of the keypad.
+    pub(crate) buttons: Vec<CustomButton>,
+    /// `"bell"` or `"flash"` to signal errors; off by default.
+    pub(crate) error_feedback: ErrorFeedback,
+    /// Whether a dot blinks in the status line on every key press.
+    pub(crate) key_click: bool,
+    /// Minutes without input before the screensaver clock appears; 0 (the default) never shows it.
+    pub(crate) idle_minutes: u32,
+    /// Whether the terminal window title shows the latest result.
+    pub(crate) window_title: bool,
+    /// `"off"`, `"steady"` or `"blinking"` (the default): the cursor at the edit position.
+    pub(crate) cursor: CursorMode,
+    /// Whether to count the functions and operators used; the counts stay in `usage.txt` on this machine.
+    pub(crate) usage_stats: bool,
+    /// `"dark"` (the default), `"light"`, `"mono"` or the name of a file in `themes/`; a profile's own theme wins.
+    pub(crate) theme: Option<String>,
+    /// `false` draws without color, with the mono theme; left out, color is used unless `NO_COLOR` is set or `TERM` is `dumb`.
+    pub(crate) color: Option<bool>,
+    /// Decimal places shown in results; a profile's own precision wins.
+    pub(crate) decimals: Option<usize>,
+    /// Extra shortcuts from a key like `"ctrl+e"` or `"f11"` to the name of a palette action.
+    pub(crate) keys: BTreeMap<String, String>,
+    /// Keys that type an operator, for layouts where it is awkward to reach: a key like `"alt+6"`, or a single
+    /// character like `"ü"`, to one of `OPERATOR_KEYS`.
+    pub(crate) operator_keys: BTreeMap<String, String>,
+    /// Named profiles, chosen with `--profile` or from the palette.
+    pub(crate) profiles: BTreeMap<String, Profile>,
+    /// An HTTP endpoint answering with `{"base": …, "rates": {…}}`; without one the built-in rates are used.
+    pub(crate) rates_url: Option<String>,
+    /// How many hours fetched rates stay fresh in the cache; 12 if left out.
+    pub(crate) rates_ttl_hours: Option<u64>,
+    /// `false` leaves the mouse to the terminal, for its own text selection; the app takes it if left out.
+    pub(crate) mouse: Option<bool>,
+    /// Run below the shell prompt in a viewport of `inline_height` rows instead of taking over the screen.
+    pub(crate) inline: bool, pub(crate) inline_height: Option<u16>,
+    /// Draw for slow connections such as SSH over a long distance: basic colors, no key highlights, no animation.
+    pub(crate) low_bandwidth: bool,
+    /// Share the history and variables with the other calculators running at the same time.
+    pub(crate) share_state: bool,
+}
+
+/// What a profile sets on top of the rest of the config: display and number settings, the theme, the modes it starts in
+/// and the variables it starts with.
+#[derive(Deserialize, Clone, Default, Debug, PartialEq)]
+#[serde(default)]
+pub(crate) struct Profile {
+    pub(crate) decimals: Option<usize>, pub(crate) backend: Option<String>, pub(crate) theme: Option<String>, pub(crate) word_bits: Option<u32>,
+    pub(crate) rpn: bool, pub(crate) stats: bool, pub(crate) finance_keypad: bool,
+    /// `"rad"` or `"deg"`: what `sin`, `cos` and `tan` take.
+    pub(crate) angles: Option<String>,
+    /// Whether SI suffixes like `4.7k` are read and shown, as the *Engineering* setting.
+    pub(crate) si_suffixes: Option<bool>,
+    pub(crate) variables: BTreeMap<String, f64>,
+}
+
+/// A keypad key from the config file: it types the `insert` snippet, or runs the palette action named by `action`.
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+pub(crate) struct CustomButton { pub(crate) label: String, pub(crate) insert: Option<String>, pub(crate) action: Option<String> }
+
+impl Config {
+    pub(crate) fn path() -> Option<PathBuf> { dirs::config_dir().map(|dir| dir.join("rust-calculator-tui").join("config.toml")) }
+
+    /// Loads the config file. A missing file gives the defaults; an unreadable or invalid one is an error.
+    pub(crate) fn load() -> Result<Config, String> {
+        let Some(path) = Config::path() else { return Ok(Config::default()) };
+        match std::fs::read_to_string(&path) {
+            Ok(text) => {
+                let config: Config = toml::from_str(&text).map_err(|e| format!("Invalid {}: {}", path.display(), e.message()))?;
+                config.check().map_err(|message| format!("Invalid {}: {}", path.display(), message))?;
+                Ok(config)
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Config::default()),
+            Err(e) => Err(format!("Cannot read {}: {}", path.display(), e)),
+        }
+    }
+
+    /// The settings of a fresh session in profile `name`: the defaults, then the config, then the profile.
+    pub(crate) fn settings(&self, name: &str) -> Settings {
+        let mut settings = Settings {
+            tax_rates: self.tax_rates.clone(), error_feedback: self.error_feedback, key_click: self.key_click,
+            idle_minutes: self.idle_minutes, window_title: self.window_title, cursor: self.cursor,
+            usage_stats: self.usage_stats, ..Settings::default()
+        };
+        if let Some(decimals) = self.decimals { settings.decimals = decimals; }
+        if let Some(profile) = self.profiles.get(name) {
+            if let Some(decimals) = profile.decimals { settings.decimals = decimals; }
+            if let Some(backend) = profile.backend.as_deref().and_then(NumberBackend::named) { settings.backend = backend; }
+            if let Some(bits) = profile.word_bits { settings.word_bits = bits; }
+            if let Some(angles) = profile.angles.as_deref() { settings.degrees = angles == "deg"; }
+            if let Some(si_suffixes) = profile.si_suffixes { settings.si_suffixes = si_suffixes; }
+        }
+        settings
+    }
+
+    /// The name of profile `name`'s theme: `mono` without color, else its own, else the config's, else `dark`.
+    pub(crate) fn theme_name(&self, name: &str) -> &str {
+        if !self.color.unwrap_or_else(|| !color_disabled()) { return "mono"; }
+        self.profiles.get(name).and_then(|profile| profile.theme.as_deref()).or(self.theme.as_deref()).unwrap_or("dark")
+    }
+
+    /// The theme of profile `name`, or the dark one if it cannot be loaded.
+    pub(crate) fn theme(&self, name: &str) -> Theme { Theme::load(self.theme_name(name)).unwrap_or_else(|_| Theme::default()) }
+
+    /// The operator `operator_keys` gives a key, if any.
+    pub(crate) fn operator_key(&self, code: KeyCode, modifiers: KeyModifiers) -> Option<String> {
+        let matches = |key: &str| parse_key(key) == Some(normalize_key(code, modifiers))
+            || modifiers.difference(KeyModifiers::SHIFT).is_empty() && key.chars().count() == 1
+                && code == KeyCode::Char(key.chars().next().unwrap_or_default());
+        self.operator_keys.iter().find(|(key, _)| matches(key)).map(|(_, operator)| operator.clone())
+    }
+
+    /// The palette action bound to a key, in `keys` or else among the built-in shortcuts.
+    pub(crate) fn key_action(&self, code: KeyCode, modifiers: KeyModifiers) -> Option<Action> {
+        let bound = |key: &str| parse_key(key) == Some(normalize_key(code, modifiers));
+        self.keys.iter().find(|(key, _)| bound(key)).map(|(_, action)| action.as_str())
+            .or_else(|| DEFAULT_KEYS.iter().find(|(key, ..)| bound(key)).map(|(_, action, _)| *action)).and_then(palette_action)
+    }
+
+    /// Every key that runs a palette action, as its label, the action and its footer wording: the keys from the
+    /// config first, then the built-in ones they leave free. The footer and the key help are drawn from this.
+    pub(crate) fn key_bindings(&self) -> Vec<(String, String, String)> {
+        let user = self.keys.iter().map(|(key, action)| (key_label(key), action.clone(), format!("for {}", action.to_lowercase())));
+        let taken = |key: &str| self.keys.keys().any(|bound| parse_key(bound) == parse_key(key));
+        let builtin = DEFAULT_KEYS.iter().filter(|(key, ..)| !taken(key)).map(|(key, action, hint)| (key_label(key), action.to_string(), hint.to_string()));
+        user.chain(builtin).collect()
+    }
+
+    /// Checks what the TOML types cannot: every button has a label and exactly one of `insert` and a known `action`,
+    /// every key binding names a key and a known action, and the theme, precision, backends and word sizes are known.
+    pub(crate) fn check(&self) -> Result<(), String> {
+        if let Some(theme) = &self.theme { Theme::load(theme)?; }
+        if self.decimals.is_some_and(|decimals| decimals > 15) { return Err("more than 15 decimals".to_string()); }
+        if self.inline_height.is_some_and(|height| height < MIN_INLINE_HEIGHT) { return Err(format!("inline_height below {} rows", MIN_INLINE_HEIGHT)); }
+        for (key, action) in &self.keys {
+            if parse_key(key).is_none() { return Err(format!("`{}` is not a key like \"ctrl+e\" or \"f11\"", key)); }
+            if palette_action(action).is_none() { return Err(format!("key `{}` has an unknown action `{}`", key, action)); }
+        }
+        for (key, operator) in &self.operator_keys {
+            if parse_key(key).is_none() && key.chars().count() != 1 { return Err(format!("`{}` is not a key like \"alt+6\" or a single character", key)); }
+            if !OPERATOR_KEYS.contains(&operator.as_str()) { return Err(format!(
+                "operator key `{}` types `{}`, which is not one of {}", key, operator, OPERATOR_KEYS.join(" ")
+            )); }
+        }
+        for (name, profile) in &self.profiles {
+            if profile.decimals.is_some_and(|decimals| decimals > 15) { return Err(format!("profile `{}` asks for more than 15 decimals", name)); }
+            if let Some(backend) = &profile.backend && NumberBackend::named(backend).is_none() { return Err(format!(
+                "profile `{}` has an unknown backend `{}`", name, backend
+            )); }
+            if let Some(theme) = &profile.theme && let Err(e) = Theme::load(theme) { return Err(format!("profile `{}`: {}", name, e)); }
+            if profile.word_bits.is_some_and(|bits| !WORD_SIZES.contains(&bits)) { return Err(format!(
+                "profile `{}` has a word size other than 8, 16, 32 or 64", name
+            )); }
+        }
+        for button in &self.buttons {
+            if button.label.trim().is_empty() { return Err("a button has an empty label".to_string()); }
+            match (&button.insert, &button.action) {
+                (Some(_), None) => {}
+                (None, Some(action)) if palette_action(action).is_some() => {}
+                (None, Some(action)) => return Err(format!("button `{}` has an unknown action `{}`", button.label, action)),
+                _ => return Err(format!("button `{}` needs either insert or action", button.label)),
+            }
+        }
+        Ok(())
+    }
+}
+
+// --- End of Config Section ---
+
+/// Flips one bit of the current value within the word size, keeping negative values signed.
+pub(crate) fn toggle_bit(app: &mut App, bit: u32) {
+    let bits = app.settings.word_bits;
+    let value = current_value(app).unwrap_or(0.0);
+    let Some(word) = to_word(value, bits) else {
+        app.error_message = Some(format!("Out of range for a {}-bit word", bits));
+        return;
+    };
+    let word = word ^ (1 << bit);
+    let n = if value < 0.0 { wrap_word(word, bits) } else { word };
+    app.edit(n.to_string());
+}
+
+/// Clicks each key in turn, evaluating right away what it queues for a worker; other effects stay queued.
+#[cfg(test)]
+pub(crate) fn press(app: &mut App, keys: &[&str]) {
+    for key in keys {
+        on_click(app, key);
+        app.effects.retain(|effect| *effect != Effect::Evaluate);
+        if let Some(pending) = app.pending.take() {
+            let (result, timings) = match pending.job {
+                Some((options, _)) => evaluate_timed(&pending.expression, &options),
+                None => pending.receiver.recv().unwrap(),
+            };
+            finish_evaluation(app, &pending.expression, result, timings);
+            follow_up(app, &pending.expression, pending.followup);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{input::*, ui::bits_per_row};
+    use crossterm::event::KeyCode;
+    use ratatui::style::Color;
+    #[test]
+    fn test_financial_functions() {
+        let near = |expression: &str, expected: f64| assert!((evaluate(expression).unwrap() - expected).abs() < 0.01, "{expression}");
+        near("pmt(0.05 / 12, 360, 200000)", -1073.64);
+        near("fv(0.06 / 12, 10, -200, -500)", 2571.18);
+        near("pv(0.08 / 12, 240, 500)", -59777.15);
+        near("pmt(0, 10, 1000)", -100.0);
+        near("npv(0.1, -10000, 3000, 4200, 6800)", 1188.44);
+        near("irr(-70000, 12000, 15000, 18000, 21000, 26000) * 100", 8.66);
+        assert_eq!(evaluate("irr(100, 200)").unwrap_err().message, "IRR needs both positive and negative cash flows");
+        let mut app = App::new();
+        press(&mut app, &["npv", "1", "0", "%", ",", "1", "1", "0", ",", "1", "2", "1", ")"]);
+        assert_eq!(app.display_value, "npv(10%, 110, 121)");
+        press(&mut app, &[",", "="]);
+        assert_eq!(app.display_value, "200");
+    }
+    #[test]
+    fn test_tax_keys() {
+        let mut app = App::new();
+        press(&mut app, &["5", "+TAX"]);
+        assert_eq!(app.error_message.as_deref(), Some("No tax rate configured (add tax_rates to config.toml)"));
+        app.settings.tax_rates = vec![21.0, 9.0];
+        press(&mut app, &["1", "0", "0", "+TAX"]);
+        assert_eq!(app.last_result, Some(6171.0));
+        press(&mut app, &["C", "1", "0", "0", "+TAX"]);
+        assert_eq!(app.notice.as_deref(), Some("Tax 21%: 21 · Total: 121"));
+        press(&mut app, &["−TAX"]);
+        assert_eq!(app.last_result, Some(100.0));
+        assert_eq!(app.notice.as_deref(), Some("Tax 21%: 21 · Net: 100"));
+        app.settings.adjust(Settings::TAX_RATE, true);
+        press(&mut app, &["C", "(", "5", "0", "+", "5", "0", "+TAX"]);
+        assert_eq!(app.last_result, Some(109.0));
+        assert_eq!(toml::from_str::<Config>("tax_rates = [21, 9.5]"), Ok(Config { tax_rates: vec![21.0, 9.5], ..Config::default() }));
+    }
+    #[test]
+    fn test_custom_buttons() {
+        let config: Config = toml::from_str(
+            "[[buttons]]\nlabel = \"VAT\"\ninsert = \"*1.21\"\n\
+             [[buttons]]\nlabel = \"kW→hp\"\ninsert = \"* 1.341=\"\n\
+             [[buttons]]\nlabel = \"Stats\"\naction = \"statistics mode\"",
+        ).unwrap();
+        assert_eq!(config.check(), Ok(()));
+        let mut app = App::new();
+        app.custom_buttons = config.buttons;
+        press(&mut app, &["1", "0", "0", "user:0"]);
+        assert_eq!(app.display_value, "100 * 1.21");
+        press(&mut app, &["C", "2", "0", "0", "user:1"]);
+        assert_eq!((app.last_expression.as_deref(), app.display_value.as_str()), (Some("200 * 1.341"), "268.2"));
+        assert_eq!(app.active_button.as_ref().map(|(label, _)| label.as_str()), Some("user:1"));
+        press(&mut app, &["user:2"]);
+        assert!(app.stats_mode);
+        let bad: Config = toml::from_str("[[buttons]]\nlabel = \"X\"\naction = \"Fly\"").unwrap();
+        assert_eq!(bad.check(), Err("button `X` has an unknown action `Fly`".to_string()));
+        let bad: Config = toml::from_str("[[buttons]]\nlabel = \"X\"").unwrap();
+        assert_eq!(bad.check(), Err("button `X` needs either insert or action".to_string()));
+    }
+    #[test]
+    fn test_profiles() {
+        let config: Config = toml::from_str(
+            "tax_rates = [21]\n[profiles.finance]\ndecimals = 2\nbackend = \"money\"\nfinance_keypad = true\n\
+             [profiles.engineering]\ntheme = \"light\"\nrpn = true",
+        ).unwrap();
+        assert_eq!(config.check(), Ok(()));
+        let mut app = App::new();
+        app.config = config;
+        press(&mut app, &["2", "+", "2", "="]);
+        switch_profile(&mut app, "finance");
+        assert_eq!(
+            (app.settings.decimals, app.settings.backend, app.finance_keypad, app.settings.tax_rates.as_slice()),
+            (2, NumberBackend::Money, true, &[21.0][..])
+        );
+        assert!(app.history.is_empty() && app.last_result.is_none());
+        press(&mut app, &["1", "0", "="]);
+        next_profile(&mut app);
+        assert_eq!((app.profile.as_str(), app.rpn_mode, app.theme.text), ("default", false, Color::White));
+        assert_eq!(app.history.iter().map(|entry| entry.expression.as_str()).collect::<Vec<_>>(), ["2 + 2"]);
+        next_profile(&mut app);
+        assert_eq!((app.profile.as_str(), app.rpn_mode, app.theme.text), ("engineering", true, Color::Black));
+        switch_profile(&mut app, "finance");
+        assert_eq!(app.history[0].expression, "10");
+        let bad: Config = toml::from_str("[profiles.x]\nbackend = \"quantum\"").unwrap();
+        assert_eq!(bad.check(), Err("profile `x` has an unknown backend `quantum`".to_string()));
+        app.config = toml::from_str("[profiles.lab]\nangles = \"deg\"\nsi_suffixes = true\n[profiles.lab.variables]\nrate = 0.05").unwrap();
+        switch_profile(&mut app, "lab");
+        assert_eq!((app.settings.degrees, app.settings.si_suffixes, app.variables.get("rate").copied()), (true, true, Some(0.05)));
+        press(&mut app, &["s", "i", "n", "(", "9", "0", ")", "="]);
+        assert_eq!(app.display_value, "1");
+        switch_profile(&mut app, "default");
+        assert!(!app.settings.degrees && !app.variables.contains_key("rate"));
+    }
+    #[test]
+    fn test_config_reload() {
+        assert_eq!(parse_key("Ctrl+Shift+E"), Some((KeyCode::Char('e'), KeyModifiers::CONTROL | KeyModifiers::SHIFT)));
+        assert_eq!((parse_key("f11"), parse_key("x"), parse_key("ctrl+f13")), (Some((KeyCode::F(11), KeyModifiers::NONE)), None, None));
+        let mut app = App::new();
+        press(&mut app, &["1", "/", "3", "="]);
+        assert_eq!(app.display_value, "0.33333333");
+        let config: Config = toml::from_str("theme = \"light\"\ndecimals = 3\n[keys]\n\"ctrl+t\" = \"statistics mode\"").unwrap();
+        assert_eq!(config.check(), Ok(()));
+        app.reload_config(config);
+        assert_eq!((app.display_value.as_str(), app.theme.text), ("0.333", Color::Black));
+        assert_eq!(app.toast.as_ref().map(|toast| (toast.text.as_str(), toast.error)), Some(("Reloaded config.toml", false)));
+        on_key(&mut app, KeyCode::Char('t'), KeyModifiers::CONTROL);
+        assert!(app.stats_mode);
+        // Shift+E arrives as a capital, with or without the Shift flag.
+        app.reload_config(toml::from_str("[keys]\n\"shift+e\" = \"statistics mode\"").unwrap());
+        on_key(&mut app, KeyCode::Char('E'), KeyModifiers::SHIFT);
+        assert!(!app.stats_mode);
+        on_key(&mut app, KeyCode::Char('E'), KeyModifiers::NONE);
+        assert!(app.stats_mode);
+        let bad: Config = toml::from_str("[keys]\n\"ctrl+t\" = \"Fly\"").unwrap();
+        assert_eq!(bad.check(), Err("key `ctrl+t` has an unknown action `Fly`".to_string()));
+        let bad: Config = toml::from_str("decimals = 20").unwrap();
+        assert_eq!(bad.check(), Err("more than 15 decimals".to_string()));
+    }
+    #[test]
+    fn test_history_reorder() {
+        let mut app = App::new();
+        for digit in ["1", "2", "3", "4"] { press(&mut app, &[digit, "="]); }
+        let tape = |app: &App| history_order(&app.history).into_iter().map(|i| app.history[i].expression.clone()).collect::<Vec<_>>().join(" ");
+        focus_history(&mut app);
+        on_history_key(&mut app, KeyCode::Char('K'));
+        on_history_key(&mut app, KeyCode::Char('K'));
+        assert_eq!(tape(&app), "1 4 2 3");
+        on_history_key(&mut app, KeyCode::Down);
+        on_history_key(&mut app, KeyCode::Char('p'));
+        assert_eq!(tape(&app), "2 1 4 3");
+        // A pinned entry only moves among the pinned ones.
+        on_history_key(&mut app, KeyCode::Char('J'));
+        assert_eq!(tape(&app), "2 1 4 3");
+        on_history_key(&mut app, KeyCode::Down);
+        on_history_key(&mut app, KeyCode::Char('J'));
+        assert_eq!(tape(&app), "2 4 1 3");
+        on_history_key(&mut app, KeyCode::Esc);
+        assert_eq!(app.history_cursor, None);
+    }
+    #[test]
+    fn test_assign_variable() {
+        let mut app = App::new();
+        for expression in ["120 + 30", "4 * 2"] { app.display_value = expression.to_string(); press(&mut app, &["="]); }
+        focus_history(&mut app);
+        on_history_key(&mut app, KeyCode::Up);
+        on_history_key(&mut app, KeyCode::Char('a'));
+        assert_eq!(app.focus(), Focus::VariablePrompt);
+        for c in "pi".chars() { on_variable_key(&mut app, KeyCode::Char(c)); }
+        on_variable_key(&mut app, KeyCode::Enter);
+        assert_eq!((app.error_message.as_deref(), app.focus()), (Some("`pi` is a built-in name"), Focus::VariablePrompt));
+        for _ in 0..2 { on_variable_key(&mut app, KeyCode::Backspace); }
+        for c in "sub-total".chars() { on_variable_key(&mut app, KeyCode::Char(c)); }
+        on_variable_key(&mut app, KeyCode::Enter);
+        assert_eq!((app.variables.get("subtotal"), app.variables_panel, app.focus()), (Some(&150.0), true, Focus::Display(Mode::ResultShown)));
+        assert_eq!((app.error_message.as_deref(), app.notice.as_deref()), (None, Some("subtotal = 150")));
+        app.display_value = "subtotal * 1.2".to_string();
+        press(&mut app, &["="]);
+        assert_eq!(app.last_result, Some(180.0));
+        assert!(variable_name_error("2x").is_some() && variable_name_error("ans2").is_some() && variable_name_error("rate_2").is_none());
+    }
+    #[test]
+    fn test_variables_file() {
+        let mut app = App::new();
+        export_variables(&mut app);
+        assert_eq!(app.error_message.as_deref(), Some("No variables to export"));
+        app.variables.extend([("rate".to_string(), 0.07), ("subtotal".to_string(), 150.0), ("huge".to_string(), f64::INFINITY)]);
+        let json = variables_json(&app.variables);
+        assert_eq!(json, "{\n  \"variables\": {\n    \"rate\": 0.07,\n    \"subtotal\": 150\n  }\n}\n");
+        export_variables(&mut app);
+        assert!(matches!(app.effects.as_slice(), [Effect::WriteFile { path, contents, .. }] if path == VARIABLES_FILE && *contents == json));
+        switch_profile(&mut app, "work");
+        assert!(app.variables.is_empty());
+        let mut imported = parse_variables(&json).unwrap();
+        imported.insert("pi".to_string(), 3.0);
+        merge_variables(&mut app, imported);
+        assert_eq!(app.variables, BTreeMap::from([("rate".to_string(), 0.07), ("subtotal".to_string(), 150.0)]));
+        assert_eq!((app.notice.as_deref(), app.variables_panel), (Some("Loaded 2 variables from variables.json, skipped pi"), true));
+        assert_eq!(parse_variables("{\"variables\": {}}").unwrap(), BTreeMap::new());
+        assert_eq!(parse_variables("{\"about\": {\"variables\": 1}, \"variables\": {\"g\": 9.81}}").unwrap(), BTreeMap::from([("g".to_string(), 9.81)]));
+        assert!(parse_variables("{\"variables\": {\"g\": \"fast\"}}").is_err());
+    }
+    #[test]
+    fn test_second_functions() {
+        assert_eq!(evaluate("mod(7, 3)"), Ok(1.0));
+        assert_eq!(evaluate("mod(-7, 3)"), Ok(2.0));
+        assert!(evaluate("mod(7, 0)").is_err());
+        let mut app = App::new();
+        press_second(&mut app, "^");
+        press(&mut app, &["1", "6", ")", "+"]);
+        press_second(&mut app, "/");
+        press(&mut app, &["7", ",", "3", ")", "="]);
+        assert_eq!(app.last_result, Some(5.0));
+        press(&mut app, &["3", "2nd", "*", "="]);
+        assert_eq!((app.last_result, app.second), (Some(9.0), false));
+        press(&mut app, &["2nd", "7"]);
+        assert_eq!((app.display_value.as_str(), app.second), ("7", false));
+    }
+    #[test]
+    fn test_history_menu() {
+        let mut app = App::new();
+        press(&mut app, &["2", "-", "7", "="]);
+        press(&mut app, &["4", "*", "5", "="]);
+        open_history_menu(&mut app);
+        on_history_menu_key(&mut app, KeyCode::PageUp);
+        on_history_menu_key(&mut app, KeyCode::Char('p'));
+        assert!(app.history[0].pinned && app.overlay.is_none());
+        press(&mut app, &["1", "0", "+"]);
+        run_history_menu(&mut app, 0, 2);
+        assert_eq!(app.display_value, "10 + (-5)");
+        press(&mut app, &["="]);
+        assert_eq!(app.last_result, Some(5.0));
+        open_history_menu(&mut app);
+        on_history_menu_key(&mut app, KeyCode::Down);
+        on_history_menu_key(&mut app, KeyCode::Up);
+        for _ in 0..4 { on_history_menu_key(&mut app, KeyCode::Down); }
+        on_history_menu_key(&mut app, KeyCode::Enter);
+        assert_eq!(app.history.iter().map(|entry| entry.expression.as_str()).collect::<Vec<_>>(), ["2 - 7", "4 * 5"]);
+    }
+    #[test]
+    fn test_practice_mode() {
+        for level in 1..=Quiz::LEVELS {
+            let mut quiz = Quiz::new(level, 42);
+            for _ in 0..50 {
+                quiz.next_problem();
+                assert!(quiz.answer.is_finite() && quiz.answer.fract() == 0.0, "{} = {}", quiz.problem, quiz.answer);
+            }
+        }
+        let mut app = App::new();
+        app.quiz = Some(Quiz::new(2, 7));
+        let answer = app.quiz.as_ref().unwrap().answer.to_string();
+        let keys: Vec<String> = answer.chars().map(|c| c.to_string()).collect();
+        press(&mut app, &keys.iter().map(String::as_str).collect::<Vec<_>>());
+        press(&mut app, &["="]);
+        assert_eq!(app.notice.as_deref(), Some("✓ Correct! Streak 1"));
+        let problem = app.quiz.as_ref().unwrap().problem.clone();
+        app.display_value = problem.clone();
+        press(&mut app, &["="]);
+        assert_eq!(app.error_message.as_deref(), Some("Type the answer as a number"));
+        app.display_value = format!("{}", app.quiz.as_ref().unwrap().answer + 1.0);
+        press(&mut app, &["="]);
+        let quiz = app.quiz.as_ref().unwrap();
+        assert_eq!((quiz.correct, quiz.asked, quiz.streak, quiz.best_streak), (1, 2, 0, 1));
+        assert!(app.error_message.as_deref().is_some_and(|message| message.starts_with(&format!("✗ {} = ", problem))));
+        change_quiz_level(&mut app, true);
+        change_quiz_level(&mut app, true);
+        assert_eq!(app.quiz.as_ref().unwrap().level, 3);
+        app.quiz.as_mut().unwrap().answer = -12.0;
+        press(&mut app, &["C", "-", "1", "2"]);
+        assert_eq!(app.display_value, "-12");
+        press(&mut app, &["="]);
+        assert_eq!(app.notice.as_deref(), Some("✓ Correct! Streak 1"));
+    }
+    #[test]
+    fn test_tutor_narration() {
+        let settings = Settings::default();
+        let tell = |expression: &str| {
+            let tree = parse_tokens(tokenize(expression, false).unwrap(), expression.len(), 64).unwrap();
+            let mut recorder = Recorder { source: expression, steps: Vec::new() };
+            eval_expr::<f64>(&tree, &settings.eval_options(), Some(&mut recorder)).unwrap();
+            narrate(&recorder.steps, |x| format_result(x, &settings.format_options()))
+        };
+        assert_eq!(
+            tell("2 + 3 * (1 - 5) ^ 2"),
+            "First the parentheses: 1 − 5 = −4; then the exponent: (−4)^2 = 16; then the multiplication: 3 × 16 = 48; finally the addition: 2 + 48 = 50."
+        );
+        assert_eq!(tell("50 + 10%"), "First the percentage: 10% of 50 = 5; finally the addition: 50 + 5 = 55.");
+        assert_eq!(tell("sqrt(16) / -2"), "First the function: sqrt(16) = 4; finally the division: 4 ÷ (−2) = −2.");
+        assert_eq!(tell("7"), "Just a number: there is nothing to work out.");
+        assert_eq!(tell("-(2 * 3)"), "First the parentheses: 2 × 3 = 6; finally the negation: −(6) = −6.");
+        let mut app = App::new();
+        app.tutor_mode = true;
+        press(&mut app, &["4", "=", "a", "n", "s", "+", "1", "="]);
+        assert_eq!(app.tutor_text.as_deref(), Some("Only the addition: 4 + 1 = 5."));
+    }
+    #[test]
+    fn test_notation() {
+        let postfix = |text| expression_notation(text, Notation::Postfix, 64, false);
+        let prefix = |text| expression_notation(text, Notation::Prefix, 64, false);
+        assert_eq!(postfix("3 + 4 * 2").as_deref(), Some("3 4 2 * +"));
+        assert_eq!(prefix("3 + 4 * 2").as_deref(), Some("+ 3 * 4 2"));
+        assert_eq!(postfix("(3 + 4) * 2").as_deref(), Some("3 4 + 2 *"));
+        assert_eq!(postfix("2 ^ 3 ^ 2").as_deref(), Some("2 3 ^ 2 ^"));
+        assert_eq!(postfix("-2 ^ 2").as_deref(), Some("2 neg 2 ^"));
+        assert_eq!(prefix("sqrt(9) + sum(1, 2, 3)").as_deref(), Some("+ sqrt 9 sum/3 1 2 3"));
+        assert_eq!(postfix("50 + 10%").as_deref(), Some("50 10 % +"));
+        assert_eq!(postfix("3 +"), None);
+        let deep = format!("{}1{}", "(".repeat(100), ")".repeat(100));
+        assert_eq!((postfix(&deep), expression_notation(&deep, Notation::Postfix, 128, false).as_deref()), (None, Some("1")));
+        let mut app = App::new();
+        cycle_notation(&mut app);
+        cycle_notation(&mut app);
+        assert_eq!(app.notation, Some(Notation::Prefix));
+        cycle_notation(&mut app);
+        assert_eq!(app.notation, None);
+    }
+    #[test]
+    fn test_rpn_mode() {
+        let mut app = App::new();
+        toggle_rpn(&mut app);
+        press(&mut app, &["3", "=", "4", "+"]);
+        assert_eq!((app.rpn_stack.as_slice(), app.display_value.as_str()), (&[7.0][..], "7"));
+        press(&mut app, &["=", "*", "2", "=", "1", "0", "=", "5"]);
+        assert_eq!(app.rpn_stack, [49.0, 2.0, 10.0]);
+        press(&mut app, &["/", "x⇄y"]);
+        assert_eq!(app.rpn_stack, [49.0, 2.0, 2.0]);
+        press(&mut app, &["R↓"]);
+        assert_eq!(app.rpn_stack, [2.0, 49.0, 2.0]);
+        press(&mut app, &["^", "Drop", "-"]);
+        assert_eq!(app.error_message.as_deref(), Some("The stack needs two values"));
+        press(&mut app, &["s", "q", "r", "t", "(", "1", "6", ")", "="]);
+        assert_eq!(app.rpn_stack, [2.0, 4.0]);
+        press(&mut app, &["0", "/"]);
+        assert_eq!((app.error_message.as_deref(), app.rpn_stack.len()), (Some("Division by zero"), 3));
+        press(&mut app, &["+/-"]);
+        assert_eq!(app.rpn_stack, [2.0, 4.0, -0.0]);
+        press(&mut app, &["C", "C"]);
+        assert!(app.rpn_stack.is_empty());
+    }
+    #[test]
+    fn test_variables_in_rpn_and_pairs() {
+        let mut app = App::new();
+        app.variables.insert("rate".to_string(), 2.0);
+        toggle_rpn(&mut app);
+        press(&mut app, &["r", "a", "t", "e", "=", "3", "*"]);
+        assert_eq!(app.rpn_stack, [6.0]);
+        toggle_rpn(&mut app);
+        app.stats_mode = true;
+        press(&mut app, &["C", "r", "a", "t", "e", ",", "3", "="]);
+        assert_eq!((app.data_points.as_slice(), app.error_message.as_deref()), (&[(Some(2.0), 3.0)][..], None));
+    }
+    #[test]
+    fn test_compare_results() {
+        assert_eq!(compare_values(80.0, 100.0), (20.0, Some(1.25), Some(25.0)));
+        assert_eq!(compare_values(-50.0, -25.0), (25.0, Some(0.5), Some(50.0)));
+        assert_eq!(compare_values(0.0, 3.0), (3.0, None, None));
+        let mut app = App::new();
+        press(&mut app, &["1", "="]);
+        open_compare(&mut app);
+        assert!(app.overlay.is_none());
+        press(&mut app, &["C", "2", "=", "C", "3", "="]);
+        open_compare(&mut app);
+        assert!(matches!(&app.overlay, Some(Overlay::Compare(compare)) if compare.marked == [1, 2]));
+        for code in [KeyCode::Up, KeyCode::Up, KeyCode::Char(' ')] { on_compare_key(&mut app, code); }
+        assert!(matches!(&app.overlay, Some(Overlay::Compare(compare)) if compare.marked == [2, 0]));
+        on_compare_key(&mut app, KeyCode::Enter);
+        assert!(matches!(&app.overlay, Some(Overlay::Compare(compare)) if compare.marked == [2]));
+        on_compare_key(&mut app, KeyCode::Esc);
+        assert!(app.overlay.is_none());
+    }
+    #[test]
+    fn test_history_notes() {
+        let mut app = App::new();
+        open_note(&mut app);
+        assert_eq!(app.error_message.as_deref(), Some("No result to label yet"));
+        press(&mut app, &["1", "2", "0", "0", "+", "3", "4", "0", "=", "C", "2", "*", "3", "="]);
+        open_note(&mut app);
+        for c in " June invoice  ".chars() { on_note_key(&mut app, KeyCode::Char(c)); }
+        on_note_key(&mut app, KeyCode::Enter);
+        assert_eq!((app.history[0].note.as_deref(), app.history[1].note.as_deref()), (None, Some("June invoice")));
+        assert_eq!(history_text(&app), "1200 + 340 = 1540\n# June invoice\n2 * 3 = 6\n");
+        open_note(&mut app);
+        assert!(matches!(&app.overlay, Some(Overlay::NoteEditor(note)) if note == "June invoice"));
+        for _ in 0..12 { on_note_key(&mut app, KeyCode::Backspace); }
+        on_note_key(&mut app, KeyCode::Enter);
+        assert_eq!(app.history[1].note, None);
+    }
+    #[test]
+    fn test_snippets() {
+        let mut app = App::new();
+        (palette_action("Snippets").unwrap())(&mut app);
+        for code in [KeyCode::Down, KeyCode::Up, KeyCode::Enter] { on_snippet_menu_key(&mut app, code); }
+        assert_eq!(app.display_value, "a*(1+r)^n");
+        press(&mut app, &["1", "0", "0", "0", "="]);
+        assert_eq!(app.error_message.as_deref(), Some("Fill in `r` first"));
+        assert_eq!(app.snippet.as_ref().unwrap().text(), ("1000*(1+r)^n".to_string(), SourceSpan { start: 8, end: 9 }));
+        press(&mut app, &["0", ".", "0", "8"]);
+        on_snippet_key(&mut app, KeyCode::Backspace);
+        press(&mut app, &["5"]);
+        on_snippet_key(&mut app, KeyCode::Tab);
+        press(&mut app, &["2", "="]);
+        assert_eq!((app.last_expression.as_deref(), app.display_value.as_str()), (Some("1000*(1+0.05)^2"), "1102.5"));
+        assert!(app.snippet.is_none());
+        start_snippet(&mut app, 0);
+        on_snippet_key(&mut app, KeyCode::BackTab);
+        press(&mut app, &["-", "3"]);
+        assert_eq!(app.display_value, "a*(1+r)^(-3)");
+        press(&mut app, &["C"]);
+        assert_eq!((app.display_value.as_str(), app.snippet.is_none()), ("0", true));
+    }
+    #[test]
+    fn test_tip_split() {
+        let mut app = App::new();
+        press(&mut app, &["8", "0", "="]);
+        open_tip_split(&mut app);
+        for code in [
+            KeyCode::Down, KeyCode::Char('+'), KeyCode::Char('+'), KeyCode::Char('+'), KeyCode::Char('+'),
+            KeyCode::Char('+'), KeyCode::Down, KeyCode::Char('+'), KeyCode::Char('+'),
+        ] { on_tip_key(&mut app, code); }
+        assert!(matches!(&app.overlay, Some(Overlay::TipSplit(tip)) if tip.amounts() == (16.0, 96.0, 24.0)));
+        for code in [KeyCode::Up, KeyCode::Up, KeyCode::Backspace, KeyCode::Char('.'), KeyCode::Char('5')] { on_tip_key(&mut app, code); }
+        assert!(matches!(&app.overlay, Some(Overlay::TipSplit(tip)) if tip.bill == "8.5"));
+        on_tip_key(&mut app, KeyCode::Esc);
+        assert!(app.overlay.is_none());
+    }
+    #[test]
+    fn test_amortization() {
+        let rows = amortization_schedule(1000.0, 12.0, 12);
+        assert_eq!(rows.len(), 12);
+        assert!((rows[0].payment - 88.85).abs() < 0.005 && (rows[0].interest - 10.0).abs() < 1e-9);
+        assert_eq!(rows[11].balance, 0.0);
+        assert!((rows.iter().map(|row| row.principal).sum::<f64>() - 1000.0).abs() < 1e-9);
+        let mut csv = Vec::new();
+        write_amortization_csv(&rows[..1], &mut csv).unwrap();
+        assert_eq!(String::from_utf8(csv).unwrap(), "period,payment,interest,principal,balance\n1,88.85,10.00,78.85,921.15\n");
+        let mut app = App::new();
+        open_amortization(&mut app);
+        for code in [
+            KeyCode::Char('5'), KeyCode::Char('0'), KeyCode::Char('0'), KeyCode::Down, KeyCode::Down,
+            KeyCode::Backspace, KeyCode::Backspace,
+        ] { on_amortization_key(&mut app, code); }
+        let Some(Overlay::Amortization(view)) = &app.overlay else { panic!("the schedule closed") };
+        assert_eq!((view.fields.clone(), view.schedule().len()), (["500".to_string(), "5".to_string(), "3".to_string()], 3));
+        // Scrolling stops at the last page, so the first PgUp after overshooting moves the table.
+        for code in [KeyCode::Char('6'), KeyCode::PageDown, KeyCode::PageDown, KeyCode::PageDown, KeyCode::PageDown] { on_amortization_key(&mut app, code); }
+        let scroll = |app: &App| match &app.overlay { Some(Overlay::Amortization(view)) => view.scroll, _ => panic!("the schedule closed") };
+        assert_eq!(scroll(&app), 36 - Amortization::PAGE);
+        on_amortization_key(&mut app, KeyCode::PageUp);
+        assert_eq!(scroll(&app), 36 - Amortization::PAGE - 12);
+    }
+    #[test]
+    fn test_business_functions_and_completion() {
+        assert_float_eq(evaluate("pctchange(80, 100)").unwrap(), 25.0);
+        assert_float_eq(evaluate("markup(80, 25)").unwrap(), 100.0);
+        assert_float_eq(evaluate("margin(100, 80)").unwrap(), 20.0);
+        assert_eq!(evaluate("pctchange(0, 5)").unwrap_err().message, "Percent change from zero is undefined");
+        assert_eq!(evaluate("markup(1)").unwrap_err().message, "Wrong number of arguments");
+        assert_eq!(completions("2 * p").iter().map(|(name, _)| *name).collect::<Vec<_>>(), ["pctchange", "pi", "pmt", "predict", "pv"]);
+        assert!(completions("12").is_empty());
+        let mut app = App::new();
+        press(&mut app, &["m", "a", "r", "k"]);
+        complete(&mut app);
+        press(&mut app, &["5", "0", ",", "1", "0", "="]);
+        assert_eq!(app.display_value, "55");
+        press(&mut app, &["C", "2", "p"]);
+        assert_eq!(app.display_value, "2");
+    }
+    #[test]
+    fn test_palette_and_mortgage_wizard() {
+        assert_eq!(palette_matches("MORTG").iter().map(|&i| PALETTE_ACTIONS[i].0).collect::<Vec<_>>(), ["Mortgage calculator"]);
+        let mut app = App::new();
+        press(&mut app, &["2", "0", "0", "0", "0", "0"]);
+        app.overlay = Some(Overlay::Palette(Palette::default()));
+        for code in [KeyCode::Char('m'), KeyCode::Char('o'), KeyCode::Down, KeyCode::Enter] { on_palette_key(&mut app, code); }
+        let Some(Overlay::Mortgage(wizard)) = &app.overlay else { panic!("the palette did not open the wizard") };
+        assert_eq!(wizard.fields[0], "200000");
+        let summary = wizard.summary().unwrap();
+        assert!((summary.monthly - 1073.64).abs() < 0.005 && summary.months == 360 && summary.interest_saved.abs() < 1e-6);
+        for code in [
+            KeyCode::Down, KeyCode::Down, KeyCode::Down, KeyCode::Char('1'), KeyCode::Char('0'), KeyCode::Char('0'),
+        ] { on_mortgage_key(&mut app, code); }
+        let Some(Overlay::Mortgage(wizard)) = &app.overlay else { panic!("the wizard closed") };
+        let summary = wizard.summary().unwrap();
+        assert!(summary.months < 360 && summary.interest_saved > 0.0);
+        on_mortgage_key(&mut app, KeyCode::Enter);
+        assert_eq!((app.display_value.as_str(), app.mode()), ("1173.64", Mode::ResultShown));
+        press(&mut app, &["*", "1", "2", "="]);
+        assert_eq!(app.display_value, "14083.68");
+    }
+    #[test]
+    fn test_statistics_mode() {
+        let summary = summarize(&[4.0, 8.0, 15.0, 16.0, 23.0, 42.0]).unwrap();
+        assert_eq!(
+            (summary.n, summary.sum, summary.mean, summary.median, summary.min, summary.max, summary.variance),
+            (6, 108.0, 18.0, 15.5, 4.0, 42.0, 182.0)
+        );
+        assert_eq!(summarize(&[]), None);
+        let mut app = App::new();
+        app.stats_mode = true;
+        press(&mut app, &["4", "=", "2", "*", "4", "=", "9", "="]);
+        assert_eq!((app.data_points.as_slice(), app.display_value.as_str()), ([(None, 4.0), (None, 8.0), (None, 9.0)].as_slice(), "0"));
+        assert!(app.history.is_empty());
+        app.data_points.pop();
+        assert_eq!(summarize(&app.data_points.iter().map(|p| p.1).collect::<Vec<_>>()).unwrap().median, 6.0);
+    }
+    #[test]
+    fn test_linear_regression() {
+        let fit = linear_fit(&[(1.0, 3.0), (2.0, 5.0), (3.0, 7.0)]).unwrap();
+        assert_eq!((fit.slope, fit.intercept, fit.r_squared), (2.0, 1.0, 1.0));
+        assert_eq!(linear_fit(&[(1.0, 2.0), (1.0, 3.0)]), None);
+        let mut app = App::new();
+        app.stats_mode = true;
+        press(&mut app, &["1", ",", "2", "=", "2", ",", "3", "*", "2", "=", "3", ",", "7", "="]);
+        assert_eq!(data_pairs(&app), vec![(1.0, 2.0), (2.0, 6.0), (3.0, 7.0)]);
+        assert_eq!(app.notice.as_deref(), Some("Added (3, 7) as pair 3"));
+        press(&mut app, &["p", "r", "e", "d", "i", "c", "t", "(", "4", ")", "="]);
+        assert_eq!(app.data_points.last(), Some(&(None, 10.0)));
+        assert!(evaluate("predict(1)").is_err());
+    }
+    #[test]
+    fn test_base_converter() {
+        let texts = |value| base_representations(value, 64).map(|rows| rows.map(|(_, text)| text));
+        assert_eq!(texts(255.0), Some(["255", "0xFF", "0b11111111", "0o377"].map(String::from)));
+        assert_eq!(texts(1e20), None);
+        assert_eq!((group_base_digits("0b101101"), group_base_digits("-1234567")), ("0b10 1101".to_string(), "-1 234 567".to_string()));
+        let mut app = App::new();
+        press(&mut app, &["4", "2"]);
+        assert_eq!(current_value(&app), Some(42.0));
+        press(&mut app, &["+", "1", "="]);
+        assert_eq!(current_value(&app), Some(43.0));
+    }
+    #[test]
+    fn test_bit_field() {
+        let mut app = App::new();
+        app.settings.word_bits = 8;
+        press(&mut app, &["5", "bit:1"]);
+        assert_eq!(app.display_value, "7");
+        press(&mut app, &["bit:7"]);
+        assert_eq!(app.display_value, "135");
+        press(&mut app, &["C", "-", "1", "=", "bit:7"]);
+        assert_eq!(app.display_value, "127");
+        press(&mut app, &["C", "3", "0", "0", "bit:0"]);
+        assert_eq!((app.display_value.as_str(), app.error_message.as_deref()), ("300", Some("Out of range for a 8-bit word")));
+        assert_eq!((bits_per_row(48, 64), bits_per_row(30, 64), bits_per_row(48, 8)), (32, 16, 8));
+    }
+    #[test]
+    fn test_code_points() {
+        assert_eq!((evaluate("code(\"€\")"), evaluate("\"A\" + 1")), (Ok(8364.0), Ok(66.0)));
+        assert_eq!(evaluate("\"ab\"").unwrap_err().message, "A character literal holds exactly one character");
+        assert_eq!(evaluate("\"a").unwrap_err().message, "Unclosed character literal");
+        assert_eq!(utf8_bytes('€'), "E2 82 AC");
+        let mut app = App::new();
+        press(&mut app, &["c", "h", "a", "r", "(", "6", "5", ")", "="]);
+        assert_eq!((app.display_value.as_str(), app.notice.as_deref()), ("\"A\"", Some("U+0041 · UTF-8 41")));
+        // The character is the label of an ordinary evaluation: the code point goes on as the value, and batch mode shows it too.
+        press(&mut app, &["+", "1", "="]);
+        assert_eq!(app.display_value, "66");
+        assert_eq!(
+            (evaluate("code(-5)").unwrap_err().message, evaluate("char(2 ^ 40)").unwrap_err().message),
+            ("Not a Unicode code point".to_string(), "Not a Unicode code point".to_string())
+        );
+        assert_eq!(crate::run_batch(&["char(64 + 1)".to_string(), "char(7)".to_string()], &app.settings), [Ok("\"A\"".to_string()), Ok("U+0007".to_string())]);
+        press(&mut app, &["C", "c", "o", "d", "e", "(", "\"", "€", "\"", ")", "="]);
+        assert_eq!(app.display_value, "8364");
+    }
+    #[test]
+    fn test_durations() {
+        let duration = |expression| evaluate_timed(expression, &EvalOptions::default()).0.map(|o| o.exact.unwrap_or_default());
+        assert_eq!(duration("1:30:15 + 0:45:50"), Ok("2:16:05".to_string()));
+        assert_eq!(
+            (duration("0:20 * 3"), duration("1:00:00 / 8"), duration("0:10 - 0:25")),
+            (Ok("1:00:00".to_string()), Ok("0:07:30".to_string()), Ok("-0:15:00".to_string()))
+        );
+        assert_eq!(duration("0:00:01.25 * 2"), Ok("0:00:02.5".to_string()));
+        assert_float_eq(evaluate("2:00 / 0:30").unwrap(), 4.0);
+        assert_eq!(evaluate("1:00 + 5").unwrap_err().message, "Cannot add s and a plain number");
+        assert_eq!(duration("1:30 + 15 min"), Ok("1:45:00".to_string()));
+        assert_eq!(evaluate("1:75").unwrap_err().message, "Invalid duration (use h:mm or h:mm:ss)");
+        let mut app = App::new();
+        press(&mut app, &["1", ":", "3", "0", "*", "3", "="]);
+        assert_eq!(app.display_value, "4:30:00");
+        press(&mut app, &["+", "0", ":", "3", "0", "="]);
+        assert_eq!(app.display_value, "5:00:00");
+    }
+    #[test]
+    fn test_unix_time() {
+        assert_eq!(unix_timestamp("2024-06-01 12:00", 0), Some(1_717_243_200));
+        assert_eq!(unix_timestamp("2024 - 6 - 1 14:00:00", 120), Some(1_717_243_200));
+        assert_eq!(
+            (unix_timestamp("1969-12-31", 0), unix_timestamp("2023-02-29", 0), unix_timestamp("2024-02-29", 0)),
+            (Some(-86_400), None, Some(1_709_164_800))
+        );
+        assert_eq!((format_timestamp(1_717_243_200, 0), format_timestamp(-1, -210)), ("2024-06-01 12:00:00".to_string(), "1969-12-31 20:29:59".to_string()));
+        assert_eq!((utc_offset_text(0), utc_offset_text(-210), utc_offset_text(330)), ("UTC".to_string(), "UTC-3:30".to_string(), "UTC+5:30".to_string()));
+        let mut app = App::new();
+        press(&mut app, &["u", "n", "i", "x", "(", "2", "0", "2", "4", "-", "0", "6", "-", "0", "1", " ", "1", "2", ":", "0", "0", ")", "="]);
+        assert_eq!(app.display_value, "1717243200");
+        press(&mut app, &["C", "d", "a", "t", "e", "(", "1", "7", "1", "7", "2", "4", "3", "2", "0", "0", "+", "6", "0", ")", "="]);
+        assert_eq!(app.display_value, "2024-06-01 12:01:00");
+        // Both are ordinary calls, so they go into larger expressions, take the time zone from the settings, and work in batch.
+        app.settings.utc_offset_minutes = 120;
+        app.display_value = "(unix(2024-06-01 14:00) - unix(2024-06-01 12:00)) / 60".to_string();
+        press(&mut app, &["="]);
+        assert_eq!((app.display_value.as_str(), app.notice.as_deref()), ("120", Some("Seconds since 1970-01-01 00:00 UTC, reading the date as UTC+2")));
+        let lines = ["unix(2024-06-01 14:00)", "date(unix(2024-06-01 14:00) + 3600)"].map(str::to_string);
+        assert_eq!(crate::run_batch(&lines, &app.settings), [Ok("1717243200".to_string()), Ok("2024-06-01 15:00:00".to_string())]);
+        assert_eq!(evaluate("unix(yesterday)").unwrap_err().message, "Expected a date like unix(2024-06-01 12:00)");
+    }
+    #[test]
+    fn test_units() {
+        let quantity = |expression| evaluate_timed(expression, &EvalOptions::default()).0.map(|o| format_outcome(
+            o.value, o.exact.as_deref(), NumberBackend::Float, &FormatOptions::default()
+        ));
+        assert_eq!(quantity("5 m / 2 s"), Ok("2.5 m/s".to_string()));
+        assert_eq!(quantity("2 km + 300 m"), Ok("2300 m".to_string()));
+        assert_eq!(quantity("3 m^2 * 2 m"), Ok("6 m^3".to_string()));
+        assert_eq!((quantity("10 kg * 9.8 m/s^2"), quantity("1 kW * 2 h")), (Ok("98 N".to_string()), Ok("7200000 J".to_string())));
+        assert_eq!((quantity("sqrt(16 m^2)"), quantity("1 / 4 s")), (Ok("4 m".to_string()), Ok("0.25 Hz".to_string())));
+        assert_eq!(quantity("6 m / 3 m"), Ok("2".to_string()));
+        assert_eq!(evaluate("5 m + 3 s").unwrap_err().message, "Cannot add m and s");
+        assert_eq!(evaluate("sin(2 m)").unwrap_err().message, "sin takes plain numbers");
+        assert_eq!(evaluate("2 m ^ 0.5").unwrap_err().message, "Units can only be raised to a whole-number power");
+        assert_eq!(quantity("2.5 m/s * 4 s"), Ok("10 m".to_string()));
+        let mut app = App::new();
+        press(&mut app, &["5", " ", "m", "/", "2", " ", "s", "="]);
+        assert_eq!(app.display_value, "2.5 m/s");
+    }
+    #[test]
+    fn test_quadratic_solver() {
+        assert_eq!(quadratic_roots(1.0, -3.0, 2.0), Ok(QuadRoots::Real(2.0, 1.0)));
+        assert_eq!(quadratic_roots(1.0, 2.0, 5.0), Ok(QuadRoots::Complex { re: -1.0, im: 2.0 }));
+        assert_eq!(quadratic_roots(0.0, 2.0, 5.0), Err("Not a quadratic (a is 0)"));
+        let QuadRoots::Real(small, _) = quadratic_roots(1.0, 1e8, 1.0).unwrap() else { panic!() };
+        assert_float_eq(small, -1e-8);
+        let mut app = App::new();
+        press(&mut app, &["q", "u", "a", "d", "(", "1", ",", "2", ",", "5", ")", "="]);
+        let roots = app.quad_roots.map(|roots| roots.lines(&app.settings.format_options()));
+        assert_eq!(roots, Some(["x₁ = -1 + 2i".to_string(), "x₂ = -1 − 2i".to_string()]));
+        press(&mut app, &["4"]);
+        assert_eq!((app.quad_roots, app.display_value.as_str()), (None, "4"));
+        // As part of an expression it is the larger real root, and batch mode prints both roots.
+        assert_eq!(evaluate("2 * quad(1, -3, 2) + 1"), Ok(5.0));
+        assert_eq!(evaluate("1 + quad(1, 2, 5)").unwrap_err().message, "The roots are complex");
+        assert_eq!(crate::run_batch(&["quad(1, -3, 2)".to_string()], &app.settings), [Ok("x₁ = 2, x₂ = 1".to_string())]);
+    }
+    #[test]
+    fn test_matrix_functions() {
+        let options = EvalOptions::default();
+        let matrix = |text: &str| match evaluate_matrix(text, 0, &options).unwrap() { MatrixValue::Matrix(m) => m.data, MatrixValue::Scalar(x) => vec![x] };
+        assert_eq!(matrix("det([1, 2; 3, 4])"), [-2.0]);
+        assert_eq!(matrix("inv([4, 7; 2, 6])"), [0.6, -0.7, -0.2, 0.4]);
+        assert_eq!(matrix("transpose([1, 2, 3; 4, 5, 6])"), [1.0, 4.0, 2.0, 5.0, 3.0, 6.0]);
+        assert_eq!(matrix("linsolve([2, 1; 1, 3], [5; 10])"), [1.0, 3.0]);
+        assert_eq!(matrix("det(inv([2, 0; 0, 4]))"), [0.125]);
+        // Whether a pivot counts as zero depends on the size of the entries, not on a fixed cut-off.
+        assert_eq!(matrix("inv([0.0000000000001, 0; 0, 0.0000000000002])"), [1e13, 0.0, 0.0, 5e12]);
+        assert_eq!(
+            evaluate_matrix(
+                "inv([100000000000000000000, 200000000000000000000; 300000000000000000000, 600000000000000100000])",
+                0, &options
+            ).unwrap_err().message,
+            "Matrix is singular"
+        );
+        assert_eq!(evaluate_matrix("inv([1, 2; 2, 4])", 0, &options).unwrap_err().message, "Matrix is singular");
+        // The determinant of this one underflows to 0, yet every pivot is as large as the entries.
+        let tiny = Matrix { rows: 2, cols: 2, data: vec![1e-200, 0.0, 0.0, 1e-200] };
+        assert_eq!((tiny.determinant(), tiny.inverse().map(|m| m.data)), (Ok(0.0), Ok(vec![1e200, 0.0, 0.0, 1e200])));
+        assert_eq!(evaluate_matrix("det([1, 2; 3])", 0, &options).unwrap_err().message, "Rows have different lengths");
+        let error = evaluate_matrix("det([1, 2; 3, x])", 0, &options).unwrap_err();
+        assert_eq!((error.describe("det([1, 2; 3, x])"), error.span), ("Unknown name in `x`".to_string(), Some(SourceSpan { start: 14, end: 15 })));
+        let mut app = App::new();
+        press(&mut app, &["i", "n", "v", "(", "[", "2", ",", "0", ";", "0", ",", "4", "]", ")", "="]);
+        assert_eq!((app.display_value.as_str(), app.matrix_result.as_ref().map(|m| m.rows)), ("[0.5, 0; 0, 0.25]", Some(2)));
+        press(&mut app, &["C", "7", "=", "t", "r", "a", "n", "s", "p", "o", "s", "e", "(", "[", "2", ",", "0", ";", "0", ",", "4", "]", ")", "="]);
+        assert_eq!((app.display_value.as_str(), app.last_result, app.matrix_result.as_ref().map(|m| m.rows)), ("[2, 0; 0, 4]", None, Some(2)));
+        app.display_value = "inv([3, 0; 0, 1])".to_string();
+        press(&mut app, &["="]);
+        assert_eq!(app.display_value, "[0.3333333333333333, 0; 0, 1]");
+        app.display_value = format!("det({})", app.display_value);
+        press(&mut app, &["="]);
+        assert_eq!(app.last_result, Some(1.0 / 3.0));
+    }
+    #[test]
+    fn test_numeric_backends() {
+        let on = |backend: NumberBackend, expression: &str| {
+            let options = EvalOptions { backend, ..EvalOptions::default() };
+            evaluate_timed(expression, &options).0.map(|o| o.exact.unwrap()).map_err(|e| e.message)
+        };
+        assert_eq!(on(NumberBackend::Decimal, "0.1 + 0.2"), Ok("0.3".to_string()));
+        assert_eq!(on(NumberBackend::Decimal, "1 / 8 - 2 ^ -3"), Ok("0".to_string()));
+        assert_eq!(on(NumberBackend::Rational, "1 / 3 + 1 / 6"), Ok("1/2".to_string()));
+        assert_eq!(on(NumberBackend::Rational, "200 + 10%"), Ok("220".to_string()));
+        assert_eq!(on(NumberBackend::BigInt, "2 ^ 100"), Ok("1267650600228229401496703205376".to_string()));
+        assert_eq!(on(NumberBackend::BigInt, "1.5 + 1"), Err("Only integers are supported in big-int mode".to_string()));
+        assert_eq!(on(NumberBackend::Rational, "1 / (3 - 3)"), Err("Division by zero".to_string()));
+        let mut app = App::new();
+        press(&mut app, &["1", "/", "3", "="]);
+        app.settings.backend = NumberBackend::Decimal;
+        cycle_backend(&mut app);
+        spawn_evaluation(&mut app);
+        while app.pending.is_some() { poll_evaluation(&mut app); }
+        assert_eq!(app.history.len(), 2);
+        assert_eq!(app.history[1].exact.as_deref(), Some("1/3"));
+        assert_eq!(app.display_value, "(1/3)");
+    }
+    #[test]
+    fn test_money_backend() {
+        let on = |expression: &str| {
+            let options = EvalOptions { backend: NumberBackend::Money, ..EvalOptions::default() };
+            evaluate_timed(expression, &options).0.map(|o| (o.exact.unwrap(), o.note)).unwrap()
+        };
+        assert_eq!(on("0.1 + 0.2"), ("0.30".to_string(), None));
+        assert_eq!(on("19.99 * 3"), ("59.97".to_string(), None));
+        assert_eq!(on("100 / 3"), ("33.33".to_string(), Some("Rounded to the cent (half-even)".to_string())));
+        assert_eq!(on("0.125 * 1"), ("0.12".to_string(), Some("Rounded to the cent (half-even)".to_string())));
+        assert_eq!(on("0.375 * 1").0, "0.38");
+        let mut app = App::new();
+        app.settings.backend = NumberBackend::Money;
+        app.settings.currency = "€";
+        press(&mut app, &["1", "0", "-", "2", "5", ".", "5", "="]);
+        assert_eq!(app.display_value, "-15.50");
+        assert_eq!(format_display(app.last_result.unwrap(), app.last_exact.as_deref(), NumberBackend::Money, &app.settings.format_options()), "-€15.50");
+        assert_eq!(app.notice, None);
+    }
+    #[test]
+    fn test_currency_symbols() {
+        let on = |backend, expression: &str| evaluate_timed(
+            expression, &EvalOptions { backend, ..EvalOptions::default() }
+        ).0.map(|o| o.exact.unwrap_or_else(|| o.value.to_string()));
+        assert_eq!(on(NumberBackend::Float, "$1500 * 1.08"), Ok("1620".to_string()));
+        assert_eq!(on(NumberBackend::Money, "$1500 * 1.08"), Ok("$1620.00".to_string()));
+        assert_eq!(on(NumberBackend::Money, "(€25 + €3.50) * 2 - €60"), Ok("-€3.00".to_string()));
+        assert_eq!(on(NumberBackend::Money, "£10 / £4"), Ok("2.50".to_string()));
+        assert_eq!(on(NumberBackend::Money, "€25 + $3").unwrap_err().message, "Cannot mix € and $ amounts without a conversion");
+        assert_eq!(on(NumberBackend::Float, "€25 + $3"), Ok("28".to_string()));
+        // The suggested conversion is what makes them go together; `fx` gives an amount in the currency it converts to.
+        assert_eq!(on(NumberBackend::Money, "€25 + fx(3 USD, EUR)"), Ok("€27.78".to_string()));
+        let suffixed = EvalOptions { backend: NumberBackend::Money, si_suffixes: true, ..EvalOptions::default() };
+        assert_eq!(evaluate_timed("$4.7k + fx(€100 EUR, USD)", &suffixed).0.map(|o| o.exact), Ok(Some("$4808.00".to_string())));
+        assert_eq!(on(NumberBackend::Money, "fx(€100 USD, EUR)").unwrap_err().message, "Cannot convert a € amount from USD");
+        let mut app = App::new();
+        (app.settings.backend, app.settings.currency, app.settings.group_separator) = (NumberBackend::Money, "$", Some(','));
+        press(&mut app, &["€", "2", "5", "0", "0", "+", "€", "3", ".", "5", "="]);
+        assert_eq!(
+            (
+                app.display_value.as_str(),
+                format_display(app.last_result.unwrap(), app.last_exact.as_deref(), NumberBackend::Money, &app.settings.format_options())
+            ),
+            ("€2503.50", "€2,503.50".to_string())
+        );
+        press(&mut app, &["*", "2", "="]);
+        assert_eq!(app.display_value, "€5007.00");
+    }
+    #[test]
+    fn test_error_keeps_expression() {
+        let mut app = App::new();
+        press(&mut app, &["1", "0", "/", "0", "="]);
+        assert_eq!(app.display_value, "10 / 0");
+        assert_eq!(app.error_message.as_deref(), Some("Division by zero"));
+        assert_eq!(app.error_span, Some(SourceSpan { start: 0, end: 6 }));
+        on_click(&mut app, "5");
+        assert_eq!(app.display_value, "10 / 5");
+        assert!(app.error_message.is_none());
+    }
+    #[test]
+    fn test_evaluation_runs_in_background() {
+        let mut app = App::new();
+        press(&mut app, &["6", "*", "7"]);
+        on_click(&mut app, "=");
+        assert!(app.pending.is_some());
+        on_click(&mut app, "5");
+        assert_eq!((app.display_value.as_str(), app.effects.as_slice()), ("6 * 7", [Effect::Evaluate].as_slice()));
+        spawn_evaluation(&mut app);
+        while app.pending.is_some() { poll_evaluation(&mut app); }
+        assert_eq!(app.display_value, "42");
+    }
+    #[test]
+    fn test_cancellation() {
+        let cancel = Arc::new(AtomicBool::new(true));
+        let options = EvalOptions { cancel: Some(cancel), ..EvalOptions::default() };
+        assert_eq!(evaluate_with("1 + 2", &options).unwrap_err().message, "Calculation cancelled");
+        let mut app = App::new();
+        press(&mut app, &["1", "+", "2"]);
+        on_click(&mut app, "=");
+        cancel_evaluation(&mut app);
+        assert!(app.pending.is_none());
+        assert_eq!(app.display_value, "1 + 2");
+        assert_ne!(app.mode(), Mode::ResultShown);
+    }
+    #[test]
+    fn test_setting_rows() {
+        let rows = [
+            (Settings::DECIMALS, "Decimal places"), (Settings::TAX_RATE, "Tax rate"),
+            (Settings::WORD_SIZE, "Word size"), (Settings::SCREENSAVER, "Screensaver"),
+            (Settings::CURSOR, "Cursor"), (Settings::ANGLES, "Angles"),
+        ];
+        assert!(rows.iter().all(|&(row, label)| SETTING_LABELS[row] == label));
+        assert_eq!(Settings::ANGLES, SETTING_LABELS.len() - 1);
+    }
+    #[test]
+    fn test_time_limit() {
+        let expired = EvalOptions { deadline: Some(Instant::now()), ..EvalOptions::default() };
+        assert_eq!(evaluate_with("1 + 2", &expired).unwrap_err().message, "Evaluation timed out");
+        let mut settings = Settings::default();
+        assert_eq!(settings.value_text(Settings::TIME_LIMIT), "5 s");
+        settings.adjust(Settings::TIME_LIMIT, false);
+        assert_eq!(settings.timeout_secs, 4);
+        let options = settings.eval_options();
+        assert!(options.deadline.is_some_and(|deadline| deadline <= Instant::now() + Duration::from_secs(4)));
+        assert_eq!(evaluate_with("1 + 2", &options), Ok(3.0));
+    }
+    #[test]
+    fn test_auto_close_parentheses() {
+        assert_eq!(unclosed_parens("((1 + 2) * (3"), 2);
+        assert_eq!(unclosed_parens("1) + (2"), 1);
+        let mut app = App::new();
+        press(&mut app, &["(", "(", "1", "+", "2", ")", "*", "3", "="]);
+        assert_eq!(app.display_value, "9");
+        assert_eq!(app.last_expression.as_deref(), Some("((1 + 2) * 3)"));
+        assert_eq!(app.notice.as_deref(), Some("Added missing `)`"));
+    }
+    #[test]
+    fn test_operator_replacement() {
+        let mut app = App::new();
+        press(&mut app, &["5", "+", "*", "3"]);
+        assert_eq!(app.display_value, "5 * 3");
+        press(&mut app, &["/", "-", "2"]);
+        assert_eq!(app.display_value, "5 * 3 / -2");
+        press(&mut app, &["^", "-", "+"]);
+        assert_eq!(app.display_value, "5 * 3 / -2 + ");
+        press(&mut app, &["(", "-", "1", "="]);
+        assert_eq!(app.last_expression.as_deref(), Some("5 * 3 / -2 + (-1)"));
+        let mut app = App::new();
+        press(&mut app, &["(", "*"]);
+        assert_eq!(app.display_value, "(");
+    }
+    #[test]
+    fn test_input_validation() {
+        let mut app = App::new();
+        press(&mut app, &["0", "0", "7", "+", "0", "0", "."]);
+        assert_eq!(app.display_value, "7 + 0.");
+        assert_eq!(validate_input("5 + 0", '7', false), InputAction::Replace(4));
+        assert_eq!(validate_input("1.5", '.', false), InputAction::Reject);
+        assert_eq!(validate_input("(", ')', false), InputAction::Reject);
+        assert_eq!(validate_input("(1)", '.', false), InputAction::Reject);
+        assert_eq!(validate_input("(1)", '2', false), InputAction::Reject);
+        assert_eq!(validate_input("1", ')', false), InputAction::Reject);
+        assert_eq!(validate_input("(1", ')', false), InputAction::Append);
+        assert_eq!(validate_input("3", '(', false), InputAction::Reject);
+        assert_eq!(validate_input("sqrt", '(', false), InputAction::Append);
+        assert_eq!(validate_input("5 + ", '%', false), InputAction::Reject);
+        assert_eq!(validate_input("(1)", '%', false), InputAction::Append);
+    }
+    #[test]
+    fn test_continue_from_result() {
+        let mut app = App::new();
+        press(&mut app, &["2", "*", "4", "=", "*"]);
+        assert_eq!(app.display_value, "8 * ");
+        assert_ne!(app.mode(), Mode::ResultShown);
+        press(&mut app, &["2", "=", "-", "1", "="]);
+        assert_eq!(app.display_value, "15");
+        press(&mut app, &["+/-"]);
+        assert_eq!(app.display_value, "-15");
+        assert_ne!(app.mode(), Mode::ResultShown);
+        press(&mut app, &["=", "."]);
+        assert_eq!(app.display_value, "0.");
+    }
+    #[test]
+    fn test_implicit_ans() {
+        let mut app = App::new();
+        press(&mut app, &["+", "5"]);
+        assert_eq!(app.display_value, "0 + 5");
+        press(&mut app, &["C", "2", "/", "3", "="]);
+        on_backspace(&mut app);
+        press(&mut app, &["*", "3", "="]);
+        assert_eq!(app.last_expression.as_deref(), Some("ans * 3"));
+        assert_float_eq(app.last_result.unwrap(), 2.0);
+    }
+    #[test]
+    fn test_chain_full_precision() {
+        let mut app = App::new();
+        press(&mut app, &["2", "/", "3", "="]);
+        assert_eq!(app.display_value, "0.66666667");
+        press(&mut app, &["*", "3", "="]);
+        assert_eq!((app.last_result, app.display_value.as_str()), (Some(2.0), "2"));
+        app.settings.backend = NumberBackend::Decimal;
+        press(&mut app, &["C", "1", "/", "3", "=", "*", "3", "="]);
+        assert_eq!((app.last_exact.as_deref(), app.display_value.as_str()), (Some("0.99999999999999999999999999999999999999999999999999"), "1"));
+        app.settings.backend = NumberBackend::Rational;
+        press(&mut app, &["C", "2", "/", "3", "=", "*", "3", "="]);
+        assert_eq!((app.last_expression.as_deref(), app.last_exact.as_deref()), (Some("(2/3) * 3"), Some("2")));
+    }
+    #[test]
+    fn test_history_references() {
+        let mut app = App::new();
+        app.settings.usage_stats = true;
+        for expression in ["2 + 2", "10", "3 * 3"] { app.display_value = expression.to_string(); press(&mut app, &["="]); }
+        press(&mut app, &["(", "@", "1", "+", "@", "3", ")", "/", "2", "="]);
+        assert_eq!((app.last_expression.as_deref(), app.last_result), (Some("(@1 + @3) / 2"), Some(6.5)));
+        app.display_value = "ans2 * ans4".to_string();
+        press(&mut app, &["="]);
+        assert_eq!(app.last_result, Some(65.0));
+        assert_eq!(app.history.iter().map(|entry| entry.reused).collect::<Vec<_>>(), [1, 1, 1, 1, 0]);
+        app.display_value = "@9".to_string();
+        press(&mut app, &["="]);
+        assert!(app.error_message.is_some() && validate_input("2 ", '@', false) == InputAction::Reject);
+        assert_eq!((history_reference("ans12"), history_reference("ans"), history_reference("ans01")), (Some(12), None, None));
+        assert_eq!(app.history.iter().map(|entry| entry.expression.as_str()).collect::<Vec<_>>(), ["2 + 2", "10", "3 * 3", "(4 + 9) / 2", "10 * 6.5"]);
+        app.history.remove(0);
+        app.display_value = "@1 - @3".to_string();
+        press(&mut app, &["="]);
+        assert_eq!((app.last_result, app.history[4].expression.as_str()), (Some(3.5), "10 - 6.5"));
+        app.history[0].value = -0.5;
+        assert_eq!(resolve_history_references("-@1 * @9", &app.history, false), "-(-0.5) * @9");
+    }
+    #[test]
+    fn test_near_integers() {
+        let mut app = App::new();
+        press(
+            &mut app,
+            &["i", "s", "_", "i", "n", "t", "(", "4", ")", "+", "a", "p", "p", "r", "o", "x", "(", "1", ",", "1", ".", "0", "5", ",", "0", ".", "1", ")", "="]
+        );
+        assert_eq!((app.last_expression.as_deref(), app.last_result), (Some("is_int(4) + approx(1, 1.05, 0.1)"), Some(2.0)));
+        for (expression, value) in [("is_int(2.5)", 0.0), ("approx(2 m, 205 cm, 0.1 m)", 1.0), ("approx(1, 2, 0.5)", 0.0)] {
+            app.display_value = expression.to_string();
+            press(&mut app, &["="]);
+            assert_eq!(app.last_result, Some(value), "{}", expression);
+        }
+        app.display_value = "approx(1, 1, -1)".to_string();
+        press(&mut app, &["="]);
+        assert!(app.error_message.as_deref().is_some_and(|message| message.contains("Tolerance cannot be negative")));
+        assert_eq!((validate_input("2", '_', false), validate_input("is ", '_', false)), (InputAction::Reject, InputAction::Reject));
+        app.settings.decimals = 12;
+        assert_eq!(
+            (format_result(2.9999999996, &app.settings.format_options()), format_result(-1e-10, &app.settings.format_options())),
+            ("2.9999999996".to_string(), "-0.0000000001".to_string())
+        );
+        app.settings.adjust(Settings::NEAR_INTEGERS, true);
+        assert_eq!(
+            (
+                format_result(2.9999999996, &app.settings.format_options()),
+                format_result(-1e-10, &app.settings.format_options()),
+                format_result(2.5, &app.settings.format_options())
+            ),
+            ("3".to_string(), "0".to_string(), "2.5".to_string())
+        );
+        assert_eq!(app.settings.value_text(Settings::NEAR_INTEGERS), "Show as integers");
+    }
+    #[test]
+    fn test_si_suffixes() {
+        // Off, a letter right after the digits is a unit as much as one after a space.
+        let mut app = App::new();
+        app.display_value = "5m + 5 m".to_string();
+        press(&mut app, &["="]);
+        assert_eq!(app.display_value, "10 m");
+        assert_eq!((validate_input("4.7", 'k', false), validate_input("4.7", 'k', true)), (InputAction::Reject, InputAction::Append));
+        app.settings.adjust(Settings::ENGINEERING, true);
+        press(&mut app, &["1", "/", "(", "2", "*", "p", "i", "*", "4", ".", "7", "k", "*", "1", "0", "0", "n", ")", "="]);
+        assert_eq!(app.last_expression.as_deref(), Some("1 / (2 * pi * 4.7k * 100n)"));
+        assert_float_eq(app.last_result.unwrap(), 1.0 / (2.0 * std::f64::consts::PI * 4700.0 * 1e-7));
+        for (expression, value) in [("4.7k", 4700.0), ("3.3M + 10u", 3_300_000.000_01), ("2G / 5m", 4e11), ("5 m + 5mm", 5.005), ("2min", 120.0)] {
+            app.display_value = expression.to_string();
+            press(&mut app, &["="]);
+            assert_eq!(app.last_result, Some(value), "{}", expression);
+        }
+        assert_eq!(
+            (validate_input("4.7k", '5', true), validate_input("4.7k", 'k', true), validate_input("$5", 'k', true), validate_input("5 ", 'k', true)),
+            (InputAction::Reject, InputAction::Reject, InputAction::Reject, InputAction::Append)
+        );
+        app.settings.decimals = 3;
+        let shown: Vec<String> = [4700.0, 338.627, 1e-7, -0.0025, 999_999.9, 1e13].iter()
+            .map(|&n| format_display(n, None, NumberBackend::Float, &app.settings.format_options())).collect();
+        assert_eq!(shown, ["4.7k", "338.627", "100n", "-2.5m", "1M", "10000000000000"]);
+    }
+    #[test]
+    fn test_stage_timings_recorded() {
+        let (result, timings) = evaluate_timed("2 + 2", &EvalOptions::default());
+        assert_eq!(result.map(|o| o.value), Ok(4.0));
+        assert_eq!(timings.total(), timings.tokenize + timings.parse + timings.evaluate);
+        let mut app = App::new();
+        press(&mut app, &["1", "+", "1", "=", "*", "3", "="]);
+        assert_eq!(app.history.len(), 2);
+        assert!(app.last_timings.is_some());
+    }
+    #[test]
+    fn test_expression_kept_with_result() {
+        let mut app = App::new();
+        press(&mut app, &["2", "*", "3", "="]);
+        assert_eq!(app.display_value, "6");
+        assert_eq!(app.last_expression.as_deref(), Some("2 * 3"));
+    }
+    #[test]
+    fn test_decimals_rerender_without_recompute() {
+        let mut app = App::new();
+        press(&mut app, &["2", "/", "3", "="]);
+        assert_eq!(app.display_value, "0.66666667");
+        for _ in 0..6 { change_decimals(&mut app, false); }
+        assert_eq!(app.display_value, "0.67");
+        assert_eq!(format_display(app.history[0].value, None, NumberBackend::Float, &app.settings.format_options()), "0.67");
+        for _ in 0..20 { change_decimals(&mut app, true); }
+        assert_eq!(app.settings.decimals, 15);
+    }
+    #[test]
+    fn test_length_guard() {
+        let mut app = App::new();
+        app.settings.max_length = 32;
+        for _ in 0..40 { on_click(&mut app, "9"); }
+        assert_eq!(app.display_value.len(), 32);
+        assert!(app.error_message.is_some());
+        on_backspace(&mut app);
+        assert!(app.error_message.is_none());
+    }
+    #[test]
+    fn test_digit_grouping() {
+        assert_eq!(group_digits("1234567.89", ','), "1,234,567.89");
+        assert_eq!(group_digits("-123456", ' '), "-123 456");
+        assert_eq!(group_digits("999", ','), "999");
+        assert_eq!(group_digits("inf", ','), "inf");
+        let mut app = App::new();
+        app.settings.group_separator = Some(',');
+        press(&mut app, &["1", "0", "0", "0", "*", "1", "0", "0", "0", "="]);
+        assert_eq!(app.display_value, "1000000");
+        assert_eq!(format_display(app.last_result.unwrap(), None, NumberBackend::Float, &app.settings.format_options()), "1,000,000");
+    }
+    #[test]
+    fn test_twos_complement() {
+        let texts = |value, bits| base_representations(value, bits).map(|rows| rows.map(|(_, text)| text));
+        assert_eq!(texts(-10.7, 8), Some(["-10", "0xF6", "0b11110110", "0o366"].map(String::from)));
+        assert_eq!(texts(-1.0, 16).unwrap()[1], "0xFFFF");
+        assert_eq!((texts(-129.0, 8), texts(256.0, 8)), (None, None));
+        let bits8 = EvalOptions { word_bits: 8, ..EvalOptions::default() };
+        let eval8 = |expression| evaluate_timed(expression, &bits8).0.map(|o| o.value);
+        assert_eq!((eval8("shl(1, 7)"), eval8("not(0)"), eval8("shr(shl(1, 7), 2)")), (Ok(-128.0), Ok(-1.0), Ok(-32.0)));
+        assert_eq!((eval8("and(255, 15)"), eval8("xor(127, -1)"), eval8("or(200, 1)")), (Ok(15.0), Ok(-128.0), Ok(-55.0)));
+        assert_eq!(eval8("shl(1, 8)").unwrap_err().message, "Shift amount is outside the word size");
+        assert_eq!(evaluate("and(1.5, 1)").unwrap_err().message, "Bitwise operations need integers");
+        assert_float_eq(evaluate("shl(1, 40)").unwrap(), 2f64.powi(40));
+    }
+    #[test]
+    fn test_osc52_sequence() {
+        assert_eq!(base64(b"154.88"), "MTU0Ljg4");
+        assert_eq!(base64(b"ab"), "YWI=");
+        assert_eq!(base64(b"a"), "YQ==");
+        assert_eq!(osc52_sequence("42"), "\x1b]52;c;NDI=\x07");
+    }
+    #[test]
+    fn test_modes() {
+        let mut app = App::new();
+        press(&mut app, &["1", "/", "0", "="]);
+        assert_eq!((app.mode(), app.focus()), (Mode::ErrorShown, Focus::Display(Mode::ErrorShown)));
+        // After an error the expression stays and is corrected in place.
+        on_backspace(&mut app);
+        press(&mut app, &["4"]);
+        assert_eq!((app.mode(), app.display_value.as_str(), app.error_message.as_deref()), (Mode::Editing, "1 / 4", None));
+        press(&mut app, &["="]);
+        assert_eq!(app.mode(), Mode::ResultShown);
+        press(&mut app, &["7"]);
+        assert_eq!((app.mode(), app.display_value.as_str()), (Mode::Editing, "7"));
+        app.overlay = Some(Overlay::Settings);
+        assert_eq!(app.focus(), Focus::Settings);
+        on_settings_key(&mut app, KeyCode::Esc);
+        on_key(&mut app, KeyCode::Char('p'), KeyModifiers::CONTROL);
+        assert_eq!(app.focus(), Focus::Palette);
+        for c in "most used".chars() { on_palette_key(&mut app, KeyCode::Char(c)); }
+        on_palette_key(&mut app, KeyCode::Enter);
+        assert_eq!(app.focus(), Focus::Usage);
+        on_usage_key(&mut app, KeyCode::Esc);
+        assert_eq!(app.focus(), Focus::Display(Mode::Editing));
+    }
+    #[test]
+    fn test_currency_conversion() {
+        let mut app = App::new();
+        let keys: Vec<String> = "fx(2*54 usd, EUR)".chars().map(String::from).collect();
+        press(&mut app, &keys.iter().map(String::as_str).chain(["="]).collect::<Vec<_>>());
+        assert_eq!((app.display_value.as_str(), app.notice.as_deref()), ("100", Some("Built-in rates from mid-2024")));
+        app.display_value = "fx(1 EUR, XYZ)".to_string();
+        press(&mut app, &["="]);
+        assert_eq!(app.error_message.as_deref(), Some("No exchange rate for XYZ"));
+        // A conversion is an ordinary call: it goes into a larger expression, and an ill-formed one is a syntax error.
+        app.display_value = "2 * fx(27 USD, EUR) + 1".to_string();
+        press(&mut app, &["="]);
+        assert_eq!((app.display_value.as_str(), app.notice.as_deref()), ("51", Some("Built-in rates from mid-2024")));
+        assert_eq!(evaluate("fx(1 USD EUR)").unwrap_err().message, "Expected a conversion like fx(100 USD, EUR)");
+        assert_eq!(evaluate("fx(1 USD, EUR").unwrap_err().message, "Missing closing parenthesis");
+        assert_eq!(crate::run_batch(&["fx(108 USD, EUR) / 4".to_string()], &app.settings), [Ok("25".to_string())]);
+        refresh_exchange_rates(&mut app, true);
+        assert!(app.error_message.as_deref().is_some_and(|message| message.contains("rates_url")) && app.effects.is_empty());
+        app.config.rates_url = Some("https://rates.example/latest".to_string());
+        refresh_exchange_rates(&mut app, false);
+        assert_eq!(app.effects, [Effect::FetchRates { url: "https://rates.example/latest".to_string(), ttl_hours: 12, force: false }]);
+    }
+    #[test]
+    fn test_usage_stats() {
+        let mut app = App::new();
+        press(&mut app, &["2", "+", "3", "="]);
+        assert!(app.usage.is_empty());
+        app.settings.adjust(Settings::USAGE_STATS, true);
+        assert_eq!(app.settings.value_text(Settings::USAGE_STATS), "On, kept locally");
+        app.display_value = "sqrt(16) + 2 * pi".to_string();
+        press(&mut app, &["="]);
+        for _ in 0..3 { app.display_value = "ans * 2".to_string(); press(&mut app, &["="]); }
+        assert_eq!(most_used(&app, 3), [("*", 4), ("ans", 3), ("+", 1)]);
+        assert_eq!(app.usage.get("sqrt()"), Some(&1));
+        // Each `ans` reuses the result before it, so none is reused three times yet; Insert from the menu is a reuse too.
+        assert!(usage_suggestions(&app).is_empty());
+        for _ in 0..3 { run_history_menu(&mut app, 1, 2); }
+        assert_eq!(usage_suggestions(&app), ["Pin sqrt(16) + 2 * pi = 10.28318531? Reused 4 times (Alt+H, select it, p)"]);
+        assert_eq!(parse_usage(&usage_text(&app.usage)), app.usage);
+        assert_eq!(parse_usage("# usage\nln() = 4\nbad line\n* = x"), BTreeMap::from([("ln()".to_string(), 4)]));
+    }
+}
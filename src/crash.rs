@@ -0,0 +1,103 @@
+//! Crash reports: on a panic or a fatal error the terminal is restored and the app's state is written to a file.
+//! The state is only read once the event loop has stopped, so nothing is kept up to date while the app runs.
+
+use crate::{app::*, engine::format_outcome, input::{disable_enhanced_keys, terminal_output}, rates::now_secs};
+use crossterm::{cursor::{SetCursorStyle, Show}, event::DisableMouseCapture, execute, terminal::{disable_raw_mode, LeaveAlternateScreen}};
+use std::{backtrace::Backtrace, io, path::{Path, PathBuf}, sync::Mutex};
+
+/// How many of the newest history entries a report includes.
+const REPORT_HISTORY: usize = 10;
+
+/// The message and backtrace of a panic on the main thread, kept by the hook until `main` writes the report.
+static PANIC: Mutex<Option<(String, String)>> = Mutex::new(None);
+
+/// The parts of the app's state that help reproduce a bug: the expression, the modes that change key handling,
+/// and the newest history entries.
+pub(crate) fn state_dump(app: &App) -> String {
+    let modes = [
+        ("rpn", app.rpn_mode), ("stats", app.stats_mode), ("finance keypad", app.finance_keypad), ("base panel", app.base_panel),
+        ("tutor", app.tutor_mode), ("practice", app.quiz.is_some()), ("presenting", app.presenting), ("2nd", app.second),
+        ("evaluating", app.pending.is_some()),
+    ];
+    let modes: Vec<&str> = modes.iter().filter(|(_, on)| *on).map(|(name, _)| *name).collect();
+    let mut text = format!("Expression: {}\nMode: {:?}\nFocus: {:?}\nActive: {}\nBackend: {}\nProfile: {}\n", app.display_value, app.mode(), app.focus(),
+        if modes.is_empty() { "-".to_string() } else { modes.join(", ") }, app.settings.backend.name(), app.profile);
+    if let Some(message) = &app.error_message { text.push_str(&format!("Error: {}\n", message)); }
+    text.push_str(&format!("History ({} entries, newest last):\n", app.history.len()));
+    for entry in app.history.iter().skip(app.history.len().saturating_sub(REPORT_HISTORY)) {
+        text.push_str(&format!(
+            "  {} = {}\n", entry.expression,
+            format_outcome(entry.value, entry.exact.as_deref(), entry.backend, &app.settings.format_options())
+        ));
+    }
+    text
+}
+
+/// The text of a crash report.
+pub(crate) fn report_text(reason: &str, state: &str, backtrace: &str) -> String {
+    format!("calc {} crash report\n\nReason: {}\n\n{}\nBacktrace:\n{}\n", env!("CARGO_PKG_VERSION"), reason, state, backtrace)
+}
+
+/// Writes a report to `dir` under a name with the time in it, and returns its path.
+pub(crate) fn write_report_in(dir: &Path, text: &str) -> io::Result<PathBuf> {
+    std::fs::create_dir_all(dir)?;
+    let path = dir.join(format!("crash-{}.txt", now_secs()));
+    std::fs::write(&path, text)?;
+    Ok(path)
+}
+
+/// Writes a report to the data directory, or the temporary one without it, and says where on stderr.
+pub(crate) fn write_report(reason: &str, state: &str, backtrace: &str) {
+    let text = report_text(reason, state, backtrace);
+    let dir = dirs::data_dir().map_or_else(std::env::temp_dir, |dir| dir.join("rust-calculator-tui"));
+    match write_report_in(&dir, &text) {
+        Ok(path) => eprintln!("calc crashed: {}\nA crash report with the app's state was written to {}", reason, path.display()),
+        Err(e) => eprintln!("calc crashed: {}\nThe crash report could not be written ({}):\n\n{}", reason, e, text),
+    }
+}
+
+/// Writes the report of the panic the hook kept, with the state `app` was left in when the event loop unwound.
+pub(crate) fn report_panic(app: &App) {
+    let (reason, backtrace) = PANIC.lock().ok().and_then(|mut panic| panic.take()).unwrap_or_else(|| ("panic".to_string(), String::new()));
+    write_report(&reason, &state_dump(app), &backtrace);
+}
+
+/// Puts the terminal back the way it was found, ignoring failures since this runs on the way out.
+pub(crate) fn restore_terminal() {
+    disable_enhanced_keys();
+    let _ = disable_raw_mode();
+    let _ = execute!(terminal_output(), LeaveAlternateScreen, DisableMouseCapture, Show, SetCursorStyle::DefaultUserShape);
+}
+
+/// On a panic on the main thread, which runs the event loop, restores the terminal first so the message is readable
+/// and keeps the message and backtrace for `report_panic`. Panics on other threads go to the hook installed before.
+pub(crate) fn install_panic_hook() {
+    let main = std::thread::current().id();
+    let previous = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        if std::thread::current().id() != main { return previous(info); }
+        restore_terminal();
+        if let Ok(mut panic) = PANIC.lock() { *panic = Some((info.to_string(), Backtrace::force_capture().to_string())); }
+    }));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_crash_report() {
+        let mut app = App::new();
+        for i in 1..=12 { app.display_value = format!("{} * 2", i); press(&mut app, &["="]); }
+        app.edit("sqrt(");
+        app.rpn_mode = true;
+        let state = state_dump(&app);
+        assert!(state.starts_with("Expression: sqrt(\nMode: Editing\nFocus: Display(Editing)\nActive: rpn\nBackend: f64\n"));
+        assert!(state.contains("History (12 entries, newest last):\n  3 * 2 = 6\n") && state.ends_with("  12 * 2 = 24\n") && !state.contains(" 2 * 2 "));
+        let text = report_text("index out of bounds", &state, "0: main");
+        assert!(text.contains("Reason: index out of bounds\n\nExpression: sqrt(") && text.ends_with("Backtrace:\n0: main\n"));
+        let dir = std::env::temp_dir().join(format!("calc-crash-{}", std::process::id()));
+        let path = write_report_in(&dir, &text).unwrap();
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), text);
+        let _ = std::fs::remove_dir_all(dir);
+    }
+}
@@ -0,0 +1,50 @@
+//! Calendar dates and Unix timestamps, in the proleptic Gregorian calendar.
+
+/// Days since 1970-01-01 of a proleptic Gregorian date.
+pub(crate) fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month as i64 + 9) % 12) + 2) / 5 + day as i64 - 1;
+    era * 146_097 + year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year - 719_468
+}
+
+/// The date `days` after 1970-01-01.
+pub(crate) fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let day_of_era = z - era * 146_097;
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let (day, month) = ((day_of_year - (153 * mp + 2) / 5 + 1) as u32, if mp < 10 { mp + 3 } else { mp - 9 } as u32);
+    (year_of_era + era * 400 + i64::from(month <= 2), month, day)
+}
+
+/// Reads `yyyy-mm-dd [hh:mm[:ss]]` as seconds since the epoch, `offset_minutes` east of UTC.
+/// Any non-digits separate the fields, so the spaces the keypad puts around `-` do no harm.
+pub(crate) fn unix_timestamp(text: &str, offset_minutes: i32) -> Option<i64> {
+    let fields: Vec<u32> = text.split(|c: char| !c.is_ascii_digit()).filter(|f| !f.is_empty()).map(|f| f.parse().ok()).collect::<Option<_>>()?;
+    let [year, month, day, time @ ..] = fields.as_slice() else { return None };
+    let (year, month, day) = (*year, *month, *day);
+    let [hour, minute, second] = match time { [] => [0, 0, 0], [h, m] => [*h, *m, 0], [h, m, s] => [*h, *m, *s], _ => return None };
+    if !(1..=12).contains(&month) { return None; }
+    let days_in_month = if month == 12 { 31 } else { civil_from_days(days_from_civil(year as i64, month + 1, 1) - 1).2 };
+    if !(1..=days_in_month).contains(&day) || hour > 23 || minute > 59 || second > 59 { return None; }
+    let seconds = days_from_civil(year as i64, month, day) * 86_400 + (hour * 3600 + minute * 60 + second) as i64;
+    Some(seconds - offset_minutes as i64 * 60)
+}
+
+/// Formats an offset east of UTC as `UTC`, `UTC+2` or `UTC-3:30`.
+pub(crate) fn utc_offset_text(offset_minutes: i32) -> String {
+    let (sign, minutes) = (if offset_minutes < 0 { '-' } else { '+' }, offset_minutes.abs());
+    match (minutes / 60, minutes % 60) { (0, 0) => "UTC".to_string(), (h, 0) => format!("UTC{}{}", sign, h), (h, m) => format!("UTC{}{}:{:02}", sign, h, m) }
+}
+
+/// Formats a Unix timestamp as `yyyy-mm-dd hh:mm:ss` in the given offset.
+pub(crate) fn format_timestamp(timestamp: i64, offset_minutes: i32) -> String {
+    let local = timestamp + offset_minutes as i64 * 60;
+    let (year, month, day) = civil_from_days(local.div_euclid(86_400));
+    let seconds = local.rem_euclid(86_400);
+    format!("{}-{:02}-{:02} {:02}:{:02}:{:02}", year, month, day, seconds / 3600, seconds / 60 % 60, seconds % 60)
+}
@@ -0,0 +1,1701 @@
+//! Evaluating syntax trees on the numeric backends, and formatting the results.
+
+use crate::{dates::*, parser::*, perf::allocations, rates::{now_secs, Rates}, units::*};
+use num_bigint::BigInt;
+use num_rational::BigRational;
+use num_traits::{Pow, Signed, ToPrimitive, Zero};
+use std::{collections::HashMap, sync::{atomic::{AtomicBool, Ordering}, Arc, LazyLock, Mutex}, time::{Duration, Instant}};
+
+// --- Evaluation Section ---
+
+/// Options that change how an expression is evaluated.
+/// Setting the `cancel` flag or passing the `deadline` makes a running evaluation stop at the next node it visits.
+/// `variables` holds named values such as `ans`, looked up before the built-in constants; `rates` are what `fx` converts with,
+/// and `unix` and `date` read and show dates `utc_offset_minutes` east of UTC.
+#[derive(Clone)]
+pub(crate) struct EvalOptions {
+    pub(crate) saturate: bool, pub(crate) max_depth: usize, pub(crate) cancel: Option<Arc<AtomicBool>>, pub(crate) deadline: Option<Instant>,
+    pub(crate) variables: HashMap<String, f64>, pub(crate) backend: NumberBackend, pub(crate) word_bits: u32, pub(crate) rates: Arc<Rates>,
+    pub(crate) utc_offset_minutes: i32, pub(crate) si_suffixes: bool, pub(crate) degrees: bool,
+}
+
+impl Default for EvalOptions {
+    fn default() -> Self {
+        EvalOptions {
+            saturate: false, max_depth: 64, cancel: None, deadline: None, variables: HashMap::new(),
+            backend: NumberBackend::Float, word_bits: 64, rates: Arc::new(Rates::builtin()), utc_offset_minutes: 0,
+            si_suffixes: false, degrees: false,
+        }
+    }
+}
+
+impl EvalOptions {
+    /// Fails once the evaluation has been cancelled or has run out of time.
+    pub(crate) fn check_interrupted(&self) -> Result<(), EvalError> {
+        let interrupted = |message: &str| Err(EvalError { message: message.to_string(), span: None });
+        match (&self.cancel, self.deadline) {
+            (Some(flag), _) if flag.load(Ordering::Relaxed) => interrupted("Calculation cancelled"),
+            (_, Some(deadline)) if Instant::now() >= deadline => interrupted("Evaluation timed out"),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Checks a computed number: NaN is always an error, infinity only when not saturating.
+pub(crate) fn check_finite(result: f64, options: &EvalOptions) -> Result<f64, &'static str> {
+    if result.is_nan() { Err("Undefined result") }
+    else if result.is_infinite() && !options.saturate { Err("Result too large") }
+    else { Ok(result) }
+}
+
+/// Applies a binary operator to two numbers.
+pub(crate) fn apply_op(op: char, a: f64, b: f64) -> Result<f64, &'static str> {
+    match op {
+        '+' => Ok(a + b),
+        '-' => Ok(a - b),
+        '*' => Ok(a * b),
+        '/' => if b == 0.0 { Err("Division by zero") } else { Ok(a / b) },
+        '^' => Ok(a.powf(b)),
+        _ => Err("Unknown operator"),
+    }
+}
+
+/// Sums with Neumaier's compensation, so small terms aren't lost next to large ones.
+pub(crate) fn compensated_sum(values: &[f64]) -> f64 {
+    let (mut sum, mut compensation) = (0.0_f64, 0.0_f64);
+    for &x in values {
+        let t = sum + x;
+        compensation += if sum.abs() >= x.abs() { (sum - t) + x } else { (x - t) + sum };
+        sum = t;
+    }
+    sum + compensation
+}
+
+/// Mean and sum of squared deviations in one pass (Welford's algorithm).
+pub(crate) fn welford(values: &[f64]) -> (f64, f64) {
+    let (mut mean, mut m2) = (0.0_f64, 0.0_f64);
+    for (i, &x) in values.iter().enumerate() {
+        let delta = x - mean;
+        mean += delta / (i + 1) as f64;
+        m2 += delta * (x - mean);
+    }
+    (mean, m2)
+}
+
+/// The time-value-of-money functions, with the spreadsheet sign convention (money paid out is negative).
+/// The optional fourth argument is the future value for `pmt` and `pv`, and the present value for `fv`.
+pub(crate) fn time_value(name: &str, args: &[f64]) -> Result<f64, &'static str> {
+    let (rate, periods, amount, extra) = match *args {
+        [r, n, a] => (r, n, a, 0.0),
+        [r, n, a, x] => (r, n, a, x),
+        _ => return Err("Wrong number of arguments"),
+    };
+    if periods <= 0.0 { return Err("The number of periods must be positive"); }
+    let growth = (1.0 + rate).powf(periods);
+    // The factor that turns a per-period payment into its value at the end of the term.
+    let annuity = if rate == 0.0 { periods } else { (growth - 1.0) / rate };
+    Ok(match name {
+        "pmt" => -(amount * growth + extra) / annuity,
+        "fv" => -(extra * growth + amount * annuity),
+        _ => -(extra + amount * annuity) / growth,
+    })
+}
+
+/// Net present value of cash flows arriving at the end of periods 1, 2, …
+pub(crate) fn npv(rate: f64, flows: &[f64]) -> f64 {
+    compensated_sum(&flows.iter().enumerate().map(|(i, flow)| flow / (1.0 + rate).powi(i as i32 + 1)).collect::<Vec<_>>())
+}
+
+/// Internal rate of return of cash flows starting at period 0, found with Newton's method.
+pub(crate) fn irr(flows: &[f64]) -> Result<f64, &'static str> {
+    if !(flows.iter().any(|&f| f > 0.0) && flows.iter().any(|&f| f < 0.0)) { return Err("IRR needs both positive and negative cash flows"); }
+    let mut rate = 0.1_f64;
+    for _ in 0..100 {
+        let (value, slope) = flows.iter().enumerate().fold((0.0, 0.0), |(v, d), (i, &flow)| {
+            let t = i as f64;
+            (v + flow / (1.0 + rate).powf(t), d - t * flow / (1.0 + rate).powf(t + 1.0))
+        });
+        let next = rate - value / slope;
+        if !next.is_finite() || next <= -1.0 { break; }
+        if (next - rate).abs() < 1e-12 { return Ok(next); }
+        rate = next;
+    }
+    Err("IRR did not converge")
+}
+
+/// One period of an amortization schedule.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct AmortizationRow {
+    pub(crate) period: u32, pub(crate) payment: f64, pub(crate) interest: f64, pub(crate) principal: f64,
+    pub(crate) balance: f64,
+}
+
+/// The monthly schedule of a fixed-rate loan; the last payment absorbs any rounding left in the balance.
+pub(crate) fn amortization_schedule(principal: f64, annual_rate: f64, months: u32) -> Vec<AmortizationRow> {
+    let rate = annual_rate / 1200.0;
+    let Ok(payment) = time_value("pmt", &[rate, months as f64, -principal]) else { return Vec::new() };
+    let mut balance = principal;
+    (1..=months).map(|period| {
+        let interest = balance * rate;
+        let repaid = if period == months { balance } else { payment - interest };
+        balance -= repaid;
+        AmortizationRow { period, payment: repaid + interest, interest, principal: repaid, balance: balance.max(0.0) }
+    }).collect()
+}
+
+/// What a mortgage costs when an extra amount is paid every month on top of the regular payment.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct MortgageSummary { pub(crate) monthly: f64, pub(crate) months: u32, pub(crate) total_interest: f64, pub(crate) interest_saved: f64 }
+
+pub(crate) fn mortgage_summary(amount: f64, annual_rate: f64, months: u32, extra: f64) -> Option<MortgageSummary> {
+    let schedule = amortization_schedule(amount, annual_rate, months);
+    let payment = schedule.first()?.payment;
+    let (mut balance, mut paid_months, mut total_interest) = (amount, 0, 0.0);
+    while balance > 1e-9 && paid_months < months {
+        let interest = balance * annual_rate / 1200.0;
+        balance -= (payment + extra - interest).min(balance);
+        total_interest += interest;
+        paid_months += 1;
+    }
+    let interest_saved = schedule.iter().map(|row| row.interest).sum::<f64>() - total_interest;
+    Some(MortgageSummary { monthly: payment + extra, months: paid_months, total_interest, interest_saved })
+}
+
+/// The built-in functions and constants with their argument hints, in the order autocomplete offers them.
+pub(crate) const COMPLETIONS: [(&str, &str); 51] = [
+    ("abs", "(x)"),
+    ("and", "(a, b)"),
+    ("approx", "(a, b, eps)"),
+    ("char", "(n)"),
+    ("circlearea", "(r)"),
+    ("circumference", "(r)"),
+    ("code", "(\"c\")"),
+    ("conevol", "(r, h)"),
+    ("cos", "(x)"),
+    ("cylvol", "(r, h)"),
+    ("date", "(timestamp)"),
+    ("det", "([a, b; c, d])"),
+    ("dist", "(x1, y1, x2, y2)"),
+    ("e", ""),
+    ("exp", "(x)"),
+    ("fv", "(rate, periods, pmt[, pv])"),
+    ("fx", "(amount USD, EUR)"),
+    ("hypot", "(a, b)"),
+    ("inv", "([a, b; c, d])"),
+    ("irr", "(cf0, cf1, …)"),
+    ("is_int", "(x)"),
+    ("linsolve", "(A, b)"),
+    ("ln", "(x)"),
+    ("log", "(x)"),
+    ("margin", "(price, cost)"),
+    ("markup", "(cost, pct)"),
+    ("mean", "(x, …)"),
+    ("mod", "(a, b)"),
+    ("molarmass", "(\"formula\")"),
+    ("not", "(a)"),
+    ("npv", "(rate, cf1, …)"),
+    ("or", "(a, b)"),
+    ("pctchange", "(old, new)"),
+    ("pi", ""),
+    ("pmt", "(rate, periods, pv[, fv])"),
+    ("predict", "(x)"),
+    ("pv", "(rate, periods, pmt[, fv])"),
+    ("quad", "(a, b, c)"),
+    ("shl", "(a, n)"),
+    ("shr", "(a, n)"),
+    ("sin", "(x)"),
+    ("spherearea", "(r)"),
+    ("spherevol", "(r)"),
+    ("sqrt", "(x)"),
+    ("stddev", "(x, …)"),
+    ("sum", "(x, …)"),
+    ("tan", "(x)"),
+    ("transpose", "(M)"),
+    ("triarea", "(a, b, c)"),
+    ("unix", "(yyyy-mm-dd hh:mm)"),
+    ("xor", "(a, b)"),
+];
+
+/// The sections of the function picker, each naming entries of `COMPLETIONS`.
+pub(crate) const FUNCTION_GROUPS: [(&str, &[&str]); 7] = [
+    ("Math", &["abs", "sqrt", "exp", "ln", "log", "sin", "cos", "tan", "mod", "pi", "e", "quad", "approx", "is_int"]),
+    ("Geometry", &["hypot", "dist", "circlearea", "circumference", "spherevol", "spherearea", "cylvol", "conevol", "triarea"]),
+    ("Finance", &["fv", "pv", "pmt", "npv", "irr", "pctchange", "markup", "margin", "fx"]),
+    ("Statistics", &["sum", "mean", "stddev", "predict"]),
+    ("Matrices", &["det", "inv", "transpose", "linsolve"]),
+    ("Bits and logic", &["and", "or", "xor", "not", "shl", "shr"]),
+    ("Text and dates", &["char", "code", "date", "unix", "molarmass"]),
+];
+
+/// The two roots of `a·x² + b·x + c = 0`; complex roots come as the conjugate pair `re ± im·i`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum QuadRoots { Real(f64, f64), Complex { re: f64, im: f64 } }
+
+impl QuadRoots {
+    /// The roots as the two display lines.
+    pub(crate) fn lines(&self, format: &FormatOptions) -> [String; 2] {
+        match *self {
+            QuadRoots::Real(x1, x2) => [format!("x₁ = {}", format_result(x1, format)), format!("x₂ = {}", format_result(x2, format))],
+            QuadRoots::Complex { re, im } => {
+                let (re, im) = (format_result(re, format), format_result(im, format));
+                [format!("x₁ = {} + {}i", re, im), format!("x₂ = {} − {}i", re, im)]
+            }
+        }
+    }
+}
+
+/// Solves a quadratic, avoiding the cancellation of the textbook formula when `b²` dwarfs `4ac`.
+pub(crate) fn quadratic_roots(a: f64, b: f64, c: f64) -> Result<QuadRoots, &'static str> {
+    if a == 0.0 { return Err("Not a quadratic (a is 0)"); }
+    let discriminant = b * b - 4.0 * a * c;
+    if discriminant < 0.0 { return Ok(QuadRoots::Complex { re: -b / (2.0 * a), im: (-discriminant).sqrt() / (2.0 * a).abs() }); }
+    let q = -0.5 * (b + discriminant.sqrt().copysign(b));
+    if q == 0.0 { return Ok(QuadRoots::Real(0.0, 0.0)); }
+    let (x1, x2) = (q / a, c / q);
+    Ok(if x1 >= x2 { QuadRoots::Real(x1, x2) } else { QuadRoots::Real(x2, x1) })
+}
+
+/// The summary statistics of a dataset; variance and standard deviation are the sample ones.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct DataSummary {
+    pub(crate) n: usize, pub(crate) sum: f64, pub(crate) mean: f64, pub(crate) median: f64, pub(crate) min: f64,
+    pub(crate) max: f64, pub(crate) variance: f64, pub(crate) stddev: f64,
+}
+
+pub(crate) fn summarize(points: &[f64]) -> Option<DataSummary> {
+    if points.is_empty() { return None; }
+    let mut sorted = points.to_vec();
+    sorted.sort_by(f64::total_cmp);
+    let n = sorted.len();
+    let median = if n % 2 == 1 { sorted[n / 2] } else { (sorted[n / 2 - 1] + sorted[n / 2]) / 2.0 };
+    let (mean, m2) = welford(points);
+    let variance = if n > 1 { m2 / (n - 1) as f64 } else { 0.0 };
+    Some(DataSummary { n, sum: compensated_sum(points), mean, median, min: sorted[0], max: sorted[n - 1], variance, stddev: variance.sqrt() })
+}
+
+/// A least-squares line through a set of points.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct LinearFit { pub(crate) slope: f64, pub(crate) intercept: f64, pub(crate) r_squared: f64 }
+
+/// Fits a line to the points; needs at least two distinct x values.
+pub(crate) fn linear_fit(points: &[(f64, f64)]) -> Option<LinearFit> {
+    if points.len() < 2 { return None; }
+    let n = points.len() as f64;
+    let mean_x = compensated_sum(&points.iter().map(|p| p.0).collect::<Vec<_>>()) / n;
+    let mean_y = compensated_sum(&points.iter().map(|p| p.1).collect::<Vec<_>>()) / n;
+    let (sxx, sxy, syy) = points.iter().fold((0.0, 0.0, 0.0), |(sxx, sxy, syy), &(x, y)| {
+        let (dx, dy) = (x - mean_x, y - mean_y);
+        (sxx + dx * dx, sxy + dx * dy, syy + dy * dy)
+    });
+    if sxx == 0.0 { return None; }
+    let slope = sxy / sxx;
+    let r_squared = if syy == 0.0 { 1.0 } else { sxy * sxy / (sxx * syy) };
+    Some(LinearFit { slope, intercept: mean_y - slope * mean_x, r_squared })
+}
+
+/// The selectable programmer word sizes, in bits.
+pub(crate) const WORD_SIZES: [u32; 4] = [8, 16, 32, 64];
+
+/// Reads `x` back as a signed `bits`-wide two's-complement integer, so overflow wraps around.
+pub(crate) fn wrap_word(x: i128, bits: u32) -> i128 {
+    let shift = 128 - bits;
+    (x << shift) >> shift
+}
+
+/// The bitwise functions, computed on `bits`-wide words with wraparound; `shr` shifts arithmetically.
+pub(crate) fn bitwise(name: &str, args: &[f64], bits: u32) -> Result<f64, &'static str> {
+    let words = args.iter()
+        .map(|&x| if x.fract() == 0.0 && x.abs() < 2f64.powi(64) { Ok(x as i128) } else { Err("Bitwise operations need integers") })
+        .collect::<Result<Vec<_>, _>>()?;
+    let shift = |n: i128| if (0..bits as i128).contains(&n) { Ok(n as u32) } else { Err("Shift amount is outside the word size") };
+    let result = match (name, words.as_slice()) {
+        ("and", [a, b]) => a & b,
+        ("or", [a, b]) => a | b,
+        ("xor", [a, b]) => a ^ b,
+        ("not", [a]) => !a,
+        ("shl", [a, n]) => wrap_word(*a, bits) << shift(*n)?,
+        ("shr", [a, n]) => wrap_word(*a, bits) >> shift(*n)?,
+        _ => return Err("Wrong number of arguments"),
+    };
+    Ok(wrap_word(result, bits) as f64)
+}
+
+/// The geometry helpers: lengths, areas and volumes of the common shapes.
+pub(crate) fn geometry(name: &str, args: &[f64]) -> Result<f64, &'static str> {
+    use std::f64::consts::PI;
+    if matches!(name, "circlearea" | "circumference" | "spherevol" | "spherearea" | "cylvol" | "conevol" | "triarea") && args.iter().any(|&x| x < 0.0) {
+        return Err("Lengths cannot be negative");
+    }
+    match (name, args) {
+        ("hypot", [a, b]) => Ok(a.hypot(*b)),
+        ("dist", [x1, y1, x2, y2]) => Ok((x2 - x1).hypot(y2 - y1)),
+        ("circlearea", [r]) => Ok(PI * r * r),
+        ("circumference", [r]) => Ok(2.0 * PI * r),
+        ("spherevol", [r]) => Ok(4.0 / 3.0 * PI * r.powi(3)),
+        ("spherearea", [r]) => Ok(4.0 * PI * r * r),
+        ("cylvol", [r, h]) => Ok(PI * r * r * h),
+        ("conevol", [r, h]) => Ok(PI * r * r * h / 3.0),
+        // Heron's formula, rearranged to stay accurate for needle-like triangles.
+        ("triarea", [a, b, c]) => {
+            let mut sides = [*a, *b, *c];
+            sides.sort_by(|x, y| y.total_cmp(x));
+            let [a, b, c] = sides;
+            if a > b + c { return Err("The sides do not form a triangle"); }
+            Ok(0.25 * ((a + (b + c)) * (c - (a - b)) * (c + (a - b)) * (a + (b - c))).sqrt())
+        }
+        _ => Err("Wrong number of arguments"),
+    }
+}
+
+/// The character with the code point `x`, if it is one.
+pub(crate) fn code_point(x: f64) -> Option<char> {
+    (x.fract() == 0.0 && (0.0..=u32::MAX as f64).contains(&x)).then(|| char::from_u32(x as u32)).flatten()
+}
+
+/// The UTF-8 encoding of `c` as hex bytes.
+pub(crate) fn utf8_bytes(c: char) -> String {
+    c.encode_utf8(&mut [0; 4]).bytes().map(|b| format!("{:02X}", b)).collect::<Vec<_>>().join(" ")
+}
+
+/// Calls a built-in function; `options` supplies the regression line for `predict`, the word size for bitwise functions
+/// and the time zone for `unix`.
+pub(crate) fn call_function(name: &str, args: &[f64], options: &EvalOptions) -> Result<f64, &'static str> {
+    match (name, args) {
+        ("and" | "or" | "xor" | "not" | "shl" | "shr", _) => return bitwise(name, args, options.word_bits),
+        ("hypot" | "dist" | "circlearea" | "circumference" | "spherevol" | "spherearea" | "cylvol" | "conevol" | "triarea", _) => return geometry(name, args),
+        ("code" | "char", [x]) => return code_point(*x).map(|_| *x).ok_or("Not a Unicode code point"),
+        ("unix", [x]) => return Ok(x - f64::from(options.utc_offset_minutes) * 60.0),
+        ("date", [x]) => return if x.abs() < 1e14 { Ok(*x) } else { Err("Timestamp out of range") },
+        // Inside a larger expression a quadratic stands for its larger real root; on its own it shows both.
+        ("quad", [a, b, c]) => {
+            return match quadratic_roots(*a, *b, *c)? {
+                QuadRoots::Real(x1, _) => Ok(x1),
+                QuadRoots::Complex { .. } => Err("The roots are complex"),
+            };
+        }
+        ("quad", _) => return Err("Wrong number of arguments"),
+        ("predict", [x]) => return match (options.variables.get("slope"), options.variables.get("intercept")) {
+            (Some(slope), Some(intercept)) => Ok(intercept + slope * x),
+            _ => Err("No regression line yet (enter x, y pairs in statistics mode)"),
+        },
+        ("pctchange", [0.0, _]) => return Err("Percent change from zero is undefined"),
+        ("pctchange", [old, new]) => return Ok((new - old) / old.abs() * 100.0),
+        ("markup", [cost, pct]) => return Ok(cost * (1.0 + pct / 100.0)),
+        ("margin", [0.0, _]) => return Err("Margin of a zero price is undefined"),
+        ("margin", [price, cost]) => return Ok((price - cost) / price * 100.0),
+        ("pctchange" | "markup" | "margin", _) => return Err("Wrong number of arguments"),
+        ("mod", [_, 0.0]) => return Err("Modulo by zero"),
+        ("mod", [a, b]) => return Ok(a - b * (a / b).floor()),
+        ("mod", _) => return Err("Wrong number of arguments"),
+        ("approx", [_, _, eps]) if *eps < 0.0 => return Err("Tolerance cannot be negative"),
+        ("approx", [a, b, eps]) => return Ok(if (a - b).abs() <= *eps { 1.0 } else { 0.0 }),
+        ("approx", _) => return Err("Wrong number of arguments"),
+        ("is_int", [x]) => return Ok(if x.is_finite() && x.fract() == 0.0 { 1.0 } else { 0.0 }),
+        ("pmt" | "fv" | "pv", _) => return time_value(name, args),
+        ("npv", [rate, flows @ ..]) if !flows.is_empty() => return Ok(npv(*rate, flows)),
+        ("npv", _) => return Err("Wrong number of arguments"),
+        ("irr", _) => return irr(args),
+        ("sum", _) => return Ok(compensated_sum(args)),
+        ("mean", []) => return Err("Mean of an empty list"),
+        ("mean", _) => return Ok(welford(args).0),
+        ("stddev", [] | [_]) => return Err("Standard deviation needs at least two values"),
+        ("stddev", _) => return Ok((welford(args).1 / (args.len() - 1) as f64).sqrt()),
+        _ => {}
+    }
+    let [x] = args else { return Err("Wrong number of arguments") };
+    let x = *x;
+    let angle = if options.degrees { x.to_radians() } else { x };
+    match name {
+        "sqrt" => if x < 0.0 { Err("Domain error: square root of a negative number") } else { Ok(x.sqrt()) },
+        "ln" => if x <= 0.0 { Err("Domain error: logarithm of a non-positive number") } else { Ok(x.ln()) },
+        "log" => if x <= 0.0 { Err("Domain error: logarithm of a non-positive number") } else { Ok(x.log10()) },
+        "exp" => Ok(x.exp()),
+        "abs" => Ok(x.abs()),
+        "sin" => Ok(angle.sin()),
+        "cos" => Ok(angle.cos()),
+        "tan" => Ok(angle.tan()),
+        _ => Err("Unknown function"),
+    }
+}
+
+/// Looks up a named constant.
+pub(crate) fn constant(name: &str) -> Option<f64> {
+    match name {
+        "pi" => Some(std::f64::consts::PI),
+        "e" => Some(std::f64::consts::E),
+        _ => None,
+    }
+}
+
+/// The amount and the currency codes of an `fx(amount FROM, TO)` call.
+pub(crate) fn conversion(expr: &Expr) -> Option<(&Expr, &str, &str)> {
+    match &expr.kind {
+        ExprKind::Call(name, args) if name == "fx" && let [amount, from, to] = args.as_slice()
+            && let (ExprKind::Name(from), ExprKind::Name(to)) = (&from.kind, &to.kind) => Some((amount, from, to)),
+        _ => None,
+    }
+}
+
+/// Records the operations of an evaluation in the order they are carried out, for the tutor.
+/// `source` is the expression text, used to spot operations written in parentheses.
+pub(crate) struct Recorder<'a> { pub(crate) source: &'a str, pub(crate) steps: Vec<TraceStep> }
+
+/// One operation of a recorded evaluation: what kind it was, its operator or function, its operands and its value.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct TraceStep { pub(crate) kind: &'static str, pub(crate) operator: String, pub(crate) operands: Vec<f64>, pub(crate) value: f64 }
+
+/// Evaluates an expression tree on the number type `N`, telling `recorder` about every operation if there is one.
+/// Functions, constants, and variables are float-based and are converted at the boundary.
+pub(crate) fn eval_expr<N: Number>(expr: &Expr, options: &EvalOptions, mut recorder: Option<&mut Recorder>) -> Result<N, EvalError> {
+    options.check_interrupted()?;
+    let fail = |message: &str| EvalError::new(message, expr.span);
+    let number = |x: f64| N::from_float(x).map_err(fail);
+    // The operation carried out here, for the recorder: its kind, operator and operands. Only built while recording.
+    let (tracing, mut step) = (recorder.is_some(), None);
+    let result = match &expr.kind {
+        ExprKind::Number(_, text) => N::from_literal(text).map_err(fail)?,
+        ExprKind::Duration(n) => number(*n)?,
+        ExprKind::Infinity if options.saturate => number(f64::INFINITY)?,
+        ExprKind::Infinity => return Err(fail("Result too large")),
+        ExprKind::Name(name) => {
+            let value = options.variables.get(name).copied().or_else(|| constant(name)).or_else(|| unit(name).map(|u| u.0));
+            number(value.ok_or_else(|| fail("Unknown name"))?)?
+        }
+        ExprKind::Neg(operand) => {
+            let x = eval_expr::<N>(operand, options, recorder.as_deref_mut())?;
+            // A minus written on a number is part of it, not a step of its own.
+            if tracing && !matches!(operand.kind, ExprKind::Number(..)) { step = Some(("negation", "−".to_string(), vec![x.to_float()])); }
+            x.neg()
+        }
+        ExprKind::Percent(operand) => {
+            let x = eval_expr::<N>(operand, options, recorder.as_deref_mut())?;
+            if tracing { step = Some(("percentage", "%".to_string(), vec![x.to_float()])); }
+            N::apply('/', &x, &number(100.0)?).map_err(fail)?
+        }
+        // `a + b%` and `a - b%` take b percent of a, like a desk calculator.
+        ExprKind::Binary(op @ ('+' | '-'), lhs, rhs) if let ExprKind::Percent(percent) = &rhs.kind => {
+            let a = eval_expr::<N>(lhs, options, recorder.as_deref_mut())?;
+            let p = eval_expr::<N>(percent, options, recorder.as_deref_mut())?;
+            let share = N::apply('*', &a, &p).map_err(fail)?;
+            let b = N::apply('/', &share, &number(100.0)?).map_err(fail)?;
+            if let Some(recorder) = recorder.as_deref_mut() {
+                recorder.steps.push(TraceStep {
+                    kind: "percentage", operator: "% of".to_string(), operands: vec![p.to_float(), a.to_float()],
+                    value: b.to_float(),
+                });
+                step = Some((if *op == '+' { "addition" } else { "subtraction" }, op.to_string(), vec![a.to_float(), b.to_float()]));
+            }
+            N::apply(*op, &a, &b).map_err(fail)?
+        }
+        ExprKind::Binary(op, lhs, rhs) => {
+            let a = eval_expr::<N>(lhs, options, recorder.as_deref_mut())?;
+            let b = eval_expr::<N>(rhs, options, recorder.as_deref_mut())?;
+            if tracing {
+                let kind = match op { '^' => "exponent", '*' => "multiplication", '/' => "division", '+' => "addition", _ => "subtraction" };
+                step = Some((kind, op.to_string(), vec![a.to_float(), b.to_float()]));
+            }
+            N::apply(*op, &a, &b).map_err(fail)?
+        }
+        ExprKind::Call(..) if let Some((amount, from, to)) = conversion(expr) => {
+            let amount = eval_expr::<N>(amount, options, recorder.as_deref_mut())?.to_float();
+            if tracing { step = Some(("conversion", format!("{}→{}", from, to), vec![amount])); }
+            number(options.rates.convert(amount, from, to).map_err(|message| EvalError { message, span: Some(expr.span) })?)?
+        }
+        ExprKind::Call(name, args) => {
+            let values = args.iter().map(|arg| eval_expr::<N>(arg, options, recorder.as_deref_mut()).map(|v| v.to_float())).collect::<Result<Vec<_>, _>>()?;
+            let value = call_function(name, &values, options).map_err(fail)?;
+            if tracing { step = Some(("function", name.clone(), values)); }
+            number(value)?
+        }
+    };
+    let result = result.check(options).map_err(fail)?;
+    if let (Some(recorder), Some((kind, operator, operands))) = (recorder, step) {
+        let grouped = recorder.source.get(expr.span.start..expr.span.end).is_some_and(is_parenthesized);
+        recorder.steps.push(TraceStep { kind: if grouped { "parentheses" } else { kind }, operator, operands, value: result.to_float() });
+    }
+    Ok(result)
+}
+
+/// Tells the steps of an evaluation as plain sentences: "First the parentheses: 1 − 5 = −4; then the exponent: …".
+pub(crate) fn narrate(steps: &[TraceStep], format: impl Fn(f64) -> String) -> String {
+    let operand = |x: f64| if x < 0.0 { format!("({})", format(x).replace('-', "−")) } else { format(x) };
+    let phrases: Vec<String> = steps.iter().map(|step| {
+        let operation = match (step.operator.as_str(), step.operands.as_slice()) {
+            ("−", [x]) => format!("−({})", format(*x)),
+            ("%", [x]) => format!("{}%", operand(*x)),
+            ("% of", [b, a]) => format!("{}% of {}", operand(*b), operand(*a)),
+            (op @ ("+" | "-" | "*" | "/" | "^"), [a, b]) => {
+                let symbol = match op { "-" => "−", "*" => "×", "/" => "÷", _ => op };
+                if op == "^" { format!("{}^{}", operand(*a), operand(*b)) } else { format!("{} {} {}", operand(*a), symbol, operand(*b)) }
+            }
+            (name, args) => format!("{}({})", name, args.iter().map(|&x| format(x)).collect::<Vec<_>>().join(", ")),
+        };
+        format!("the {}: {} = {}", step.kind, operation, format(step.value).replace('-', "−"))
+    }).collect();
+    match phrases.as_slice() {
+        [] => "Just a number: there is nothing to work out.".to_string(),
+        [only] => format!("Only {}.", only),
+        [first, middle @ .., last] => {
+            let middle: String = middle.iter().map(|phrase| format!("; then {}", phrase)).collect();
+            format!("First {}{}; finally {}.", first, middle, last)
+        }
+    }
+}
+
+/// The result of an evaluation: the value as a float, plus the exact text from a non-float backend,
+/// any note attached to it, both roots when the expression is a `quad` call, and the label shown instead of the
+/// value when the expression asks for something other than a number, like the character of `char(65)`.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Outcome {
+    pub(crate) value: f64, pub(crate) exact: Option<String>, pub(crate) note: Option<String>,
+    pub(crate) roots: Option<QuadRoots>, pub(crate) label: Option<String>,
+}
+
+/// A whole-number literal exponent, as in `m^2` or `s^-1`.
+pub(crate) fn integer_literal(expr: &Expr) -> Option<i32> {
+    match &expr.kind {
+        ExprKind::Number(n, _) if n.fract() == 0.0 && n.abs() <= 32.0 => Some(*n as i32),
+        ExprKind::Neg(operand) => integer_literal(operand).map(|n| -n),
+        _ => None,
+    }
+}
+
+/// The dimension of the tree's result, rejecting operations that mix dimensions.
+/// Durations are times; names that are variables or constants shadow units.
+pub(crate) fn dimension(expr: &Expr, variables: &HashMap<String, f64>) -> Result<Dimension, EvalError> {
+    let fail = |message: String| Err(EvalError { message, span: Some(expr.span) });
+    let describe = |d: Dimension| if d == DIMENSIONLESS { "a plain number".to_string() } else { unit_text(d) };
+    match &expr.kind {
+        ExprKind::Number(..) | ExprKind::Infinity => Ok(DIMENSIONLESS),
+        ExprKind::Duration(_) => Ok(TIME),
+        ExprKind::Name(name) if variables.contains_key(name) || constant(name).is_some() => Ok(DIMENSIONLESS),
+        ExprKind::Name(name) => Ok(unit(name).map_or(DIMENSIONLESS, |u| u.1)),
+        ExprKind::Neg(operand) | ExprKind::Percent(operand) => dimension(operand, variables),
+        ExprKind::Binary('+' | '-', lhs, rhs) if matches!(rhs.kind, ExprKind::Percent(_)) => dimension(lhs, variables),
+        ExprKind::Binary(op, lhs, rhs) => {
+            let (a, b) = (dimension(lhs, variables)?, dimension(rhs, variables)?);
+            match op {
+                '+' | '-' if a == b => Ok(a),
+                '+' | '-' => fail(format!("Cannot {} {} and {}", if *op == '+' { "add" } else { "subtract" }, describe(a), describe(b))),
+                '*' => Ok([a[0] + b[0], a[1] + b[1], a[2] + b[2], a[3] + b[3]]),
+                '/' => Ok([a[0] - b[0], a[1] - b[1], a[2] - b[2], a[3] - b[3]]),
+                _ if a == DIMENSIONLESS && b == DIMENSIONLESS => Ok(a),
+                _ => match (b, integer_literal(rhs)) {
+                    (DIMENSIONLESS, Some(k)) => Ok(a.map(|p| p * k)),
+                    _ => fail("Units can only be raised to a whole-number power".to_string()),
+                },
+            }
+        }
+        ExprKind::Call(name, args) => {
+            let dims = args.iter().map(|arg| dimension(arg, variables)).collect::<Result<Vec<_>, _>>()?;
+            match (name.as_str(), dims.as_slice()) {
+                (_, dims) if dims.iter().all(|&d| d == DIMENSIONLESS) => Ok(DIMENSIONLESS),
+                ("sqrt", [d]) if d.iter().all(|p| p % 2 == 0) => Ok(d.map(|p| p / 2)),
+                // Comparing two lengths is fine as long as the tolerance is a length too; the answer is a plain 1 or 0.
+                ("approx", [first, rest @ ..]) if rest.iter().all(|d| d == first) => Ok(DIMENSIONLESS),
+                ("abs" | "sum" | "mean" | "stddev" | "hypot" | "dist" | "circumference", [first, rest @ ..]) if rest.iter().all(|d| d == first) => Ok(*first),
+                ("circlearea" | "spherearea" | "triarea", [first, rest @ ..]) if rest.iter().all(|d| d == first) => Ok(first.map(|p| p * 2)),
+                ("spherevol" | "cylvol" | "conevol", [first, rest @ ..]) if rest.iter().all(|d| d == first) => Ok(first.map(|p| p * 3)),
+                _ => fail(format!("{} takes plain numbers", name)),
+            }
+        }
+    }
+}
+
+/// The currency of the tree's result in money mode: the symbol its amounts were typed with, or `None` for plain
+/// numbers. A plain number scales an amount, and an amount divided by another in the same currency is a plain ratio;
+/// amounts in different currencies only go together after a conversion with `fx`, whose result is in the symbol of
+/// the currency converted to (a plain number if it has none).
+pub(crate) fn currency(expr: &Expr, source: &str) -> Result<Option<char>, EvalError> {
+    let fail = |message: String| Err(EvalError { message, span: Some(expr.span) });
+    let symbol = |code: &str| CURRENCY_CODES.iter().find(|(_, c)| *c == code).map(|(symbol, _)| *symbol);
+    match &expr.kind {
+        ExprKind::Call(..) if let Some((amount, from, to)) = conversion(expr) => match (currency(amount, source)?, symbol(from)) {
+            (Some(tag), Some(from_symbol)) if tag != from_symbol => fail(format!("Cannot convert a {} amount from {}", tag, from)),
+            _ => Ok(symbol(to)),
+        },
+        ExprKind::Number(..) => Ok(currency_tag(source, expr.span)),
+        ExprKind::Neg(operand) | ExprKind::Percent(operand) => currency(operand, source),
+        ExprKind::Binary(op, lhs, rhs) => match (op, currency(lhs, source)?, currency(rhs, source)?) {
+            (_, Some(a), Some(b)) if a != b => fail(format!("Cannot mix {} and {} amounts without a conversion", a, b)),
+            ('/', Some(_), Some(_)) => Ok(None),
+            ('*' | '^', Some(_), Some(_)) => fail("Cannot multiply two amounts".to_string()),
+            (_, a, b) => Ok(a.or(b)),
+        },
+        ExprKind::Call(_, args) => args.iter().try_fold(None, |common, arg| match (common, currency(arg, source)?) {
+            (Some(a), Some(b)) if a != b => fail(format!("Cannot mix {} and {} amounts without a conversion", a, b)),
+            (common, tag) => Ok(common.or(tag)),
+        }),
+        _ => Ok(None),
+    }
+}
+
+/// Whether the tree contains an `h:mm:ss` literal, which makes a time result show as a duration.
+pub(crate) fn has_duration(expr: &Expr) -> bool {
+    match &expr.kind {
+        ExprKind::Duration(_) => true,
+        ExprKind::Neg(operand) | ExprKind::Percent(operand) => has_duration(operand),
+        ExprKind::Binary(_, lhs, rhs) => has_duration(lhs) || has_duration(rhs),
+        ExprKind::Call(_, args) => args.iter().any(has_duration),
+        _ => false,
+    }
+}
+
+/// Whether the tree calls the function `name` anywhere.
+pub(crate) fn calls(expr: &Expr, name: &str) -> bool {
+    match &expr.kind {
+        ExprKind::Neg(operand) | ExprKind::Percent(operand) => calls(operand, name),
+        ExprKind::Binary(_, lhs, rhs) => calls(lhs, name) || calls(rhs, name),
+        ExprKind::Call(called, args) => called == name || args.iter().any(|arg| calls(arg, name)),
+        _ => false,
+    }
+}
+
+/// Evaluates a tree on the backend selected in `options`. When `source` is the text the tree was parsed from, the f64
+/// backend reuses the program compiled for it last time.
+pub(crate) fn eval_on_backend(tree: &Expr, source: Option<&str>, options: &EvalOptions) -> Result<Outcome, EvalError> {
+    fn run<N: Number>(tree: &Expr, options: &EvalOptions, exact: bool) -> Result<Outcome, EvalError> {
+        eval_expr::<N>(tree, options, None).map(|n| Outcome {
+            value: n.to_float(), exact: exact.then(|| n.render()), note: n.note().map(str::to_string), roots: None,
+            label: None,
+        })
+    }
+    match options.backend {
+        NumberBackend::Float => {
+            let mut variables: Vec<_> = options.variables.iter().collect();
+            variables.sort_by(|a, b| a.0.cmp(b.0));
+            let (slots, values): (Vec<&str>, Vec<f64>) = variables.into_iter().map(|(name, value)| (name.as_str(), *value)).unzip();
+            let program = match source {
+                Some(source) => Program::cached(source, options.si_suffixes, tree, &slots),
+                None => Arc::new(Program::compile(tree, &slots)),
+            };
+            program.run(&values, options).map(|value| Outcome { value, exact: None, note: None, roots: None, label: None })
+        }
+        NumberBackend::Decimal => run::<Decimal>(tree, options, true),
+        NumberBackend::Rational => run::<BigRational>(tree, options, true),
+        NumberBackend::BigInt => run::<BigInt>(tree, options, true),
+        NumberBackend::Money => run::<Money>(tree, options, true),
+    }
+}
+
+/// Solves a `quad(a, b, c)` call that is the whole expression, keeping both roots. The value is the larger real root,
+/// or the real part of a complex pair.
+pub(crate) fn quadratic_outcome(tree: &Expr, args: &[Expr], options: &EvalOptions) -> Result<Outcome, EvalError> {
+    let coefficients = args.iter().map(|arg| eval_on_backend(arg, None, options).map(|o| o.value)).collect::<Result<Vec<_>, _>>()?;
+    let roots = quadratic_roots(coefficients[0], coefficients[1], coefficients[2]).map_err(|message| EvalError::new(message, tree.span))?;
+    let (value, note) = match roots {
+        QuadRoots::Real(x1, x2) if x1 == x2 => (x1, "One repeated root"),
+        QuadRoots::Real(x1, _) => (x1, "Two real roots"),
+        QuadRoots::Complex { re, .. } => (re, "Two complex roots"),
+    };
+    Ok(Outcome { value, exact: None, note: Some(note.to_string()), roots: Some(roots), label: None })
+}
+
+/// Evaluates an expression, treating overflow as an error.
+#[cfg(test)]
+pub(crate) fn evaluate(expression: &str) -> Result<f64, EvalError> { evaluate_with(expression, &EvalOptions::default()) }
+
+/// The main evaluation function: parses the expression and evaluates the resulting tree.
+#[cfg(test)]
+pub(crate) fn evaluate_with(expression: &str, options: &EvalOptions) -> Result<f64, EvalError> { evaluate_timed(expression, options).0.map(|o| o.value) }
+
+/// How long each stage of one evaluation took, and how many heap allocations it made in all when the build counts them.
+#[derive(Clone, Copy, Default, Debug)]
+pub(crate) struct EvalTimings { pub(crate) tokenize: Duration, pub(crate) parse: Duration, pub(crate) evaluate: Duration, pub(crate) allocations: Option<u64> }
+
+impl EvalTimings {
+    pub(crate) fn total(&self) -> Duration { self.tokenize + self.parse + self.evaluate }
+}
+
+/// Evaluates an expression, measuring the tokenize, parse, and evaluate stages separately and counting allocations.
+pub(crate) fn evaluate_timed(expression: &str, options: &EvalOptions) -> (Result<Outcome, EvalError>, EvalTimings) {
+    let before = allocations();
+    let (result, timings) = evaluate_stages(expression, options);
+    (result, EvalTimings { allocations: allocations().zip(before).map(|(after, before)| after - before), ..timings })
+}
+
+fn evaluate_stages(expression: &str, options: &EvalOptions) -> (Result<Outcome, EvalError>, EvalTimings) {
+    let mut timings = EvalTimings::default();
+    let stage_start = Instant::now();
+    let tokens = tokenize(expression, options.si_suffixes);
+    timings.tokenize = stage_start.elapsed();
+    let tokens = match tokens { Ok(tokens) => tokens, Err(e) => return (Err(e), timings) };
+    let stage_start = Instant::now();
+    let tree = parse_tokens(tokens, expression.len(), options.max_depth);
+    timings.parse = stage_start.elapsed();
+    let tree = match tree { Ok(tree) => tree, Err(e) => return (Err(e), timings) };
+    let stage_start = Instant::now();
+    let dimension = match dimension(&tree, &options.variables) { Ok(dimension) => dimension, Err(e) => return (Err(e), timings) };
+    // Money mode carries the currency amounts were typed with through to the result; elsewhere the symbols are only decoration.
+    let tag = match options.backend {
+        NumberBackend::Money => match currency(&tree, expression) { Ok(tag) => tag, Err(e) => return (Err(e), timings) },
+        _ => None,
+    };
+    let result = match &tree.kind {
+        ExprKind::Call(name, args) if name == "quad" && args.len() == 3 => quadratic_outcome(&tree, args, options),
+        _ => eval_on_backend(&tree, Some(expression), options),
+    };
+    timings.evaluate = stage_start.elapsed();
+    // A quantity keeps its unit in the exact text, the way fractions keep theirs; times typed as `h:mm:ss` stay durations.
+    let result = result.map(|outcome| match dimension {
+        DIMENSIONLESS => outcome,
+        TIME if has_duration(&tree) => Outcome { exact: Some(format_duration(outcome.value)), ..outcome },
+        _ => Outcome { exact: Some(format!("{} {}", outcome.exact.clone().unwrap_or_else(|| outcome.value.to_string()), unit_text(dimension))), ..outcome },
+    });
+    let result = result.map(|outcome| match (tag, outcome.exact.as_deref()) {
+        (Some(tag), Some(text)) => {
+            let exact = match text.strip_prefix('-') { Some(amount) => format!("-{}{}", tag, amount), None => format!("{}{}", tag, text) };
+            Outcome { exact: Some(exact), ..outcome }
+        }
+        _ => outcome,
+    });
+    // `char(n)` on its own shows the character, quoted so it can be typed back in; a control character shows its code.
+    let result = result.map(|outcome| match (&tree.kind, code_point(outcome.value)) {
+        (ExprKind::Call(name, _), Some(c)) if name == "char" => Outcome {
+            label: Some(if c.is_control() { format!("U+{:04X}", c as u32) } else { format!("\"{}\"", c) }),
+            note: Some(format!("U+{:04X} · UTF-8 {}", c as u32, utf8_bytes(c))), ..outcome
+        },
+        _ => outcome,
+    });
+    // `date(timestamp)` on its own shows the date; a date read by `unix` says which time zone it was read in.
+    let offset = options.utc_offset_minutes;
+    let result = result.map(|outcome| match &tree.kind {
+        ExprKind::Call(name, _) if name == "date" => {
+            let label = format_timestamp(outcome.value.floor() as i64, offset);
+            Outcome { label: Some(label), note: Some(utc_offset_text(offset)), ..outcome }
+        }
+        _ if calls(&tree, "unix") => {
+            let note = format!("Seconds since 1970-01-01 00:00 UTC, reading the date as {}", utc_offset_text(offset));
+            Outcome { note: Some(note), ..outcome }
+        }
+        _ => outcome,
+    });
+    // A conversion says which rates it used and how old they are.
+    let result = result.map(|outcome| if calls(&tree, "fx") { Outcome { note: Some(options.rates.describe(now_secs())), ..outcome } } else { outcome });
+    (result, timings)
+}
+
+// --- End of Evaluation Section ---
+
+// --- Numeric Backends Section ---
+
+/// The number types the evaluator can run on, cycled with F4.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub(crate) enum NumberBackend { Float, Decimal, Rational, BigInt, Money }
+
+impl NumberBackend {
+    pub(crate) fn name(self) -> &'static str {
+        match self {
+            NumberBackend::Float => "f64",
+            NumberBackend::Decimal => "decimal",
+            NumberBackend::Rational => "rational",
+            NumberBackend::BigInt => "big-int",
+            NumberBackend::Money => "money",
+        }
+    }
+
+    /// The backend with this display name, as a profile gives it.
+    pub(crate) fn named(name: &str) -> Option<Self> {
+        [
+            NumberBackend::Float, NumberBackend::Decimal, NumberBackend::Rational, NumberBackend::BigInt,
+            NumberBackend::Money,
+        ].into_iter().find(|backend| backend.name() == name)
+    }
+
+    pub(crate) fn next(self) -> Self {
+        match self {
+            NumberBackend::Float => NumberBackend::Decimal,
+            NumberBackend::Decimal => NumberBackend::Rational,
+            NumberBackend::Rational => NumberBackend::BigInt,
+            NumberBackend::BigInt => NumberBackend::Money,
+            NumberBackend::Money => NumberBackend::Float,
+        }
+    }
+}
+
+/// A value type the evaluator is generic over.
+pub(crate) trait Number: Clone {
+    /// Converts a float through its shortest decimal form, so literals like `0.1` stay exact.
+    fn from_float(x: f64) -> Result<Self, &'static str>;
+    /// Reads a literal as `TokenKind::Number` keeps it, without going through a float.
+    fn from_literal(text: &str) -> Result<Self, &'static str>;
+    fn to_float(&self) -> f64;
+    fn apply(op: char, a: &Self, b: &Self) -> Result<Self, &'static str>;
+    fn neg(&self) -> Self;
+    /// The exact textual form of the value.
+    fn render(&self) -> String;
+    /// Rejects values the options do not allow; only floats can overflow.
+    fn check(self, _options: &EvalOptions) -> Result<Self, &'static str> { Ok(self) }
+    /// A remark to show alongside the result, e.g. that it was rounded.
+    fn note(&self) -> Option<&'static str> { None }
+}
+
+impl Number for f64 {
+    fn from_float(x: f64) -> Result<Self, &'static str> { Ok(x) }
+    fn from_literal(text: &str) -> Result<Self, &'static str> { text.parse().map_err(|_| "Invalid number") }
+    fn to_float(&self) -> f64 { *self }
+    fn apply(op: char, a: &Self, b: &Self) -> Result<Self, &'static str> { apply_op(op, *a, *b) }
+    fn neg(&self) -> Self { -self }
+    fn render(&self) -> String { self.to_string() }
+    fn check(self, options: &EvalOptions) -> Result<Self, &'static str> { check_finite(self, options) }
+}
+
+/// The largest integer exponent the exact backends will expand.
+pub(crate) const MAX_EXACT_EXPONENT: i64 = 4096;
+
+/// About how many bits a power in the exact backends may take, so `(2^4096)^4096` fails at once instead of
+/// running for minutes where neither the time limit nor Esc can stop it.
+pub(crate) const MAX_EXACT_BITS: u64 = 1 << 20;
+
+/// Fails when raising a value of `bits` bits to the power `e` would go past `MAX_EXACT_BITS`.
+pub(crate) fn check_power_size(bits: u64, e: i64) -> Result<(), &'static str> {
+    if bits.saturating_mul(e.unsigned_abs()) > MAX_EXACT_BITS { Err("Result too large for exact arithmetic") } else { Ok(()) }
+}
+
+/// Splits a plain decimal string into an integer mantissa and the number of decimals.
+pub(crate) fn parse_decimal(text: &str) -> Option<(BigInt, u32)> {
+    let (int_part, frac_part) = text.split_once('.').unwrap_or((text, ""));
+    Some((format!("{}{}", int_part, frac_part).parse().ok()?, frac_part.len() as u32))
+}
+
+/// Reads a literal such as `12.5` or `4.7e3` as `mantissa · 10^exponent`, exactly.
+pub(crate) fn parse_literal(text: &str) -> Option<(BigInt, i64)> {
+    let (digits, power) = text.split_once('e').unwrap_or((text, "0"));
+    let (mantissa, scale) = parse_decimal(digits)?;
+    Some((mantissa, power.parse::<i64>().ok()? - i64::from(scale)))
+}
+
+/// A literal as an exact fraction.
+pub(crate) fn literal_rational(text: &str) -> Result<BigRational, &'static str> {
+    let (mantissa, exponent) = parse_literal(text).ok_or("Invalid number")?;
+    let power = BigInt::from(10).pow(exponent.unsigned_abs() as u32);
+    Ok(if exponent >= 0 { BigRational::from_integer(mantissa * power) } else { BigRational::new(mantissa, power) })
+}
+
+/// Returns the exponent of `^` when it is a small enough integer for exact expansion.
+pub(crate) fn exact_exponent(b: &BigRational) -> Option<i64> {
+    if !b.is_integer() { return None; }
+    b.to_integer().to_i64().filter(|e| e.abs() <= MAX_EXACT_EXPONENT)
+}
+
+impl Number for BigRational {
+    fn from_float(x: f64) -> Result<Self, &'static str> {
+        if !x.is_finite() { return Err("Infinity is not supported in exact arithmetic"); }
+        let (mantissa, scale) = parse_decimal(&x.to_string()).ok_or("Invalid number")?;
+        Ok(BigRational::new(mantissa, BigInt::from(10).pow(scale)))
+    }
+    fn from_literal(text: &str) -> Result<Self, &'static str> { literal_rational(text) }
+    fn to_float(&self) -> f64 { ToPrimitive::to_f64(self).unwrap_or(f64::NAN) }
+    fn apply(op: char, a: &Self, b: &Self) -> Result<Self, &'static str> {
+        match op {
+            '+' => Ok(a + b),
+            '-' => Ok(a - b),
+            '*' => Ok(a * b),
+            '/' => if b.is_zero() { Err("Division by zero") } else { Ok(a / b) },
+            '^' => match exact_exponent(b) {
+                Some(e) if e < 0 && a.is_zero() => Err("Division by zero"),
+                Some(e) => { check_power_size(a.numer().bits() + a.denom().bits(), e)?; Ok(Pow::pow(a, e as i32)) }
+                None => <Self as Number>::from_float(a.to_float().powf(b.to_float())),
+            },
+            _ => Err("Unknown operator"),
+        }
+    }
+    fn neg(&self) -> Self { -self }
+    fn render(&self) -> String { if self.is_integer() { self.numer().to_string() } else { format!("{}/{}", self.numer(), self.denom()) } }
+}
+
+/// An arbitrary-precision decimal: `mantissa / 10^scale`.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Decimal { pub(crate) mantissa: BigInt, pub(crate) scale: u32 }
+
+impl Decimal {
+    /// Digits kept after the point when a division does not terminate.
+    pub(crate) const DIVISION_DIGITS: u32 = 50;
+
+    /// Drops trailing zero decimals.
+    pub(crate) fn normalized(mut self) -> Self {
+        let ten = BigInt::from(10);
+        while self.scale > 0 && (&self.mantissa % &ten).is_zero() { self.mantissa /= &ten; self.scale -= 1; }
+        self
+    }
+
+    pub(crate) fn rescaled(&self, scale: u32) -> BigInt { &self.mantissa * BigInt::from(10).pow(scale - self.scale) }
+}
+
+impl Number for Decimal {
+    fn from_float(x: f64) -> Result<Self, &'static str> {
+        if !x.is_finite() { return Err("Infinity is not supported in exact arithmetic"); }
+        let (mantissa, scale) = parse_decimal(&x.to_string()).ok_or("Invalid number")?;
+        Ok(Decimal { mantissa, scale }.normalized())
+    }
+    fn from_literal(text: &str) -> Result<Self, &'static str> {
+        let (mantissa, exponent) = parse_literal(text).ok_or("Invalid number")?;
+        let scale = u32::try_from(-exponent.min(0)).map_err(|_| "Invalid number")?;
+        let mantissa = if exponent > 0 { mantissa * BigInt::from(10).pow(exponent as u32) } else { mantissa };
+        Ok(Decimal { mantissa, scale }.normalized())
+    }
+    fn to_float(&self) -> f64 { self.render().parse().unwrap_or(f64::NAN) }
+    fn apply(op: char, a: &Self, b: &Self) -> Result<Self, &'static str> {
+        let scale = a.scale.max(b.scale);
+        let result = match op {
+            '+' => Decimal { mantissa: a.rescaled(scale) + b.rescaled(scale), scale },
+            '-' => Decimal { mantissa: a.rescaled(scale) - b.rescaled(scale), scale },
+            '*' => Decimal { mantissa: &a.mantissa * &b.mantissa, scale: a.scale + b.scale },
+            '/' if b.mantissa.is_zero() => return Err("Division by zero"),
+            '/' => {
+                let numerator = &a.mantissa * BigInt::from(10).pow(b.scale + Self::DIVISION_DIGITS);
+                let denominator = &b.mantissa * BigInt::from(10).pow(a.scale);
+                Decimal { mantissa: numerator / denominator, scale: Self::DIVISION_DIGITS }
+            }
+            '^' => {
+                let exponent = BigRational::new(b.mantissa.clone(), BigInt::from(10).pow(b.scale));
+                match exact_exponent(&exponent) {
+                    // The scale grows with the power as much as the digits do, and counts towards the size the same way.
+                    Some(e) if e >= 0 => {
+                        check_power_size(a.mantissa.bits() + u64::from(a.scale) * 4, e)?;
+                        let scale = a.scale.checked_mul(e as u32).ok_or("Result too large for exact arithmetic")?;
+                        Decimal { mantissa: Pow::pow(&a.mantissa, e as u32), scale }
+                    }
+                    Some(e) => {
+                        let power = Self::apply('^', a, &Decimal { mantissa: BigInt::from(-e), scale: 0 })?;
+                        return Self::apply('/', &Decimal { mantissa: BigInt::from(1), scale: 0 }, &power);
+                    }
+                    None => return Self::from_float(a.to_float().powf(b.to_float())),
+                }
+            }
+            _ => return Err("Unknown operator"),
+        };
+        Ok(result.normalized())
+    }
+    fn neg(&self) -> Self { Decimal { mantissa: -&self.mantissa, scale: self.scale } }
+    fn render(&self) -> String {
+        let digits = self.mantissa.abs().to_string();
+        let sign = if self.mantissa.is_negative() { "-" } else { "" };
+        let scale = self.scale as usize;
+        if scale == 0 { return format!("{}{}", sign, digits); }
+        let padded = format!("{:0>width$}", digits, width = scale + 1);
+        let (int_part, frac_part) = padded.split_at(padded.len() - scale);
+        format!("{}{}.{}", sign, int_part, frac_part)
+    }
+}
+
+impl Number for BigInt {
+    fn from_float(x: f64) -> Result<Self, &'static str> {
+        if !x.is_finite() || x.fract() != 0.0 { return Err("Only integers are supported in big-int mode"); }
+        parse_decimal(&x.to_string()).map(|(mantissa, _)| mantissa).ok_or("Invalid number")
+    }
+    fn from_literal(text: &str) -> Result<Self, &'static str> {
+        let value = literal_rational(text)?;
+        if value.is_integer() { Ok(value.to_integer()) } else { Err("Only integers are supported in big-int mode") }
+    }
+    fn to_float(&self) -> f64 { ToPrimitive::to_f64(self).unwrap_or(f64::NAN) }
+    fn apply(op: char, a: &Self, b: &Self) -> Result<Self, &'static str> {
+        match op {
+            '+' => Ok(a + b),
+            '-' => Ok(a - b),
+            '*' => Ok(a * b),
+            '/' => if b.is_zero() { Err("Division by zero") } else { Ok(a / b) },
+            '^' => match b.to_i64().filter(|e| (0..=MAX_EXACT_EXPONENT).contains(e)) {
+                Some(e) => { check_power_size(a.bits(), e)?; Ok(Pow::pow(a, e as u32)) }
+                None => Err("Exponent must be a small non-negative integer in big-int mode"),
+            },
+            _ => Err("Unknown operator"),
+        }
+    }
+    fn neg(&self) -> Self { -self }
+    fn render(&self) -> String { self.to_string() }
+}
+
+/// A money amount in whole cents. Every operation is computed exactly and then rounded to the cent
+/// with banker's rounding; `rounded` records that a fraction of a cent was lost along the way.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Money { pub(crate) cents: BigInt, pub(crate) rounded: bool }
+
+impl Money {
+    pub(crate) fn from_exact(amount: BigRational, rounded: bool) -> Self {
+        let scaled = amount * BigRational::from_integer(BigInt::from(100));
+        let floor = scaled.floor();
+        let remainder = &scaled - &floor;
+        let half = BigRational::new(BigInt::from(1), BigInt::from(2));
+        let mut cents = floor.to_integer();
+        if remainder > half || (remainder == half && !(&cents % BigInt::from(2)).is_zero()) { cents += 1; }
+        Money { cents, rounded: rounded || !remainder.is_zero() }
+    }
+
+    pub(crate) fn exact(&self) -> BigRational { BigRational::new(self.cents.clone(), BigInt::from(100)) }
+}
+
+impl Number for Money {
+    fn from_float(x: f64) -> Result<Self, &'static str> {
+        if !x.is_finite() { return Err("Infinity is not supported in exact arithmetic"); }
+        let (mantissa, scale) = parse_decimal(&x.to_string()).ok_or("Invalid number")?;
+        Ok(Money::from_exact(BigRational::new(mantissa, BigInt::from(10).pow(scale)), false))
+    }
+    fn from_literal(text: &str) -> Result<Self, &'static str> { Ok(Money::from_exact(literal_rational(text)?, false)) }
+    fn to_float(&self) -> f64 { self.render().parse().unwrap_or(f64::NAN) }
+    fn apply(op: char, a: &Self, b: &Self) -> Result<Self, &'static str> {
+        Ok(Money::from_exact(<BigRational as Number>::apply(op, &a.exact(), &b.exact())?, a.rounded || b.rounded))
+    }
+    fn neg(&self) -> Self { Money { cents: -&self.cents, rounded: self.rounded } }
+    fn render(&self) -> String { Decimal { mantissa: self.cents.clone(), scale: 2 }.render() }
+    fn note(&self) -> Option<&'static str> { self.rounded.then_some("Rounded to the cent (half-even)") }
+}
+
+// --- End of Numeric Backends Section ---
+
+// --- Bytecode Section ---
+
+/// One instruction of the stack machine that runs compiled expressions.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Instruction {
+    Push(f64), Load(usize), Neg, Binary(char),
+    /// `a ± b%`: pops b and a, pushes a ± a·b/100.
+    PercentOf(char),
+    /// `fx`: pops an amount in the first currency, pushes it in the second.
+    Convert(String, String),
+    Call(String, usize), Fail(&'static str),
+}
+
+/// An expression lowered to bytecode, for evaluating the same tree many times on the f64 backend.
+/// Each instruction keeps the span of the node it came from, so errors point at the same text.
+#[derive(Clone, Debug)]
+pub(crate) struct Program { pub(crate) code: Vec<(Instruction, SourceSpan)>, pub(crate) max_stack: usize }
+
+/// What a compiled program depends on: the expression text, whether SI suffixes were read in it, and the slot names.
+type ProgramKey = (String, bool, Vec<String>);
+
+/// Programs compiled for recent expressions, so evaluating the same text again skips compiling.
+static PROGRAMS: LazyLock<Mutex<HashMap<ProgramKey, Arc<Program>>>> = LazyLock::new(Mutex::default);
+
+/// How many compiled programs are kept before the cache starts over.
+const PROGRAM_CACHE_SIZE: usize = 64;
+
+impl Program {
+    /// The program for `tree`, parsed from `source`, taken from the cache or compiled and cached.
+    pub(crate) fn cached(source: &str, si_suffixes: bool, tree: &Expr, slots: &[&str]) -> Arc<Program> {
+        let key = (source.to_string(), si_suffixes, slots.iter().map(|slot| slot.to_string()).collect());
+        let mut programs = PROGRAMS.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Some(program) = programs.get(&key) { return Arc::clone(program); }
+        if programs.len() >= PROGRAM_CACHE_SIZE { programs.clear(); }
+        Arc::clone(programs.entry(key).or_insert_with(|| Arc::new(Program::compile(tree, slots))))
+    }
+
+    /// Compiles a tree; names listed in `slots` are read from the values passed to `run`, in that order.
+    pub(crate) fn compile(tree: &Expr, slots: &[&str]) -> Program {
+        fn emit(expr: &Expr, slots: &[&str], code: &mut Vec<(Instruction, SourceSpan)>) {
+            let instruction = match &expr.kind {
+                ExprKind::Number(n, _) | ExprKind::Duration(n) => Instruction::Push(*n),
+                ExprKind::Infinity => Instruction::Push(f64::INFINITY),
+                ExprKind::Name(name) => match (slots.iter().position(|s| s == name), constant(name).or_else(|| unit(name).map(|u| u.0))) {
+                    (Some(slot), _) => Instruction::Load(slot),
+                    (None, Some(value)) => Instruction::Push(value),
+                    (None, None) => Instruction::Fail("Unknown name"),
+                },
+                ExprKind::Neg(operand) => { emit(operand, slots, code); Instruction::Neg }
+                ExprKind::Percent(operand) => { emit(operand, slots, code); code.push((Instruction::Push(100.0), expr.span)); Instruction::Binary('/') }
+                ExprKind::Binary(op @ ('+' | '-'), lhs, rhs) if matches!(rhs.kind, ExprKind::Percent(_)) => {
+                    let ExprKind::Percent(percent) = &rhs.kind else { unreachable!() };
+                    emit(lhs, slots, code); emit(percent, slots, code);
+                    Instruction::PercentOf(*op)
+                }
+                ExprKind::Binary(op, lhs, rhs) => { emit(lhs, slots, code); emit(rhs, slots, code); Instruction::Binary(*op) }
+                ExprKind::Call(..) if let Some((amount, from, to)) = conversion(expr) => {
+                    emit(amount, slots, code);
+                    Instruction::Convert(from.to_string(), to.to_string())
+                }
+                ExprKind::Call(name, args) => {
+                    args.iter().for_each(|arg| emit(arg, slots, code));
+                    Instruction::Call(name.clone(), args.len())
+                }
+            };
+            code.push((instruction, expr.span));
+        }
+        let mut code = Vec::new();
+        emit(tree, slots, &mut code);
+        let (mut depth, mut max_stack) = (0_usize, 0_usize);
+        for (instruction, _) in &code {
+            depth = match instruction {
+                Instruction::Push(_) | Instruction::Load(_) | Instruction::Fail(_) => depth + 1,
+                Instruction::Neg | Instruction::Convert(..) => depth,
+                Instruction::Binary(_) | Instruction::PercentOf(_) => depth - 1,
+                Instruction::Call(_, argc) => depth + 1 - argc,
+            };
+            max_stack = max_stack.max(depth);
+        }
+        Program { code, max_stack }
+    }
+
+    /// Runs the program with `values` bound to the compiled slots, checking for interruption before each
+    /// instruction as the tree walker does before each node.
+    pub(crate) fn run(&self, values: &[f64], options: &EvalOptions) -> Result<f64, EvalError> {
+        let mut stack: Vec<f64> = Vec::with_capacity(self.max_stack);
+        for (instruction, span) in &self.code {
+            options.check_interrupted()?;
+            let fail = |message: &str| EvalError::new(message, *span);
+            let result = match instruction {
+                Instruction::Push(value) => *value,
+                Instruction::Load(slot) => values[*slot],
+                Instruction::Neg => -stack.pop().unwrap(),
+                Instruction::Binary(op) => {
+                    let (b, a) = (stack.pop().unwrap(), stack.pop().unwrap());
+                    apply_op(*op, a, b).map_err(fail)?
+                }
+                Instruction::PercentOf(op) => {
+                    let (b, a) = (stack.pop().unwrap(), stack.pop().unwrap());
+                    apply_op(*op, a, a * b / 100.0).map_err(fail)?
+                }
+                Instruction::Convert(from, to) => {
+                    options.rates.convert(stack.pop().unwrap(), from, to).map_err(|message| EvalError { message, span: Some(*span) })?
+                }
+                Instruction::Call(name, argc) => {
+                    let value = call_function(name, &stack[stack.len() - argc..], options).map_err(fail)?;
+                    stack.truncate(stack.len() - argc);
+                    value
+                }
+                Instruction::Fail(message) => return Err(fail(message)),
+            };
+            stack.push(check_finite(result, options).map_err(fail)?);
+        }
+        Ok(stack.pop().unwrap())
+    }
+}
+
+// --- End of Bytecode Section ---
+
+// --- Matrix Section ---
+
+/// A small dense matrix, stored row by row. Written as `[1, 2; 3, 4]` in expressions.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Matrix { pub(crate) rows: usize, pub(crate) cols: usize, pub(crate) data: Vec<f64> }
+
+/// The result of a matrix expression: `det` gives a number, the other functions a matrix.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum MatrixValue { Scalar(f64), Matrix(Matrix) }
+
+/// The largest matrix side the solver accepts.
+pub(crate) const MATRIX_MAX_SIDE: usize = 8;
+
+impl Matrix {
+    pub(crate) fn get(&self, row: usize, col: usize) -> f64 { self.data[row * self.cols + col] }
+
+    pub(crate) fn transpose(&self) -> Matrix {
+        Matrix {
+            rows: self.cols, cols: self.rows,
+            data: (0..self.cols).flat_map(|c| (0..self.rows).map(move |r| (r, c))).map(|(r, c)| self.get(r, c)).collect(),
+        }
+    }
+
+    /// The largest sum of absolute values in a row, the scale the solver measures small pivots against.
+    pub(crate) fn norm(&self) -> f64 {
+        (0..self.rows).map(|r| (0..self.cols).map(|c| self.get(r, c).abs()).sum::<f64>()).fold(0.0, f64::max)
+    }
+
+    /// Gauss-Jordan elimination with partial pivoting, applying the same row steps to `rhs`; returns the reduced matrix and the determinant,
+    /// or `None` for a singular matrix: one with a pivot within rounding error of zero, relative to the size of the entries.
+    /// The determinant alone cannot tell, since the product of pivots can underflow to 0 for a well-conditioned matrix.
+    pub(crate) fn eliminate(&self, rhs: &mut Matrix) -> Result<Option<(Matrix, f64)>, &'static str> {
+        if self.rows != self.cols { return Err("Matrix must be square"); }
+        let (n, mut a, mut det) = (self.rows, self.clone(), 1.0);
+        let tolerance = n as f64 * f64::EPSILON * self.norm();
+        for col in 0..n {
+            let pivot = (col..n).max_by(|&x, &y| a.get(x, col).abs().total_cmp(&a.get(y, col).abs())).unwrap_or(col);
+            if a.get(pivot, col).abs() <= tolerance { return Ok(None); }
+            if pivot != col {
+                for m in [&mut a, &mut *rhs] { for c in 0..m.cols { m.data.swap(pivot * m.cols + c, col * m.cols + c); } }
+                det = -det;
+            }
+            let p = a.get(col, col);
+            det *= p;
+            for row in 0..n {
+                if row == col { continue; }
+                let factor = a.get(row, col) / p;
+                if factor == 0.0 { continue; }
+                for m in [&mut a, &mut *rhs] { for c in 0..m.cols { m.data[row * m.cols + c] -= factor * m.data[col * m.cols + c]; } }
+            }
+        }
+        for row in 0..n {
+            let p = a.get(row, row);
+            for m in [&mut a, &mut *rhs] { for c in 0..m.cols { m.data[row * m.cols + c] /= p; } }
+        }
+        Ok(Some((a, det)))
+    }
+
+    pub(crate) fn determinant(&self) -> Result<f64, &'static str> {
+        self.eliminate(&mut Matrix { rows: self.rows, cols: 0, data: Vec::new() }).map(|reduced| reduced.map_or(0.0, |(_, det)| det))
+    }
+
+    /// Solves `self * x = rhs` for `x`; with the identity as `rhs` that is the inverse.
+    pub(crate) fn solve(&self, rhs: &Matrix) -> Result<Matrix, &'static str> {
+        if rhs.rows != self.rows { return Err("Sizes do not match"); }
+        let mut x = rhs.clone();
+        match self.eliminate(&mut x)? { Some(_) => Ok(x), None => Err("Matrix is singular") }
+    }
+
+    pub(crate) fn inverse(&self) -> Result<Matrix, &'static str> {
+        let n = self.rows;
+        self.solve(&Matrix { rows: n, cols: n, data: (0..n * n).map(|i| if i % (n + 1) == 0 { 1.0 } else { 0.0 }).collect() })
+    }
+
+    /// The matrix as an expression literal at full precision, so a result can be fed back into another function
+    /// without losing digits; the side panel shows it rounded.
+    pub(crate) fn literal(&self) -> String {
+        let row = |r| (0..self.cols).map(|c| full_operand_text(self.get(r, c), None)).collect::<Vec<_>>().join(", ");
+        let rows: Vec<String> = (0..self.rows).map(row).collect();
+        format!("[{}]", rows.join("; "))
+    }
+}
+
+/// Splits `text` at each `separator` outside parentheses and brackets, returning each part with its offset.
+pub(crate) fn split_top_level(text: &str, separator: char) -> Vec<(usize, &str)> {
+    let (mut parts, mut depth, mut start) = (Vec::new(), 0i32, 0);
+    for (i, c) in text.char_indices() {
+        match c {
+            '(' | '[' => depth += 1,
+            ')' | ']' => depth -= 1,
+            c if c == separator && depth == 0 => { parts.push((start, &text[start..i])); start = i + c.len_utf8(); }
+            _ => {}
+        }
+    }
+    parts.push((start, &text[start..]));
+    parts
+}
+
+/// Evaluates a matrix expression such as `inv([1, 2; 3, 4])`; `offset` places error spans in the full input.
+pub(crate) fn evaluate_matrix(text: &str, offset: usize, options: &EvalOptions) -> Result<MatrixValue, EvalError> {
+    let lead = text.len() - text.trim_start().len();
+    let (text, offset) = (text.trim(), offset + lead);
+    let span = SourceSpan { start: offset, end: offset + text.len() };
+    if let Some(inner) = text.strip_prefix('[').and_then(|t| t.strip_suffix(']')) {
+        let mut data = Vec::new();
+        let mut cols = None;
+        let rows = split_top_level(inner, ';');
+        for &(row_start, row) in &rows {
+            let entries = split_top_level(row, ',');
+            if *cols.get_or_insert(entries.len()) != entries.len() { return Err(EvalError::new("Rows have different lengths", span)); }
+            for (entry_start, entry) in entries {
+                let start = offset + 1 + row_start + entry_start;
+                let value = evaluate_timed(entry, options).0.map(|o| o.value).map_err(|e| EvalError {
+                    span: Some(e.span.map_or(
+                        SourceSpan { start, end: start + entry.len() },
+                        |s| SourceSpan { start: start + s.start, end: start + s.end }
+                    )), ..e
+                })?;
+                data.push(value);
+            }
+        }
+        let cols = cols.unwrap_or(0);
+        if rows.len() > MATRIX_MAX_SIDE || cols > MATRIX_MAX_SIDE { return Err(EvalError::new("Matrix is too large", span)); }
+        return Ok(MatrixValue::Matrix(Matrix { rows: rows.len(), cols, data }));
+    }
+    let Some((name, args)) = text.strip_suffix(')').and_then(|t| t.split_once('(')) else { return Err(EvalError::new("Expected a matrix", span)) };
+    let args_offset = offset + name.len() + 1;
+    let matrices = split_top_level(args, ',').into_iter().map(|(start, arg)| match evaluate_matrix(arg, args_offset + start, options)? {
+        MatrixValue::Matrix(m) => Ok(m),
+        MatrixValue::Scalar(_) => Err(EvalError::new("Expected a matrix", SourceSpan { start: args_offset + start, end: args_offset + start + arg.len() })),
+    }).collect::<Result<Vec<_>, _>>()?;
+    let fail = |message| EvalError::new(message, span);
+    match (name.trim(), matrices.as_slice()) {
+        ("det", [m]) => m.determinant().map(MatrixValue::Scalar).map_err(fail),
+        ("inv", [m]) => m.inverse().map(MatrixValue::Matrix).map_err(fail),
+        ("transpose", [m]) => Ok(MatrixValue::Matrix(m.transpose())),
+        ("linsolve", [a, b]) => a.solve(b).map(MatrixValue::Matrix).map_err(fail),
+        ("det" | "inv" | "transpose" | "linsolve", _) => Err(fail("Wrong number of arguments")),
+        _ => Err(fail("Unknown matrix function")),
+    }
+}
+
+// --- End of Matrix Section ---
+
+/// How results are rounded to the displayed number of decimals.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub(crate) enum RoundingMode { HalfUp, HalfEven, Truncate }
+
+impl RoundingMode {
+    pub(crate) fn name(self) -> &'static str {
+        match self { RoundingMode::HalfUp => "Half-up", RoundingMode::HalfEven => "Half-even", RoundingMode::Truncate => "Truncate" }
+    }
+
+    pub(crate) fn next(self, up: bool) -> Self {
+        const ALL: [RoundingMode; 3] = [RoundingMode::HalfUp, RoundingMode::HalfEven, RoundingMode::Truncate];
+        let i = ALL.iter().position(|&m| m == self).unwrap_or(0);
+        ALL[if up { (i + 1) % ALL.len() } else { (i + ALL.len() - 1) % ALL.len() }]
+    }
+}
+
+/// How results are written out: the display settings the formatting functions read.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct FormatOptions {
+    pub(crate) decimals: usize, pub(crate) rounding: RoundingMode, pub(crate) group_separator: Option<char>, pub(crate) currency: &'static str,
+    pub(crate) snap_integers: bool, pub(crate) superscripts: bool, pub(crate) si_suffixes: bool,
+}
+
+impl Default for FormatOptions {
+    fn default() -> Self {
+        FormatOptions {
+            decimals: 8, rounding: RoundingMode::HalfUp, group_separator: None, currency: "$", snap_integers: false,
+            superscripts: false, si_suffixes: false,
+        }
+    }
+}
+
+/// How close a result has to be to an integer to be shown as one when the setting asks for it.
+pub(crate) const NEAR_INTEGER: f64 = 1e-9;
+
+/// Formats the result to the configured number of decimals and rounding mode, removing trailing zeros.
+/// With `snap_integers`, a result within `NEAR_INTEGER` of an integer, like `2.9999999996`, is shown as that integer.
+pub(crate) fn format_result(n: f64, format: &FormatOptions) -> String {
+    if n.is_nan() { "Error".to_string() }
+    else if n.is_infinite() { if n > 0.0 { "∞".to_string() } else { "-∞".to_string() } }
+    else if n.fract() == 0.0 { format!("{:.0}", n) }
+    else if format.snap_integers && (n - n.round()).abs() <= NEAR_INTEGER { format!("{:.0}", n.round() + 0.0) }
+    else { round_decimal(&n.to_string(), format.decimals, format.rounding) }
+}
+
+/// The exact decimal expansion of a finite float; every binary fraction has one.
+pub(crate) fn exact_decimal(value: f64) -> String {
+    let bits = value.abs().to_bits();
+    let (biased, fraction) = ((bits >> 52) as i32, bits & ((1 << 52) - 1));
+    let (mantissa, exponent) = if biased == 0 { (fraction, -1074) } else { (fraction | 1 << 52, biased - 1075) };
+    let sign = if value.is_sign_negative() && mantissa != 0 { "-" } else { "" };
+    if exponent >= 0 { return format!("{}{}", sign, BigInt::from(mantissa) << exponent as usize); }
+    // m / 2^k has exactly k decimal places: m * 5^k / 10^k.
+    let places = (-exponent) as usize;
+    let digits = format!("{:0>width$}", BigInt::from(mantissa) * BigInt::from(5).pow(places as u32), width = places + 1);
+    let (int_part, frac_part) = digits.split_at(digits.len() - places);
+    let frac_part = frac_part.trim_end_matches('0');
+    if frac_part.is_empty() { format!("{}{}", sign, int_part) } else { format!("{}{}.{}", sign, int_part, frac_part) }
+}
+
+/// Formats a result that may carry the exact text of a non-float backend.
+/// Exact decimals are rounded like floats; fractions and money amounts are shown as they are.
+pub(crate) fn format_outcome(n: f64, exact: Option<&str>, backend: NumberBackend, format: &FormatOptions) -> String {
+    match exact {
+        Some(text) if let Some((number, unit)) = text.split_once(' ') => format!("{} {}", format_outcome(n, Some(number), backend, format), unit),
+        Some(text) if text.contains(['/', ':']) || backend == NumberBackend::Money => text.to_string(),
+        Some(text) => round_decimal(text, format.decimals, format.rounding),
+        None => format_result(n, format),
+    }
+}
+
+/// Formats a result for use as the first operand of the next expression; fractions get parentheses.
+pub(crate) fn operand_text(n: f64, exact: Option<&str>, backend: NumberBackend, format: &FormatOptions) -> String {
+    let text = format_outcome(n, exact, backend, format);
+    if text.contains('/') && !text.contains(' ') { format!("({})", text) } else { text }
+}
+
+/// A result at full precision, to carry on calculating from: the exact text of the exact backends, or else the
+/// shortest text that reads back as the same float. Fractions get parentheses, as in `operand_text`.
+pub(crate) fn full_operand_text(n: f64, exact: Option<&str>) -> String {
+    let text = exact.map_or_else(|| n.to_string(), str::to_string);
+    if text.contains('/') && !text.contains(' ') { format!("({})", text) } else { text }
+}
+
+/// Formats a result for display only, applying digit grouping and the currency symbol on top of `format_outcome`.
+pub(crate) fn format_display(n: f64, exact: Option<&str>, backend: NumberBackend, format: &FormatOptions) -> String {
+    if format.si_suffixes && exact.is_none() && let Some(engineering) = si_suffixed(n, format) { return engineering; }
+    if format.superscripts && exact.is_none() && let Some(scientific) = superscript_scientific(n, format) { return scientific; }
+    let plain = format_outcome(n, exact, backend, format);
+    // An amount typed with a currency symbol keeps it; other money results get the one from the options.
+    let (symbol, plain) = match plain.trim_start_matches('-').chars().next().filter(|c| CURRENCY_TAGS.contains(c)) {
+        Some(tag) => (tag.to_string(), plain.replacen(tag, "", 1)),
+        None => (format.currency.to_string(), plain),
+    };
+    let grouped = match format.group_separator { Some(separator) => group_digits(&plain, separator), None => plain };
+    if backend != NumberBackend::Money { return grouped; }
+    match grouped.strip_prefix('-') { Some(amount) => format!("-{}{}", symbol, amount), None => format!("{}{}", symbol, grouped) }
+}
+
+/// The superscript form of an integer exponent: `-6` is `⁻⁶`.
+pub(crate) fn superscript(exponent: &str) -> String {
+    exponent.chars().map(|c| match c {
+        '-' => '⁻',
+        _ => c.to_digit(10).map_or(c, |d| ['⁰', '¹', '²', '³', '⁴', '⁵', '⁶', '⁷', '⁸', '⁹'][d as usize]),
+    }).collect()
+}
+
+/// `expression` with whole-number powers written as superscripts: `2 ^ 3` is `2³` and `3 m^2` is `3 m²`.
+/// Other powers stay as they are, and so does a power of a power, which would read as a single exponent.
+pub(crate) fn superscript_powers(expression: &str) -> String {
+    let mut out = String::new();
+    let mut rest = expression;
+    while let Some(caret) = rest.find('^') {
+        let base = rest[..caret].trim_end();
+        let after = rest[caret + 1..].trim_start();
+        let digits = after.strip_prefix('-').unwrap_or(after);
+        let length = digits.find(|c: char| !c.is_ascii_digit()).unwrap_or(digits.len());
+        let whole = length > 0 && !digits[length..].starts_with(['.', ':']);
+        let after_power = base.ends_with(|c: char| "⁰¹²³⁴⁵⁶⁷⁸⁹".contains(c));
+        if whole && !base.is_empty() && !after_power {
+            let exponent_length = after.len() - digits.len() + length;
+            out.push_str(base);
+            out.push_str(&superscript(&after[..exponent_length]));
+            rest = &after[exponent_length..];
+        } else {
+            out.push_str(&rest[..=caret]);
+            rest = &rest[caret + 1..];
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// `n` as a mantissa times a power of ten, like `1.5×10⁶`, when it is too large or too small to read well as digits.
+pub(crate) fn superscript_scientific(n: f64, format: &FormatOptions) -> Option<String> {
+    if !n.is_finite() || n == 0.0 || (1e-5..1e15).contains(&n.abs()) { return None; }
+    let text = format!("{:e}", n);
+    let (mantissa, exponent) = text.split_once('e')?;
+    let mut exponent: i32 = exponent.parse().ok()?;
+    let mut mantissa = round_decimal(mantissa, format.decimals, format.rounding);
+    // Rounding 9.99… up gives 10, which belongs to the next power.
+    if let Some(rest) = mantissa.strip_prefix("10").or_else(|| mantissa.strip_prefix("-10")) && !rest.starts_with(|c: char| c.is_ascii_digit()) {
+        mantissa = if n < 0.0 { "-1".to_string() } else { "1".to_string() };
+        exponent += 1;
+    }
+    Some(format!("{}×10{}", mantissa, superscript(&exponent.to_string())))
+}
+
+/// `n` in engineering notation with an SI suffix, as in `4.7k` or `338.6u`, which can be typed back in. Numbers
+/// from 1 to 1000 and those beyond the suffixes are left to the usual formatting.
+pub(crate) fn si_suffixed(n: f64, format: &FormatOptions) -> Option<String> {
+    if !n.is_finite() || n == 0.0 { return None; }
+    let text = format!("{:e}", n);
+    let (digits, exponent) = text.split_once('e')?;
+    let exponent: i32 = exponent.parse().ok()?;
+    let mut power = exponent.div_euclid(3) * 3;
+    let scaled: f64 = format!("{}e{}", digits, exponent - power).parse().ok()?;
+    let mut mantissa = round_decimal(&scaled.to_string(), format.decimals, format.rounding);
+    // Rounding 999.9… up gives 1000, which belongs to the next suffix.
+    if mantissa.trim_start_matches('-').split('.').next().is_some_and(|whole| whole.len() > 3) {
+        mantissa = if n < 0.0 { "-1".to_string() } else { "1".to_string() };
+        power += 3;
+    }
+    let (suffix, _) = SI_SUFFIXES.iter().find(|(_, p)| *p == power)?;
+    Some(format!("{}{}", mantissa, suffix))
+}
+
+/// Inserts `separator` between every three digits of the integer part of a plain number.
+pub(crate) fn group_digits(plain: &str, separator: char) -> String {
+    let (sign, digits) = match plain.strip_prefix('-') { Some(rest) => ("-", rest), None => ("", plain) };
+    let (int_part, frac_part) = match digits.split_once('.') { Some((i, f)) => (i, Some(f)), None => (digits, None) };
+    if !int_part.bytes().all(|d| d.is_ascii_digit()) { return plain.to_string(); }
+    let mut grouped = String::from(sign);
+    for (i, digit) in int_part.chars().enumerate() {
+        if i > 0 && (int_part.len() - i) % 3 == 0 { grouped.push(separator); }
+        grouped.push(digit);
+    }
+    if let Some(frac) = frac_part { grouped.push('.'); grouped.push_str(frac); }
+    grouped
+}
+
+/// Rounds a plain decimal string (as produced by `f64`'s `Display`) to `decimals` places.
+/// Working on the shortest decimal representation avoids binary artifacts like `2.675` rounding down.
+pub(crate) fn round_decimal(repr: &str, decimals: usize, mode: RoundingMode) -> String {
+    let (negative, digits) = match repr.strip_prefix('-') { Some(rest) => (true, rest), None => (false, repr) };
+    let (int_part, frac_part) = digits.split_once('.').unwrap_or((digits, ""));
+    let (kept, dropped) = frac_part.split_at(decimals.min(frac_part.len()));
+    let mut number: Vec<u8> = int_part.bytes().chain(kept.bytes()).collect();
+    let first_dropped = dropped.bytes().next().unwrap_or(b'0');
+    let round_up = match mode {
+        RoundingMode::Truncate => false,
+        RoundingMode::HalfUp => first_dropped >= b'5',
+        RoundingMode::HalfEven => first_dropped > b'5' || (first_dropped == b'5'
+            && (dropped.bytes().skip(1).any(|d| d != b'0') || number.last().is_some_and(|d| (d - b'0') % 2 == 1))),
+    };
+    if round_up {
+        let mut i = number.len();
+        loop {
+            if i == 0 { number.insert(0, b'1'); break; }
+            i -= 1;
+            if number[i] == b'9' { number[i] = b'0'; } else { number[i] += 1; break; }
+        }
+    }
+    let split = number.len() - kept.len();
+    let (int_digits, frac_digits) = number.split_at(split);
+    let mut result = String::from_utf8_lossy(int_digits).into_owned();
+    let frac = String::from_utf8_lossy(frac_digits);
+    let frac = frac.trim_end_matches('0');
+    if !frac.is_empty() { result.push('.'); result.push_str(frac); }
+    if negative && result.bytes().any(|d| d != b'0' && d != b'.') { result.insert(0, '-'); }
+    result
+}
+
+/// A helper function for comparing floating-point numbers.
+#[cfg(test)]
+pub(crate) fn assert_float_eq(a: f64, b: f64) { assert!((a - b).abs() < 1e-9, "Expected {}, got {}", b, a); }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test] fn test_simple_addition() { assert_float_eq(evaluate("5 + 3").unwrap(), 8.0); }
+    #[test] fn test_simple_subtraction() { assert_float_eq(evaluate("10 - 4").unwrap(), 6.0); }
+    #[test] fn test_simple_multiplication() { assert_float_eq(evaluate("7 * 3").unwrap(), 21.0); }
+    #[test] fn test_simple_division() { assert_float_eq(evaluate("20 / 4").unwrap(), 5.0); }
+    #[test] fn test_order_of_operations() { assert_float_eq(evaluate("5 + 2 * 3").unwrap(), 11.0); }
+    #[test] fn test_parentheses() { assert_float_eq(evaluate("(5 + 2) * 3").unwrap(), 21.0); }
+    #[test] fn test_floating_point() { assert_float_eq(evaluate("1.5 + 2.5").unwrap(), 4.0); }
+    #[test] fn test_unary_minus() { assert_float_eq(evaluate("10 * -2").unwrap(), -20.0); }
+    #[test] fn test_exponentiation() { assert_float_eq(evaluate("2 ^ 3").unwrap(), 8.0); }
+    #[test]
+    fn test_percentage() {
+        assert_float_eq(evaluate("50%").unwrap(), 0.5);
+        assert_float_eq(evaluate("200 + 10%").unwrap(), 220.0);
+        assert_float_eq(evaluate("100 * 50%").unwrap(), 50.0);
+        assert_float_eq(evaluate("100 - 25%").unwrap(), 75.0);
+    }
+    #[test] fn test_complex_expression() { assert_float_eq(evaluate("3 + 4 * 2 / ( 1 - 5 ) ^ 2").unwrap(), 3.5); }
+    #[test] fn test_division_by_zero() { assert!(evaluate("10 / 0").is_err()); }
+    #[test] fn test_syntax_error() { assert!(evaluate("5 * + 3").is_err()); }
+    #[test]
+    fn test_error_points_at_subexpression() {
+        let source = "1 + 4 / (2 - 2)";
+        let error = evaluate(source).unwrap_err();
+        assert_eq!(error.span, Some(SourceSpan { start: 4, end: 15 }));
+        assert_eq!(error.describe(source), "Division by zero in `4 / (2 - 2)`");
+        assert_eq!(evaluate("3 * ln(-1)").unwrap_err().describe("3 * ln(-1)"), "Domain error: logarithm of a non-positive number in `ln(-1)`");
+        assert_eq!(evaluate("(1 + 2").unwrap_err().message, "Missing closing parenthesis");
+        assert_eq!(evaluate("1 + 2)").unwrap_err().span, Some(SourceSpan { start: 5, end: 6 }));
+    }
+    #[test]
+    fn test_nesting_limit() {
+        let shallow = EvalOptions { max_depth: 4, ..EvalOptions::default() };
+        assert_float_eq(evaluate_with("((((1))))", &shallow).unwrap(), 1.0);
+        let error = evaluate_with("(((((1)))))", &shallow).unwrap_err();
+        assert_eq!(error.message, "Expression too deeply nested");
+        assert_eq!(error.span, Some(SourceSpan { start: 4, end: 5 }));
+        assert!(evaluate_with("-----1", &shallow).is_err());
+        let deep = "(".repeat(100_000) + "1" + &")".repeat(100_000);
+        assert_eq!(evaluate(&deep).unwrap_err().message, "Expression too deeply nested");
+    }
+    #[test]
+    fn test_list_functions() {
+        assert_float_eq(evaluate("sum(1, 2, 3) + mean(2, 4)").unwrap(), 9.0);
+        assert_float_eq(evaluate("stddev(2, 4, 4, 4, 5, 5, 7, 9)").unwrap(), (32.0_f64 / 7.0).sqrt());
+        assert_eq!(evaluate("stddev(1)").unwrap_err().message, "Standard deviation needs at least two values");
+        assert_eq!(compensated_sum(&[1e16, 1.0, -1e16]), 1.0);
+        assert_eq!(compensated_sum(&[0.1; 10]), 1.0);
+        let (mean, m2) = welford(&[1e9 + 4.0, 1e9 + 7.0, 1e9 + 13.0, 1e9 + 16.0]);
+        assert_eq!((mean, m2), (1e9 + 10.0, 90.0));
+    }
+    #[test]
+    fn test_bytecode_matches_tree() {
+        let options = EvalOptions { variables: HashMap::from([("x".to_string(), 3.0)]), ..EvalOptions::default() };
+        for expression in [
+            "2 + 3 * x ^ 2", "-(x - 5) / 2", "200 + 10%", "50% * x", "sum(1, x, mean(2, 4)) - sqrt(16)", "pi * e",
+            "1 / (x - 3)", "sqrt(-x)", "y + 1", "10 ^ 400",
+        ] {
+            let tree = parse_tokens(tokenize(expression, false).unwrap(), expression.len(), 64).unwrap();
+            let compiled = Program::compile(&tree, &["x"]).run(&[3.0], &options);
+            assert_eq!(compiled, eval_expr::<f64>(&tree, &options, None), "{expression}");
+        }
+        let tree = parse_tokens(tokenize("x * 2", false).unwrap(), 5, 64).unwrap();
+        let first = Program::cached("x * 2", false, &tree, &["x"]);
+        assert!(Arc::ptr_eq(&first, &Program::cached("x * 2", false, &tree, &["x"])));
+        assert!(!Arc::ptr_eq(&first, &Program::cached("x * 2", false, &tree, &["x", "y"])));
+        assert_eq!(evaluate_with("x * 2", &options), Ok(6.0));
+        let cancelled = EvalOptions { cancel: Some(Arc::new(AtomicBool::new(true))), ..options };
+        assert_eq!(first.run(&[3.0], &cancelled).unwrap_err().message, "Calculation cancelled");
+    }
+    /// Run with `cargo test --release -- --ignored` to check that the bytecode beats walking the tree.
+    #[test]
+    #[ignore]
+    fn bench_bytecode_vs_tree() {
+        let expression = "3 * x ^ 2 - 2 * x + sqrt(x + 1) / (1 + x%)";
+        let tree = parse_tokens(tokenize(expression, false).unwrap(), expression.len(), 64).unwrap();
+        let program = Program::compile(&tree, &["x"]);
+        let mut options = EvalOptions::default();
+        let iterations = 200_000;
+        let started = Instant::now();
+        let tree_sum: f64 = (0..iterations).map(|i| {
+            options.variables.insert("x".to_string(), i as f64);
+            eval_expr::<f64>(&tree, &options, None).unwrap()
+        }).sum();
+        let tree_time = started.elapsed();
+        let started = Instant::now();
+        let vm_sum: f64 = (0..iterations).map(|i| program.run(&[i as f64], &options).unwrap()).sum();
+        let vm_time = started.elapsed();
+        assert_eq!(tree_sum, vm_sum);
+        assert!(vm_time < tree_time, "bytecode took {vm_time:?}, tree-walking {tree_time:?} for {iterations} evaluations");
+    }
+    #[test]
+    fn test_superscripts() {
+        assert_eq!(superscript_powers("2 ^ 3 + 10 ^ -2"), "2³ + 10⁻²");
+        assert_eq!(superscript_powers("(1 + 2)^12 * 3 m^2"), "(1 + 2)¹² * 3 m²");
+        assert_eq!(superscript_powers("2 ^ 3 ^ 2"), "2³ ^ 2");
+        assert_eq!(superscript_powers("2 ^ 0.5 + 2 ^ (1 / 2)"), "2 ^ 0.5 + 2 ^ (1 / 2)");
+        let mut format = FormatOptions::default();
+        assert_eq!(format_display(1.5e20, None, NumberBackend::Float, &format), "150000000000000000000");
+        format.superscripts = true;
+        assert_eq!(format_display(1.5e20, None, NumberBackend::Float, &format), "1.5×10²⁰");
+        assert_eq!(format_display(-2.5e-7, None, NumberBackend::Float, &format), "-2.5×10⁻⁷");
+        assert_eq!(format_display(1234.5, None, NumberBackend::Float, &format), "1234.5");
+        format.decimals = 2;
+        assert_eq!(format_display(9.999e20, None, NumberBackend::Float, &format), "1×10²¹");
+    }
+    #[test]
+    fn test_exact_decimal() {
+        assert_eq!(exact_decimal(0.1), "0.1000000000000000055511151231257827021181583404541015625");
+        assert_eq!((exact_decimal(0.5), exact_decimal(-2.0), exact_decimal(1e20)), ("0.5".to_string(), "-2".to_string(), "100000000000000000000".to_string()));
+        assert_eq!(exact_decimal(f64::from_bits(1)).len(), 1076);
+        assert_eq!(exact_decimal(-0.0), "0");
+    }
+    #[test]
+    fn test_geometry_functions() {
+        assert_float_eq(evaluate("hypot(3, 4)").unwrap(), 5.0);
+        assert_float_eq(evaluate("dist(1, 1, 4, 5)").unwrap(), 5.0);
+        assert_float_eq(evaluate("circlearea(2)").unwrap(), 4.0 * std::f64::consts::PI);
+        assert_float_eq(evaluate("spherevol(3) / conevol(3, 4)").unwrap(), 3.0);
+        assert_float_eq(evaluate("triarea(3, 4, 5)").unwrap(), 6.0);
+        assert_eq!(evaluate("triarea(1, 2, 5)").unwrap_err().message, "The sides do not form a triangle");
+        assert_eq!(evaluate("circlearea(-1)").unwrap_err().message, "Lengths cannot be negative");
+        assert_eq!(evaluate_timed("hypot(3 m, 4 m)", &EvalOptions::default()).0.unwrap().exact.as_deref(), Some("5 m"));
+        assert_eq!(evaluate_timed("circlearea(1 m)", &EvalOptions::default()).0.unwrap().exact.as_deref(), Some("3.141592653589793 m^2"));
+    }
+    #[test]
+    fn test_molar_mass() {
+        assert_float_eq(evaluate("molarmass(\"C6H12O6\")").unwrap(), 180.156);
+        assert_float_eq(evaluate("mm(\"Ca(OH)2\") * 2").unwrap(), 148.184);
+        assert_float_eq(molar_mass("CuSO4·5H2O").unwrap(), 249.677);
+        assert_float_eq(molar_mass("K4[Fe(CN)6]").unwrap(), 368.345);
+        assert_eq!(molar_mass("Xx2"), Err("Unknown element in formula"));
+        assert_eq!(molar_mass("Ca(OH2"), Err("Unbalanced parentheses in formula"));
+        assert_eq!(evaluate("molarmass(\"H2O\"").unwrap_err().message, "Missing closing parenthesis");
+        assert_float_eq(evaluate("5 mm / 1 mm").unwrap(), 5.0);
+    }
+    #[test]
+    fn test_exact_literals() {
+        let exact = |expression: &str, backend| {
+            let options = EvalOptions { backend, si_suffixes: true, ..EvalOptions::default() };
+            evaluate_timed(expression, &options).0.map(|o| o.exact.unwrap_or_default()).map_err(|e| e.message)
+        };
+        assert_eq!(exact("99999999999999999999 + 1", NumberBackend::BigInt), Ok("100000000000000000000".to_string()));
+        assert_eq!(exact("12345678901234567890.123 - 12345678901234567890", NumberBackend::Decimal), Ok("0.123".to_string()));
+        assert_eq!(exact("4.7k * 100n", NumberBackend::Rational), Ok("47/100000".to_string()));
+        assert_eq!(exact("2.5k", NumberBackend::BigInt), Ok("2500".to_string()));
+        assert_eq!(exact("2.5", NumberBackend::BigInt), Err("Only integers are supported in big-int mode".to_string()));
+        for backend in [NumberBackend::BigInt, NumberBackend::Decimal, NumberBackend::Rational] {
+            assert_eq!(exact("(2 ^ 4096) ^ 4096", backend), Err("Result too large for exact arithmetic".to_string()));
+        }
+        assert_eq!(exact("(0.5 ^ 4096) ^ 4096", NumberBackend::Decimal), Err("Result too large for exact arithmetic".to_string()));
+        assert_eq!(exact("2 ^ 4096", NumberBackend::BigInt).map(|digits| digits.len()), Ok(1234));
+    }
+    #[test] fn test_functions_and_constants() { assert_float_eq(evaluate("sqrt(16) + ln(e) * 2").unwrap(), 6.0); }
+    #[test]
+    fn test_overflow() {
+        let saturate = EvalOptions { saturate: true, ..EvalOptions::default() };
+        assert_eq!(evaluate("10 ^ 400").unwrap_err().message, "Result too large");
+        assert_eq!(evaluate_with("10 ^ 400", &saturate), Ok(f64::INFINITY));
+        assert_eq!(evaluate_with("∞ - ∞", &saturate).unwrap_err().message, "Undefined result");
+        assert_eq!(format_result(f64::NEG_INFINITY, &FormatOptions::default()), "-∞");
+    }
+    #[test]
+    fn test_rounding_modes() {
+        assert_eq!(round_decimal("2.675", 2, RoundingMode::HalfUp), "2.68");
+        assert_eq!(round_decimal("2.665", 2, RoundingMode::HalfEven), "2.66");
+        assert_eq!(round_decimal("2.675", 2, RoundingMode::HalfEven), "2.68");
+        assert_eq!(round_decimal("2.6651", 2, RoundingMode::HalfEven), "2.67");
+        assert_eq!(round_decimal("2.679", 2, RoundingMode::Truncate), "2.67");
+        assert_eq!(round_decimal("-9.995", 2, RoundingMode::HalfUp), "-10");
+        assert_eq!(round_decimal("-0.001", 2, RoundingMode::HalfUp), "0");
+        assert_eq!(round_decimal("0.5", 0, RoundingMode::HalfEven), "0");
+    }
+}
@@ -0,0 +1,990 @@
+//! The event loop: mapping terminal events to actions.
+
+use crate::{app::*, engine::*, rates::{now_secs, refresh_rates}, session::autosave, shared::{merge_shared, share_changes}, tasks::*, ui::*};
+use crossterm::{
+    cursor::SetCursorStyle,
+    event::{
+        self, Event, KeyCode, KeyEvent, KeyEventKind, KeyEventState, KeyModifiers, KeyboardEnhancementFlags, MouseButton,
+        MouseEvent, MouseEventKind, PopKeyboardEnhancementFlags, PushKeyboardEnhancementFlags,
+    },
+    execute, terminal, Command,
+};
+use ratatui::prelude::*;
+use std::{io::{self, IsTerminal, Write}, sync::{atomic::{AtomicBool, Ordering}, Arc}, time::{Duration, Instant}};
+
+/// Handles the keys that move around a snippet: Tab and Shift+Tab cycle through the fields,
+/// Backspace edits the current one, and Esc leaves the snippet on the display as plain text.
+pub(crate) fn on_snippet_key(app: &mut App, code: KeyCode) {
+    let Some(fill) = &mut app.snippet else { return };
+    let count = fill.values.len();
+    match code {
+        KeyCode::Tab => fill.field = (fill.field + 1) % count,
+        KeyCode::BackTab => fill.field = (fill.field + count - 1) % count,
+        KeyCode::Backspace => { fill.values[fill.field].pop(); }
+        KeyCode::Esc => { app.snippet = None; return; }
+        _ => {}
+    }
+    app.display_value = fill.text().0;
+}
+
+/// Handles a key press in the snippet menu.
+pub(crate) fn on_snippet_menu_key(app: &mut App, code: KeyCode) {
+    let Some(Overlay::SnippetMenu(cursor)) = &mut app.overlay else { return };
+    match code {
+        KeyCode::Up => *cursor = cursor.saturating_sub(1),
+        KeyCode::Down => *cursor = (*cursor + 1).min(SNIPPETS.len() - 1),
+        KeyCode::Enter => { let index = *cursor; app.overlay = None; start_snippet(app, index); }
+        KeyCode::Esc => app.overlay = None,
+        _ => {}
+    }
+}
+
+/// Handles a key press in the function picker: ↑↓ and PgUp/PgDn move, Enter types the function.
+pub(crate) fn on_function_picker_key(app: &mut App, code: KeyCode) {
+    let Some(Overlay::FunctionPicker(cursor)) = &mut app.overlay else { return };
+    let last = picker_functions().len() - 1;
+    match code {
+        KeyCode::Up => *cursor = cursor.saturating_sub(1),
+        KeyCode::Down => *cursor = (*cursor + 1).min(last),
+        KeyCode::PageUp => *cursor = cursor.saturating_sub(10),
+        KeyCode::PageDown => *cursor = (*cursor + 10).min(last),
+        KeyCode::Enter => { let index = *cursor; app.overlay = None; insert_function(app, index); }
+        KeyCode::Esc => app.overlay = None,
+        _ => {}
+    }
+}
+
+/// Handles a key press in the compare view: ↑↓ move, Space or Enter marks an entry, replacing the older mark.
+pub(crate) fn on_compare_key(app: &mut App, code: KeyCode) {
+    let Some(Overlay::Compare(compare)) = &mut app.overlay else { return };
+    match code {
+        KeyCode::Up => compare.cursor = compare.cursor.saturating_sub(1),
+        KeyCode::Down => compare.cursor = (compare.cursor + 1).min(app.history.len().saturating_sub(1)),
+        KeyCode::Char(' ') | KeyCode::Enter if !compare.marked.contains(&compare.cursor) => {
+            if compare.marked.len() == 2 { compare.marked.remove(0); }
+            compare.marked.push(compare.cursor);
+        }
+        KeyCode::Char(' ') | KeyCode::Enter => compare.marked.retain(|&i| i != compare.cursor),
+        KeyCode::Esc => app.overlay = None,
+        _ => {}
+    }
+}
+
+/// Handles a key press in the usage view: Esc or Enter closes it, and Delete forgets the counts.
+pub(crate) fn on_usage_key(app: &mut App, code: KeyCode) {
+    match code {
+        KeyCode::Esc | KeyCode::Enter => app.overlay = None,
+        KeyCode::Delete => { app.usage.clear(); app.history.iter_mut().for_each(|entry| entry.reused = 0); }
+        _ => {}
+    }
+}
+
+/// Handles a key press in the key help: any key closes it.
+pub(crate) fn on_key_help_key(app: &mut App, _: KeyCode) { app.overlay = None; }
+
+/// Handles a key press in the theme preview: Esc or Enter closes it.
+pub(crate) fn on_theme_preview_key(app: &mut App, code: KeyCode) {
+    if matches!(code, KeyCode::Esc | KeyCode::Enter) { app.overlay = None; }
+}
+
+/// Handles a key press in the history menu: ↑↓ choose an item, PgUp/PgDn an older or newer entry, Enter or an item's first letter runs it.
+pub(crate) fn on_history_menu_key(app: &mut App, code: KeyCode) {
+    let Some(Overlay::HistoryMenu(menu)) = &mut app.overlay else { return };
+    match code {
+        KeyCode::Up => menu.cursor = menu.cursor.saturating_sub(1),
+        KeyCode::Down => menu.cursor = (menu.cursor + 1).min(HISTORY_MENU_ITEMS.len() - 1),
+        KeyCode::PageUp => menu.entry = menu.entry.saturating_sub(1),
+        KeyCode::PageDown => menu.entry = (menu.entry + 1).min(app.history.len().saturating_sub(1)),
+        KeyCode::Enter => { let (entry, item) = (menu.entry, menu.cursor); run_history_menu(app, entry, item); }
+        KeyCode::Char(c) if let Some(item) = HISTORY_MENU_ITEMS.iter().position(|name| name.starts_with(c.to_ascii_uppercase())) => {
+            let entry = menu.entry;
+            run_history_menu(app, entry, item);
+        }
+        KeyCode::Esc => app.overlay = None,
+        _ => {}
+    }
+}
+
+/// Handles a key press while the history tape has the focus: ↑↓ (or k/j) select, K/J move the entry among the
+/// pinned or unpinned ones, p pins, Enter opens the entry's menu and Esc gives the focus back.
+pub(crate) fn on_history_key(app: &mut App, code: KeyCode) {
+    let Some(cursor) = app.history_cursor else { return };
+    let order = history_order(&app.history);
+    let Some(position) = order.iter().position(|&i| i == cursor) else { app.history_cursor = None; return };
+    match code {
+        KeyCode::Up | KeyCode::Char('k') => app.history_cursor = Some(order[position.saturating_sub(1)]),
+        KeyCode::Down | KeyCode::Char('j') => app.history_cursor = Some(order[(position + 1).min(order.len() - 1)]),
+        KeyCode::Char(c @ ('K' | 'J')) => {
+            let neighbour = if c == 'K' { position.checked_sub(1) } else { Some(position + 1) };
+            if let Some(&other) = neighbour.and_then(|p| order.get(p)) && app.history[other].pinned == app.history[cursor].pinned {
+                app.history.swap(cursor, other);
+                app.history_cursor = Some(other);
+            }
+        }
+        KeyCode::Char('p') => app.history[cursor].pinned = !app.history[cursor].pinned,
+        KeyCode::Char('a') => open_variable_prompt(app, cursor),
+        KeyCode::Enter => app.overlay = Some(Overlay::HistoryMenu(HistoryMenu { entry: cursor, cursor: 0, at: None })),
+        KeyCode::Esc => app.history_cursor = None,
+        _ => {}
+    }
+}
+
+/// Handles a key press in the chart of results: the arrows move the highlight, Enter inserts the highlighted result.
+pub(crate) fn on_chart_key(app: &mut App, code: KeyCode) {
+    let Some(Overlay::Chart(index)) = app.overlay else { return };
+    let entries = chart_entries(&app.history);
+    let Some(position) = entries.iter().position(|&i| i == index) else { app.overlay = None; return };
+    match code {
+        KeyCode::Left | KeyCode::Char('h') => app.overlay = Some(Overlay::Chart(entries[position.saturating_sub(1)])),
+        KeyCode::Right | KeyCode::Char('l') => app.overlay = Some(Overlay::Chart(entries[(position + 1).min(entries.len() - 1)])),
+        KeyCode::Home => app.overlay = entries.first().copied().map(Overlay::Chart),
+        KeyCode::End => app.overlay = entries.last().copied().map(Overlay::Chart),
+        KeyCode::Enter => { app.overlay = None; run_history_menu(app, index, 2); }
+        KeyCode::Esc | KeyCode::Char('q') => app.overlay = None,
+        _ => {}
+    }
+}
+
+/// Handles a key press in the note editor; an empty note removes the label.
+pub(crate) fn on_note_key(app: &mut App, code: KeyCode) {
+    let Some(Overlay::NoteEditor(note)) = &mut app.overlay else { return };
+    match code {
+        KeyCode::Char(c) if note.chars().count() < NOTE_MAX_LENGTH => note.push(c),
+        KeyCode::Backspace => { note.pop(); }
+        KeyCode::Enter => {
+            let note = note.trim().to_string();
+            if let Some(entry) = app.history.last_mut() { entry.note = Some(note).filter(|note| !note.is_empty()); }
+            app.overlay = None;
+        }
+        KeyCode::Esc => app.overlay = None,
+        _ => {}
+    }
+}
+
+/// Handles a key press in the prompt for a variable name; a name the calculator already uses keeps it open.
+pub(crate) fn on_variable_key(app: &mut App, code: KeyCode) {
+    let Some(Overlay::VariablePrompt(_, name)) = &mut app.overlay else { return };
+    match code {
+        KeyCode::Char(c) if (c.is_ascii_alphanumeric() || c == '_') && name.len() < NOTE_MAX_LENGTH => name.push(c),
+        KeyCode::Backspace => { name.pop(); }
+        KeyCode::Enter => assign_variable(app),
+        KeyCode::Esc => app.overlay = None,
+        _ => {}
+    }
+}
+
+/// Handles a key press while the settings overlay is open.
+pub(crate) fn on_settings_key(app: &mut App, code: KeyCode) {
+    match code {
+        KeyCode::Up => app.settings_cursor = app.settings_cursor.saturating_sub(1),
+        KeyCode::Down => app.settings_cursor = (app.settings_cursor + 1).min(SETTING_LABELS.len() - 1),
+        KeyCode::Left | KeyCode::Right => { app.settings.adjust(app.settings_cursor, code == KeyCode::Right); app.refresh_result(); }
+        KeyCode::Esc | KeyCode::F(2) => app.overlay = None,
+        _ => {}
+    }
+}
+
+/// Applies a digit, `.`, or Backspace to a numeric form field; returns whether the key was used.
+pub(crate) fn edit_number_field(field: &mut String, code: KeyCode) -> bool {
+    match code {
+        KeyCode::Char(c @ '0'..='9') if field == "0" => *field = c.to_string(),
+        KeyCode::Char(c @ '0'..='9') if field.len() < 12 => field.push(c),
+        KeyCode::Char('.') if !field.contains('.') => field.push('.'),
+        KeyCode::Backspace => { field.pop(); if field.is_empty() { field.push('0'); } }
+        _ => return false,
+    }
+    true
+}
+
+/// Handles a key press while the tip-split helper is open: digits edit the bill, `+`/`-` change the selected row.
+pub(crate) fn on_tip_key(app: &mut App, code: KeyCode) {
+    let Some(Overlay::TipSplit(tip)) = &mut app.overlay else { return };
+    if edit_number_field(&mut tip.bill, code) { return; }
+    match code {
+        KeyCode::Up => tip.cursor = tip.cursor.saturating_sub(1),
+        KeyCode::Down => tip.cursor = (tip.cursor + 1).min(2),
+        KeyCode::Char('+') | KeyCode::Right => match tip.cursor {
+            1 => tip.tip_percent = (tip.tip_percent + 1).min(100),
+            2 => tip.people = (tip.people + 1).min(99),
+            _ => {}
+        },
+        KeyCode::Char('-') | KeyCode::Left => match tip.cursor {
+            1 => tip.tip_percent = tip.tip_percent.saturating_sub(1),
+            2 => tip.people = (tip.people - 1).max(1),
+            _ => {}
+        },
+        KeyCode::Esc | KeyCode::F(6) => app.overlay = None,
+        _ => {}
+    }
+}
+
+/// Handles a key press in the amortization view: digits edit the selected field, PgUp/PgDn scroll, `e` exports CSV.
+pub(crate) fn on_amortization_key(app: &mut App, code: KeyCode) {
+    let Some(Overlay::Amortization(view)) = &mut app.overlay else { return };
+    if edit_number_field(&mut view.fields[view.cursor], code) { return; }
+    match code {
+        KeyCode::Up => view.cursor = view.cursor.saturating_sub(1),
+        KeyCode::Down => view.cursor = (view.cursor + 1).min(2),
+        KeyCode::PageDown => view.scroll = (view.scroll + 12).min(view.schedule().len().saturating_sub(Amortization::PAGE)),
+        KeyCode::PageUp => view.scroll = view.scroll.saturating_sub(12),
+        KeyCode::Home => view.scroll = 0,
+        KeyCode::Char('e') => {
+            let (path, mut csv) = ("amortization.csv", Vec::new());
+            if write_amortization_csv(&view.schedule(), &mut csv).is_ok() {
+                let notice = format!("Saved the schedule to {}", path);
+                app.effects.push(Effect::WriteFile { path: path.to_string(), contents: String::from_utf8_lossy(&csv).into_owned(), notice });
+            }
+        }
+        KeyCode::Esc | KeyCode::F(7) => app.overlay = None,
+        _ => {}
+    }
+}
+/// Handles a key press in the mortgage wizard; Enter puts the monthly payment on the display.
+pub(crate) fn on_mortgage_key(app: &mut App, code: KeyCode) {
+    let Some(Overlay::Mortgage(wizard)) = &mut app.overlay else { return };
+    if edit_number_field(&mut wizard.fields[wizard.cursor], code) { return; }
+    match code {
+        KeyCode::Up => wizard.cursor = wizard.cursor.saturating_sub(1),
+        KeyCode::Down | KeyCode::Tab => wizard.cursor = (wizard.cursor + 1).min(Mortgage::LABELS.len() - 1),
+        KeyCode::Enter => if let Some(summary) = wizard.summary() {
+            let monthly = (summary.monthly * 100.0).round() / 100.0;
+            app.overlay = None;
+            app.clear_messages();
+            app.last_expression = None;
+            app.last_result = Some(monthly);
+            (app.last_exact, app.last_label) = (None, None);
+            app.show_result(operand_text(monthly, None, NumberBackend::Float, &app.settings.format_options()));
+        },
+        KeyCode::Esc => app.overlay = None,
+        _ => {}
+    }
+}
+
+/// Handles a key press in the command palette: typing filters, Enter runs the selected action.
+pub(crate) fn on_palette_key(app: &mut App, code: KeyCode) {
+    let Some(Overlay::Palette(palette)) = &mut app.overlay else { return };
+    match code {
+        KeyCode::Char(c) => { palette.query.push(c); palette.cursor = 0; }
+        KeyCode::Backspace => { palette.query.pop(); palette.cursor = 0; }
+        KeyCode::Up => palette.cursor = palette.cursor.saturating_sub(1),
+        KeyCode::Down => palette.cursor = (palette.cursor + 1).min(palette_matches(&palette.query).len().saturating_sub(1)),
+        KeyCode::Enter => {
+            let selected = palette_matches(&palette.query).get(palette.cursor).copied();
+            app.overlay = None;
+            if let Some(i) = selected { (PALETTE_ACTIONS[i].1)(app); }
+        }
+        KeyCode::Esc => app.overlay = None,
+        _ => {}
+    }
+}
+
+/// The window title the settings ask for, e.g. `calc — 154.88`, or `None` to leave the terminal's own title alone.
+pub(crate) fn window_title(app: &App) -> Option<String> {
+    if !app.settings.window_title { return None; }
+    Some(match app.last_result {
+        Some(value) => format!("calc — {}", format_display(value, app.last_exact.as_deref(), app.settings.backend, &app.settings.format_options())),
+        None => "calc".to_string(),
+    })
+}
+
+/// The OSC 0 sequence that sets the window title, with control characters left out.
+pub(crate) fn title_sequence(title: &str) -> String { format!("\x1b]0;{}\x07", title.chars().filter(|c| !c.is_control()).collect::<String>()) }
+
+/// The escape sequences that set the window title, or restore the terminal's own with `None`. The original is saved
+/// on the terminal's title stack (XTWINOPS 22/23) before the first change, since it cannot be read back.
+pub(crate) fn title_change(active: bool, title: Option<&str>) -> String {
+    match title {
+        Some(title) if active => title_sequence(title),
+        Some(title) => format!("\x1b[22;0t{}", title_sequence(title)),
+        None if active => "\x1b[23;0t".to_string(),
+        None => String::new(),
+    }
+}
+
+/// The escape sequence for the cursor shape the settings ask for.
+pub(crate) fn cursor_sequence(mode: CursorMode) -> String {
+    let mut sequence = String::new();
+    let style = if mode == CursorMode::Steady { SetCursorStyle::SteadyBar } else { SetCursorStyle::BlinkingBar };
+    let _ = style.write_ansi(&mut sequence);
+    sequence
+}
+
+/// Signals a new error the way the settings ask: a terminal bell, or a short flash of the display border.
+pub(crate) fn signal_error(app: &mut App, host: &mut impl Host) -> io::Result<()> {
+    match app.settings.error_feedback {
+        ErrorFeedback::Off => Ok(()),
+        ErrorFeedback::Bell => host.control("\x07"),
+        ErrorFeedback::Flash => { app.flash_until = Some(Instant::now() + Duration::from_millis(200)); Ok(()) }
+    }
+}
+
+// --- Event Section ---
+
+/// An input event as the app loop sees it, so the loop can be driven by a real terminal or by a test script.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum AppEvent {
+    Key(KeyCode, KeyModifiers),
+    Click { column: u16, row: u16, button: MouseButton },
+    Resize(u16, u16),
+}
+
+impl AppEvent {
+    /// Translates a terminal event; pointer movement, releases, focus changes and pastes have no counterpart.
+    pub(crate) fn from_terminal(event: Event) -> Option<AppEvent> {
+        match event {
+            Event::Key(key) if key.kind == KeyEventKind::Release => None,
+            // The numpad's decimal key means a decimal point even where the layout makes it a comma, which would
+            // separate arguments here. Terminals only say a key is on the numpad under the kitty keyboard protocol.
+            Event::Key(KeyEvent { code: KeyCode::Char(','), modifiers, state, .. }) if state.contains(KeyEventState::KEYPAD) => {
+                Some(AppEvent::Key(KeyCode::Char('.'), modifiers))
+            }
+            // AltGr arrives as Ctrl+Alt on Windows. A symbol that comes with both was typed with AltGr, such as `^`
+            // or `{` on many European layouts, so it counts as typed plainly rather than as a shortcut.
+            Event::Key(KeyEvent { code: KeyCode::Char(c), modifiers, .. })
+                if modifiers.contains(KeyModifiers::CONTROL | KeyModifiers::ALT) && !c.is_alphanumeric() =>
+            {
+                Some(AppEvent::Key(KeyCode::Char(c), modifiers.difference(KeyModifiers::CONTROL | KeyModifiers::ALT)))
+            }
+            Event::Key(key) => Some(AppEvent::Key(key.code, key.modifiers)),
+            Event::Mouse(MouseEvent { kind: MouseEventKind::Down(button), column, row, .. }) => Some(AppEvent::Click { column, row, button }),
+            Event::Resize(width, height) => Some(AppEvent::Resize(width, height)),
+            _ => None,
+        }
+    }
+}
+
+/// Where the app loop gets its events and sends the escape sequences for the window title, cursor shape and bell.
+pub(crate) trait Host {
+    /// The next event, or `None` if nothing arrived within `timeout`. Without a timeout it waits for one.
+    fn next_event(&mut self, timeout: Option<Duration>) -> io::Result<Option<AppEvent>>;
+    fn control(&mut self, sequence: &str) -> io::Result<()>;
+}
+
+/// Whether the kitty keyboard protocol was switched on, so it is switched off again on the way out.
+static ENHANCED_KEYS: AtomicBool = AtomicBool::new(false);
+
+/// Asks the terminal to report keys unambiguously (the kitty keyboard protocol) where it supports that, so chords
+/// like `Shift+Enter` and `Ctrl+Enter` and the numpad keys can be told apart. Other terminals keep sending plain
+/// keys, where those chords arrive as `Enter`. Call it in raw mode, which the support query needs.
+pub(crate) fn enable_enhanced_keys() -> io::Result<()> {
+    if terminal::supports_keyboard_enhancement().unwrap_or(false) {
+        execute!(terminal_output(), PushKeyboardEnhancementFlags(KeyboardEnhancementFlags::DISAMBIGUATE_ESCAPE_CODES))?;
+        ENHANCED_KEYS.store(true, Ordering::Relaxed);
+    }
+    Ok(())
+}
+
+/// Restores the terminal's own key reporting if `enable_enhanced_keys` changed it.
+pub(crate) fn disable_enhanced_keys() {
+    if ENHANCED_KEYS.swap(false, Ordering::Relaxed) { let _ = execute!(terminal_output(), PopKeyboardEnhancementFlags); }
+}
+
+/// Where the UI and escape sequences go: stdout, or stderr while stdout is captured, as in `$(calc --print-on-exit)`.
+pub(crate) fn terminal_output() -> Box<dyn Write + Send> {
+    if io::stdout().is_terminal() { Box::new(io::stdout()) } else { Box::new(io::stderr()) }
+}
+
+/// Makes up for how crossterm reads the Windows console API, which every Windows terminal goes through.
+pub(crate) struct ConsoleEvents {
+    /// Whether the events come from the Windows console.
+    windows: bool,
+    /// Whether the left button went down and has not come up since.
+    left_down: bool,
+}
+
+impl ConsoleEvents {
+    pub(crate) fn new(windows: bool) -> Self { ConsoleEvents { windows, left_down: false } }
+
+    /// Adjusts an event on Windows. The second click of a double-click reaches crossterm as a double-click
+    /// record, which it drops, so only its release comes through; that release counts as the click. A resize
+    /// reports the size of the screen buffer, which in conhost holds thousands of rows of scrollback, so the
+    /// window's size is asked for through `window` instead.
+    pub(crate) fn adapt(&mut self, event: Event, window: impl FnOnce() -> io::Result<(u16, u16)>) -> Event {
+        match event {
+            Event::Mouse(MouseEvent { kind: MouseEventKind::Down(MouseButton::Left), .. }) => { self.left_down = true; event }
+            Event::Mouse(mouse @ MouseEvent { kind: MouseEventKind::Up(MouseButton::Left), .. }) if !std::mem::take(&mut self.left_down) && self.windows => {
+                Event::Mouse(MouseEvent { kind: MouseEventKind::Down(MouseButton::Left), ..mouse })
+            }
+            Event::Resize(..) if self.windows => window().map_or(event, |(width, height)| Event::Resize(width, height)),
+            event => event,
+        }
+    }
+}
+
+/// The real terminal: events come from crossterm and escape sequences go to `terminal_output`.
+pub(crate) struct TerminalHost { pub(crate) console: ConsoleEvents }
+
+impl Host for TerminalHost {
+    fn next_event(&mut self, timeout: Option<Duration>) -> io::Result<Option<AppEvent>> {
+        if let Some(timeout) = timeout && !event::poll(timeout)? { return Ok(None); }
+        Ok(AppEvent::from_terminal(self.console.adapt(event::read()?, terminal::size)))
+    }
+    fn control(&mut self, sequence: &str) -> io::Result<()> {
+        if sequence.is_empty() { return Ok(()); }
+        let mut output = terminal_output();
+        output.write_all(sequence.as_bytes())?;
+        output.flush()
+    }
+}
+
+/// The key handler of an overlay with focus. The history tape is left out: Alt+H reaches the app while it has focus.
+pub(crate) fn overlay_keys(focus: Focus) -> Option<fn(&mut App, KeyCode)> {
+    match focus {
+        Focus::Palette => Some(on_palette_key),
+        Focus::Settings => Some(on_settings_key),
+        Focus::KeyHelp => Some(on_key_help_key),
+        Focus::Usage => Some(on_usage_key),
+        Focus::ThemePreview => Some(on_theme_preview_key),
+        Focus::TipSplit => Some(on_tip_key),
+        Focus::Amortization => Some(on_amortization_key),
+        Focus::Mortgage => Some(on_mortgage_key),
+        Focus::SnippetMenu => Some(on_snippet_menu_key),
+        Focus::FunctionPicker => Some(on_function_picker_key),
+        Focus::NoteEditor => Some(on_note_key),
+        Focus::VariablePrompt => Some(on_variable_key),
+        Focus::Compare => Some(on_compare_key),
+        Focus::Chart => Some(on_chart_key),
+        Focus::HistoryMenu => Some(on_history_menu_key),
+        Focus::History | Focus::Display(_) => None,
+    }
+}
+
+/// A change the user asks for, in the app's own terms. Every interaction goes through `update` as one of these,
+/// so a recorded list of messages replays a session.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Message {
+    /// A calculator button, by its label.
+    ButtonPressed(String),
+    /// A button's secondary function, used with a right-click.
+    SecondPressed(String),
+    /// A key for the display or for the overlay that has focus.
+    KeyTyped(KeyCode, KeyModifiers),
+    /// `=`, with what to do with the result.
+    Evaluate(Followup),
+    HistoryMenuOpened { entry: usize, at: (u16, u16) },
+    HistoryMenuPicked { entry: usize, item: usize },
+    HistoryMenuClosed,
+    Resized(u16, u16),
+    Quit,
+}
+
+/// The message an input event stands for, with clicks resolved against the buttons as last drawn.
+pub(crate) fn message_for(app: &App, event: AppEvent) -> Option<Message> {
+    let hit = |rect: &Rect, column: u16, row: u16| rect.contains((column, row).into());
+    match event {
+        AppEvent::Key(KeyCode::Char('q'), modifiers) if modifiers.contains(KeyModifiers::CONTROL) => Some(Message::Quit),
+        AppEvent::Key(KeyCode::Enter, modifiers) if matches!(app.focus(), Focus::Display(_)) => Some(Message::Evaluate(match modifiers {
+            _ if modifiers.contains(KeyModifiers::SHIFT) => Followup::KeepExpression,
+            _ if modifiers.contains(KeyModifiers::CONTROL) => Followup::Copy,
+            _ => Followup::Show,
+        })),
+        AppEvent::Key(code, modifiers) => Some(Message::KeyTyped(code, modifiers)),
+        AppEvent::Resize(width, height) => Some(Message::Resized(width, height)),
+        // While the history menu is open a click either picks one of its items or closes it.
+        AppEvent::Click { column, row, button } if let Some(Overlay::HistoryMenu(menu)) = &app.overlay => {
+            let item = app.button_rects.iter()
+                .find_map(|(rect, label)| label.strip_prefix("menu:").filter(|_| hit(rect, column, row)).and_then(|item| item.parse().ok()));
+            Some(match item {
+                Some(item) if button == MouseButton::Left => Message::HistoryMenuPicked { entry: menu.entry, item },
+                _ => Message::HistoryMenuClosed,
+            })
+        }
+        // A right-click opens a history entry's menu, or uses a button's secondary function.
+        AppEvent::Click { column, row, button: MouseButton::Right } => {
+            if let Some(&(_, entry)) = app.history_rows.iter().find(|(rect, _)| hit(rect, column, row)) {
+                return Some(Message::HistoryMenuOpened { entry, at: (column, row) });
+            }
+            app.button_rects.iter().find(|(rect, _)| hit(rect, column, row)).map(|(_, value)| Message::SecondPressed(value.clone()))
+        }
+        AppEvent::Click { column, row, button: MouseButton::Left } => app.button_rects.iter().find(|(rect, _)| hit(rect, column, row)).map(|(_, label)| {
+            if label == "=" { Message::Evaluate(Followup::Show) } else { Message::ButtonPressed(label.clone()) }
+        }),
+        AppEvent::Click { .. } => None,
+    }
+}
+
+/// Applies a message to the app and hands back the effects it queued, leaving them to the caller to carry out.
+pub(crate) fn update(app: &mut App, message: Message) -> Vec<Effect> {
+    match message {
+        Message::ButtonPressed(label) => on_click(app, &label),
+        Message::SecondPressed(label) => press_second(app, &label),
+        Message::KeyTyped(code, modifiers) => on_key(app, code, modifiers),
+        Message::Evaluate(followup) => evaluate_then(app, followup),
+        Message::HistoryMenuOpened { entry, at } => app.overlay = Some(Overlay::HistoryMenu(HistoryMenu { entry, cursor: 0, at: Some(at) })),
+        Message::HistoryMenuPicked { entry, item } => run_history_menu(app, entry, item),
+        Message::HistoryMenuClosed => app.overlay = None,
+        // The popup anchored at the old layout closes; the terminal itself is resized as an effect.
+        Message::Resized(width, height) => {
+            if matches!(&app.overlay, Some(Overlay::HistoryMenu(menu)) if menu.at.is_some()) { app.overlay = None; }
+            app.display_scroll = 0;
+            app.effects.push(Effect::Resize(width, height));
+        }
+        Message::Quit => { save_usage(app); app.should_quit = true; }
+    }
+    std::mem::take(&mut app.effects)
+}
+
+/// The keys a composed character stands for. A dead `^` followed by a digit composes a superscript on many layouts,
+/// and some layouts have keys for `×`, `÷` and the minus sign.
+pub(crate) fn composed_keys(c: char) -> Option<&'static [&'static str]> {
+    const POWERS: [[&str; 2]; 10] = [["^", "0"], ["^", "1"], ["^", "2"], ["^", "3"], ["^", "4"], ["^", "5"], ["^", "6"], ["^", "7"], ["^", "8"], ["^", "9"]];
+    Some(match c {
+        '×' | '·' => &["*"], '÷' => &["/"], '−' => &["-"],
+        '⁰' => &POWERS[0], '¹' => &POWERS[1], '²' => &POWERS[2], '³' => &POWERS[3], '⁴' => &POWERS[4],
+        '⁵' => &POWERS[5], '⁶' => &POWERS[6], '⁷' => &POWERS[7], '⁸' => &POWERS[8], '⁹' => &POWERS[9],
+        _ => return None,
+    })
+}
+
+/// Handles a key: the overlay with focus gets it first, then the display.
+pub(crate) fn on_key(app: &mut App, code: KeyCode, modifiers: KeyModifiers) {
+    match (code, modifiers) {
+        (KeyCode::F(12), _) => app.debug_overlay = !app.debug_overlay,
+        (code, _) if let Some(handler) = overlay_keys(app.focus()) => handler(app, code),
+        (KeyCode::Char('h'), modifiers) if modifiers.contains(KeyModifiers::ALT) && !app.base_panel => focus_history(app),
+        (code, _) if app.focus() == Focus::History => on_history_key(app, code),
+        (code, _) if app.snippet.is_some() && matches!(code, KeyCode::Tab | KeyCode::BackTab | KeyCode::Backspace | KeyCode::Esc) => on_snippet_key(app, code),
+        (code, modifiers) if let Some(action) = app.config.key_action(code, modifiers) => action(app),
+        (code, modifiers) if !in_char_literal(&app.display_value) && let Some(operator) = app.config.operator_key(code, modifiers) => on_click(app, &operator),
+        (KeyCode::Char('p'), modifiers) if modifiers.contains(KeyModifiers::CONTROL) => app.overlay = Some(Overlay::Palette(Palette::default())),
+        (code, modifiers) => match code {
+            KeyCode::Char(c) if in_char_literal(&app.display_value) && !modifiers.contains(KeyModifiers::CONTROL) => on_click(app, &c.to_string()),
+            KeyCode::Char(c) if let Some(keys) = composed_keys(c) => for key in keys { on_click(app, key); },
+            KeyCode::Char(c @ ('0'..='9' | '(' | ')' | '"')) => on_click(app, &c.to_string()),
+            KeyCode::Char(c @ ('+' | '-' | '*' | '/' | '^' | '%')) => on_click(app, &c.to_string()),
+            KeyCode::Char('#') => open_note(app),
+            KeyCode::Char(c @ ('.' | ',' | ':' | '[' | ']' | ';' | ' ' | '$' | '€' | '£' | '¥' | '@' | '_')) => on_click(app, &c.to_string()),
+            KeyCode::Enter => on_click(app, "="),
+            KeyCode::Backspace => on_backspace(app),
+            KeyCode::Tab if app.rpn_mode => on_click(app, "x⇄y"),
+            KeyCode::Delete if app.rpn_mode => on_click(app, "Drop"),
+            KeyCode::PageDown if app.rpn_mode => on_click(app, "R↓"),
+            KeyCode::PageUp if app.quiz.is_some() => change_quiz_level(app, true),
+            KeyCode::PageDown if app.quiz.is_some() => change_quiz_level(app, false),
+            KeyCode::Delete if app.stats_mode => { app.data_points.pop(); }
+            KeyCode::Char('k') if app.stats_mode && modifiers.contains(KeyModifiers::CONTROL) => app.data_points.clear(),
+            KeyCode::Esc if app.pending.is_some() => cancel_evaluation(app),
+            KeyCode::Esc => on_click(app, "C"),
+            KeyCode::Char('{') => change_decimals(app, false),
+            KeyCode::Char('}') => change_decimals(app, true),
+            KeyCode::Char(c @ ('d' | 'h' | 'b' | 'o')) if app.base_panel && modifiers.contains(KeyModifiers::ALT) => copy_base(app, c),
+            KeyCode::Char('w') if app.base_panel && modifiers.contains(KeyModifiers::ALT) => app.settings.adjust(Settings::WORD_SIZE, true),
+            KeyCode::Char(c) if c.is_ascii_alphabetic() && !modifiers.contains(KeyModifiers::CONTROL) => on_click(app, &c.to_string()),
+            KeyCode::Tab => complete(app),
+            KeyCode::Left => app.display_scroll += 1,
+            KeyCode::Right => app.display_scroll = app.display_scroll.saturating_sub(1),
+            _ => {}
+        },
+    }
+}
+
+/// Carries out an effect and reports how it went. A resize takes effect right away, and the frame is drawn again
+/// so clicks hit the new button positions rather than the ones from before.
+pub(crate) fn run_effect<B: Backend>(terminal: &mut Terminal<B>, app: &mut App, host: &mut impl Host, effect: Effect) -> io::Result<()> {
+    match effect {
+        Effect::Copy(text) => {
+            // A console without OSC 52 would print the sequence, so the system clipboard takes over there.
+            let copied = if app.settings.osc52 && app.capabilities.escapes { host.control(&osc52_sequence(&text)) } else { copy_system(&text) };
+            match copied {
+                Ok(()) => app.notice = Some(format!("Copied {}", text)),
+                Err(e) => app.error_message = Some(format!("Copy failed: {}", e)),
+            }
+        }
+        Effect::WriteFile { path, contents, notice } => app.tasks.spawn(move || {
+            let parent = std::path::Path::new(&path).parent().map_or(Ok(()), std::fs::create_dir_all);
+            match parent.and_then(|()| std::fs::write(&path, contents)) {
+                Ok(()) => TaskOutput::Notice(notice),
+                Err(e) => TaskOutput::Error(format!("Cannot write {}: {}", path, e)),
+            }
+        }),
+        Effect::Resize(width, height) => {
+            terminal.resize(Rect::new(0, 0, width, height))?;
+            terminal.draw(|f| ui(f, app))?;
+        }
+        Effect::Evaluate => spawn_evaluation(app),
+        Effect::FetchRates { url, ttl_hours, force } => app.tasks.spawn(move || {
+            let (rates, warning) = refresh_rates(&url, ttl_hours, force);
+            TaskOutput::Rates(rates, warning)
+        }),
+        Effect::ReadVariables => app.tasks.spawn(read_variables),
+    }
+    Ok(())
+}
+
+/// Shows what a background task reported.
+pub(crate) fn finish_task(app: &mut App, output: TaskOutput) {
+    match output {
+        // A job that finished quietly, like saving the session.
+        TaskOutput::Notice(notice) if notice.is_empty() => {}
+        TaskOutput::Notice(notice) => app.notice = Some(notice),
+        TaskOutput::Error(message) => app.error_message = Some(message),
+        TaskOutput::ConfigChanged(Ok(config)) => app.reload_config(config),
+        TaskOutput::ConfigChanged(Err(message)) => app.show_toast(&message, true),
+        TaskOutput::ThemeChanged(name, theme) if name == app.config.theme_name(&app.profile) => match theme {
+            Ok(theme) => { app.theme = theme; app.show_toast(&format!("Reloaded theme {}", name), false); }
+            Err(message) => app.show_toast(&message, true),
+        },
+        TaskOutput::ThemeChanged(..) => {}
+        TaskOutput::Rates(rates, warning) => { app.rates = Arc::new(rates); if warning.is_some() { app.notice = warning; } }
+        TaskOutput::Variables(variables) => merge_variables(app, variables),
+        TaskOutput::Shared(state) => merge_shared(app, state),
+    }
+}
+
+/// Applies one input event to the app and carries out what it leads to.
+pub(crate) fn handle_event<B: Backend>(terminal: &mut Terminal<B>, app: &mut App, host: &mut impl Host, event: AppEvent) -> io::Result<()> {
+    app.dirty = true;
+    // A key or click wakes the screensaver and is used up doing it.
+    if matches!(event, AppEvent::Key(..) | AppEvent::Click { .. }) {
+        let idle = is_idle(app);
+        app.last_input = Instant::now();
+        if idle { return Ok(()); }
+    }
+    let Some(message) = message_for(app, event) else { return Ok(()) };
+    for effect in update(app, message) { run_effect(terminal, app, host, effect)?; }
+    Ok(())
+}
+
+/// How long a pressed button stays highlighted.
+pub(crate) const BUTTON_FLASH: Duration = Duration::from_millis(100);
+/// How often the spinner turns while an evaluation or a background job runs.
+pub(crate) const SPINNER_TICK: Duration = Duration::from_millis(100);
+/// How long the loop keeps taking events that are already waiting before it draws again.
+pub(crate) const BATCH_TIME: Duration = Duration::from_millis(16);
+/// How often the loop looks for a changed config or theme file while it would otherwise sleep until the next key.
+pub(crate) const WATCH_TICK: Duration = Duration::from_secs(1);
+/// How long quitting waits for a file still being written before it gives up on it.
+pub(crate) const QUIT_WAIT: Duration = Duration::from_secs(2);
+
+/// How long the event loop may wait for input before something on screen changes by itself: the spinner turns,
+/// a highlight, flash or toast runs out, or the screensaver starts or moves its clock. `None` waits for input alone.
+pub(crate) fn next_wake(app: &App) -> Option<Duration> {
+    if app.pending.is_some() || app.tasks.busy() { return Some(SPINNER_TICK); }
+    let now = Instant::now();
+    let idle_after = Duration::from_secs(u64::from(app.settings.idle_minutes) * 60);
+    let screensaver = match app.settings.idle_minutes {
+        0 => None,
+        _ if is_idle(app) => Some(now + Duration::from_secs(60 - now_secs() % 60)),
+        _ => Some(app.last_input + idle_after),
+    };
+    let deadlines = [
+        app.active_button.as_ref().map(|(_, time)| *time + BUTTON_FLASH), app.flash_until, app.toast.as_ref().map(|toast| toast.until),
+        screensaver, app.autosave.as_ref().map(|autosave| autosave.next),
+    ];
+    let wake = deadlines.into_iter().flatten().min().map(|deadline| deadline.saturating_duration_since(now));
+    // File watchers report through the task channel, which waiting for a key does not see.
+    if app.tasks.watching() { Some(wake.map_or(WATCH_TICK, |wake| wake.min(WATCH_TICK))) } else { wake }
+}
+
+/// The main application loop: handles events from `host` until the app quits, drawing the UI only when something
+/// on screen may have changed since the last frame.
+pub(crate) fn run_app<B: Backend>(terminal: &mut Terminal<B>, app: &mut App, host: &mut impl Host) -> io::Result<()> {
+    let mut had_error = false;
+    let mut title: Option<String> = None;
+    let mut cursor = None;
+    let mut clock = None;
+    app.dirty = true;
+    loop {
+        // The spinner turns while an evaluation runs, and its result or a task's output changes the screen.
+        if app.pending.is_some() && !app.low_bandwidth { app.dirty = true; }
+        poll_evaluation(app);
+        share_changes(app);
+        autosave(app, false);
+        for output in app.tasks.poll() { finish_task(app, output); app.dirty = true; }
+        // A finished evaluation can still have something to do, like copying its result.
+        for effect in std::mem::take(&mut app.effects) { run_effect(terminal, app, host, effect)?; }
+        // The screensaver comes on by itself, and its clock moves on once a minute.
+        let minute = is_idle(app).then(|| now_secs() / 60);
+        if minute != clock { clock = minute; app.dirty = true; }
+        let now = Instant::now();
+        if let Some((_, time)) = app.active_button && now - time >= BUTTON_FLASH { app.active_button = None; app.dirty = true; }
+        if app.flash_until.is_some_and(|until| now >= until) { app.flash_until = None; app.dirty = true; }
+        if app.toast.as_ref().is_some_and(|toast| now >= toast.until) { app.toast = None; app.dirty = true; }
+        // The cursor shape and the window title are raw escape sequences, which the legacy Windows console prints as text.
+        if app.capabilities.escapes && cursor != Some(app.settings.cursor) {
+            host.control(&cursor_sequence(app.settings.cursor))?;
+            cursor = Some(app.settings.cursor);
+        }
+        let wanted = window_title(app).filter(|_| app.capabilities.escapes);
+        if wanted != title { host.control(&title_change(title.is_some(), wanted.as_deref()))?; title = wanted; }
+        // Every key clears the error line, so an error showing now that was not before is a new one.
+        if app.error_message.is_some() && !had_error { signal_error(app, host)?; }
+        had_error = app.error_message.is_some();
+        if app.dirty {
+            let start = Instant::now();
+            terminal.draw(|f| ui(f, app))?;
+            app.frames.frame(start);
+            app.dirty = false;
+        }
+        if let Some(event) = host.next_event(next_wake(app))? {
+            let start = Instant::now();
+            app.frames.input(start);
+            handle_event(terminal, app, host, event)?;
+            // A held key repeats faster than a slow terminal draws: take the repeats already waiting in one go,
+            // so the screen catches up with one frame instead of replaying one frame per repeat.
+            while !app.should_quit && start.elapsed() < BATCH_TIME && let Some(event) = host.next_event(Some(Duration::ZERO))? {
+                handle_event(terminal, app, host, event)?;
+            }
+        }
+        if app.should_quit {
+            // The last snapshot is written here rather than by `update`, which only changes the state.
+            autosave(app, true);
+            app.tasks.finish(QUIT_WAIT);
+            let mut reset = String::new();
+            if cursor.is_some() { let _ = SetCursorStyle::DefaultUserShape.write_ansi(&mut reset); }
+            return host.control(&(reset + &title_change(title.is_some(), None)));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ratatui::backend::TestBackend;
+    use std::collections::VecDeque;
+
+    /// Feeds a fixed list of events to the loop, then quits, and records the escape sequences it sends.
+    #[derive(Default)]
+    struct Script { events: VecDeque<AppEvent>, output: String }
+
+    impl Host for Script {
+        fn next_event(&mut self, _: Option<Duration>) -> io::Result<Option<AppEvent>> {
+            Ok(Some(self.events.pop_front().unwrap_or(AppEvent::Key(KeyCode::Char('q'), KeyModifiers::CONTROL))))
+        }
+        fn control(&mut self, sequence: &str) -> io::Result<()> { self.output.push_str(sequence); Ok(()) }
+    }
+
+    fn keys(text: &str) -> impl Iterator<Item = AppEvent> + '_ { text.chars().map(|c| AppEvent::Key(KeyCode::Char(c), KeyModifiers::NONE)) }
+
+    fn screen(terminal: &Terminal<TestBackend>) -> String {
+        let buffer = terminal.backend().buffer();
+        buffer.content.chunks(buffer.area.width as usize).map(|row| row.iter().map(|cell| cell.symbol()).collect::<String>() + "\n").collect()
+    }
+    #[test]
+    fn test_resize() {
+        let mut app = App::new();
+        press(&mut app, &["1", "="]);
+        let mut terminal = Terminal::new(ratatui::backend::TestBackend::new(90, 30)).unwrap();
+        terminal.draw(|f| ui(f, &mut app)).unwrap();
+        assert!(!app.history_rows.is_empty());
+        app.overlay = Some(Overlay::HistoryMenu(HistoryMenu { entry: 0, cursor: 0, at: Some((80, 20)) }));
+        terminal.backend_mut().resize(40, 16);
+        handle_event(&mut terminal, &mut app, &mut Script::default(), AppEvent::Resize(40, 16)).unwrap();
+        let screen = Rect::new(0, 0, 40, 16);
+        assert!(app.overlay.is_none() && app.history_rows.is_empty());
+        assert!(app.button_rects.iter().all(|(rect, _)| screen.union(*rect) == screen));
+        let bottom = app.button_rects.iter().find(|(_, value)| value == "=").map(|(rect, _)| rect.bottom());
+        assert_eq!(bottom, Some(16));
+    }
+    #[test]
+    fn test_console_events() {
+        let mouse = |kind| Event::Mouse(MouseEvent { kind, column: 3, row: 7, modifiers: KeyModifiers::NONE });
+        let (down, up) = (mouse(MouseEventKind::Down(MouseButton::Left)), mouse(MouseEventKind::Up(MouseButton::Left)));
+        let window = || Ok((80, 25));
+        let mut console = ConsoleEvents::new(true);
+        // A double-click: press, release, then only the second release.
+        let clicks: Vec<_> = [down.clone(), up.clone(), up.clone()].into_iter()
+            .filter_map(|event| AppEvent::from_terminal(console.adapt(event, window))).collect();
+        assert_eq!(clicks, [AppEvent::Click { column: 3, row: 7, button: MouseButton::Left }; 2]);
+        assert_eq!(AppEvent::from_terminal(console.adapt(Event::Resize(80, 9001), window)), Some(AppEvent::Resize(80, 25)));
+        let mut unix = ConsoleEvents::new(false);
+        assert_eq!([down, up.clone(), up].into_iter().filter_map(|event| AppEvent::from_terminal(unix.adapt(event, window))).count(), 1);
+        assert_eq!(unix.adapt(Event::Resize(100, 30), window), Event::Resize(100, 30));
+    }
+    #[test]
+    fn test_window_title() {
+        let mut app = App::new();
+        press(&mut app, &["1", "2", "*", "3", "="]);
+        assert_eq!(window_title(&app), None);
+        app.settings.adjust(Settings::WINDOW_TITLE, true);
+        assert_eq!(app.settings.value_text(Settings::WINDOW_TITLE), "Result");
+        assert_eq!(window_title(&app).as_deref(), Some("calc — 36"));
+        assert_eq!(title_sequence("calc — 1\n2"), "\x1b]0;calc — 12\x07");
+        assert!(toml::from_str::<Config>("window_title = true").unwrap().settings("default").window_title);
+    }
+    #[test]
+    fn test_error_feedback() {
+        let config: Config = toml::from_str("error_feedback = \"flash\"\nkey_click = true").unwrap();
+        assert_eq!((config.error_feedback, config.key_click), (ErrorFeedback::Flash, true));
+        assert_eq!(Config::default().error_feedback, ErrorFeedback::Off);
+        assert!(toml::from_str::<Config>("error_feedback = \"siren\"").is_err());
+        let (mut app, mut host) = (App::new(), Script::default());
+        signal_error(&mut app, &mut host).unwrap();
+        assert!(app.flash_until.is_none() && host.output.is_empty());
+        app.settings.adjust(Settings::ERROR_FEEDBACK, true);
+        signal_error(&mut app, &mut host).unwrap();
+        assert_eq!(host.output, "\x07");
+        app.settings.adjust(Settings::ERROR_FEEDBACK, true);
+        assert_eq!(app.settings.value_text(Settings::ERROR_FEEDBACK), "Flash");
+        signal_error(&mut app, &mut host).unwrap();
+        assert!(app.flash_until.is_some());
+    }
+    /// Runs the loop one event at a time, letting each evaluation finish before the next, then draws the outcome.
+    fn play(terminal: &mut Terminal<TestBackend>, app: &mut App, host: &mut Script, events: impl IntoIterator<Item = AppEvent>) {
+        for event in events.into_iter().map(Some).chain([None]) {
+            host.events.extend(event);
+            app.should_quit = false;
+            run_app(terminal, app, host).unwrap();
+            settle(app);
+        }
+    }
+
+    /// Finishes the pending evaluation, running it here when its `Effect::Evaluate` was not carried out.
+    fn settle(app: &mut App) {
+        if let Some(pending) = app.pending.take() {
+            let (result, timings) = match pending.job {
+                Some((options, _)) => evaluate_timed(&pending.expression, &options),
+                None => pending.receiver.recv().unwrap(),
+            };
+            finish_evaluation(app, &pending.expression, result, timings);
+            follow_up(app, &pending.expression, pending.followup);
+        }
+    }
+    #[test]
+    fn test_event_loop() {
+        let mut app = App::new();
+        app.settings.window_title = true;
+        let (mut terminal, mut host) = (Terminal::new(TestBackend::new(60, 24)).unwrap(), Script::default());
+        play(&mut terminal, &mut app, &mut host, keys("12*3").chain([AppEvent::Key(KeyCode::Enter, KeyModifiers::NONE)]));
+        assert!(screen(&terminal).contains("36"));
+        // Clicks land on the buttons as last drawn.
+        let (rect, _) = app.button_rects.iter().find(|(_, value)| value == "7").unwrap().clone();
+        let click = AppEvent::Click { column: rect.x + 1, row: rect.y + 1, button: MouseButton::Left };
+        play(&mut terminal, &mut app, &mut host, keys("+").chain([click, AppEvent::Key(KeyCode::Enter, KeyModifiers::NONE)]));
+        assert!(app.should_quit);
+        assert_eq!(app.last_result, Some(43.0));
+        assert!(screen(&terminal).contains("43"));
+        assert!(host.output.contains(&title_sequence("calc — 43")) && host.output.ends_with("\x1b[23;0t"));
+        let mut app = App::new();
+        handle_event(&mut terminal, &mut app, &mut host, AppEvent::Key(KeyCode::Char('p'), KeyModifiers::CONTROL)).unwrap();
+        terminal.draw(|f| ui(f, &mut app)).unwrap();
+        assert!(app.focus() == Focus::Palette && screen(&terminal).contains("Presentation mode"));
+    }
+    /// Lets the given number of polls pass without input, 20 ms each, then quits.
+    struct Idle(usize);
+
+    impl Host for Idle {
+        fn next_event(&mut self, _: Option<Duration>) -> io::Result<Option<AppEvent>> {
+            if self.0 == 0 { return Ok(Some(AppEvent::Key(KeyCode::Char('q'), KeyModifiers::CONTROL))); }
+            self.0 -= 1;
+            std::thread::sleep(Duration::from_millis(20));
+            Ok(None)
+        }
+        fn control(&mut self, _: &str) -> io::Result<()> { Ok(()) }
+    }
+    #[test]
+    fn test_redraw_only_when_dirty() {
+        let mut app = App::new();
+        let mut terminal = Terminal::new(TestBackend::new(60, 24)).unwrap();
+        run_app(&mut terminal, &mut app, &mut Idle(5)).unwrap();
+        assert_eq!(app.frames.draws_per_second(), 1);
+        app.show_toast("Saved", false);
+        (app.toast.as_mut().unwrap().until, app.should_quit) = (Instant::now() + Duration::from_millis(30), false);
+        run_app(&mut terminal, &mut app, &mut Idle(5)).unwrap();
+        assert!(app.toast.is_none() && !screen(&terminal).contains("Saved"));
+        assert_eq!(app.frames.draws_per_second(), 3);
+    }
+    #[test]
+    fn test_next_wake() {
+        let mut app = App::new();
+        app.settings.idle_minutes = 0;
+        assert_eq!(next_wake(&app), None);
+        app.settings.idle_minutes = 2;
+        assert!(next_wake(&app).is_some_and(|wake| wake > Duration::from_secs(119) && wake <= Duration::from_secs(120)));
+        app.last_input = Instant::now() - Duration::from_secs(150);
+        assert!(next_wake(&app).is_some_and(|wake| wake <= Duration::from_secs(60)));
+        app.show_toast("Saved", false);
+        app.active_button = Some(("7".to_string(), Instant::now()));
+        assert!(next_wake(&app).is_some_and(|wake| wake <= BUTTON_FLASH));
+        app.tasks.every(Duration::from_secs(60), || None);
+        app.active_button = None;
+        assert_eq!(next_wake(&app), Some(WATCH_TICK));
+        app.tasks.spawn(|| TaskOutput::Notice(String::new()));
+        assert_eq!(next_wake(&app), Some(SPINNER_TICK));
+    }
+    #[test]
+    fn test_key_repeat_batch() {
+        let mut app = App::new();
+        app.display_value = "1+".repeat(40) + "1";
+        let mut terminal = Terminal::new(TestBackend::new(60, 24)).unwrap();
+        let mut host = Script { events: std::iter::repeat_n(AppEvent::Key(KeyCode::Backspace, KeyModifiers::NONE), 30).collect(), ..Script::default() };
+        run_app(&mut terminal, &mut app, &mut host).unwrap();
+        assert_eq!((app.display_value.len(), app.frames.draws_per_second()), (51, 1));
+    }
+    #[test]
+    fn test_enhanced_keys() {
+        let mut app = App::new();
+        app.settings.osc52 = true;
+        let (mut terminal, mut host) = (Terminal::new(TestBackend::new(60, 24)).unwrap(), Script::default());
+        play(&mut terminal, &mut app, &mut host, keys("12*3").chain([AppEvent::Key(KeyCode::Enter, KeyModifiers::SHIFT)]));
+        assert_eq!((app.display_value.as_str(), app.mode(), app.notice.as_deref(), app.last_result), ("12 * 3", Mode::Editing, Some("= 36"), Some(36.0)));
+        play(&mut terminal, &mut app, &mut host, keys("+1").chain([AppEvent::Key(KeyCode::Enter, KeyModifiers::CONTROL)]));
+        assert_eq!((app.display_value.as_str(), app.mode()), ("37", Mode::ResultShown));
+        assert!(host.output.contains(&osc52_sequence("37")));
+        let keypad = KeyEvent::new_with_kind_and_state(KeyCode::Char(','), KeyModifiers::NONE, KeyEventKind::Press, KeyEventState::KEYPAD);
+        assert_eq!(AppEvent::from_terminal(Event::Key(keypad)), Some(AppEvent::Key(KeyCode::Char('.'), KeyModifiers::NONE)));
+        assert_eq!(AppEvent::from_terminal(Event::Key(KeyEvent { kind: KeyEventKind::Release, ..keypad })), None);
+    }
+    #[test]
+    fn test_international_keys() {
+        let altgr = |c| AppEvent::from_terminal(Event::Key(KeyEvent::new(KeyCode::Char(c), KeyModifiers::CONTROL | KeyModifiers::ALT)));
+        assert_eq!(altgr('^'), Some(AppEvent::Key(KeyCode::Char('^'), KeyModifiers::NONE)));
+        assert_eq!(altgr('q'), Some(AppEvent::Key(KeyCode::Char('q'), KeyModifiers::CONTROL | KeyModifiers::ALT)));
+        let mut app = App::new();
+        app.config = toml::from_str("[operator_keys]\n\"alt+6\" = \"^\"\n\"ü\" = \"*\"").unwrap();
+        assert!(app.config.check().is_ok());
+        let (mut terminal, mut host) = (Terminal::new(TestBackend::new(60, 24)).unwrap(), Script::default());
+        play(&mut terminal, &mut app, &mut host, keys("3²×2").chain([AppEvent::Key(KeyCode::Char('6'), KeyModifiers::ALT)]).chain(keys("2ü2−1")));
+        assert_eq!(app.display_value, "3 ^ 2 * 2 ^ 2 * 2 - 1");
+        let config: Config = toml::from_str("[operator_keys]\n\"alt+6\" = \"sqrt\"").unwrap();
+        assert_eq!(config.check(), Err("operator key `alt+6` types `sqrt`, which is not one of + - * / ^ % ( )".to_string()));
+    }
+    #[test]
+    fn test_debug_overlay() {
+        let mut app = App::new();
+        let (mut terminal, mut host) = (Terminal::new(TestBackend::new(60, 24)).unwrap(), Script::default());
+        play(&mut terminal, &mut app, &mut host, keys("2^10").chain([AppEvent::Key(KeyCode::Enter, KeyModifiers::NONE)]));
+        assert!(!screen(&terminal).contains("Debug (F12)"));
+        assert!(app.last_timings.is_some_and(|timings| timings.allocations.is_some_and(|count| count > 0) == cfg!(feature = "alloc-count")));
+        play(&mut terminal, &mut app, &mut host, [AppEvent::Key(KeyCode::F(12), KeyModifiers::NONE)]);
+        let text = screen(&terminal);
+        assert!(app.debug_overlay && text.contains("Debug (F12)") && text.contains("Eval allocs") && text.contains(" /s"));
+        assert!(app.frames.draws_per_second() > 0 && app.frames.latency > Duration::ZERO);
+    }
+    #[test]
+    fn test_update_replay() {
+        let key = |c| Message::KeyTyped(KeyCode::Char(c), KeyModifiers::NONE);
+        let messages = [
+            key('1'), Message::ButtonPressed("+".to_string()), key('2'), Message::Evaluate(Followup::Show),
+            Message::KeyTyped(KeyCode::Char('c'), KeyModifiers::CONTROL),
+        ];
+        let run = |app: &mut App| messages.iter().flat_map(|message| { let effects = update(app, message.clone()); settle(app); effects }).collect::<Vec<_>>();
+        let (mut first, mut second) = (App::new(), App::new());
+        // Starting the worker and copying are left to the caller, so the app only reports a copy once the effect has run.
+        assert_eq!(run(&mut first), [Effect::Evaluate, Effect::Copy("3".to_string())]);
+        assert!(first.notice.is_none());
+        run(&mut second);
+        assert_eq!((second.display_value.as_str(), second.history.len(), second.mode()), (first.display_value.as_str(), 1, Mode::ResultShown));
+        let mut terminal = Terminal::new(TestBackend::new(60, 24)).unwrap();
+        let mut host = Script::default();
+        first.settings.osc52 = true;
+        run_effect(&mut terminal, &mut first, &mut host, Effect::Copy("3".to_string())).unwrap();
+        assert_eq!((host.output, first.notice.as_deref()), (osc52_sequence("3"), Some("Copied 3")));
+        assert_eq!(update(&mut first, Message::Resized(40, 16)), [Effect::Resize(40, 16)]);
+    }
+    #[test]
+    fn test_background_tasks() {
+        let mut app = App::new();
+        let mut terminal = Terminal::new(TestBackend::new(60, 24)).unwrap();
+        let path = std::env::temp_dir().join(format!("calc-task-{}.txt", std::process::id()));
+        let effect = Effect::WriteFile { path: path.display().to_string(), contents: "1 + 2 = 3\n".to_string(), notice: "Saved".to_string() };
+        run_effect(&mut terminal, &mut app, &mut Script::default(), effect).unwrap();
+        assert!(app.notice.is_none() && app.tasks.busy());
+        for output in app.tasks.finish(QUIT_WAIT) { finish_task(&mut app, output); }
+        assert_eq!((app.notice.as_deref(), std::fs::read_to_string(&path).ok().as_deref()), (Some("Saved"), Some("1 + 2 = 3\n")));
+        let _ = std::fs::remove_file(path);
+        finish_task(&mut app, TaskOutput::ConfigChanged(toml::from_str("window_title = true").map_err(|e: toml::de::Error| e.to_string())));
+        assert!(app.settings.window_title && app.config.window_title);
+        assert_eq!(app.toast.as_ref().map(|toast| (toast.text.as_str(), toast.error)), Some(("Reloaded config.toml", false)));
+    }
+}
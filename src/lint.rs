@@ -0,0 +1,96 @@
+//! Warnings about expressions that evaluate but may not mean what was typed, shown next to the result.
+
+use crate::{engine::NumberBackend, parser::*, units::unit};
+
+/// The warnings for `expression` on `backend`, in the order their causes appear. An expression that does not
+/// parse within `max_depth` only gets the warnings that read the tokens, which may explain why it does not.
+pub(crate) fn lint(expression: &str, backend: NumberBackend, max_depth: usize, si_suffixes: bool) -> Vec<String> {
+    let Ok(tokens) = tokenize(expression, si_suffixes) else { return Vec::new() };
+    let mut warnings = missing_multiplications(expression, &tokens);
+    if let Ok(tree) = parse_tokens(tokens, expression.len(), max_depth) { lint_tree(expression, &tree, backend, &mut warnings); }
+    warnings
+}
+
+/// Two operands side by side, like `2 3` or `(1 + 2)(3 + 4)`, where a `*` was probably left out.
+/// A number followed by a unit (`5 m`) is a quantity, a name followed by `(` a call, and the code after the amount
+/// of `fx(100 USD, EUR)` its currency.
+fn missing_multiplications(expression: &str, tokens: &[Token]) -> Vec<String> {
+    tokens.windows(2).enumerate().filter(|(i, pair)| {
+        let ends_operand = matches!(pair[0].kind, TokenKind::Number(..) | TokenKind::Duration(_) | TokenKind::RParen | TokenKind::Percent);
+        match &pair[1].kind {
+            TokenKind::Number(..) | TokenKind::Duration(_) | TokenKind::LParen => ends_operand,
+            TokenKind::Ident(name) => ends_operand && !matches!(pair[0].kind, TokenKind::Number(..) if unit(name).is_some()) && !currency_code(tokens, i + 1),
+            _ => false,
+        }
+    }).map(|(_, pair)| {
+        let text = |i: usize| &expression[pair[i].span.start..pair[i].span.end];
+        format!("Missing `*` between `{}` and `{}`?", text(0), text(1))
+    }).collect()
+}
+
+/// Whether the token at `i` is the currency code of an `fx` call: the last token before its first top-level comma.
+fn currency_code(tokens: &[Token], i: usize) -> bool {
+    if tokens.get(i + 1).map(|t| &t.kind) != Some(&TokenKind::Comma) { return false; }
+    let mut depth = 0;
+    for j in (0..i).rev() {
+        match tokens[j].kind {
+            TokenKind::RParen => depth += 1,
+            TokenKind::LParen if depth > 0 => depth -= 1,
+            TokenKind::LParen => return j > 0 && tokens[j - 1].kind == TokenKind::Ident("fx".to_string()),
+            TokenKind::Comma if depth == 0 => return false,
+            _ => {}
+        }
+    }
+    false
+}
+
+fn lint_tree(expression: &str, expr: &Expr, backend: NumberBackend, warnings: &mut Vec<String>) {
+    let text = |expr: &Expr| expression[expr.span.start..expr.span.end].to_string();
+    match &expr.kind {
+        // A parenthesized negative number is written on purpose; a bare one right after the sign is likely a typo.
+        ExprKind::Binary(op @ ('+' | '-'), _, rhs) if matches!(rhs.kind, ExprKind::Neg(_)) && expression[rhs.span.start..].starts_with('-') => {
+            let meaning = if *op == '-' { "subtracts" } else { "adds" };
+            warnings.push(format!("`{}-` {} a negative number; one sign too many?", op, meaning));
+        }
+        ExprKind::Binary('^', lhs, rhs) if matches!(lhs.kind, ExprKind::Percent(_)) || matches!(rhs.kind, ExprKind::Percent(_)) => {
+            warnings.push(format!("`%` applies before `^` in `{}`; add parentheses to be sure", text(expr)));
+        }
+        ExprKind::Binary('/', lhs, rhs) if backend == NumberBackend::BigInt && let (ExprKind::Number(a, _), ExprKind::Number(b, _)) = (&lhs.kind, &rhs.kind)
+            && *b != 0.0 && a % b != 0.0 => {
+            warnings.push(format!("`{}` drops the remainder in big-int mode", text(expr)));
+        }
+        _ => {}
+    }
+    match &expr.kind {
+        ExprKind::Neg(operand) | ExprKind::Percent(operand) => lint_tree(expression, operand, backend, warnings),
+        ExprKind::Binary(_, lhs, rhs) => { lint_tree(expression, lhs, backend, warnings); lint_tree(expression, rhs, backend, warnings); }
+        ExprKind::Call(_, args) => for arg in args { lint_tree(expression, arg, backend, warnings); },
+        _ => {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_lint() {
+        let float = |expression| lint(expression, NumberBackend::Float, 64, false);
+        assert_eq!(float("5--3"), ["`--` subtracts a negative number; one sign too many?"]);
+        assert!(float("5 - (-3)").is_empty() && float("-5 - 3").is_empty() && float("2 ^ -1").is_empty());
+        assert_eq!(float("2 ^ 50%"), ["`%` applies before `^` in `2 ^ 50%`; add parentheses to be sure"]);
+        assert_eq!(float("2 3"), ["Missing `*` between `2` and `3`?"]);
+        assert_eq!(float("(1 + 2)(3 + 4) + 2 pi"), ["Missing `*` between `)` and `(`?", "Missing `*` between `2` and `pi`?"]);
+        assert!(float("5 m + 2 sqrt(4)").len() == 1 && float("sqrt(4) * 2").is_empty());
+        assert_eq!(lint("7 / 2 + 8 / 2", NumberBackend::BigInt, 64, false), ["`7 / 2` drops the remainder in big-int mode"]);
+        assert!(float("7 / 2").is_empty());
+        let deep = format!("{}5--3{}", "(".repeat(100), ")".repeat(100));
+        assert!(float(&deep).is_empty() && lint(&deep, NumberBackend::Float, 128, false).len() == 1);
+        let mut app = crate::app::App::new();
+        app.display_value = "2 ^ 50%".to_string();
+        crate::app::press(&mut app, &["="]);
+        assert_eq!((app.last_result, app.notice.as_deref()), (Some(2f64.sqrt()), Some("`%` applies before `^` in `2 ^ 50%`; add parentheses to be sure")));
+        app.display_value = "2 3".to_string();
+        crate::app::press(&mut app, &["="]);
+        assert_eq!(app.error_message.as_deref(), Some("Syntax error in `3` · Missing `*` between `2` and `3`?"));
+    }
+}
@@ -1,361 +1,294 @@
 use crossterm::{
-    event::{self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode, MouseEventKind},
+    event::{DisableMouseCapture, EnableMouseCapture},
     execute,
     terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
 };
-use ratatui::{prelude::*, widgets::*};
-use std::{error::Error, io, time::{Duration, Instant}};
+use ratatui::prelude::*;
+use rayon::prelude::*;
+use std::{backtrace::Backtrace, error::Error, io::{self, BufRead, IsTerminal, Write}, panic::AssertUnwindSafe, time::{Duration, Instant}};
 
-// --- Expression Parser Section (Shunting-yard Algorithm) ---
+mod app;
+mod crash;
+mod dates;
+mod engine;
+mod input;
+mod lint;
+mod parser;
+mod perf;
+mod rates;
+mod release;
+mod session;
+mod shared;
+mod tasks;
+mod ui;
+mod units;
 
-/// Returns the precedence of an operator.
-fn precedence(op: char) -> u8 {
-    match op {
-        '+' | '-' => 1,
-        '*' | '/' => 2,
-        '^' => 3,
-        _ => 0,
-    }
-}
+use app::*;
+use engine::*;
+use input::{run_app, ConsoleEvents, TerminalHost};
+use parser::is_syntax_error;
 
-/// Applies an operator to two numbers.
-fn apply_op(op: char, b: f64, a: f64) -> Result<f64, &'static str> {
-    match op {
-        '+' => Ok(a + b),
-        '-' => Ok(a - b),
-        '*' => Ok(a * b),
-        '/' => if b == 0.0 { Err("Division by zero") } else { Ok(a / b) },
-        '^' => Ok(a.powf(b)),
-        _ => Err("Unknown operator"),
-    }
-}
+// --- Batch Section ---
 
-/// The main evaluation function that respects the order of operations.
-fn evaluate(expression: &str) -> Result<f64, &'static str> {
-    let mut values: Vec<f64> = Vec::new();
-    let mut ops: Vec<char> = Vec::new();
-    let mut chars = expression.chars().filter(|&c| !c.is_whitespace()).peekable();
-    let mut last_was_op = true;
-
-    while let Some(token) = chars.next() {
-        match token {
-            '0'..='9' | '.' => {
-                let mut num_str = String::new();
-                num_str.push(token);
-                while let Some(&c) = chars.peek() {
-                    if c.is_digit(10) || c == '.' {
-                        num_str.push(chars.next().unwrap());
-                    } else {
-                        break;
-                    }
-                }
-                let mut num: f64 = num_str.parse().map_err(|_| "Invalid number")?;
-                
-                if let Some('%') = chars.peek() {
-                    if let Some(last_op) = ops.last() {
-                        if *last_op == '+' || *last_op == '-' {
-                            let prev_val = values.last().ok_or("Syntax error")?;
-                            num = prev_val * (num / 100.0);
-                        } else {
-                            num /= 100.0;
-                        }
-                    } else {
-                        num /= 100.0;
-                    }
-                    chars.next(); // Consume the '%'
-                }
-                
-                values.push(num);
-                last_was_op = false;
-            }
-            '(' => { ops.push('('); last_was_op = true; }
-            ')' => {
-                while let Some(op) = ops.pop() {
-                    if op == '(' { break; }
-                    let val2 = values.pop().ok_or("Syntax error")?;
-                    let val1 = values.pop().ok_or("Syntax error")?;
-                    values.push(apply_op(op, val2, val1)?);
-                }
-                last_was_op = false;
-            }
-            op @ ('+' | '*' | '/' | '^') => {
-                while let Some(&top_op) = ops.last() {
-                    if top_op != '(' && precedence(top_op) >= precedence(op) {
-                        let val2 = values.pop().ok_or("Syntax error")?;
-                        let val1 = values.pop().ok_or("Syntax error")?;
-                        values.push(apply_op(ops.pop().unwrap(), val2, val1)?);
-                    } else { break; }
-                }
-                ops.push(op);
-                last_was_op = true;
-            }
-            '-' => {
-                if last_was_op {
-                    let mut num_str = String::from("-");
-                    while let Some(&c) = chars.peek() {
-                        if c.is_digit(10) || c == '.' { num_str.push(chars.next().unwrap()); } else { break; }
-                    }
-                    let mut num: f64 = num_str.parse().map_err(|_| "Invalid number")?;
-                     if let Some('%') = chars.peek() {
-                        num /= 100.0;
-                        chars.next();
-                    }
-                    values.push(num);
-                    last_was_op = false;
-                } else {
-                    while let Some(&top_op) = ops.last() {
-                        if top_op != '(' && precedence(top_op) >= precedence('-') {
-                            let val2 = values.pop().ok_or("Syntax error")?;
-                            let val1 = values.pop().ok_or("Syntax error")?;
-                            values.push(apply_op(ops.pop().unwrap(), val2, val1)?);
-                        } else { break; }
-                    }
-                    ops.push('-');
-                    last_was_op = true;
-                }
-            }
-            _ => return Err("Invalid character"),
+/// Splits `name = expr` into its parts; other lines are plain expressions.
+pub(crate) fn split_assignment(line: &str) -> (Option<&str>, &str) {
+    match line.split_once('=') {
+        Some((name, expression))
+            if name.trim().starts_with(|c: char| c.is_ascii_alphabetic()) && name.trim().chars().all(|c| c.is_ascii_alphanumeric() || c == '_') =>
+        {
+            (Some(name.trim()), expression)
         }
+        _ => (None, line),
     }
-
-    while let Some(op) = ops.pop() {
-        let val2 = values.pop().ok_or("Syntax error")?;
-        let val1 = values.pop().ok_or("Syntax error")?;
-        values.push(apply_op(op, val2, val1)?);
-    }
-
-    values.pop().ok_or("Syntax error")
 }
 
+/// Why batch mode failed, as its exit status: 1 for a line that does not parse, 2 for one that cannot be
+/// computed, 3 for input that cannot be read.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub(crate) enum Failure { Parse = 1, Math = 2, Io = 3 }
 
-// --- End of Parser Section ---
-
-/// A struct for storing the color theme.
-struct Theme {
-    background: Color, display_bg: Color, border: Color, text: Color,
-    num_button_fg: Color, op_button_fg: Color, num_button_bg: Color,
-    op_button_bg: Color, equal_button_bg: Color, active_button_bg: Color,
-}
-
-impl Theme {
-    fn default() -> Self {
-        Theme {
-            background: Color::Rgb(20, 20, 30), display_bg: Color::Rgb(50, 50, 60),
-            border: Color::Rgb(80, 80, 90), text: Color::White,
-            num_button_fg: Color::White, op_button_fg: Color::Rgb(20, 20, 30),
-            num_button_bg: Color::Rgb(60, 70, 80), op_button_bg: Color::Rgb(255, 159, 67),
-            equal_button_bg: Color::Rgb(255, 99, 132), active_button_bg: Color::White,
+/// Evaluates batch lines, keeping their order. Runs of plain expressions are spread across
+/// a thread pool; each assignment is evaluated on its own before the lines that follow it.
+/// Each line gets the length limit and the time limit of the settings, as if it were typed.
+pub(crate) fn run_batch(lines: &[String], settings: &Settings) -> Vec<Result<String, (Failure, String)>> {
+    let mut options = EvalOptions { deadline: None, ..settings.eval_options() };
+    let evaluate_line = |expression: &str, options: &EvalOptions| {
+        if expression.trim().chars().count() > settings.max_length {
+            return Err((Failure::Parse, format!("Expression is limited to {} characters", settings.max_length)));
+        }
+        let options = EvalOptions { deadline: Some(Instant::now() + Duration::from_secs(settings.timeout_secs)), ..options.clone() };
+        evaluate_timed(expression, &options).0.map_err(|e| {
+            (if is_syntax_error(expression, options.max_depth, options.si_suffixes) { Failure::Parse } else { Failure::Math }, e.describe(expression))
+        })
+    };
+    // `quad` has two results, which go on one line; a label like the character of `char(65)` is printed as shown.
+    let format = settings.format_options();
+    let text = |o: &Outcome| match (o.roots, &o.label) {
+        (Some(roots), _) => roots.lines(&format).join(", "),
+        (None, Some(label)) => label.clone(),
+        (None, None) => format_outcome(o.value, o.exact.as_deref(), settings.backend, &format),
+    };
+    let mut results = Vec::with_capacity(lines.len());
+    let mut rest = lines;
+    while !rest.is_empty() {
+        let run = rest.iter().position(|line| split_assignment(line).0.is_some()).unwrap_or(rest.len());
+        results.par_extend(rest[..run].par_iter().map(|line| evaluate_line(line, &options).map(|o| text(&o))));
+        if let Some(line) = rest.get(run) {
+            let (Some(name), expression) = split_assignment(line) else { unreachable!() };
+            results.push(evaluate_line(expression, &options).map(|o| {
+                options.variables.insert(name.to_string(), o.value);
+                format!("{name} = {}", text(&o))
+            }));
         }
+        rest = &rest[(run + 1).min(rest.len())..];
     }
+    results
 }
 
-/// The main application struct.
-struct App {
-    display_value: String, is_result_displayed: bool, active_button: Option<(String, Instant)>,
-    button_rects: Vec<(Rect, String)>, should_quit: bool, theme: Theme, last_op_duration: Option<Duration>,
+/// The text batch mode prints for one line. Quiet, only results are printed, without the name of an assignment,
+/// and a failed line prints an empty line so the output still has one line per expression.
+pub(crate) fn batch_line(line: &str, result: &Result<String, (Failure, String)>, quiet: bool) -> String {
+    match result {
+        Ok(text) if quiet && let Some(name) = split_assignment(line).0 => text.trim_start_matches(&format!("{name} = ")).to_string(),
+        Ok(text) => text.clone(),
+        Err(_) if quiet => String::new(),
+        Err((_, message)) => format!("Error: {message}"),
+    }
 }
 
-impl App {
-    fn new() -> App {
-        App {
-            display_value: String::from("0"), is_result_displayed: false, active_button: None,
-            button_rects: Vec::new(), should_quit: false, theme: Theme::default(), last_op_duration: None,
-        }
+/// Reads expressions (one per line; blank lines and `#` comments are skipped) and prints each result in order.
+/// Returns the failure of the first line that failed, if any.
+pub(crate) fn batch_main(input: Box<dyn BufRead>, quiet: bool) -> io::Result<Option<Failure>> {
+    let lines: Vec<String> = input.lines().collect::<io::Result<Vec<_>>>()?.into_iter()
+        .filter(|l| !l.trim().is_empty() && !l.trim_start().starts_with('#')).collect();
+    let started = Instant::now();
+    let results = run_batch(&lines, &Settings::default());
+    let elapsed = started.elapsed();
+    let mut stdout = io::stdout().lock();
+    for (line, result) in lines.iter().zip(&results) {
+        writeln!(stdout, "{}", batch_line(line, result, quiet))?;
     }
-    
-    fn set_active_button(&mut self, label: &str) {
-        self.active_button = Some((label.to_string(), Instant::now()));
+    stdout.flush()?;
+    let rate = results.len() as f64 / elapsed.as_secs_f64().max(1e-9);
+    if !quiet { eprintln!("{} expressions in {:.2} ms ({rate:.0}/s)", results.len(), elapsed.as_secs_f64() * 1000.0); }
+    Ok(results.iter().find_map(|result| result.as_ref().err().map(|(failure, _)| *failure)))
+}
+
+/// The exit status of batch mode: 0 when every line worked, else the `Failure` code. Read errors are reported
+/// on stderr unless quiet.
+pub(crate) fn batch_status(outcome: io::Result<Option<Failure>>, quiet: bool) -> i32 {
+    match outcome {
+        Ok(failure) => failure.map_or(0, |failure| failure as i32),
+        Err(e) => { if !quiet { eprintln!("calc: {}", e); } Failure::Io as i32 }
     }
 }
 
-/// The logic executed when a button is clicked.
-fn on_click(app: &mut App, value: &str) {
-    app.set_active_button(value);
-    
-    match value {
-        "0" | "1" | "2" | "3" | "4" | "5" | "6" | "7" | "8" | "9" | "(" | ")" => {
-            if app.is_result_displayed { app.display_value = String::from(value); app.is_result_displayed = false; }
-            else if app.display_value == "0" { app.display_value = String::from(value); }
-            else { app.display_value.push_str(value); }
-        }
-        "." => {
-            let last_segment = app.display_value.split(&['+', '-', '*', '/', '^', '(', ')'][..]).last().unwrap_or("");
-            if !last_segment.contains('.') { app.display_value.push('.'); }
-        }
-        "C" => { app.display_value = String::from("0"); app.is_result_displayed = false; app.last_op_duration = None; }
-        "+/-" => {
-             if let Some(last_num_start) = app.display_value.rfind(|c: char| !c.is_digit(10) && c != '.') {
-                 let (before, after) = app.display_value.split_at(last_num_start + 1);
-                 if after.starts_with('-') { app.display_value = format!("{}{}", before, &after[1..]); }
-                 else { app.display_value = format!("{}-{}", before, after); }
-             } else {
-                 if app.display_value.starts_with('-') { app.display_value = app.display_value[1..].to_string(); }
-                 else if app.display_value != "0" { app.display_value = format!("-{}", app.display_value); }
-             }
-        }
-        "%" => {
-            let last_char = app.display_value.chars().last().unwrap_or(' ');
-            if last_char.is_digit(10) || last_char == ')' { app.display_value.push_str(value); }
-        }
-        "+" | "-" | "*" | "/" | "^" => {
-            app.display_value = app.display_value.trim().to_string();
-            app.display_value.push_str(&format!(" {} ", value));
-            app.is_result_displayed = false;
-        }
-        "=" => {
-            let start_time = Instant::now();
-            let result = evaluate(&app.display_value);
-            let duration = start_time.elapsed();
-            app.last_op_duration = Some(duration);
+// --- End of Batch Section ---
 
-            match result {
-                Ok(res) => { app.display_value = format_result(res); app.is_result_displayed = true; }
-                Err(e) => { app.display_value = e.to_string(); app.is_result_displayed = true; }
-            }
-        }
-        _ => {}
-    }
+/// Whether the app takes over the mouse for clicks, which keeps the terminal from selecting text: unless
+/// `--no-mouse` is given or the config sets `mouse = false`.
+pub(crate) fn captures_mouse(config: &Config, args: &[String]) -> bool {
+    config.mouse != Some(false) && !args.iter().any(|arg| arg == "--no-mouse")
 }
 
-/// Handles the Backspace key press.
-fn on_backspace(app: &mut App) {
-    if app.is_result_displayed {
-        app.display_value = String::from("0");
-        app.is_result_displayed = false;
-    } else if app.display_value.len() > 1 {
-        let last_char = app.display_value.pop();
-        // If the last character was a space, pop again to remove the operator
-        if last_char == Some(' ') {
-            app.display_value.pop();
+/// The profile named by `--profile`, or `default` without one. A name the config file does not define is an error,
+/// so a typo does not quietly start a fresh profile.
+pub(crate) fn chosen_profile<'a>(config: &Config, args: &'a [String]) -> Result<&'a str, String> {
+    let Some(at) = args.iter().position(|arg| arg == "--profile") else { return Ok("default") };
+    match args.get(at + 1).map(String::as_str) {
+        None => Err("--profile needs a name".to_string()),
+        Some(name) if name == "default" || config.profiles.contains_key(name) => Ok(name),
+        Some(name) => {
+            let known: Vec<&str> = config.profiles.keys().map(String::as_str).collect();
+            Err(format!("Unknown profile `{}`; the config file has {}", name, if known.is_empty() { "none".to_string() } else { known.join(", ") }))
         }
-    } else {
-        app.display_value = String::from("0");
     }
 }
 
-
-/// Formats the result, removing trailing zeros.
-fn format_result(n: f64) -> String {
-    if n.is_nan() { "Error".to_string() }
-    else if n.fract() == 0.0 { format!("{:.0}", n) }
-    else { format!("{:.8}", n).trim_end_matches('0').trim_end_matches('.').to_string() }
+/// The height of the viewport when running inline below the prompt, with `--inline` or `inline = true`;
+/// `None` for the full screen.
+pub(crate) fn inline_height(config: &Config, args: &[String]) -> Option<u16> {
+    (config.inline || args.iter().any(|arg| arg == "--inline")).then(|| config.inline_height.unwrap_or(INLINE_HEIGHT).max(MIN_INLINE_HEIGHT))
 }
 
+/// What to print when the UI quits: `--print-on-exit` for the last result, `--print-on-exit tape` for the whole tape.
+pub(crate) fn print_on_exit(args: &[String]) -> Option<PrintOnExit> {
+    let at = args.iter().position(|arg| arg == "--print-on-exit")?;
+    Some(if args.get(at + 1).is_some_and(|arg| arg == "tape") { PrintOnExit::Tape } else { PrintOnExit::Result })
+}
 
-/// The main function of the program.
-fn main() -> Result<(), Box<dyn Error>> {
-    enable_raw_mode()?;
-    let mut stdout = io::stdout();
-    execute!(stdout, EnterAlternateScreen, EnableMouseCapture)?;
-    let backend = CrosstermBackend::new(stdout);
-    let mut terminal = Terminal::new(backend)?;
+/// The main function of the program. With `--batch [file]`, or when stdin is not a terminal, runs in batch mode;
+/// `update --check` looks for a newer release.
+pub(crate) fn main() -> Result<(), Box<dyn Error>> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    if args.first().is_some_and(|arg| arg == "update") { return Ok(release::update_main(&args[1..])?); }
+    let quiet = args.iter().any(|arg| arg == "--quiet");
+    if let Some(path) = args.iter().position(|arg| arg == "--batch").and_then(|i| args.get(i + 1)).filter(|path| !path.starts_with("--")) {
+        let outcome = std::fs::File::open(path).and_then(|file| batch_main(Box::new(io::BufReader::new(file)), quiet));
+        std::process::exit(batch_status(outcome, quiet));
+    }
+    if args.iter().any(|arg| arg == "--batch") || !io::stdin().is_terminal() {
+        std::process::exit(batch_status(batch_main(Box::new(io::stdin().lock()), quiet), quiet));
+    }
+    crash::install_panic_hook();
     let mut app = App::new();
-    let res = run_app(&mut terminal, &mut app);
+    match Config::load() {
+        Ok(config) => app.config = config,
+        Err(message) => app.error_message = Some(message),
+    }
+    app.capabilities = ui::Capabilities::detect(|name| std::env::var(name).ok(), cfg!(windows));
+    app.low_bandwidth = app.config.low_bandwidth || args.iter().any(|arg| arg == "--low-bandwidth");
+    // 24-bit colors take three times the bytes of the basic ones on every change of color.
+    if app.low_bandwidth { app.capabilities.truecolor = false; }
+    let (mouse, inline) = (captures_mouse(&app.config, &args), inline_height(&app.config, &args));
+    let profile = chosen_profile(&app.config, &args)?.to_string();
+    enable_raw_mode()?;
+    input::enable_enhanced_keys()?;
+    let mut output = input::terminal_output();
+    if inline.is_none() { execute!(output, EnterAlternateScreen)?; }
+    if mouse { execute!(output, EnableMouseCapture)?; }
+    let backend = CrosstermBackend::new(output);
+    let mut terminal = Terminal::with_options(backend, TerminalOptions { viewport: inline.map_or(Viewport::Fullscreen, Viewport::Inline) })?;
+    app.custom_buttons = app.config.buttons.clone();
+    tasks::watch_config(&mut app.tasks);
+    tasks::watch_themes(&mut app.tasks);
+    refresh_exchange_rates(&mut app, false);
+    ProfileSession::new(&app.config, &profile).exchange(&mut app);
+    app.profile = profile;
+    load_usage(&mut app);
+    if let Some(path) = session::session_path() {
+        if args.iter().any(|arg| arg == "--resume") { session::resume(&mut app, &path); }
+        app.autosave = Some(session::Autosave::new(path));
+    }
+    if app.config.share_state || args.iter().any(|arg| arg == "--share") { shared::start_sharing(&mut app); }
+    // A panic unwinds out of the event loop to here, where the app's state is still at hand for the crash report.
+    let res = std::panic::catch_unwind(AssertUnwindSafe(|| run_app(&mut terminal, &mut app, &mut TerminalHost { console: ConsoleEvents::new(cfg!(windows)) })));
+    input::disable_enhanced_keys();
     disable_raw_mode()?;
-    execute!(terminal.backend_mut(), LeaveAlternateScreen, DisableMouseCapture)?;
+    if inline.is_some() {
+        // Inline, the calculator leaves nothing behind: its rows are cleared and the prompt comes back where it started.
+        let top = terminal.get_frame().size().y;
+        terminal.clear()?;
+        terminal.set_cursor(0, top)?;
+    } else {
+        execute!(terminal.backend_mut(), LeaveAlternateScreen)?;
+    }
+    if mouse { execute!(terminal.backend_mut(), DisableMouseCapture)?; }
     terminal.show_cursor()?;
-    if let Err(err) = res { println!("{err:?}"); }
-    Ok(())
-}
-
-/// The main application loop: handles events and draws the UI.
-fn run_app<B: Backend>(terminal: &mut Terminal<B>, app: &mut App) -> io::Result<()> {
-    loop {
-        terminal.draw(|f| ui(f, app))?;
-        if let Some((_, time)) = app.active_button {
-            if time.elapsed().as_millis() > 100 { app.active_button = None; }
-        }
-        if crossterm::event::poll(Duration::from_millis(100))? {
-            match event::read()? {
-                Event::Key(key) if key.code == KeyCode::Char('q') => app.should_quit = true,
-                Event::Mouse(mouse) if mouse.kind == MouseEventKind::Down(event::MouseButton::Left) => {
-                    if let Some(label) = app.button_rects.iter().find_map(|(rect, label)| {
-                        if rect.contains((mouse.column, mouse.row).into()) { Some(label.clone()) } else { None }
-                    }) {
-                        on_click(app, &label);
-                    }
-                },
-                Event::Key(key) => {
-                    match key.code {
-                        KeyCode::Char(c @ ('0'..='9' | '(' | ')')) => on_click(app, &c.to_string()),
-                        KeyCode::Char(c @ ('+' | '-' | '*' | '/' | '^' | '%')) => on_click(app, &c.to_string()),
-                        KeyCode::Char('.') => on_click(app, "."),
-                        KeyCode::Enter => on_click(app, "="),
-                        KeyCode::Backspace => on_backspace(app),
-                        KeyCode::Esc => on_click(app, "C"),
-                        _ => {}
-                    }
-                }
-                _ => {}
-            }
-        }
-        if app.should_quit { return Ok(()); }
+    match res {
+        Err(_) => { crash::report_panic(&app); std::process::exit(101); }
+        Ok(Err(err)) => crash::write_report(&err.to_string(), &crash::state_dump(&app), &Backtrace::force_capture().to_string()),
+        Ok(Ok(())) => {}
     }
-}
-
-/// The function that draws the entire UI.
-fn ui(f: &mut Frame, app: &mut App) {
-    app.button_rects.clear();
-    let theme = &app.theme;
-    f.render_widget(Block::default().bg(theme.background), f.size());
-    let main_chunks = Layout::default().direction(Direction::Vertical).margin(1)
-        .constraints([Constraint::Length(1), Constraint::Length(3), Constraint::Min(0), Constraint::Length(1)].as_ref())
-        .split(f.size());
-    let time_text = if let Some(duration) = app.last_op_duration { format!("Last operation: {} µs", duration.as_micros()) } else { "Waiting for calculation...".to_string() };
-    f.render_widget(Paragraph::new(time_text).style(Style::default().fg(theme.border)).alignment(Alignment::Right), main_chunks[0]);
-    f.render_widget(Paragraph::new(app.display_value.as_str()).style(Style::default().fg(theme.text).bg(theme.display_bg)).block(Block::default().borders(Borders::ALL).border_style(Style::default().fg(theme.border))).alignment(Alignment::Right), main_chunks[1]);
-    f.render_widget(Paragraph::new(" Press 'q' to quit").style(Style::default().fg(theme.border)), main_chunks[3]);
-    let button_definitions = [
-        ("C", 0, 0, 1, 1), ("(", 1, 0, 1, 1), (")", 2, 0, 1, 1), ("/", 3, 0, 1, 1), ("%", 4, 0, 1, 1),
-        ("7", 0, 1, 1, 1), ("8", 1, 1, 1, 1), ("9", 2, 1, 1, 1), ("*", 3, 1, 1, 1), ("^", 4, 1, 1, 1),
-        ("4", 0, 2, 1, 1), ("5", 1, 2, 1, 1), ("6", 2, 2, 1, 1), ("-", 3, 2, 1, 1), ("+/-", 4, 2, 1, 1),
-        ("1", 0, 3, 1, 1), ("2", 1, 3, 1, 1), ("3", 2, 3, 1, 1), ("+", 3, 3, 1, 2),
-        ("0", 0, 4, 2, 1), (".", 2, 4, 1, 1), ("=", 4, 3, 1, 2),
-    ];
-    let rows = Layout::default().direction(Direction::Vertical).constraints([Constraint::Ratio(1, 5); 5]).split(main_chunks[2]);
-    let mut cols_per_row = Vec::new();
-    for row_area in rows.iter() { cols_per_row.push(Layout::default().direction(Direction::Horizontal).constraints([Constraint::Ratio(1, 5); 5]).split(*row_area)); }
-    for (label, x, y, w, h) in button_definitions.iter() {
-        let button_area = cols_per_row[*y as usize][*x as usize].union(cols_per_row[(*y + *h - 1) as usize][(*x + *w - 1) as usize]);
-        app.button_rects.push((button_area, label.to_string()));
-        let is_active = app.active_button.as_ref().map_or(false, |(l, _)| l == *label);
-        let (fg_color, bg_color) = if is_active {
-            (theme.op_button_fg, theme.active_button_bg)
-        } else {
-            match *label {
-                "C" | "/" | "*" | "-" | "+" | "%" | "^" | "+/-" | "(" | ")" => (theme.op_button_fg, theme.op_button_bg),
-                "=" => (theme.op_button_fg, theme.equal_button_bg),
-                _ => (theme.num_button_fg, theme.num_button_bg),
-            }
-        };
-        f.render_widget(Paragraph::new(*label).style(Style::default().fg(fg_color).bg(bg_color)).alignment(Alignment::Center).block(Block::default().borders(Borders::ALL).border_style(Style::default().fg(theme.background))), button_area);
+    if let Some(print) = print_on_exit(&args) {
+        match exit_text(&app, print) { Some(text) => print!("{}", text), None => std::process::exit(1) }
     }
+    Ok(())
 }
 
-
-// --- Test Suite ---
 #[cfg(test)]
 mod tests {
     use super::*;
-    /// A helper function for comparing floating-point numbers.
-    fn assert_float_eq(a: f64, b: f64) { assert!((a - b).abs() < 1e-9, "Expected {}, got {}", b, a); }
-    #[test] fn test_simple_addition() { assert_float_eq(evaluate("5 + 3").unwrap(), 8.0); }
-    #[test] fn test_simple_subtraction() { assert_float_eq(evaluate("10 - 4").unwrap(), 6.0); }
-    #[test] fn test_simple_multiplication() { assert_float_eq(evaluate("7 * 3").unwrap(), 21.0); }
-    #[test] fn test_simple_division() { assert_float_eq(evaluate("20 / 4").unwrap(), 5.0); }
-    #[test] fn test_order_of_operations() { assert_float_eq(evaluate("5 + 2 * 3").unwrap(), 11.0); }
-    #[test] fn test_parentheses() { assert_float_eq(evaluate("(5 + 2) * 3").unwrap(), 21.0); }
-    #[test] fn test_floating_point() { assert_float_eq(evaluate("1.5 + 2.5").unwrap(), 4.0); }
-    #[test] fn test_unary_minus() { assert_float_eq(evaluate("10 * -2").unwrap(), -20.0); }
-    #[test] fn test_exponentiation() { assert_float_eq(evaluate("2 ^ 3").unwrap(), 8.0); }
     #[test]
-    fn test_percentage() {
-        assert_float_eq(evaluate("50%").unwrap(), 0.5);
-        assert_float_eq(evaluate("200 + 10%").unwrap(), 220.0);
-        assert_float_eq(evaluate("100 * 50%").unwrap(), 50.0);
-        assert_float_eq(evaluate("100 - 25%").unwrap(), 75.0);
+    fn test_batch_order_and_assignments() {
+        let lines: Vec<String> = ["x = 2", "x * 3", "1 / 0", "x = x + 1", "x ^ 2"].iter().map(|l| l.to_string())
+            .chain((1..=200).map(|i| format!("{i} + x"))).collect();
+        let results = run_batch(&lines, &Settings::default());
+        assert_eq!(results[..5], [
+            Ok("x = 2".to_string()), Ok("6".to_string()), Err((Failure::Math, "Division by zero".to_string())),
+            Ok("x = 3".to_string()), Ok("9".to_string()),
+        ]);
+        assert!(results[5..].iter().enumerate().all(|(i, r)| r == &Ok((i + 4).to_string())));
+        assert_eq!(split_assignment("a1 = 5"), (Some("a1"), " 5"));
+        assert_eq!(split_assignment("2 = 5").0, None);
     }
-    #[test] fn test_complex_expression() { assert_float_eq(evaluate("3 + 4 * 2 / ( 1 - 5 ) ^ 2").unwrap(), 3.5); }
-    #[test] fn test_division_by_zero() { assert!(evaluate("10 / 0").is_err()); }
-    #[test] fn test_syntax_error() { assert!(evaluate("5 * + 3").is_err()); }
-}
\ No newline at end of file
+    #[test]
+    fn test_batch_exit_status() {
+        let lines: Vec<String> = ["x = 6 * 7", "2 +* 3", "sqrt(-1)", "x"].iter().map(|l| l.to_string()).collect();
+        let results = run_batch(&lines, &Settings::default());
+        assert!(matches!((&results[1], &results[2]), (Err((Failure::Parse, _)), Err((Failure::Math, _)))));
+        let printed = |quiet| lines.iter().zip(&results).map(|(line, result)| batch_line(line, result, quiet)).collect::<Vec<_>>();
+        assert_eq!(printed(true), ["42", "", "", "42"]);
+        assert_eq!(printed(false)[0], "x = 42");
+        assert!(printed(false)[1].starts_with("Error: "));
+        assert_eq!(batch_main(Box::new(io::Cursor::new("")), true).map(|failure| batch_status(Ok(failure), true)).unwrap(), 0);
+        assert_eq!((batch_status(Ok(Some(Failure::Parse)), true), batch_status(Ok(Some(Failure::Math)), true)), (1, 2));
+        assert_eq!(batch_status(Err(io::Error::other("unreadable")), true), 3);
+    }
+    #[test]
+    fn test_batch_limits() {
+        let huge = vec!["1+".repeat(200_000) + "1", "2 * 21".to_string()];
+        let results = run_batch(&huge, &Settings::default());
+        assert_eq!(results, [Err((Failure::Parse, "Expression is limited to 256 characters".to_string())), Ok("42".to_string())]);
+        let longest = vec!["1+".repeat(511) + "1"];
+        assert_eq!(run_batch(&longest, &Settings { max_length: 1024, ..Settings::default() }), [Ok("512".to_string())]);
+    }
+    #[test]
+    fn test_print_on_exit() {
+        let args = |list: &[&str]| list.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+        assert_eq!((print_on_exit(&args(&["--inline"])), print_on_exit(&args(&["--print-on-exit", "--inline"]))), (None, Some(PrintOnExit::Result)));
+        assert_eq!(print_on_exit(&args(&["--print-on-exit", "tape"])), Some(PrintOnExit::Tape));
+        let mut app = App::new();
+        assert_eq!(exit_text(&app, PrintOnExit::Result), None);
+        app.settings.group_separator = Some(',');
+        press(&mut app, &["1", "2", "0", "0", "*", "3", "=", "/", "8", "="]);
+        assert_eq!(exit_text(&app, PrintOnExit::Result).as_deref(), Some("450\n"));
+        assert_eq!(exit_text(&app, PrintOnExit::Tape).as_deref(), Some("1200 * 3 = 3600\n3600 / 8 = 450\n"));
+    }
+    #[test]
+    fn test_terminal_options() {
+        let args = |list: &[&str]| list.iter().map(|arg| arg.to_string()).collect::<Vec<_>>();
+        assert!(captures_mouse(&Config::default(), &args(&["--profile", "finance"])));
+        assert!(!captures_mouse(&Config::default(), &args(&["--no-mouse"])));
+        assert!(!captures_mouse(&toml::from_str("mouse = false").unwrap(), &[]));
+        assert!(!captures_mouse(&toml::from_str("mouse = true").unwrap(), &args(&["--no-mouse"])));
+        assert_eq!((inline_height(&Config::default(), &[]), inline_height(&Config::default(), &args(&["--inline"]))), (None, Some(INLINE_HEIGHT)));
+        let config: Config = toml::from_str("inline = true\ninline_height = 16").unwrap();
+        assert_eq!((inline_height(&config, &[]), config.check()), (Some(16), Ok(())));
+        assert_eq!(toml::from_str::<Config>("inline_height = 4").unwrap().check(), Err("inline_height below 12 rows".to_string()));
+        let config: Config = toml::from_str("[profiles.work]\nangles = \"deg\"").unwrap();
+        assert_eq!((chosen_profile(&config, &[]), chosen_profile(&config, &args(&["--profile", "work"]))), (Ok("default"), Ok("work")));
+        assert_eq!(chosen_profile(&config, &args(&["--profile", "wrok"])), Err("Unknown profile `wrok`; the config file has work".to_string()));
+        assert_eq!(chosen_profile(&Config::default(), &args(&["--profile"])), Err("--profile needs a name".to_string()));
+    }
+}
@@ -0,0 +1,501 @@
+//! Tokenizing and parsing expressions into a syntax tree.
+
+use crate::{dates::unix_timestamp, units::unit};
+
+// --- Expression Parser Section (Recursive Descent into an AST) ---
+
+/// A byte range into the source expression.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct SourceSpan { pub(crate) start: usize, pub(crate) end: usize }
+
+impl SourceSpan {
+    pub(crate) fn to(self, other: SourceSpan) -> SourceSpan { SourceSpan { start: self.start, end: other.end } }
+}
+
+/// An evaluation failure, pointing at the sub-expression that caused it when known.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct EvalError { pub(crate) message: String, pub(crate) span: Option<SourceSpan> }
+
+impl EvalError {
+    pub(crate) fn new(message: &str, span: SourceSpan) -> Self { EvalError { message: message.to_string(), span: Some(span) } }
+
+    /// Describes the error, quoting the offending part of `source` when it is not the whole input.
+    pub(crate) fn describe(&self, source: &str) -> String {
+        match self.span.and_then(|span| source.get(span.start..span.end)) {
+            Some(part) if part.trim() != source.trim() && !part.trim().is_empty() => format!("{} in `{}`", self.message, part.trim()),
+            _ => self.message.clone(),
+        }
+    }
+}
+
+/// The kinds of lexical tokens. A number keeps its digits as written, in plain or `digits e power` form, so the exact
+/// backends read `99999999999999999999` as it is rather than as the nearest float.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum TokenKind { Number(f64, String), Duration(f64), Ident(String), Op(char), Percent, LParen, RParen, Comma, Infinity }
+
+/// A token together with its position in the source.
+#[derive(Clone, Debug)]
+pub(crate) struct Token { pub(crate) kind: TokenKind, pub(crate) span: SourceSpan }
+
+/// The currency symbols an amount can be typed with, as in `$1500` or `€3.50`.
+pub(crate) const CURRENCY_TAGS: [char; 4] = ['$', '€', '£', '¥'];
+
+/// The currency each symbol stands for in an `fx` conversion.
+pub(crate) const CURRENCY_CODES: [(char, &str); 4] = [('$', "USD"), ('€', "EUR"), ('£', "GBP"), ('¥', "JPY")];
+
+/// The engineering suffixes a number can carry, as in `4.7k` or `100n`, with the power of ten each one stands for.
+pub(crate) const SI_SUFFIXES: [(char, i32); 6] = [('n', -9), ('u', -6), ('m', -3), ('k', 3), ('M', 6), ('G', 9)];
+
+/// The currency symbol a number was typed with: the one its source text starts with, inside any parentheses.
+pub(crate) fn currency_tag(source: &str, span: SourceSpan) -> Option<char> {
+    source.get(span.start..)?.trim_start_matches(|c: char| c == '(' || c.is_whitespace()).chars().next().filter(|c| CURRENCY_TAGS.contains(c))
+}
+
+/// The power of ten of an engineering suffix right after the digits ending at `end`, unless it starts a word.
+pub(crate) fn si_suffix(expression: &str, end: usize) -> Option<i32> {
+    let (_, power) = SI_SUFFIXES.iter().find(|(c, _)| expression[end..].starts_with(*c))?;
+    (!expression[end + 1..].starts_with(|d: char| d.is_ascii_alphanumeric() || d == '_')).then_some(*power)
+}
+
+/// Splits an expression into tokens. With `si_suffixes` off, a letter right after the digits is a unit, so `5m` is five metres.
+pub(crate) fn tokenize(expression: &str, si_suffixes: bool) -> Result<Vec<Token>, EvalError> {
+    let mut tokens = Vec::new();
+    let mut chars = expression.char_indices().peekable();
+    while let Some((start, c)) = chars.next() {
+        let kind = match c {
+            c if c.is_whitespace() => continue,
+            '0'..='9' | '.' => {
+                let mut end = start + 1;
+                while let Some(&(i, d)) = chars.peek() {
+                    if d.is_ascii_digit() || d == '.' { end = i + 1; chars.next(); } else { break; }
+                }
+                // `h:mm` and `h:mm:ss` are durations, kept in seconds.
+                while expression[end..].starts_with(':') && expression[end + 1..].starts_with(|d: char| d.is_ascii_digit()) {
+                    end += 1 + expression[end + 1..].find(|d: char| !(d.is_ascii_digit() || d == '.')).unwrap_or(expression.len() - end - 1);
+                }
+                while chars.next_if(|&(i, _)| i < end).is_some() {}
+                if expression[start..end].contains(':') {
+                    let span = SourceSpan { start, end };
+                    let seconds = parse_duration(&expression[start..end]).ok_or(EvalError::new("Invalid duration (use h:mm or h:mm:ss)", span))?;
+                    tokens.push(Token { kind: TokenKind::Duration(seconds), span });
+                    continue;
+                }
+                // A suffix right after the digits scales the number, so `4.7k` is 4700; with a space, `5 m` is a unit.
+                // Scaling the written digits keeps `4.7k` exact, where multiplying 4.7 by 1000 would not be.
+                let suffix = si_suffix(expression, end).filter(|_| si_suffixes);
+                let text = suffix.map_or_else(|| expression[start..end].to_string(), |power| format!("{}e{}", &expression[start..end], power));
+                let end = end + usize::from(suffix.is_some());
+                while chars.next_if(|&(i, _)| i < end).is_some() {}
+                let number = text.parse().map_err(|_| EvalError::new("Invalid number", SourceSpan { start, end }))?;
+                tokens.push(Token { kind: TokenKind::Number(number, text), span: SourceSpan { start, end } });
+                continue;
+            }
+            // A currency symbol is a tag on the number right after it; the number's span takes it in, which is
+            // where `currency_tag` finds it again. The number can carry a suffix, as in `$4.7k`.
+            c if CURRENCY_TAGS.contains(&c) && expression[start + c.len_utf8()..].starts_with(|d: char| d.is_ascii_digit() || d == '.') => {
+                let digits = start + c.len_utf8();
+                let end = expression[digits..].find(|d: char| !(d.is_ascii_digit() || d == '.')).map_or(expression.len(), |i| digits + i);
+                let suffix = si_suffix(expression, end).filter(|_| si_suffixes);
+                let text = suffix.map_or_else(|| expression[digits..end].to_string(), |power| format!("{}e{}", &expression[digits..end], power));
+                let end = end + usize::from(suffix.is_some());
+                while chars.next_if(|&(i, _)| i < end).is_some() {}
+                let number = text.parse().map_err(|_| EvalError::new("Invalid number", SourceSpan { start, end }))?;
+                tokens.push(Token { kind: TokenKind::Number(number, text), span: SourceSpan { start, end } });
+                continue;
+            }
+            c if c.is_ascii_alphabetic() => {
+                let mut end = start + 1;
+                while let Some(&(i, d)) = chars.peek() {
+                    if d.is_ascii_alphanumeric() || d == '_' { end = i + 1; chars.next(); } else { break; }
+                }
+                tokens.push(Token { kind: TokenKind::Ident(expression[start..end].to_string()), span: SourceSpan { start, end } });
+                continue;
+            }
+            // `@3` is the third history result, the same name as `ans3`.
+            '@' if expression[start + 1..].starts_with(|d: char| d.is_ascii_digit()) => {
+                let end = expression[start + 1..].find(|d: char| !d.is_ascii_digit()).map_or(expression.len(), |i| start + 1 + i);
+                while chars.next_if(|&(i, _)| i < end).is_some() {}
+                tokens.push(Token { kind: TokenKind::Ident(format!("ans{}", &expression[start + 1..end])), span: SourceSpan { start, end } });
+                continue;
+            }
+            '+' | '-' | '*' | '/' | '^' => TokenKind::Op(c),
+            '%' => TokenKind::Percent,
+            // `unix(2024-06-01 12:00)` holds a date rather than an expression. It is read here as a timestamp in UTC,
+            // and `unix` moves it to the configured time zone.
+            '(' if matches!(tokens.last(), Some(Token { kind: TokenKind::Ident(name), .. }) if name == "unix") => {
+                tokens.push(Token { kind: TokenKind::LParen, span: SourceSpan { start, end: start + 1 } });
+                let end = expression[start + 1..].find(')').map_or(expression.len(), |i| start + 1 + i);
+                let span = SourceSpan { start: start + 1, end };
+                let timestamp = unix_timestamp(&expression[start + 1..end], 0).ok_or(EvalError::new("Expected a date like unix(2024-06-01 12:00)", span))?;
+                while chars.next_if(|&(i, _)| i < end).is_some() {}
+                tokens.push(Token { kind: TokenKind::Number(timestamp as f64, timestamp.to_string()), span });
+                continue;
+            }
+            '(' => TokenKind::LParen,
+            ')' => TokenKind::RParen,
+            ',' => TokenKind::Comma,
+            '∞' => TokenKind::Infinity,
+            // A quoted character stands for its code point, so `"A"` is 65.
+            '"' => {
+                let Some(length) = expression[start + 1..].find('"') else {
+                    return Err(EvalError::new("Unclosed character literal", SourceSpan { start, end: expression.len() }));
+                };
+                let span = SourceSpan { start, end: start + length + 2 };
+                // `molarmass("H2O")` (or `mm(…)`) is read whole, the formula never being a value of its own.
+                if let [.., Token { kind: TokenKind::Ident(name), span: call_start }, Token { kind: TokenKind::LParen, .. }] = tokens.as_slice()
+                    && (name == "molarmass" || name == "mm") {
+                    let call_start = call_start.start;
+                    let close = expression[span.end..].find(|c: char| !c.is_whitespace()).map(|i| span.end + i).filter(|&i| expression[i..].starts_with(')'));
+                    let Some(close) = close else { return Err(EvalError::new("Missing closing parenthesis", SourceSpan { start: call_start, end: span.end })) };
+                    let mass = molar_mass(&expression[start + 1..start + 1 + length]).map_err(|message| EvalError::new(message, span))?;
+                    while chars.next_if(|&(i, _)| i <= close).is_some() {}
+                    tokens.truncate(tokens.len() - 2);
+                    tokens.push(Token { kind: TokenKind::Number(mass, mass.to_string()), span: SourceSpan { start: call_start, end: close + 1 } });
+                    continue;
+                }
+                let mut inner = expression[start + 1..start + 1 + length].chars();
+                let (Some(c), None) = (inner.next(), inner.next()) else { return Err(EvalError::new("A character literal holds exactly one character", span)) };
+                while chars.next_if(|&(i, _)| i < span.end).is_some() {}
+                tokens.push(Token { kind: TokenKind::Number(c as u32 as f64, (c as u32).to_string()), span });
+                continue;
+            }
+            _ => return Err(EvalError::new("Invalid character", SourceSpan { start, end: start + c.len_utf8() })),
+        };
+        tokens.push(Token { kind, span: SourceSpan { start, end: start + c.len_utf8() } });
+    }
+    Ok(tokens)
+}
+
+/// Reads `h:mm` or `h:mm:ss` (seconds may have a fraction) as a number of seconds.
+pub(crate) fn parse_duration(text: &str) -> Option<f64> {
+    let parts: Vec<&str> = text.split(':').collect();
+    let hours: u64 = parts[0].parse().ok()?;
+    let minutes: u64 = parts.get(1)?.parse().ok().filter(|&m| m < 60)?;
+    let seconds = match parts.get(2) { Some(s) => s.parse::<f64>().ok().filter(|&s| s < 60.0)?, None => 0.0 };
+    if parts.len() > 3 { return None; }
+    Some((hours * 3600 + minutes * 60) as f64 + seconds)
+}
+
+/// Formats seconds as `h:mm:ss`, keeping up to milliseconds.
+pub(crate) fn format_duration(seconds: f64) -> String {
+    let millis = (seconds.abs() * 1000.0).round() as u64;
+    let (hours, minutes, secs, frac) = (millis / 3_600_000, millis / 60_000 % 60, millis / 1000 % 60, millis % 1000);
+    let sign = if seconds < 0.0 && millis > 0 { "-" } else { "" };
+    let frac = if frac == 0 { String::new() } else { format!(".{:03}", frac).trim_end_matches('0').to_string() };
+    format!("{}{}:{:02}:{:02}{}", sign, hours, minutes, secs, frac)
+}
+
+/// Standard atomic weights in g/mol, by atomic number; elements without a stable isotope use their longest-lived one.
+pub(crate) const ELEMENTS: [(&str, f64); 118] = [
+    ("H", 1.008), ("He", 4.0026), ("Li", 6.94), ("Be", 9.0122), ("B", 10.81),
+    ("C", 12.011), ("N", 14.007), ("O", 15.999), ("F", 18.998), ("Ne", 20.180),
+    ("Na", 22.990), ("Mg", 24.305), ("Al", 26.982), ("Si", 28.085), ("P", 30.974),
+    ("S", 32.06), ("Cl", 35.45), ("Ar", 39.95), ("K", 39.098), ("Ca", 40.078),
+    ("Sc", 44.956), ("Ti", 47.867), ("V", 50.942), ("Cr", 51.996), ("Mn", 54.938),
+    ("Fe", 55.845), ("Co", 58.933), ("Ni", 58.693), ("Cu", 63.546), ("Zn", 65.38),
+    ("Ga", 69.723), ("Ge", 72.630), ("As", 74.922), ("Se", 78.971), ("Br", 79.904),
+    ("Kr", 83.798), ("Rb", 85.468), ("Sr", 87.62), ("Y", 88.906), ("Zr", 91.224),
+    ("Nb", 92.906), ("Mo", 95.95), ("Tc", 98.0), ("Ru", 101.07), ("Rh", 102.91),
+    ("Pd", 106.42), ("Ag", 107.87), ("Cd", 112.41), ("In", 114.82), ("Sn", 118.71),
+    ("Sb", 121.76), ("Te", 127.60), ("I", 126.90), ("Xe", 131.29), ("Cs", 132.91),
+    ("Ba", 137.33), ("La", 138.91), ("Ce", 140.12), ("Pr", 140.91), ("Nd", 144.24),
+    ("Pm", 145.0), ("Sm", 150.36), ("Eu", 151.96), ("Gd", 157.25), ("Tb", 158.93),
+    ("Dy", 162.50), ("Ho", 164.93), ("Er", 167.26), ("Tm", 168.93), ("Yb", 173.05),
+    ("Lu", 174.97), ("Hf", 178.49), ("Ta", 180.95), ("W", 183.84), ("Re", 186.21),
+    ("Os", 190.23), ("Ir", 192.22), ("Pt", 195.08), ("Au", 196.97), ("Hg", 200.59),
+    ("Tl", 204.38), ("Pb", 207.2), ("Bi", 208.98), ("Po", 209.0), ("At", 210.0),
+    ("Rn", 222.0), ("Fr", 223.0), ("Ra", 226.0), ("Ac", 227.0), ("Th", 232.04),
+    ("Pa", 231.04), ("U", 238.03), ("Np", 237.0), ("Pu", 244.0), ("Am", 243.0),
+    ("Cm", 247.0), ("Bk", 247.0), ("Cf", 251.0), ("Es", 252.0), ("Fm", 257.0),
+    ("Md", 258.0), ("No", 259.0), ("Lr", 266.0), ("Rf", 267.0), ("Db", 268.0),
+    ("Sg", 269.0), ("Bh", 270.0), ("Hs", 269.0), ("Mt", 278.0), ("Ds", 281.0),
+    ("Rg", 282.0), ("Cn", 285.0), ("Nh", 286.0), ("Fl", 289.0), ("Mc", 290.0),
+    ("Lv", 293.0), ("Ts", 294.0), ("Og", 294.0),
+];
+
+/// The molar mass of a formula like `Ca(OH)2` or the hydrate `CuSO4·5H2O`, in g/mol.
+pub(crate) fn molar_mass(formula: &str) -> Result<f64, &'static str> {
+    let mut total = 0.0;
+    for part in formula.split(['·', '*']) {
+        let part = part.trim();
+        let digits = part.len() - part.trim_start_matches(|c: char| c.is_ascii_digit()).len();
+        let coefficient = if digits == 0 { 1.0 } else { part[..digits].parse::<f64>().map_err(|_| "Invalid chemical formula")? };
+        let chars: Vec<char> = part[digits..].chars().collect();
+        if chars.is_empty() { return Err("Empty chemical formula"); }
+        let (mass, end) = formula_group(&chars, 0)?;
+        if end != chars.len() { return Err("Unbalanced parentheses in formula"); }
+        total += coefficient * mass;
+    }
+    Ok(total)
+}
+
+/// Sums the formula from `i` up to the closing bracket that ends the group, returning where it stopped.
+pub(crate) fn formula_group(chars: &[char], mut i: usize) -> Result<(f64, usize), &'static str> {
+    let mut mass = 0.0;
+    while i < chars.len() {
+        let (part, next) = match chars[i] {
+            open @ ('(' | '[') => {
+                let (inner, end) = formula_group(chars, i + 1)?;
+                if chars.get(end) != Some(&if open == '(' { ')' } else { ']' }) { return Err("Unbalanced parentheses in formula"); }
+                (inner, end + 1)
+            }
+            ')' | ']' => return Ok((mass, i)),
+            c if c.is_ascii_uppercase() => {
+                let end = i + 1 + chars[i + 1..].iter().take_while(|c| c.is_ascii_lowercase()).count();
+                let symbol: String = chars[i..end].iter().collect();
+                (ELEMENTS.iter().find(|(s, _)| *s == symbol).ok_or("Unknown element in formula")?.1, end)
+            }
+            _ => return Err("Invalid chemical formula"),
+        };
+        let end = next + chars[next..].iter().take_while(|c| c.is_ascii_digit()).count();
+        let count = if end == next { 1.0 } else { chars[next..end].iter().collect::<String>().parse::<f64>().map_err(|_| "Invalid chemical formula")? };
+        mass += part * count;
+        i = end;
+    }
+    Ok((mass, i))
+}
+
+/// The kinds of expression tree nodes.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum ExprKind {
+    /// A number with its digits as written, like `TokenKind::Number`.
+    Number(f64, String),
+    Duration(f64),
+    Infinity,
+    Name(String),
+    Neg(Box<Expr>),
+    Percent(Box<Expr>),
+    Binary(char, Box<Expr>, Box<Expr>),
+    Call(String, Vec<Expr>),
+}
+
+/// A node of the expression tree, spanning the source text it was parsed from.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct Expr { pub(crate) kind: ExprKind, pub(crate) span: SourceSpan }
+
+/// A recursive-descent parser over a token list.
+/// `depth` counts the open parentheses, calls, and unary minuses being parsed, bounded by `max_depth`.
+pub(crate) struct Parser { pub(crate) tokens: Vec<Token>, pub(crate) pos: usize, pub(crate) end: usize, pub(crate) depth: usize, pub(crate) max_depth: usize }
+
+impl Parser {
+    pub(crate) fn peek(&self) -> Option<&TokenKind> { self.tokens.get(self.pos).map(|t| &t.kind) }
+
+    pub(crate) fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).cloned();
+        self.pos += 1;
+        token
+    }
+
+    /// The span of the current token, or an empty span at the end of input.
+    pub(crate) fn here(&self) -> SourceSpan { self.tokens.get(self.pos).map_or(SourceSpan { start: self.end, end: self.end }, |t| t.span) }
+
+    /// Enters one level of nesting, failing once the limit is exceeded.
+    pub(crate) fn descend(&mut self, span: SourceSpan) -> Result<(), EvalError> {
+        self.depth += 1;
+        if self.depth > self.max_depth { Err(EvalError::new("Expression too deeply nested", span)) } else { Ok(()) }
+    }
+
+    /// Parses a left-associative chain of binary operators drawn from `ops`.
+    pub(crate) fn binary_chain(&mut self, ops: &[char], operand: fn(&mut Parser) -> Result<Expr, EvalError>) -> Result<Expr, EvalError> {
+        let mut lhs = operand(self)?;
+        while let Some(&TokenKind::Op(op)) = self.peek() {
+            if !ops.contains(&op) { break; }
+            self.next();
+            let rhs = operand(self)?;
+            let span = lhs.span.to(rhs.span);
+            lhs = Expr { kind: ExprKind::Binary(op, Box::new(lhs), Box::new(rhs)), span };
+        }
+        Ok(lhs)
+    }
+
+    pub(crate) fn expr(&mut self) -> Result<Expr, EvalError> { self.binary_chain(&['+', '-'], Parser::term) }
+
+    pub(crate) fn term(&mut self) -> Result<Expr, EvalError> { self.binary_chain(&['*', '/'], Parser::power) }
+
+    pub(crate) fn power(&mut self) -> Result<Expr, EvalError> { self.binary_chain(&['^'], Parser::unary) }
+
+    pub(crate) fn unary(&mut self) -> Result<Expr, EvalError> {
+        if let Some(TokenKind::Op('-')) = self.peek() {
+            let start = self.next().unwrap().span;
+            self.descend(start)?;
+            let operand = self.unary()?;
+            self.depth -= 1;
+            let span = start.to(operand.span);
+            return Ok(Expr { kind: ExprKind::Neg(Box::new(operand)), span });
+        }
+        let mut operand = self.primary()?;
+        // A unit right after a number multiplies it, taking its own power: `5 m`, `3 m^2`.
+        if matches!(operand.kind, ExprKind::Number(..)) && let Some(TokenKind::Ident(name)) = self.peek().cloned() && unit(&name).is_some()
+            && self.tokens.get(self.pos + 1).map(|t| &t.kind) != Some(&TokenKind::LParen) {
+            let mut quantity_unit = Expr { kind: ExprKind::Name(name), span: self.next().unwrap().span };
+            if let Some(TokenKind::Op('^')) = self.peek() {
+                self.next();
+                let exponent = self.unary()?;
+                let span = quantity_unit.span.to(exponent.span);
+                quantity_unit = Expr { kind: ExprKind::Binary('^', Box::new(quantity_unit), Box::new(exponent)), span };
+            }
+            let span = operand.span.to(quantity_unit.span);
+            operand = Expr { kind: ExprKind::Binary('*', Box::new(operand), Box::new(quantity_unit)), span };
+        }
+        while let Some(TokenKind::Percent) = self.peek() {
+            let span = operand.span.to(self.next().unwrap().span);
+            operand = Expr { kind: ExprKind::Percent(Box::new(operand)), span };
+        }
+        Ok(operand)
+    }
+
+    pub(crate) fn primary(&mut self) -> Result<Expr, EvalError> {
+        let here = self.here();
+        let token = self.next().ok_or(EvalError::new("Syntax error", here))?;
+        match token.kind {
+            TokenKind::Number(n, text) => Ok(Expr { kind: ExprKind::Number(n, text), span: token.span }),
+            TokenKind::Duration(seconds) => Ok(Expr { kind: ExprKind::Duration(seconds), span: token.span }),
+            TokenKind::Infinity => Ok(Expr { kind: ExprKind::Infinity, span: token.span }),
+            TokenKind::LParen => {
+                self.descend(token.span)?;
+                let inner = self.expr()?;
+                self.depth -= 1;
+                match self.next() {
+                    Some(Token { kind: TokenKind::RParen, span }) => Ok(Expr { kind: inner.kind, span: token.span.to(span) }),
+                    _ => Err(EvalError::new("Missing closing parenthesis", token.span.to(inner.span))),
+                }
+            }
+            // `fx(100 USD, EUR)` names its currencies by code; the codes become names the evaluator reads as they are.
+            TokenKind::Ident(name) if name == "fx" && self.peek() == Some(&TokenKind::LParen) => {
+                self.next();
+                self.descend(token.span)?;
+                let amount = self.expr()?;
+                let expected = |parser: &Parser| EvalError::new("Expected a conversion like fx(100 USD, EUR)", token.span.to(parser.here()));
+                let code = |parser: &mut Parser| match parser.next() {
+                    Some(Token { kind: TokenKind::Ident(code), span }) => Ok(Expr { kind: ExprKind::Name(code.to_uppercase()), span }),
+                    _ => Err(expected(parser)),
+                };
+                let from = code(self)?;
+                if self.next().map(|t| t.kind) != Some(TokenKind::Comma) { return Err(expected(self)); }
+                let to = code(self)?;
+                self.depth -= 1;
+                match self.next() {
+                    Some(Token { kind: TokenKind::RParen, span }) => Ok(Expr { kind: ExprKind::Call(name, vec![amount, from, to]), span: token.span.to(span) }),
+                    _ => Err(EvalError::new("Missing closing parenthesis", token.span.to(self.here()))),
+                }
+            }
+            TokenKind::Ident(name) if self.peek() == Some(&TokenKind::LParen) => {
+                self.next();
+                self.descend(token.span)?;
+                let mut args = Vec::new();
+                if self.peek() != Some(&TokenKind::RParen) {
+                    loop {
+                        args.push(self.expr()?);
+                        if self.peek() == Some(&TokenKind::Comma) { self.next(); } else { break; }
+                    }
+                }
+                self.depth -= 1;
+                match self.next() {
+                    Some(Token { kind: TokenKind::RParen, span }) => Ok(Expr { kind: ExprKind::Call(name, args), span: token.span.to(span) }),
+                    _ => Err(EvalError::new("Missing closing parenthesis", token.span.to(self.here()))),
+                }
+            }
+            TokenKind::Ident(name) => Ok(Expr { kind: ExprKind::Name(name), span: token.span }),
+            _ => Err(EvalError::new("Syntax error", token.span)),
+        }
+    }
+}
+
+/// Parses tokenized input into a tree, rejecting nesting deeper than `max_depth`.
+/// `end` is the source length, used to point at a missing token.
+pub(crate) fn parse_tokens(tokens: Vec<Token>, end: usize, max_depth: usize) -> Result<Expr, EvalError> {
+    let mut parser = Parser { tokens, pos: 0, end, depth: 0, max_depth };
+    let expr = parser.expr()?;
+    match parser.next() {
+        None => Ok(expr),
+        Some(Token { kind: TokenKind::RParen, span }) => Err(EvalError::new("Unmatched closing parenthesis", span)),
+        Some(token) => Err(EvalError::new("Syntax error", token.span)),
+    }
+}
+
+/// Whether `expression` fails to tokenize or parse, as opposed to failing on its values once evaluated.
+pub(crate) fn is_syntax_error(expression: &str, max_depth: usize, si_suffixes: bool) -> bool {
+    tokenize(expression, si_suffixes).and_then(|tokens| parse_tokens(tokens, expression.len(), max_depth)).is_err()
+}
+
+/// The written orders a tree can be shown in besides infix.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Notation { Postfix, Prefix }
+
+/// Writes the tree in postfix (RPN) or prefix (Polish) notation. Negation is `neg`, and a function
+/// taking other than one argument carries its arity, as in `sum/3`, so the text stays unambiguous.
+pub(crate) fn write_notation(expr: &Expr, notation: Notation) -> String {
+    let (operator, operands): (String, Vec<&Expr>) = match &expr.kind {
+        ExprKind::Number(n, _) => return n.to_string(),
+        ExprKind::Duration(seconds) => return format_duration(*seconds),
+        ExprKind::Infinity => return "∞".to_string(),
+        ExprKind::Name(name) => return name.clone(),
+        ExprKind::Neg(operand) => ("neg".to_string(), vec![operand]),
+        ExprKind::Percent(operand) => ("%".to_string(), vec![operand]),
+        ExprKind::Binary(op, lhs, rhs) => (op.to_string(), vec![lhs, rhs]),
+        ExprKind::Call(name, args) if args.len() == 1 => (name.clone(), args.iter().collect()),
+        ExprKind::Call(name, args) => (format!("{}/{}", name, args.len()), args.iter().collect()),
+    };
+    let operands = operands.into_iter().map(|operand| write_notation(operand, notation));
+    match notation {
+        Notation::Postfix => operands.chain([operator]).collect::<Vec<_>>().join(" "),
+        Notation::Prefix => [operator].into_iter().chain(operands).collect::<Vec<_>>().join(" "),
+    }
+}
+
+/// Parses `expression` and writes it in `notation`, or `None` while it does not parse or nests deeper than `max_depth`.
+pub(crate) fn expression_notation(expression: &str, notation: Notation, max_depth: usize, si_suffixes: bool) -> Option<String> {
+    let tree = parse_tokens(tokenize(expression, si_suffixes).ok()?, expression.len(), max_depth).ok()?;
+    Some(write_notation(&tree, notation))
+}
+
+/// Draws the tree as indented lines joined by box-drawing branches, one node per line.
+pub(crate) fn tree_lines(expr: &Expr) -> Vec<String> {
+    let (label, children): (String, Vec<&Expr>) = match &expr.kind {
+        ExprKind::Number(n, _) => (n.to_string(), Vec::new()),
+        ExprKind::Duration(seconds) => (format_duration(*seconds), Vec::new()),
+        ExprKind::Infinity => ("∞".to_string(), Vec::new()),
+        ExprKind::Name(name) => (name.clone(), Vec::new()),
+        ExprKind::Neg(operand) => ("neg".to_string(), vec![operand]),
+        ExprKind::Percent(operand) => ("%".to_string(), vec![operand]),
+        ExprKind::Binary(op, lhs, rhs) => (op.to_string(), vec![lhs, rhs]),
+        ExprKind::Call(name, args) => (format!("{}()", name), args.iter().collect()),
+    };
+    let mut lines = vec![label];
+    for (i, child) in children.iter().enumerate() {
+        let last = i + 1 == children.len();
+        for (j, line) in tree_lines(child).into_iter().enumerate() {
+            let branch = match (j, last) { (0, false) => "├─ ", (0, true) => "└─ ", (_, false) => "│  ", (_, true) => "   " };
+            lines.push(format!("{}{}", branch, line));
+        }
+    }
+    lines
+}
+
+/// Whether the whole expression is one parenthesized group, like `(1 + 2)` but not `(1) + (2)`.
+pub(crate) fn is_parenthesized(expression: &str) -> bool {
+    let mut depth = 0;
+    for (i, c) in expression.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => { depth -= 1; if depth == 0 && i + 1 < expression.len() { return false; } }
+            _ if depth == 0 => return false,
+            _ => {}
+        }
+    }
+    depth == 0 && expression.starts_with('(')
+}
+
+// --- End of Parser Section ---
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_parse_tree() {
+        let tree = parse_tokens(tokenize("1 + 2 * sqrt(-x)", false).unwrap(), 16, 64).unwrap();
+        assert_eq!(tree_lines(&tree), ["+", "├─ 1", "└─ *", "   ├─ 2", "   └─ sqrt()", "      └─ neg", "         └─ x"]);
+        let tree = parse_tokens(tokenize("(1 - 5) ^ 2", false).unwrap(), 11, 64).unwrap();
+        assert_eq!(tree_lines(&tree), ["^", "├─ -", "│  ├─ 1", "│  └─ 5", "└─ 2"]);
+    }
+}
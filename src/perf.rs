@@ -0,0 +1,89 @@
+//! Performance counters for the hidden F12 overlay: frame times, draw rate, input latency and heap allocations.
+//! Allocations are only counted in builds with the `alloc-count` feature, which wraps the global allocator.
+
+use std::{collections::VecDeque, time::{Duration, Instant}};
+#[cfg(feature = "alloc-count")]
+use std::{alloc::{GlobalAlloc, Layout, System}, cell::Cell};
+
+/// The system allocator, counting the allocations of each thread.
+#[cfg(feature = "alloc-count")]
+struct CountingAlloc;
+
+#[cfg(feature = "alloc-count")]
+thread_local! {
+    static ALLOCATIONS: Cell<u64> = const { Cell::new(0) };
+}
+
+#[cfg(feature = "alloc-count")]
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        // `try_with` because the thread-local is gone while a thread shuts down, and allocations still happen then.
+        let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+        unsafe { System.alloc(layout) }
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) { unsafe { System.dealloc(ptr, layout) } }
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+        unsafe { System.realloc(ptr, layout, new_size) }
+    }
+}
+
+#[cfg(feature = "alloc-count")]
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+/// How many allocations and reallocations the current thread has made so far, if this build counts them.
+#[cfg(feature = "alloc-count")]
+pub(crate) fn allocations() -> Option<u64> { Some(ALLOCATIONS.with(Cell::get)) }
+#[cfg(not(feature = "alloc-count"))]
+pub(crate) fn allocations() -> Option<u64> { None }
+
+/// Timings of the event loop, kept whether or not the overlay is open so it shows real numbers as soon as it is.
+#[derive(Default)]
+pub(crate) struct FrameStats {
+    /// When each frame of the last second was drawn.
+    draws: VecDeque<Instant>,
+    /// How long the last frame took to render.
+    pub(crate) render: Duration,
+    /// From the last input event arriving to the frame showing its effect being drawn.
+    pub(crate) latency: Duration,
+    /// When the input event waiting for a frame arrived.
+    input_at: Option<Instant>,
+}
+
+impl FrameStats {
+    /// Notes an input event, to measure how long it takes to show.
+    pub(crate) fn input(&mut self, at: Instant) { self.input_at = Some(at); }
+
+    /// Notes a frame that started at `start` and has just been drawn.
+    pub(crate) fn frame(&mut self, start: Instant) {
+        let now = Instant::now();
+        self.render = now - start;
+        if let Some(at) = self.input_at.take() { self.latency = now - at; }
+        self.draws.push_back(now);
+        while self.draws.front().is_some_and(|&draw| now - draw > Duration::from_secs(1)) { self.draws.pop_front(); }
+    }
+
+    /// How many frames were drawn in the last second.
+    pub(crate) fn draws_per_second(&self) -> usize { self.draws.len() }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_frame_stats() {
+        let before = allocations();
+        let numbers: Vec<Box<u32>> = (0..10).map(Box::new).collect();
+        let counted = allocations().zip(before).map(|(after, before)| after - before);
+        assert!(numbers.len() == 10 && if cfg!(feature = "alloc-count") { counted >= Some(11) } else { counted.is_none() });
+        let mut stats = FrameStats::default();
+        let start = Instant::now();
+        stats.input(start);
+        std::thread::sleep(Duration::from_millis(5));
+        stats.frame(start);
+        assert!(stats.render >= Duration::from_millis(5) && stats.latency == stats.render);
+        stats.frame(Instant::now());
+        assert_eq!((stats.draws_per_second(), stats.latency >= Duration::from_millis(5)), (2, true));
+    }
+}
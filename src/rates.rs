@@ -0,0 +1,121 @@
+//! Currency exchange rates: a built-in table, refreshed from an HTTP endpoint when one is configured and cached on disk.
+
+use crate::tasks::http_get;
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, path::PathBuf, time::{SystemTime, UNIX_EPOCH}};
+
+/// Where a set of rates came from.
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub(crate) enum RateSource { #[default] Builtin, Cache, Online }
+
+/// Exchange rates as units of each currency per one unit of `base`, fetched at `fetched` (seconds since 1970).
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub(crate) struct Rates {
+    pub(crate) base: String, pub(crate) fetched: u64, pub(crate) rates: BTreeMap<String, f64>,
+    #[serde(skip)] pub(crate) source: RateSource,
+}
+
+/// Approximate euro rates from mid-2024, for when no endpoint is configured or none could be reached.
+const BUILTIN_RATES: [(&str, f64); 12] = [
+    ("USD", 1.08), ("GBP", 0.85), ("JPY", 169.0), ("CHF", 0.96), ("CAD", 1.47), ("AUD", 1.62),
+    ("CNY", 7.8), ("SEK", 11.3), ("NOK", 11.5), ("DKK", 7.46), ("PLN", 4.3), ("INR", 90.0),
+];
+
+/// Seconds since 1970, or 0 if the clock is set before it.
+pub(crate) fn now_secs() -> u64 { SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs()) }
+
+impl Rates {
+    pub(crate) fn builtin() -> Self {
+        Rates {
+            base: "EUR".to_string(), fetched: 0,
+            rates: BUILTIN_RATES.iter().map(|&(code, rate)| (code.to_string(), rate)).collect(),
+            source: RateSource::Builtin,
+        }
+    }
+
+    /// Units of `code` per unit of the base currency.
+    fn rate(&self, code: &str) -> Option<f64> { if code == self.base { Some(1.0) } else { self.rates.get(code).copied() } }
+
+    /// Converts `amount` from one currency to another, going through the base currency.
+    pub(crate) fn convert(&self, amount: f64, from: &str, to: &str) -> Result<f64, String> {
+        let rate = |code: &str| self.rate(code).filter(|rate| *rate > 0.0).ok_or_else(|| format!("No exchange rate for {}", code));
+        Ok(amount / rate(from)? * rate(to)?)
+    }
+
+    /// Where the rates came from and how old they are, for the notice under a conversion.
+    pub(crate) fn describe(&self, now: u64) -> String {
+        let age = now.saturating_sub(self.fetched) / 60;
+        let age = if age < 60 { format!("{} min", age) } else { format!("{} h", age / 60) };
+        match self.source {
+            RateSource::Builtin => "Built-in rates from mid-2024".to_string(),
+            RateSource::Cache => format!("Cached rates, {} old", age),
+            RateSource::Online => format!("Rates fetched {} ago", age),
+        }
+    }
+}
+
+/// The part of a rate endpoint's answer that is used; other fields, such as the date, are ignored.
+#[derive(Deserialize)]
+struct RateAnswer { base: String, rates: BTreeMap<String, f64> }
+
+/// Reads `{"base": "EUR", "rates": {"USD": 1.08, …}}`, the shape most rate APIs answer with.
+pub(crate) fn parse_rates(text: &str) -> Option<(String, BTreeMap<String, f64>)> {
+    let answer: RateAnswer = serde_json::from_str(text).ok()?;
+    (!answer.rates.is_empty()).then_some((answer.base, answer.rates))
+}
+
+/// Where fetched rates are kept between runs.
+pub(crate) fn cache_path() -> Option<PathBuf> { dirs::cache_dir().map(|dir| dir.join("rust-calculator-tui").join("rates.toml")) }
+
+fn load_cache() -> Option<Rates> {
+    let text = std::fs::read_to_string(cache_path()?).ok()?;
+    toml::from_str(&text).ok().map(|rates| Rates { source: RateSource::Cache, ..rates })
+}
+
+fn save_cache(rates: &Rates) -> Result<(), String> {
+    let path = cache_path().ok_or("No cache directory")?;
+    if let Some(dir) = path.parent() { std::fs::create_dir_all(dir).map_err(|e| e.to_string())?; }
+    std::fs::write(&path, toml::to_string(rates).map_err(|e| e.to_string())?).map_err(|e| e.to_string())
+}
+
+fn fetch(url: &str) -> Result<Rates, String> {
+    let (base, rates) = parse_rates(&String::from_utf8_lossy(&http_get(url)?)).ok_or("unexpected answer from the rate endpoint")?;
+    Ok(Rates { base, fetched: now_secs(), rates, source: RateSource::Online })
+}
+
+/// The freshest rates available: the cache while it is younger than `ttl_hours`, else a new download, else the stale
+/// cache or the built-in table. The second part explains a failed download.
+pub(crate) fn refresh_rates(url: &str, ttl_hours: u64, force: bool) -> (Rates, Option<String>) {
+    let cached = load_cache();
+    if !force && let Some(rates) = &cached && now_secs().saturating_sub(rates.fetched) < ttl_hours * 3600 { return (rates.clone(), None); }
+    match fetch(url) {
+        Ok(rates) => {
+            let warning = save_cache(&rates).err().map(|e| format!("Could not cache exchange rates: {}", e));
+            (rates, warning)
+        }
+        Err(e) => (cached.unwrap_or_else(Rates::builtin), Some(format!("Exchange rates not updated ({}), using the last known ones", e))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_exchange_rates() {
+        let (base, rates) = parse_rates(r#"{"amount":1.0,"base":"EUR","date":"2024-06-03","rates":{"GBP":0.85, "USD":1.0875}}"#).unwrap();
+        assert_eq!((base.as_str(), rates.len(), rates["USD"]), ("EUR", 2, 1.0875));
+        assert!(parse_rates(r#"{"base":"EUR","rates":{}}"#).is_none());
+        assert!(parse_rates("<html>Service unavailable</html>").is_none());
+        // The keys are matched as keys, whatever the strings and nested objects before them hold.
+        let decoy = r#"{"note":"rates, base","source":{"base":"USD","rates":{"EUR":0.9}},"base":"EUR","rates":{"GBP":0.85, "USD":1.0875}}"#;
+        let (base, nested) = parse_rates(decoy).unwrap();
+        assert_eq!((base.as_str(), nested), ("EUR", rates.clone()));
+        let table = Rates { base, fetched: 0, rates, source: RateSource::Online };
+        assert!((table.convert(108.75, "USD", "EUR").unwrap() - 100.0).abs() < 1e-9);
+        assert!((table.convert(100.0, "GBP", "USD").unwrap() - 127.94).abs() < 0.01);
+        assert_eq!(table.convert(1.0, "EUR", "XYZ"), Err("No exchange rate for XYZ".to_string()));
+        let cached: Rates = toml::from_str(&toml::to_string(&table).unwrap()).unwrap();
+        assert_eq!((cached.rates, cached.source), (table.rates, RateSource::Builtin));
+        assert_eq!(Rates { source: RateSource::Cache, fetched: 1000, ..Rates::builtin() }.describe(1000 + 3 * 3600), "Cached rates, 3 h old");
+    }
+}
@@ -0,0 +1,165 @@
+//! The `update` subcommand: checks the release feed for a newer version and can install it in place.
+
+use crate::tasks::{http_get, TaskOutput, Tasks};
+use sha2::{Digest, Sha256};
+use std::{io, path::Path, time::Duration};
+
+/// How long `update` waits for the release check and download before it gives up.
+const UPDATE_WAIT: Duration = Duration::from_secs(300);
+
+/// The latest release of this project, as GitHub's API describes it.
+pub(crate) const RELEASE_FEED: &str = "https://api.github.com/repos/Ruventium/rust-calculator-tui-gemini-test/releases/latest";
+
+/// A published release: its version and the download links of its binaries.
+#[derive(Debug, PartialEq)]
+pub(crate) struct Release { pub(crate) version: String, pub(crate) assets: Vec<String> }
+
+/// Reads the `tag_name` and every `browser_download_url` out of a release feed answer.
+pub(crate) fn parse_release(text: &str) -> Option<Release> {
+    let strings = |key: &str| text.match_indices(&format!("\"{}\"", key)).filter_map(|(at, _)| {
+        let value = text[at + key.len() + 2..].trim_start().strip_prefix(':')?.trim_start().strip_prefix('"')?;
+        value.split('"').next().map(str::to_string)
+    }).collect::<Vec<_>>();
+    let version = strings("tag_name").into_iter().next()?.trim_start_matches('v').to_string();
+    Some(Release { version, assets: strings("browser_download_url") })
+}
+
+/// Whether version `a` is newer than `b`, comparing the dot-separated numbers first; a part that is not a number
+/// counts as 0. A pre-release such as `0.2.0-rc1` comes before its release, and pre-releases compare by their suffix.
+pub(crate) fn is_newer(a: &str, b: &str) -> bool {
+    let key = |version: &str| {
+        let (core, pre) = version.split_once('-').map_or((version, None), |(core, pre)| (core, Some(pre.to_string())));
+        (core.split('.').map(|part| part.parse::<u64>().unwrap_or(0)).collect::<Vec<_>>(), pre.is_none(), pre)
+    };
+    key(a) > key(b)
+}
+
+/// The name of the release binary for `os` and `arch` as Rust names them, like `calc-x86_64-linux`,
+/// `calc-aarch64-darwin` or `calc-x86_64-windows.exe`.
+pub(crate) fn asset_name(os: &str, arch: &str) -> String {
+    let os = match os { "macos" => "darwin", os => os };
+    format!("calc-{}-{}{}", arch, os, if os == "windows" { ".exe" } else { "" })
+}
+
+/// The download link of the asset named exactly `name`.
+pub(crate) fn asset_url<'a>(release: &'a Release, name: &str) -> Option<&'a str> {
+    release.assets.iter().map(String::as_str).find(|url| url.rsplit('/').next() == Some(name))
+}
+
+/// Checks `bytes` against a published SHA-256 checksum file, which starts with the hex digest (as `sha256sum` writes it).
+pub(crate) fn verify_checksum(bytes: &[u8], published: &str) -> Result<(), String> {
+    let expected = published.split_whitespace().next().ok_or("the checksum file is empty")?.to_lowercase();
+    let actual: String = Sha256::digest(bytes).iter().map(|b| format!("{:02x}", b)).collect();
+    if actual == expected { Ok(()) } else { Err(format!("checksum mismatch (expected {}, got {})", expected, actual)) }
+}
+
+/// Downloads the new binary and its checksum, and only once they match writes it next to the running one. The running
+/// binary is then renamed aside to `.old`, since Windows refuses to rename over a running `.exe` but allows renaming
+/// it, and the new one takes its place; if that fails the old binary is put back.
+fn replace_binary(url: &str, checksum_url: &str) -> Result<(), String> {
+    let bytes = http_get(url)?;
+    verify_checksum(&bytes, &String::from_utf8_lossy(&http_get(checksum_url)?))?;
+    let current = std::env::current_exe().map_err(|e| e.to_string())?;
+    let (staged, old) = (current.with_extension("new"), current.with_extension("old"));
+    std::fs::write(&staged, bytes).map_err(|e| format!("cannot write {}: {}", staged.display(), e))?;
+    // The binary set aside by the last update; on Windows it could only go once that run had ended.
+    let _ = std::fs::remove_file(&old);
+    make_executable(&staged).and_then(|()| swap_in(&staged, &current, &old)).map_err(|e| {
+        let _ = std::fs::remove_file(&staged);
+        format!("cannot replace {}: {}", current.display(), e)
+    })
+}
+
+/// Moves `current` to `old` and `staged` to `current`, moving `old` back if the second step fails.
+fn swap_in(staged: &Path, current: &Path, old: &Path) -> io::Result<()> {
+    std::fs::rename(current, old)?;
+    std::fs::rename(staged, current).inspect_err(|_| { let _ = std::fs::rename(old, current); })
+}
+
+#[cfg(unix)]
+fn make_executable(path: &Path) -> io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+    std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o755))
+}
+
+#[cfg(not(unix))]
+fn make_executable(_: &Path) -> io::Result<()> { Ok(()) }
+
+/// Checks the feed and, with `install`, downloads a newer release over this binary.
+pub(crate) fn check_for_update(install: bool) -> TaskOutput {
+    let current = env!("CARGO_PKG_VERSION");
+    let text = match http_get(RELEASE_FEED) {
+        Ok(bytes) => String::from_utf8_lossy(&bytes).to_string(),
+        Err(e) => return TaskOutput::Error(format!("Cannot reach the release feed: {}", e)),
+    };
+    let Some(release) = parse_release(&text) else { return TaskOutput::Error("Unexpected answer from the release feed".to_string()) };
+    if !is_newer(&release.version, current) { return TaskOutput::Notice(format!("calc {} is up to date", current)); }
+    if !install { return TaskOutput::Notice(format!("calc {} is available (this is {}); run `update --install` to get it", release.version, current)); }
+    let name = asset_name(std::env::consts::OS, std::env::consts::ARCH);
+    let Some(url) = asset_url(&release, &name) else { return TaskOutput::Error(format!("calc {} has no {} binary", release.version, name)) };
+    let Some(checksum_url) = asset_url(&release, &format!("{}.sha256", name)) else {
+        return TaskOutput::Error(format!("calc {} publishes no checksum for {}, so it is not installed", release.version, name));
+    };
+    match replace_binary(url, checksum_url) {
+        Ok(()) => TaskOutput::Notice(format!("Updated calc {} to {}", current, release.version)),
+        Err(e) => TaskOutput::Error(format!("Update failed: {}", e)),
+    }
+}
+
+/// Runs `update --check` or `update --install` from the command line on the background task layer and prints the outcome.
+pub(crate) fn update_main(args: &[String]) -> Result<(), String> {
+    let install = args.iter().any(|arg| arg == "--install");
+    if !install && !args.iter().any(|arg| arg == "--check") { return Err("usage: update --check | update --install".to_string()); }
+    let mut tasks = Tasks::new();
+    tasks.spawn(move || check_for_update(install));
+    for output in tasks.finish(UPDATE_WAIT) {
+        match output {
+            TaskOutput::Notice(text) => println!("{}", text),
+            TaskOutput::Error(message) => return Err(message),
+            _ => {}
+        }
+    }
+    if tasks.busy() { return Err(format!("The update took longer than {} seconds", UPDATE_WAIT.as_secs())); }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_release_feed() {
+        let feed = r#"{"tag_name": "v0.3.1", "assets": [
+            {"name": "calc-x86_64-linux-musl", "browser_download_url": "https://example.com/dl/calc-x86_64-linux-musl"},
+            {"name": "calc-x86_64-linux", "browser_download_url": "https://example.com/dl/calc-x86_64-linux"},
+            {"name": "calc-x86_64-linux.sha256", "browser_download_url": "https://example.com/dl/calc-x86_64-linux.sha256"}]}"#;
+        let release = parse_release(feed).unwrap();
+        assert_eq!((release.version.as_str(), release.assets.len()), ("0.3.1", 3));
+        assert!(parse_release("{\"message\": \"Not Found\"}").is_none());
+        assert!(is_newer("0.10.0", "0.9.2") && is_newer("1.0.0", "0.1.0") && !is_newer("0.1.0", "0.1.0") && !is_newer("0.0.9", "0.1.0"));
+        assert!(!is_newer("0.1.0-rc1", "0.1.0") && is_newer("0.1.0", "0.1.0-rc1") && is_newer("0.1.1-rc1", "0.1.0") && is_newer("0.2.0-rc2", "0.2.0-rc1"));
+        let dir = std::env::temp_dir().join(format!("calc-swap-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (staged, current, old) = (dir.join("calc.new"), dir.join("calc"), dir.join("calc.old"));
+        std::fs::write(&staged, "new").unwrap();
+        std::fs::write(&current, "running").unwrap();
+        swap_in(&staged, &current, &old).unwrap();
+        assert_eq!(
+            (std::fs::read_to_string(&current).unwrap(), std::fs::read_to_string(&old).unwrap(), staged.exists()),
+            ("new".to_string(), "running".to_string(), false)
+        );
+        // Without a staged file the running binary goes back where it was.
+        assert!(swap_in(&staged, &current, &old).is_err() && std::fs::read_to_string(&current).unwrap() == "new");
+        std::fs::remove_dir_all(&dir).unwrap();
+        // Only the exact name matches, so the musl build or the checksum file is never taken for the binary.
+        assert_eq!(asset_url(&release, &asset_name("linux", "x86_64")), Some("https://example.com/dl/calc-x86_64-linux"));
+        assert_eq!(asset_url(&release, &asset_name("macos", "aarch64")), None);
+        assert_eq!(
+            (asset_name("macos", "aarch64"), asset_name("windows", "x86_64")),
+            ("calc-aarch64-darwin".to_string(), "calc-x86_64-windows.exe".to_string())
+        );
+        let digest = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+        assert_eq!(verify_checksum(b"abc", &format!("{}  calc-x86_64-linux\n", digest.to_uppercase())), Ok(()));
+        assert!(verify_checksum(b"abd", digest).is_err_and(|e| e.starts_with("checksum mismatch")) && verify_checksum(b"abc", "").is_err());
+        assert!(update_main(&[]).is_err());
+    }
+}
@@ -0,0 +1,144 @@
+//! Session snapshots: the expression, tape, variables and modes, saved every few seconds so `--resume` can bring
+//! them back, even after a crash or a closed terminal.
+
+use crate::{app::*, engine::{EvalTimings, NumberBackend}, tasks::TaskOutput};
+use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, path::{Path, PathBuf}, time::{Duration, Instant}};
+
+/// How often the session is saved while it changes.
+pub(crate) const AUTOSAVE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// A history entry as a snapshot keeps it; timings are not worth keeping.
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub(crate) struct SavedEntry { expression: String, value: f64, exact: Option<String>, backend: String, note: Option<String>, #[serde(default)] pinned: bool }
+
+/// A statistics data point; TOML arrays cannot hold a missing `x`, tables can.
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub(crate) struct SavedPoint { x: Option<f64>, y: f64 }
+
+/// What `--resume` brings back: the active profile's session as it was last saved.
+#[derive(Clone, PartialEq, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub(crate) struct Snapshot {
+    profile: String, expression: String, result_shown: bool, backend: String,
+    last_expression: Option<String>, last_result: Option<f64>, last_exact: Option<String>,
+    rpn: bool, stats: bool, finance_keypad: bool, base_panel: bool, tree_panel: bool, tutor: bool, variables_panel: bool,
+    variables: BTreeMap<String, f64>, rpn_stack: Vec<f64>, data_points: Vec<SavedPoint>, history: Vec<SavedEntry>,
+}
+
+impl Snapshot {
+    pub(crate) fn of(app: &App) -> Self {
+        Snapshot {
+            profile: app.profile.clone(), expression: app.display_value.clone(),
+            result_shown: app.mode() == Mode::ResultShown, backend: app.settings.backend.name().to_string(),
+            last_expression: app.last_expression.clone(), last_result: app.last_result,
+            last_exact: app.last_exact.clone(), rpn: app.rpn_mode, stats: app.stats_mode,
+            finance_keypad: app.finance_keypad, base_panel: app.base_panel, tree_panel: app.tree_panel,
+            tutor: app.tutor_mode, variables_panel: app.variables_panel, variables: app.variables.clone(),
+            rpn_stack: app.rpn_stack.clone(),
+            data_points: app.data_points.iter().map(|&(x, y)| SavedPoint { x, y }).collect(),
+            history: app.history.iter().map(|entry| SavedEntry {
+                expression: entry.expression.clone(), value: entry.value, exact: entry.exact.clone(),
+                backend: entry.backend.name().to_string(), note: entry.note.clone(), pinned: entry.pinned,
+            }).collect(),
+        }
+    }
+
+    /// Puts the session back, in its profile. The settings stay those of the config, apart from the backend.
+    pub(crate) fn restore(self, app: &mut App) {
+        if !self.profile.is_empty() { switch_profile(app, &self.profile); }
+        let backend = |name: &str| NumberBackend::named(name).unwrap_or(NumberBackend::Float);
+        app.settings.backend = backend(&self.backend);
+        if self.result_shown { app.show_result(self.expression) } else { app.edit(self.expression) }
+        (app.last_expression, app.last_result, app.last_exact) = (self.last_expression, self.last_result, self.last_exact);
+        (app.rpn_mode, app.stats_mode, app.finance_keypad, app.base_panel, app.tree_panel, app.tutor_mode, app.variables_panel) =
+            (self.rpn, self.stats, self.finance_keypad, self.base_panel, self.tree_panel, self.tutor, self.variables_panel);
+        (app.variables, app.rpn_stack) = (self.variables, self.rpn_stack);
+        app.data_points = self.data_points.into_iter().map(|point| (point.x, point.y)).collect();
+        app.history = self.history.into_iter().map(|entry| HistoryEntry {
+            backend: backend(&entry.backend), expression: entry.expression, value: entry.value, exact: entry.exact,
+            timings: EvalTimings::default(), note: entry.note, pinned: entry.pinned, reused: 0,
+        }).collect();
+        if app.display_value.is_empty() { app.display_value = String::from("0"); }
+    }
+}
+
+/// Where the last session is kept.
+pub(crate) fn session_path() -> Option<PathBuf> { dirs::data_dir().map(|dir| dir.join("rust-calculator-tui").join("session.toml")) }
+
+/// The saving side of the snapshots: where they go, what was written last and when to look for changes again.
+pub(crate) struct Autosave { pub(crate) path: PathBuf, saved: String, pub(crate) next: Instant }
+
+impl Autosave {
+    pub(crate) fn new(path: PathBuf) -> Self { Autosave { path, saved: String::new(), next: Instant::now() + AUTOSAVE_INTERVAL } }
+}
+
+/// Writes `text` next to `path` first and then moves it over, so a run that dies halfway leaves the last snapshot whole.
+pub(crate) fn write_snapshot(path: &Path, text: &str) -> Result<(), String> {
+    let partial = path.with_extension("toml.part");
+    if let Some(dir) = path.parent() { std::fs::create_dir_all(dir).map_err(|e| e.to_string())?; }
+    std::fs::write(&partial, text).and_then(|()| std::fs::rename(&partial, path)).map_err(|e| e.to_string())
+}
+
+/// Saves the session in the background if it changed since the last save. `force` saves now, as on quit;
+/// otherwise this waits for the interval, so a run of keys is written once.
+pub(crate) fn autosave(app: &mut App, force: bool) {
+    let now = Instant::now();
+    match &mut app.autosave {
+        Some(autosave) if force || now >= autosave.next => autosave.next = now + AUTOSAVE_INTERVAL,
+        _ => return,
+    }
+    let Ok(text) = toml::to_string(&Snapshot::of(app)) else { return };
+    let Some(autosave) = app.autosave.as_mut().filter(|autosave| autosave.saved != text) else { return };
+    autosave.saved = text.clone();
+    let path = autosave.path.clone();
+    app.tasks.spawn(move || match write_snapshot(&path, &text) {
+        Ok(()) => TaskOutput::Notice(String::new()),
+        Err(e) => TaskOutput::Error(format!("Cannot save the session: {}", e)),
+    });
+}
+
+/// Brings back the last saved session for `--resume`.
+pub(crate) fn resume(app: &mut App, path: &Path) {
+    match std::fs::read_to_string(path) {
+        Ok(text) => match toml::from_str::<Snapshot>(&text) {
+            Ok(snapshot) => { snapshot.restore(app); app.notice = Some("Resumed the last session".to_string()); }
+            Err(e) => app.error_message = Some(format!("Cannot resume from {}: {}", path.display(), e.message())),
+        },
+        Err(_) => app.notice = Some("No saved session to resume".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::{finish_task, QUIT_WAIT};
+    #[test]
+    fn test_session_snapshot() {
+        let path = std::env::temp_dir().join(format!("calc-session-{}", std::process::id())).join("session.toml");
+        let mut app = App::new();
+        app.autosave = Some(Autosave::new(path.clone()));
+        for expression in ["6 * 7", "1 / 3"] { app.display_value = expression.to_string(); press(&mut app, &["="]); }
+        app.history[0].pinned = true;
+        (app.rpn_mode, app.rpn_stack, app.data_points) = (true, vec![1.5, f64::INFINITY], vec![(None, 2.0), (Some(1.0), 3.0)]);
+        app.variables.insert("rate".to_string(), 0.07);
+        app.edit("ans + ".to_string());
+        autosave(&mut app, false);
+        assert!(!app.tasks.busy());
+        autosave(&mut app, true);
+        for output in app.tasks.finish(QUIT_WAIT) { finish_task(&mut app, output); }
+        assert_eq!((app.notice.as_deref(), app.error_message.as_deref()), (None, None));
+        // Nothing changed, so nothing is written.
+        autosave(&mut app, true);
+        assert!(!app.tasks.busy());
+        let mut resumed = App::new();
+        resume(&mut resumed, &path);
+        assert_eq!(resumed.notice.as_deref(), Some("Resumed the last session"));
+        assert_eq!(Snapshot::of(&resumed), Snapshot::of(&app));
+        assert_eq!((resumed.display_value.as_str(), resumed.mode(), resumed.last_result), ("ans + ", Mode::Editing, Some(1.0 / 3.0)));
+        let _ = std::fs::remove_dir_all(path.parent().unwrap());
+        let mut fresh = App::new();
+        resume(&mut fresh, &path);
+        assert_eq!((fresh.notice.as_deref(), fresh.history.len()), (Some("No saved session to resume"), 0));
+    }
+}
@@ -0,0 +1,157 @@
+//! History and variables shared between calculators running at the same time, through a locked file in the data directory.
+
+use crate::{app::*, engine::{EvalTimings, NumberBackend}, rates::now_secs, tasks::{watch_shared, TaskOutput}};
+use serde::{Deserialize, Serialize};
+use std::{collections::{BTreeMap, HashSet}, fs::{File, OpenOptions}, io::{self, Read, Seek, Write}, path::{Path, PathBuf}};
+
+/// How many of the newest results the shared file keeps.
+const SHARED_HISTORY: usize = 200;
+
+/// A result as the other instances see it: which instance computed it, and its number there.
+#[derive(Clone, PartialEq, Debug, Serialize, Deserialize)]
+pub(crate) struct SharedEntry {
+    pub(crate) origin: String, pub(crate) id: u64, pub(crate) expression: String, pub(crate) value: f64,
+    pub(crate) exact: Option<String>, pub(crate) backend: String,
+}
+
+/// What the shared file holds.
+#[derive(Clone, PartialEq, Debug, Default, Serialize, Deserialize)]
+pub(crate) struct SharedState { #[serde(default)] pub(crate) entries: Vec<SharedEntry>, #[serde(default)] pub(crate) variables: BTreeMap<String, f64> }
+
+/// This instance's link to the shared file: how much of the history and which variables it has published,
+/// and the results of other instances it has taken in.
+pub(crate) struct Share {
+    pub(crate) path: PathBuf, pub(crate) origin: String, pub(crate) published: usize,
+    pub(crate) variables: BTreeMap<String, f64>, next_id: u64, seen: HashSet<(String, u64)>,
+}
+
+impl Share {
+    pub(crate) fn new(path: PathBuf) -> Self {
+        Share { path, origin: format!("{}-{}", std::process::id(), now_secs()), published: 0, variables: BTreeMap::new(), next_id: 0, seen: HashSet::new() }
+    }
+}
+
+/// Where the shared history and variables are kept.
+pub(crate) fn shared_path() -> Option<PathBuf> { dirs::data_dir().map(|dir| dir.join("rust-calculator-tui").join("shared.toml")) }
+
+/// Reads the shared file under a shared lock, so a file being written is never read half done. A missing file is empty.
+pub(crate) fn read_shared(path: &Path) -> Result<SharedState, String> {
+    let mut file = match File::open(path) {
+        Ok(file) => file,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(SharedState::default()),
+        Err(e) => return Err(e.to_string()),
+    };
+    file.lock_shared().map_err(|e| e.to_string())?;
+    let mut text = String::new();
+    file.read_to_string(&mut text).map_err(|e| e.to_string())?;
+    toml::from_str(&text).map_err(|e| e.message().to_string())
+}
+
+/// Adds results and variables to the shared file under an exclusive lock, keeping the newest results, and returns
+/// what the file holds now.
+pub(crate) fn publish(path: &Path, entries: Vec<SharedEntry>, variables: BTreeMap<String, f64>) -> Result<SharedState, String> {
+    let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path).map_err(|e| e.to_string())?;
+    file.lock().map_err(|e| e.to_string())?;
+    let mut text = String::new();
+    file.read_to_string(&mut text).map_err(|e| e.to_string())?;
+    // A file that does not read is started over, rather than stopping every instance from sharing for good.
+    let mut state: SharedState = toml::from_str(&text).unwrap_or_default();
+    state.entries.extend(entries);
+    state.entries.drain(..state.entries.len().saturating_sub(SHARED_HISTORY));
+    state.variables.extend(variables);
+    let text = toml::to_string(&state).map_err(|e| e.to_string())?;
+    file.set_len(0).and_then(|()| file.rewind()).and_then(|()| file.write_all(text.as_bytes())).map_err(|e| e.to_string())?;
+    Ok(state)
+}
+
+/// Publishes in the background the results and the variables that changed since the last call. Called on every
+/// pass of the event loop, so nothing waits long to be shared.
+pub(crate) fn share_changes(app: &mut App) {
+    let Some(share) = &mut app.share else { return };
+    // Deleting entries shortens the history; what comes after them is new.
+    share.published = share.published.min(app.history.len());
+    let entries: Vec<SharedEntry> = app.history[share.published..].iter().map(|entry| {
+        share.next_id += 1;
+        SharedEntry {
+            origin: share.origin.clone(), id: share.next_id, expression: entry.expression.clone(),
+            value: entry.value, exact: entry.exact.clone(), backend: entry.backend.name().to_string(),
+        }
+    }).collect();
+    let variables: BTreeMap<String, f64> = app.variables.iter().filter(|(name, value)| share.variables.get(*name) != Some(*value))
+        .map(|(name, &value)| (name.clone(), value)).collect();
+    if entries.is_empty() && variables.is_empty() { return; }
+    (share.published, share.variables) = (app.history.len(), app.variables.clone());
+    let path = share.path.clone();
+    app.tasks.spawn(move || match publish(&path, entries, variables) {
+        Ok(state) => TaskOutput::Shared(state),
+        Err(e) => TaskOutput::Error(format!("Cannot share results: {}", e)),
+    });
+}
+
+/// Takes in the results and variables the other instances published: their results go to the end of the history.
+pub(crate) fn merge_shared(app: &mut App, state: SharedState) {
+    share_changes(app);
+    let Some(share) = &mut app.share else { return };
+    for entry in state.entries {
+        if entry.origin == share.origin || !share.seen.insert((entry.origin.clone(), entry.id)) { continue; }
+        let backend = NumberBackend::named(&entry.backend).unwrap_or(NumberBackend::Float);
+        app.history.push(HistoryEntry {
+            expression: entry.expression, value: entry.value, exact: entry.exact, backend,
+            timings: EvalTimings::default(), note: None, pinned: false, reused: 0,
+        });
+        share.published += 1;
+    }
+    for (name, value) in state.variables.into_iter().filter(|(name, _)| variable_name_error(name).is_none()) {
+        share.variables.insert(name.clone(), value);
+        app.variables.insert(name, value);
+    }
+}
+
+/// Starts sharing: takes in what the other instances shared so far and watches for more.
+pub(crate) fn start_sharing(app: &mut App) {
+    let Some(path) = shared_path() else { return };
+    if let Some(dir) = path.parent() && let Err(e) = std::fs::create_dir_all(dir) { app.error_message = Some(format!("Cannot share results: {}", e)); return; }
+    // What the calculator had before, like a resumed session, is its own.
+    app.share = Some(Share { published: app.history.len(), variables: app.variables.clone(), ..Share::new(path.clone()) });
+    match read_shared(&path) {
+        Ok(state) => merge_shared(app, state),
+        Err(e) => app.error_message = Some(format!("Cannot read {}: {}", path.display(), e)),
+    }
+    watch_shared(&mut app.tasks, path);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::{finish_task, QUIT_WAIT};
+    #[test]
+    fn test_shared_state() {
+        let path = std::env::temp_dir().join(format!("calc-shared-{}.toml", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let (mut left, mut right) = (App::new(), App::new());
+        left.share = Some(Share { origin: "left".to_string(), ..Share::new(path.clone()) });
+        right.share = Some(Share { origin: "right".to_string(), ..Share::new(path.clone()) });
+        for expression in ["6 * 7", "2 ^ 10"] { left.display_value = expression.to_string(); press(&mut left, &["="]); }
+        left.variables.insert("rate".to_string(), 0.07);
+        share_changes(&mut left);
+        for output in left.tasks.finish(QUIT_WAIT) { finish_task(&mut left, output); }
+        assert_eq!(left.history.len(), 2);
+        right.display_value = "1 + 1".to_string();
+        press(&mut right, &["="]);
+        merge_shared(&mut right, read_shared(&path).unwrap());
+        for output in right.tasks.finish(QUIT_WAIT) { finish_task(&mut right, output); }
+        let tape: Vec<&str> = right.history.iter().map(|entry| entry.expression.as_str()).collect();
+        assert_eq!((tape, right.variables.get("rate")), (vec!["1 + 1", "6 * 7", "2 ^ 10"], Some(&0.07)));
+        right.display_value = "@3 / @2".to_string();
+        press(&mut right, &["="]);
+        assert_eq!(right.last_result, Some(1024.0 / 42.0));
+        // Nothing is published twice: the right side shared only its own two results.
+        share_changes(&mut right);
+        for output in right.tasks.finish(QUIT_WAIT) { finish_task(&mut right, output); }
+        let state = read_shared(&path).unwrap();
+        assert_eq!(state.entries.iter().map(|entry| entry.origin.as_str()).collect::<Vec<_>>(), ["left", "left", "right", "right"]);
+        merge_shared(&mut left, state);
+        assert_eq!(left.history.len(), 4);
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -0,0 +1,189 @@
+//! Background work for slow IO, run on worker threads so the event loop never waits on it.
+
+use crate::{app::Config, rates::Rates, shared::{read_shared, SharedState}, ui::Theme};
+use notify::{RecommendedWatcher, RecursiveMode, Watcher};
+use std::{
+    collections::{BTreeMap, HashMap}, panic::{self, AssertUnwindSafe}, path::{Path, PathBuf}, process::Command,
+    sync::mpsc, thread, time::{Duration, Instant, SystemTime},
+};
+
+/// What a background task reports back to the event loop.
+#[derive(Debug)]
+pub(crate) enum TaskOutput {
+    Notice(String),
+    Error(String),
+    ConfigChanged(Result<Config, String>),
+    /// A theme file was saved: its name and the theme it now gives.
+    ThemeChanged(String, Result<Theme, String>),
+    /// New exchange rates, and why they could not be updated if they are not fresh.
+    Rates(Rates, Option<String>),
+    /// Variables read from an exported file.
+    Variables(BTreeMap<String, f64>),
+    /// What the calculators running at the same time share, after one of them changed it.
+    Shared(SharedState),
+}
+
+/// The background tasks of the app. One-off jobs and watchers send their output through one channel,
+/// which the event loop drains with `poll` on every pass. File watchers stop when they are dropped, so they live here.
+pub(crate) struct Tasks {
+    sender: mpsc::Sender<(TaskOutput, bool)>, receiver: mpsc::Receiver<(TaskOutput, bool)>, running: usize, watchers: Vec<RecommendedWatcher>, repeating: bool,
+}
+
+impl Tasks {
+    pub(crate) fn new() -> Self {
+        let (sender, receiver) = mpsc::channel();
+        Tasks { sender, receiver, running: 0, watchers: Vec::new(), repeating: false }
+    }
+
+    /// Runs `job` on its own thread; what it returns arrives through `poll`. A job that panics reports an error,
+    /// so it is not counted as running forever.
+    pub(crate) fn spawn(&mut self, job: impl FnOnce() -> TaskOutput + Send + 'static) {
+        let sender = self.sender.clone();
+        self.running += 1;
+        thread::spawn(move || {
+            let output = panic::catch_unwind(AssertUnwindSafe(job)).unwrap_or_else(|_| TaskOutput::Error("A background task failed".to_string()));
+            let _ = sender.send((output, true));
+        });
+    }
+
+    /// Calls `check` every `interval` on a thread of its own and reports whatever it returns, until the app exits.
+    pub(crate) fn every(&mut self, interval: Duration, mut check: impl FnMut() -> Option<TaskOutput> + Send + 'static) {
+        let sender = self.sender.clone();
+        self.repeating = true;
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            if let Some(output) = check() && sender.send((output, false)).is_err() { return; }
+        });
+    }
+
+    /// Calls `check` with each file in `dir` that changes and reports whatever it returns.
+    /// Editors often write a file in several steps, so `check` runs a moment after the change.
+    pub(crate) fn watch(&mut self, dir: &Path, mut check: impl FnMut(&Path) -> Option<TaskOutput> + Send + 'static) -> notify::Result<()> {
+        let sender = self.sender.clone();
+        let mut watcher = notify::recommended_watcher(move |event: notify::Result<notify::Event>| {
+            let Ok(event) = event else { return };
+            if event.kind.is_access() { return; }
+            thread::sleep(Duration::from_millis(100));
+            for output in event.paths.iter().filter_map(|path| check(path)) { let _ = sender.send((output, false)); }
+        })?;
+        watcher.watch(dir, RecursiveMode::NonRecursive)?;
+        self.watchers.push(watcher);
+        Ok(())
+    }
+
+    /// The outputs that arrived since the last call, without waiting.
+    pub(crate) fn poll(&mut self) -> Vec<TaskOutput> {
+        let outputs: Vec<_> = self.receiver.try_iter().collect();
+        self.running -= outputs.iter().filter(|(_, done)| *done).count();
+        outputs.into_iter().map(|(output, _)| output).collect()
+    }
+
+    /// Whether a one-off job is still running.
+    pub(crate) fn busy(&self) -> bool { self.running > 0 }
+
+    /// Whether a watcher or a repeating check may report something at any time.
+    pub(crate) fn watching(&self) -> bool { !self.watchers.is_empty() || self.repeating }
+
+    /// Waits up to `limit` for the one-off jobs still running, so a file being written is not cut off at exit;
+    /// a job that takes longer is left behind and `busy` stays true.
+    pub(crate) fn finish(&mut self, limit: Duration) -> Vec<TaskOutput> {
+        let (deadline, mut outputs) = (Instant::now() + limit, Vec::new());
+        while self.running > 0 && let Ok((output, done)) = self.receiver.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            if done { self.running -= 1; }
+            outputs.push(output);
+        }
+        outputs
+    }
+}
+
+/// Downloads `url` with `curl`, which is on nearly every system and brings its own TLS. It blocks, so call it from a task.
+pub(crate) fn http_get(url: &str) -> Result<Vec<u8>, String> {
+    let output = Command::new("curl").args(["-fsSL", "--max-time", "30", url]).output().map_err(|e| format!("cannot run curl: {}", e))?;
+    if !output.status.success() { return Err(String::from_utf8_lossy(&output.stderr).trim().to_string()); }
+    Ok(output.stdout)
+}
+
+/// The modification time of a file, or `None` if it cannot be read.
+fn modified(path: &PathBuf) -> Option<SystemTime> { std::fs::metadata(path).and_then(|meta| meta.modified()).ok() }
+
+/// Loads the config again, reporting it only when it differs from the last load, so a save that changes nothing
+/// or repeats the last error stays quiet.
+fn config_reloader() -> impl FnMut() -> Option<TaskOutput> + Send + 'static {
+    let mut last = Config::load();
+    move || {
+        let config = Config::load();
+        if config == last { return None; }
+        last = config.clone();
+        Some(TaskOutput::ConfigChanged(config))
+    }
+}
+
+/// Reloads the config file whenever it changes on disk. Where its directory cannot be watched, for example
+/// because it does not exist yet, the file is checked once a second instead.
+pub(crate) fn watch_config(tasks: &mut Tasks) {
+    let Some(path) = Config::path() else { return };
+    let (name, mut reload) = (path.file_name().map(|name| name.to_os_string()), config_reloader());
+    if let Some(dir) = path.parent()
+        && tasks.watch(dir, move |changed| if changed.file_name() == name.as_deref() { reload() } else { None }).is_ok() { return; }
+    let (mut seen, mut reload) = (modified(&path), config_reloader());
+    tasks.every(Duration::from_secs(1), move || {
+        let now = modified(&path);
+        if now == seen { return None; }
+        seen = now;
+        reload()
+    });
+}
+/// Reads the shared history and variables again whenever another instance changes them, checking once a second
+/// where the data directory cannot be watched.
+pub(crate) fn watch_shared(tasks: &mut Tasks, path: PathBuf) {
+    let name = path.file_name().map(|name| name.to_os_string());
+    let read = |path: &Path| read_shared(path).map_or_else(|e| TaskOutput::Error(format!("Cannot read {}: {}", path.display(), e)), TaskOutput::Shared);
+    let watched = path.clone();
+    if let Some(dir) = path.parent()
+        && tasks.watch(dir, move |changed| (changed.file_name() == name.as_deref()).then(|| read(&watched))).is_ok() { return; }
+    let mut seen = modified(&path);
+    tasks.every(Duration::from_secs(1), move || {
+        let now = modified(&path);
+        if now == seen { return None; }
+        seen = now;
+        Some(read(&path))
+    });
+}
+
+/// Reloads a theme file in `themes/` whenever it is saved, so a theme can be edited while the calculator shows it.
+/// Only a theme that loads differently from last time is reported. The folder has to exist when the app starts.
+pub(crate) fn watch_themes(tasks: &mut Tasks) {
+    let Some(dir) = Theme::dir() else { return };
+    let mut last: HashMap<String, Result<Theme, String>> = HashMap::new();
+    let _ = tasks.watch(&dir, move |path| {
+        let name = path.extension().is_some_and(|extension| extension == "toml").then(|| path.file_stem()?.to_str()).flatten()?.to_string();
+        let theme = Theme::load(&name);
+        if last.get(&name) == Some(&theme) { return None; }
+        last.insert(name.clone(), theme.clone());
+        Some(TaskOutput::ThemeChanged(name, theme))
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_tasks() {
+        let mut tasks = Tasks::new();
+        tasks.spawn(|| { thread::sleep(Duration::from_millis(20)); TaskOutput::Notice("done".to_string()) });
+        assert!(tasks.busy());
+        let outputs = tasks.finish(Duration::from_secs(5));
+        assert!(matches!(outputs.as_slice(), [TaskOutput::Notice(text)] if text == "done"));
+        assert!(!tasks.busy() && tasks.poll().is_empty());
+        tasks.spawn(|| panic!("broken job"));
+        let outputs = tasks.finish(Duration::from_secs(5));
+        assert!(matches!(outputs.as_slice(), [TaskOutput::Error(text)] if text == "A background task failed") && !tasks.busy());
+        tasks.spawn(|| { thread::sleep(Duration::from_secs(2)); TaskOutput::Notice("late".to_string()) });
+        let start = Instant::now();
+        assert!(tasks.finish(Duration::from_millis(20)).is_empty() && tasks.busy() && start.elapsed() < Duration::from_secs(1));
+        let mut ticks = 0;
+        tasks.every(Duration::from_millis(1), move || { ticks += 1; (ticks == 3).then(|| TaskOutput::Error("third".to_string())) });
+        let output = tasks.receiver.recv_timeout(Duration::from_secs(5)).map(|(output, done)| (format!("{output:?}"), done));
+        assert_eq!(output, Ok(("Error(\"third\")".to_string(), false)));
+    }
+}
@@ -0,0 +1,1522 @@
+//! Drawing the calculator with ratatui.
+
+use crate::{app::*, engine::*, parser::*};
+use ratatui::{prelude::*, widgets::*};
+use std::{collections::HashMap, path::PathBuf, str::FromStr, time::Duration};
+
+/// A struct for storing the color theme.
+#[derive(Clone, PartialEq, Debug)]
+pub(crate) struct Theme {
+    pub(crate) background: Color, pub(crate) display_bg: Color, pub(crate) border: Color, pub(crate) text: Color,
+    pub(crate) num_button_fg: Color, pub(crate) op_button_fg: Color, pub(crate) num_button_bg: Color,
+    pub(crate) op_button_bg: Color, pub(crate) equal_button_bg: Color, pub(crate) active_button_bg: Color, pub(crate) error: Color,
+    /// The colors of brackets on the display, by nesting depth.
+    pub(crate) brackets: [Color; 4],
+}
+
+impl Theme {
+    pub(crate) fn default() -> Self {
+        Theme {
+            background: Color::Rgb(20, 20, 30), display_bg: Color::Rgb(50, 50, 60),
+            border: Color::Rgb(80, 80, 90), text: Color::White,
+            num_button_fg: Color::White, op_button_fg: Color::Rgb(20, 20, 30),
+            num_button_bg: Color::Rgb(60, 70, 80), op_button_bg: Color::Rgb(255, 159, 67),
+            equal_button_bg: Color::Rgb(255, 99, 132), active_button_bg: Color::White,
+            error: Color::Rgb(255, 99, 132),
+            brackets: [Color::Rgb(255, 214, 102), Color::Rgb(199, 146, 234), Color::Rgb(102, 217, 239), Color::Rgb(166, 226, 46)],
+        }
+    }
+
+    /// Dark text on light keys, for bright rooms.
+    pub(crate) fn light() -> Self {
+        Theme {
+            background: Color::Rgb(235, 235, 230), display_bg: Color::Rgb(255, 255, 255),
+            border: Color::Rgb(130, 130, 140), text: Color::Black,
+            num_button_fg: Color::Black, op_button_fg: Color::White,
+            num_button_bg: Color::Rgb(210, 210, 215), op_button_bg: Color::Rgb(60, 110, 200),
+            equal_button_bg: Color::Rgb(210, 70, 100), active_button_bg: Color::Rgb(30, 30, 40),
+            error: Color::Rgb(200, 30, 30),
+            brackets: [Color::Rgb(180, 120, 0), Color::Rgb(140, 60, 180), Color::Rgb(0, 130, 160), Color::Rgb(40, 140, 40)],
+        }
+    }
+
+    /// The theme without color. Its colors only tell the roles apart for `monochrome`, which turns them into
+    /// bold, reversed and underlined text in the terminal's own colors.
+    pub(crate) fn mono() -> Self {
+        Theme {
+            background: Color::Black, display_bg: Color::Indexed(232), border: Color::Gray, text: Color::White,
+            num_button_fg: Color::White, op_button_fg: Color::Black, num_button_bg: Color::Indexed(233),
+            op_button_bg: Color::Yellow, equal_button_bg: Color::Magenta, active_button_bg: Color::Cyan, error: Color::Red,
+            brackets: [Color::White; 4],
+        }
+    }
+
+    /// The built-in theme a profile names: `dark` (the default), `light` or `mono`.
+    pub(crate) fn named(name: &str) -> Option<Self> {
+        match name { "dark" => Some(Theme::default()), "light" => Some(Theme::light()), "mono" => Some(Theme::mono()), _ => None }
+    }
+
+    /// The colors a theme file can set, by the names it uses for them, in the order the preview lists them.
+    pub(crate) fn roles(&self) -> [(&'static str, Color); 11] {
+        [("background", self.background), ("display_bg", self.display_bg), ("border", self.border), ("text", self.text),
+            ("num_button_fg", self.num_button_fg), ("num_button_bg", self.num_button_bg), ("op_button_fg", self.op_button_fg),
+            ("op_button_bg", self.op_button_bg), ("equal_button_bg", self.equal_button_bg), ("active_button_bg", self.active_button_bg), ("error", self.error)]
+    }
+
+    fn role_mut(&mut self, role: &str) -> Option<&mut Color> {
+        Some(match role {
+            "background" => &mut self.background, "display_bg" => &mut self.display_bg, "border" => &mut self.border, "text" => &mut self.text,
+            "num_button_fg" => &mut self.num_button_fg, "num_button_bg" => &mut self.num_button_bg, "op_button_fg" => &mut self.op_button_fg,
+            "op_button_bg" => &mut self.op_button_bg, "equal_button_bg" => &mut self.equal_button_bg, "active_button_bg" => &mut self.active_button_bg,
+            "error" => &mut self.error, _ => return None,
+        })
+    }
+
+    /// Reads a theme file: `base` names the built-in theme it starts from, each role takes a color like `"#ff9f43"`
+    /// or `"red"`, and `brackets` lists up to four colors for nesting depths.
+    pub(crate) fn parse(text: &str) -> Result<Self, String> {
+        let table: toml::Table = toml::from_str(text).map_err(|e| e.message().to_string())?;
+        let color = |key: &str, value: &toml::Value| value.as_str().and_then(|text| Color::from_str(text).ok())
+            .ok_or_else(|| format!("`{}` is not a color like \"#ff9f43\" or \"red\"", key));
+        let mut theme = match table.get("base") {
+            None => Theme::default(),
+            Some(base) => base.as_str().and_then(Theme::named).ok_or("`base` must be \"dark\", \"light\" or \"mono\"")?,
+        };
+        for (key, value) in &table {
+            match key.as_str() {
+                "base" => {}
+                "brackets" => {
+                    let colors = value.as_array().filter(|colors| (1..=4).contains(&colors.len())).ok_or("`brackets` must list one to four colors")?;
+                    for (i, slot) in theme.brackets.iter_mut().enumerate() { *slot = color(key, &colors[i % colors.len()])?; }
+                }
+                role => *theme.role_mut(role).ok_or_else(|| format!("unknown theme role `{}`", role))? = color(key, value)?,
+            }
+        }
+        Ok(theme)
+    }
+
+    /// Where theme files live: `themes/<name>.toml` next to the config file.
+    pub(crate) fn dir() -> Option<PathBuf> { Config::path().map(|path| path.with_file_name("themes")) }
+
+    /// The theme called `name`: a built-in one, else the theme file of that name.
+    pub(crate) fn load(name: &str) -> Result<Self, String> {
+        if let Some(theme) = Theme::named(name) { return Ok(theme); }
+        let path = Theme::dir().map(|dir| dir.join(format!("{}.toml", name))).ok_or_else(|| format!("unknown theme `{}`", name))?;
+        let text = std::fs::read_to_string(&path).map_err(|_| format!("unknown theme `{}`: no {}", name, path.display()))?;
+        Theme::parse(&text).map_err(|e| format!("Invalid theme {}: {}", path.display(), e))
+    }
+}
+
+/// Whether the environment asks for no color: `NO_COLOR` is set to anything, or `TERM` is `dumb`.
+pub(crate) fn color_disabled() -> bool {
+    std::env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty()) || std::env::var("TERM").is_ok_and(|term| term == "dumb")
+}
+
+/// What the terminal understands beyond the escape sequences crossterm translates for every console.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub(crate) struct Capabilities {
+    /// 24-bit colors; without them every color is drawn as the nearest of the 16 basic ones.
+    pub(crate) truecolor: bool,
+    /// The raw sequences the app writes itself: OSC 0 and 52 for the window title and the clipboard, and the cursor shape.
+    pub(crate) escapes: bool,
+}
+
+impl Capabilities {
+    pub(crate) const FULL: Capabilities = Capabilities { truecolor: true, escapes: true };
+
+    /// Guesses the capabilities from the environment, read through `var`. On Windows, Windows Terminal, ConEmu,
+    /// VS Code and mintty announce themselves and handle everything; the legacy console host does neither.
+    /// Elsewhere only the Linux virtual console falls short.
+    pub(crate) fn detect(var: impl Fn(&str) -> Option<String>, windows: bool) -> Self {
+        let truecolor = var("COLORTERM").is_some_and(|value| value == "truecolor" || value == "24bit");
+        let modern = if windows {
+            // `TERM` says nothing here: Git Bash sets it in a plain conhost window too.
+            var("WT_SESSION").is_some() || var("TERM_PROGRAM").is_some() || var("ConEmuANSI").is_some_and(|value| value == "ON")
+        } else { var("TERM").is_none_or(|term| term != "linux") };
+        Capabilities { truecolor: truecolor || modern, escapes: modern }
+    }
+}
+
+/// The 16 basic colors with the values most terminals give them.
+const BASIC_COLORS: [(Color, (u8, u8, u8)); 16] = [
+    (Color::Black, (0, 0, 0)), (Color::Red, (205, 0, 0)), (Color::Green, (0, 205, 0)), (Color::Yellow, (205, 205, 0)),
+    (Color::Blue, (0, 0, 238)), (Color::Magenta, (205, 0, 205)), (Color::Cyan, (0, 205, 205)), (Color::Gray, (229, 229, 229)),
+    (Color::DarkGray, (127, 127, 127)), (Color::LightRed, (255, 0, 0)), (Color::LightGreen, (0, 255, 0)), (Color::LightYellow, (255, 255, 0)),
+    (Color::LightBlue, (92, 92, 255)), (Color::LightMagenta, (255, 0, 255)), (Color::LightCyan, (0, 255, 255)), (Color::White, (255, 255, 255)),
+];
+
+/// The basic color closest to `color`; basic and reset colors stay as they are.
+pub(crate) fn basic_color(color: Color) -> Color {
+    let (r, g, b) = match color {
+        Color::Rgb(r, g, b) => (r, g, b),
+        Color::Indexed(n) if n < 16 => return BASIC_COLORS[n as usize].0,
+        // The 6×6×6 color cube, then 24 grays.
+        Color::Indexed(n) if n < 232 => {
+            let level = |i: u8| if i == 0 { 0 } else { 55 + i * 40 };
+            (level((n - 16) / 36), level((n - 16) / 6 % 6), level((n - 16) % 6))
+        }
+        Color::Indexed(n) => { let gray = 8 + (n - 232) * 10; (gray, gray, gray) }
+        color => return color,
+    };
+    let distance = |(cr, cg, cb): (u8, u8, u8)| [(r, cr), (g, cg), (b, cb)].iter().map(|&(a, c)| (i32::from(a) - i32::from(c)).pow(2)).sum::<i32>();
+    BASIC_COLORS.iter().min_by_key(|(_, rgb)| distance(*rgb)).map_or(color, |(basic, _)| *basic)
+}
+
+/// Redraws a frame drawn with the mono theme without color. Highlights (the pressed key, selected rows, the
+/// snippet field) become reversed, the operator keys and accents bold, and errors bold and underlined.
+pub(crate) fn monochrome(buffer: &mut Buffer, theme: &Theme, buttons: &[(Rect, String)]) {
+    let (area, width) = (buffer.area, usize::from(buffer.area.width.max(1)));
+    for (i, cell) in buffer.content.iter_mut().enumerate() {
+        // The index is only narrowed after dividing, so no cell count can wrap it.
+        let (x, y) = (area.x + (i % width) as u16, area.y + (i / width) as u16);
+        let on_key = buttons.iter().any(|(rect, _)| rect.contains((x, y).into()));
+        let mut modifier = match cell.bg {
+            bg if bg == theme.active_button_bg || bg == theme.border => Modifier::REVERSED | Modifier::BOLD,
+            bg if (bg == theme.op_button_bg || bg == theme.equal_button_bg) && on_key => Modifier::BOLD,
+            bg if bg == theme.op_button_bg || bg == theme.equal_button_bg => Modifier::REVERSED,
+            _ => Modifier::empty(),
+        };
+        if cell.fg == theme.error { modifier |= Modifier::BOLD | Modifier::UNDERLINED; }
+        if cell.fg == theme.op_button_bg || cell.fg == theme.equal_button_bg { modifier |= Modifier::BOLD; }
+        cell.modifier |= modifier;
+        (cell.fg, cell.bg) = (Color::Reset, Color::Reset);
+    }
+}
+
+/// A color as a theme file writes it.
+pub(crate) fn color_text(color: Color) -> String {
+    match color { Color::Rgb(r, g, b) => format!("#{:02x}{:02x}{:02x}", r, g, b), color => format!("{:?}", color).to_lowercase() }
+}
+
+/// Whether the screensaver is showing: input has been idle for the configured number of minutes.
+pub(crate) fn is_idle(app: &App) -> bool {
+    app.settings.idle_minutes > 0 && app.last_input.elapsed() >= Duration::from_secs(u64::from(app.settings.idle_minutes) * 60)
+}
+
+/// Whether the display shows the cursor at the edit position: while typing, with nothing covering the display
+/// and the end of the expression in view.
+pub(crate) fn shows_cursor(app: &App) -> bool {
+    let overlay = app.overlay.is_some() || app.stats_open || app.inspector_open;
+    app.settings.cursor != CursorMode::Off && app.mode() != Mode::ResultShown && app.display_scroll == 0 && app.pending.is_none()
+        && app.quad_roots.is_none() && app.snippet.is_none() && app.history_cursor.is_none() && !overlay
+}
+
+/// The terminal width from which the history tape gets a side panel next to the keypad.
+pub(crate) const WIDE_LAYOUT: u16 = 70;
+/// Below this width there is no side panel at all, so the keypad keeps usable buttons; panels return when it grows.
+pub(crate) const NARROW_LAYOUT: u16 = 50;
+/// Below this height the margin and the key help line are dropped to leave room for the keypad.
+pub(crate) const SHORT_LAYOUT: u16 = 20;
+
+/// The function that draws the entire UI.
+pub(crate) fn ui(f: &mut Frame, app: &mut App) {
+    app.button_rects.clear();
+    app.history_rows.clear();
+    if is_idle(app) { render_screensaver(f, app); } else if app.presenting { render_presentation(f, app); } else { render_calculator(f, app); }
+    if app.theme == Theme::mono() { monochrome(f.buffer_mut(), &app.theme, &app.button_rects); }
+    if !app.capabilities.truecolor { for cell in &mut f.buffer_mut().content { (cell.fg, cell.bg) = (basic_color(cell.fg), basic_color(cell.bg)); } }
+}
+
+/// Draws the calculator: the display, the keypad, the side panel and whatever overlays are open.
+pub(crate) fn render_calculator(f: &mut Frame, app: &mut App) {
+    let theme = &app.theme;
+    f.render_widget(Block::default().bg(theme.background), f.size());
+    let short = f.size().height < SHORT_LAYOUT;
+    let main_chunks = Layout::default().direction(Direction::Vertical).margin(if short { 0 } else { 1 })
+        .constraints([
+            Constraint::Length(1), Constraint::Length(4), Constraint::Length(1), Constraint::Min(0),
+            Constraint::Length(if short { 0 } else { 1 }),
+        ].as_ref())
+        .split(f.size());
+    let mut time_text = app.last_timings.map_or_else(
+        || "Waiting for calculation...".to_string(),
+        |timings| format!("Last operation: {} µs", timings.total().as_micros()),
+    );
+    if app.tasks.busy() { time_text = format!("Busy · {}", time_text); }
+    f.render_widget(Paragraph::new(time_text).style(Style::default().fg(theme.border)).alignment(Alignment::Right), main_chunks[0]);
+    // The key click is a dot that blinks for as long as the pressed button stays highlighted.
+    let click = if app.settings.key_click && app.active_button.is_some() { " ●" } else { "" };
+    let profile = if app.profile == "default" { String::new() } else { format!(" · {}", app.profile) };
+    let angles = if app.settings.degrees { " · DEG" } else { "" };
+    f.render_widget(
+        Paragraph::new(format!("[{}] F4{}{}{}", app.settings.backend.name(), angles, profile, click)).style(Style::default().fg(theme.op_button_bg)),
+        main_chunks[0]
+    );
+    const SPINNER: [&str; 10] = ["⠋", "⠙", "⠹", "⠸", "⠼", "⠴", "⠦", "⠧", "⠇", "⠏"];
+    let expression_line = match (&app.last_expression, app.mode() == Mode::ResultShown) {
+        _ if app.pending.is_some() => {
+            let frame = if app.low_bandwidth { 0 } else { app.pending.as_ref().map_or(0, |p| p.started.elapsed().as_millis() / 100) as usize % SPINNER.len() };
+            Line::styled(format!("{} Calculating…", SPINNER[frame]), Style::default().fg(theme.op_button_bg))
+        }
+        (Some(expression), true) => {
+            let shown = app.notation.and_then(|notation| expression_notation(expression, notation, app.settings.max_depth, app.settings.si_suffixes))
+                .unwrap_or_else(|| if app.settings.superscripts { superscript_powers(expression) } else { expression.clone() });
+            Line::styled(format!("{} =", shown), Style::default().fg(theme.border))
+        }
+        _ if let Some(quiz) = &app.quiz => Line::styled(format!("{} = ?", quiz.problem), Style::default().fg(theme.op_button_bg)),
+        // While typing, the chosen notation previews the expression as it parses so far.
+        (_, false) if let Some(notation) = app.notation => {
+            let name = if notation == Notation::Postfix { "RPN" } else { "Prefix" };
+            let shown = expression_notation(&app.display_value, notation, app.settings.max_depth, app.settings.si_suffixes)
+                .unwrap_or_else(|| "…".to_string());
+            Line::styled(format!("{}: {}", name, shown), Style::default().fg(theme.border))
+        }
+        _ => Line::raw(""),
+    };
+    let shown_value = shown_value(app);
+    // The cursor takes a column of its own after the text, where the next character goes.
+    let cursor = shows_cursor(app);
+    let width = (main_chunks[1].width.saturating_sub(2) as usize).saturating_sub(usize::from(cursor));
+    let length = shown_value.chars().count();
+    app.display_scroll = app.display_scroll.min((length + 1).saturating_sub(width));
+    let highlight = match &app.snippet {
+        Some(fill) => Some((fill.text().1, Style::default().fg(theme.op_button_fg).bg(theme.op_button_bg))),
+        None => app.error_span.map(|span| (span, Style::default().fg(theme.error).add_modifier(Modifier::UNDERLINED))),
+    };
+    let mut value_line = scrolled_line(&shown_value, highlight, width, app.display_scroll, theme);
+    if cursor {
+        value_line.spans.push(Span::raw(" "));
+        f.set_cursor(main_chunks[1].right().saturating_sub(2), main_chunks[1].y + 2);
+    }
+    let display_text = match &app.quad_roots {
+        Some(roots) => roots.lines(&app.settings.format_options()).map(Line::raw).to_vec(),
+        None => vec![expression_line, value_line],
+    };
+    let border_color = if app.flash_until.is_some() { theme.error } else { theme.border };
+    let mut display_block = Block::default().borders(Borders::ALL).border_style(Style::default().fg(border_color));
+    if app.mode() != Mode::ResultShown && length * 4 >= app.settings.max_length * 3 {
+        let counter_color = if length >= app.settings.max_length { theme.error } else { theme.border };
+        display_block = display_block.title(Span::styled(format!(" {}/{} ", length, app.settings.max_length), Style::default().fg(counter_color)));
+    }
+    f.render_widget(
+        Paragraph::new(display_text).style(Style::default().fg(theme.text).bg(theme.display_bg)).block(display_block).alignment(Alignment::Right),
+        main_chunks[1]
+    );
+    if let Some(error) = &app.error_message {
+        f.render_widget(Paragraph::new(format!("⚠ {}", error)).style(Style::default().fg(theme.error)).alignment(Alignment::Right), main_chunks[2]);
+    } else if let Some(notice) = &app.notice {
+        f.render_widget(Paragraph::new(notice.as_str()).style(Style::default().fg(theme.border)).alignment(Alignment::Right), main_chunks[2]);
+    } else if let Some(fill) = &app.snippet {
+        let hint = format!("Filling in `{}` · Tab for the next field, = to evaluate", SnippetFill::names(fill.template)[fill.field]);
+        f.render_widget(Paragraph::new(hint).style(Style::default().fg(theme.border)).alignment(Alignment::Right), main_chunks[2]);
+    } else if app.mode() != Mode::ResultShown && let matches = completions(&app.display_value) && !matches.is_empty() {
+        let hint = matches.iter().take(3).map(|(name, args)| format!("{}{}", name, args)).collect::<Vec<_>>().join(" · ");
+        f.render_widget(Paragraph::new(format!("Tab: {}", hint)).style(Style::default().fg(theme.border)).alignment(Alignment::Right), main_chunks[2]);
+    }
+    f.render_widget(Paragraph::new(footer_text(&app.config)).style(Style::default().fg(theme.border)), main_chunks[4]);
+    const STANDARD_BUTTONS: [(&str, u16, u16, u16, u16); 23] = [
+        ("C", 0, 0, 1, 1), ("(", 1, 0, 1, 1), (")", 2, 0, 1, 1), ("/", 3, 0, 1, 1), ("%", 4, 0, 1, 1),
+        ("7", 0, 1, 1, 1), ("8", 1, 1, 1, 1), ("9", 2, 1, 1, 1), ("*", 3, 1, 1, 1), ("^", 4, 1, 1, 1),
+        ("4", 0, 2, 1, 1), ("5", 1, 2, 1, 1), ("6", 2, 2, 1, 1), ("-", 3, 2, 1, 1), ("+/-", 4, 2, 1, 1),
+        ("1", 0, 3, 1, 1), ("2", 1, 3, 1, 1), ("3", 2, 3, 1, 1), ("+", 3, 3, 1, 2),
+        ("2nd", 0, 4, 1, 1), ("0", 1, 4, 1, 1), (".", 2, 4, 1, 1), ("=", 4, 3, 1, 2),
+    ];
+    // The finance page trades `C`, `^` and `+/-` for the time-value-of-money functions and an argument separator.
+    const FINANCE_BUTTONS: [(&str, u16, u16, u16, u16); 25] = [
+        ("pmt", 0, 0, 1, 1), ("fv", 1, 0, 1, 1), ("pv", 2, 0, 1, 1), ("npv", 3, 0, 1, 1), ("irr", 4, 0, 1, 1),
+        ("7", 0, 1, 1, 1), ("8", 1, 1, 1, 1), ("9", 2, 1, 1, 1), ("(", 3, 1, 1, 1), (")", 4, 1, 1, 1),
+        ("4", 0, 2, 1, 1), ("5", 1, 2, 1, 1), ("6", 2, 2, 1, 1), (",", 3, 2, 1, 1), ("/", 4, 2, 1, 1),
+        ("1", 0, 3, 1, 1), ("2", 1, 3, 1, 1), ("3", 2, 3, 1, 1), ("-", 3, 3, 1, 1), ("*", 4, 3, 1, 1),
+        ("0", 0, 4, 1, 1), (".", 1, 4, 1, 1), ("%", 2, 4, 1, 1), ("+", 3, 4, 1, 1), ("=", 4, 4, 1, 1),
+    ];
+    let button_definitions: &[(&str, u16, u16, u16, u16)] = if app.finance_keypad { &FINANCE_BUTTONS } else { &STANDARD_BUTTONS };
+    let panel_open = app.stats_mode || app.rpn_mode || app.quiz.is_some() || app.tree_panel || app.tutor_mode || app.base_panel
+        || app.matrix_result.is_some() || app.variables_panel;
+    let (keypad_area, side_area) = if main_chunks[3].width >= WIDE_LAYOUT || panel_open && main_chunks[3].width >= NARROW_LAYOUT {
+        let areas = Layout::default().direction(Direction::Horizontal).constraints([Constraint::Min(0), Constraint::Length(30)]).split(main_chunks[3]);
+        (areas[0], Some(areas[1]))
+    } else { (main_chunks[3], None) };
+    // The base converter adds a clickable bit field below the keypad.
+    let keypad_area = if app.base_panel {
+        let rows = app.settings.word_bits.div_ceil(bits_per_row(keypad_area.width.saturating_sub(2), app.settings.word_bits)) as u16;
+        let areas = Layout::default().direction(Direction::Vertical).constraints([Constraint::Min(0), Constraint::Length(rows * 2 + 2)]).split(keypad_area);
+        let cells = render_bit_field(f, app, areas[1]);
+        app.button_rects.extend(cells);
+        areas[0]
+    } else { keypad_area };
+    match side_area {
+        Some(area) if app.stats_mode => render_data_summary(f, app, area),
+        Some(area) if app.rpn_mode => render_rpn_stack(f, app, area),
+        Some(area) if let Some(quiz) = &app.quiz => render_quiz(f, app, quiz, area),
+        Some(area) if app.tree_panel => render_parse_tree(f, app, area),
+        Some(area) if app.tutor_mode => render_tutor(f, app, area),
+        Some(area) if app.base_panel => render_base_converter(f, app, area),
+        Some(area) if let Some(matrix) = &app.matrix_result => render_matrix(f, app, matrix, area),
+        Some(area) if app.variables_panel && app.history_cursor.is_none() => render_variables(f, app, area),
+        Some(area) => app.history_rows = render_history(f, app, area),
+        None => {}
+    }
+    // Configured quick keys get a user row below the keypad.
+    let taxes: &[&str] = if app.settings.tax_rates.is_empty() { &[] } else { &["+TAX", "−TAX"] };
+    let stack_keys: &[&str] = if app.rpn_mode { &["x⇄y", "Drop", "R↓"] } else { &[] };
+    let user_buttons: Vec<(String, String)> = stack_keys.iter().chain(taxes).map(|label| (label.to_string(), label.to_string()))
+        .chain(app.custom_buttons.iter().enumerate().map(|(i, button)| (format!("user:{}", i), button.label.clone()))).collect();
+    let (keypad_area, user_row) = if user_buttons.is_empty() { (keypad_area, None) } else {
+        let areas = Layout::default().direction(Direction::Vertical).constraints([Constraint::Ratio(5, 6), Constraint::Ratio(1, 6)]).split(keypad_area);
+        (areas[0], Some(areas[1]))
+    };
+    let rows = Layout::default().direction(Direction::Vertical).constraints([Constraint::Ratio(1, 5); 5]).split(keypad_area);
+    let mut cols_per_row = Vec::new();
+    for row_area in rows.iter() {
+        cols_per_row.push(Layout::default().direction(Direction::Horizontal).constraints([Constraint::Ratio(1, 5); 5]).split(*row_area));
+    }
+    // Each button has a click value and a label; they differ only for configured keys, which click as `user:N`.
+    let mut buttons: Vec<(Rect, String, String)> = button_definitions.iter().map(|(label, x, y, w, h)| {
+        (cols_per_row[*y as usize][*x as usize].union(cols_per_row[(*y + *h - 1) as usize][(*x + *w - 1) as usize]), label.to_string(), label.to_string())
+    }).collect();
+    if let Some(row) = user_row {
+        let cells = Layout::default().direction(Direction::Horizontal)
+            .constraints(vec![Constraint::Ratio(1, user_buttons.len() as u32); user_buttons.len()]).split(row);
+        buttons.extend(cells.iter().copied().zip(user_buttons).map(|(cell, (value, label))| (cell, value, label)));
+    }
+    for (button_area, value, label) in buttons {
+        let is_active = app.active_button.as_ref().is_some_and(|(l, _)| *l == value) || value == "2nd" && app.second;
+        let label = label.as_str();
+        let (fg_color, bg_color) = if is_active {
+            (theme.op_button_fg, theme.active_button_bg)
+        } else {
+            match label {
+                "C" | "/" | "*" | "-" | "+" | "%" | "^" | "+/-" | "(" | ")" | "," | "+TAX" | "−TAX" | "x⇄y" | "Drop" | "R↓" | "2nd" => {
+                    (theme.op_button_fg, theme.op_button_bg)
+                }
+                _ if value.starts_with("user:") => (theme.op_button_fg, theme.op_button_bg),
+                "pmt" | "fv" | "pv" | "npv" | "irr" => (theme.op_button_fg, theme.equal_button_bg),
+                "=" => (theme.op_button_fg, theme.equal_button_bg),
+                _ => (theme.num_button_fg, theme.num_button_bg),
+            }
+        };
+        let mut block = Block::default().borders(Borders::ALL).border_style(Style::default().fg(theme.background));
+        // The secondary function's label sits small in the top corner, brighter while `2nd` is on.
+        if let Some((_, corner)) = second_function(&value) {
+            let style = if app.second { Style::default().fg(theme.equal_button_bg).add_modifier(Modifier::BOLD) } else { Style::default().fg(theme.border) };
+            block = block.title(Span::styled(corner, style)).title_alignment(Alignment::Right);
+        }
+        f.render_widget(Paragraph::new(label).style(Style::default().fg(fg_color).bg(bg_color)).alignment(Alignment::Center).block(block), button_area);
+        app.button_rects.push((button_area, value));
+    }
+    if app.stats_open { render_stats(f, app); }
+    if app.inspector_open { render_inspector(f, app); }
+    match &app.overlay {
+        Some(Overlay::Settings) => render_settings(f, app),
+        Some(Overlay::Usage) => render_usage(f, app),
+        Some(Overlay::KeyHelp) => render_key_help(f, app),
+        Some(Overlay::ThemePreview) => render_theme_preview(f, app),
+        Some(Overlay::TipSplit(tip)) => render_tip_split(f, app, tip),
+        Some(Overlay::Amortization(view)) => render_amortization(f, app, view),
+        Some(Overlay::Mortgage(wizard)) => render_mortgage(f, app, wizard),
+        Some(Overlay::Palette(palette)) => render_palette(f, app, palette),
+        Some(Overlay::SnippetMenu(cursor)) => render_snippet_menu(f, app, *cursor),
+        Some(Overlay::FunctionPicker(cursor)) => render_function_picker(f, app, *cursor),
+        Some(Overlay::NoteEditor(note)) => render_note_editor(f, app, note),
+        Some(Overlay::VariablePrompt(entry, name)) => render_variable_prompt(f, app, *entry, name),
+        Some(Overlay::Compare(compare)) => render_compare(f, app, compare),
+        Some(Overlay::Chart(index)) => render_results_chart(f, app, *index),
+        Some(Overlay::HistoryMenu(menu)) => {
+            let items = render_history_menu(f, app, menu);
+            app.button_rects.extend(items);
+        }
+        None => {}
+    }
+    if app.debug_overlay { render_debug_overlay(f, app); }
+    if let Some(toast) = &app.toast { render_toast(f, app, toast); }
+}
+
+/// Draws the hidden F12 overlay in the bottom left corner: what the last frame and the last evaluation cost.
+pub(crate) fn render_debug_overlay(f: &mut Frame, app: &App) {
+    let screen = f.size();
+    let area = Rect::new(screen.x, screen.bottom().saturating_sub(6), 32.min(screen.width), 6.min(screen.height));
+    let stats = &app.frames;
+    let millis = |duration: Duration| format!("{:.2} ms", duration.as_secs_f64() * 1000.0);
+    let allocations = app.last_timings.and_then(|timings| timings.allocations).map_or("-".to_string(), |count| count.to_string());
+    let rows = [
+        ("Render", millis(stats.render)), ("Draws", format!("{} /s", stats.draws_per_second())),
+        ("Latency", millis(stats.latency)), ("Eval allocs", allocations),
+    ];
+    let label = |text| Span::styled(format!(" {:<12}", text), Style::default().fg(app.theme.border));
+    let lines: Vec<Line> = rows.into_iter().map(|(text, value)| Line::from(vec![label(text), Span::raw(value)])).collect();
+    f.render_widget(Clear, area);
+    f.render_widget(Paragraph::new(lines).style(Style::default().fg(app.theme.text).bg(app.theme.display_bg))
+        .block(Block::default().title(" Debug (F12) ").borders(Borders::ALL).border_style(Style::default().fg(app.theme.border))), area);
+}
+
+/// Draws a toast in the top right corner, wrapped to at most half the screen.
+pub(crate) fn render_toast(f: &mut Frame, app: &App, toast: &Toast) {
+    let screen = f.size();
+    let width = (toast.text.chars().count() as u16 + 4).min(screen.width / 2).max(12).min(screen.width);
+    let height = (toast.text.chars().count() as u16).div_ceil(width.saturating_sub(4).max(1)) + 2;
+    let area = Rect::new(screen.right().saturating_sub(width), screen.y, width, height.min(screen.height));
+    let color = if toast.error { app.theme.error } else { app.theme.border };
+    f.render_widget(Clear, area);
+    f.render_widget(Paragraph::new(toast.text.as_str()).wrap(Wrap { trim: true }).style(Style::default().fg(app.theme.text).bg(app.theme.display_bg))
+        .block(Block::default().borders(Borders::ALL).padding(Padding::horizontal(1)).border_style(Style::default().fg(color))), area);
+}
+
+/// The nesting depth of each bracket in `text` by byte offset, counting from 0 for the outermost pair;
+/// `None` marks a closing bracket without an opening one. Brackets inside a character literal don't count.
+pub(crate) fn bracket_depths(text: &str) -> HashMap<usize, Option<usize>> {
+    let mut depths = HashMap::new();
+    let (mut depth, mut quoted) = (0usize, false);
+    for (index, c) in text.char_indices() {
+        match c {
+            '"' => quoted = !quoted,
+            '(' | '[' if !quoted => { depths.insert(index, Some(depth)); depth += 1; }
+            ')' | ']' if !quoted => { depths.insert(index, depth.checked_sub(1)); depth = depth.saturating_sub(1); }
+            _ => {}
+        }
+    }
+    depths
+}
+
+/// Builds the display line for `text`, scrolled `scroll` characters back from its end so it fits `width`.
+/// Clipped sides are marked with `…`, brackets are colored by depth, and the `highlight` byte range is drawn in its style.
+pub(crate) fn scrolled_line(text: &str, highlight: Option<(SourceSpan, Style)>, width: usize, scroll: usize, theme: &Theme) -> Line<'static> {
+    let depths = bracket_depths(text);
+    let chars: Vec<(usize, char)> = text.char_indices().collect();
+    let (start, end) = if chars.len() <= width { (0, chars.len()) } else {
+        let end = chars.len() - scroll;
+        let room = width.saturating_sub(1 + usize::from(scroll > 0));
+        (end.saturating_sub(room), end)
+    };
+    let mut spans = Vec::new();
+    if start > 0 { spans.push(Span::styled("…", Style::default().fg(theme.border))); }
+    for &(index, c) in &chars[start..end] {
+        match highlight {
+            Some((span, style)) if span.start <= index && index < span.end => spans.push(Span::styled(c.to_string(), style)),
+            _ if let Some(depth) = depths.get(&index) => {
+                let color = depth.map_or(theme.error, |depth| theme.brackets[depth % theme.brackets.len()]);
+                spans.push(Span::styled(c.to_string(), Style::default().fg(color)));
+            }
+            _ => spans.push(Span::raw(c.to_string())),
+        }
+    }
+    if end < chars.len() { spans.push(Span::styled("…", Style::default().fg(theme.border))); }
+    Line::from(spans)
+}
+
+/// The glyphs of the large block font, five rows each; characters without one are drawn small on the middle row.
+pub(crate) const BIG_GLYPHS: [(char, [&str; 5]); 23] = [
+    ('0', ["███", "█ █", "█ █", "█ █", "███"]),
+    ('1', [" █ ", "██ ", " █ ", " █ ", "███"]),
+    ('2', ["███", "  █", "███", "█  ", "███"]),
+    ('3', ["███", "  █", "███", "  █", "███"]),
+    ('4', ["█ █", "█ █", "███", "  █", "  █"]),
+    ('5', ["███", "█  ", "███", "  █", "███"]),
+    ('6', ["███", "█  ", "███", "█ █", "███"]),
+    ('7', ["███", "  █", "  █", "  █", "  █"]),
+    ('8', ["███", "█ █", "███", "█ █", "███"]),
+    ('9', ["███", "█ █", "███", "  █", "███"]),
+    (':', [" ", "█", " ", "█", " "]),
+    ('.', [" ", " ", " ", " ", "█"]),
+    (',', [" ", " ", " ", "█", "▘"]),
+    ('-', ["   ", "   ", "███", "   ", "   "]),
+    ('+', ["   ", " █ ", "███", " █ ", "   "]),
+    ('*', ["   ", "█ █", " █ ", "█ █", "   "]),
+    ('/', ["  █", "  █", " █ ", "█  ", "█  "]),
+    ('^', [" █ ", "█ █", "   ", "   ", "   "]),
+    ('(', [" █", "█ ", "█ ", "█ ", " █"]),
+    (')', ["█ ", " █", " █", " █", "█ "]),
+    ('%', ["█ █", "  █", " █ ", "█  ", "█ █"]),
+    ('=', ["   ", "███", "   ", "███", "   "]),
+    (' ', [" ", " ", " ", " ", " "]),
+];
+
+/// Renders `text` in the large block font as five rows, with a blank column between characters.
+pub(crate) fn big_text(text: &str) -> [String; 5] {
+    let mut rows: [String; 5] = Default::default();
+    for (i, c) in text.chars().enumerate() {
+        let glyph = BIG_GLYPHS.iter().find(|(g, _)| *g == c).map(|(_, rows)| rows.map(str::to_string))
+            .unwrap_or_else(|| ["   ".to_string(), "   ".to_string(), format!(" {} ", c), "   ".to_string(), "   ".to_string()]);
+        for (row, part) in rows.iter_mut().zip(glyph) {
+            if i > 0 { row.push(' '); }
+            row.push_str(&part);
+        }
+    }
+    rows
+}
+
+/// The text of the display's value line: the formatted result, or what is being typed.
+pub(crate) fn shown_value(app: &App) -> String {
+    match (app.mode() == Mode::ResultShown, app.last_result, &app.last_label) {
+        (true, Some(value), None) => format_display(value, app.last_exact.as_deref(), app.settings.backend, &app.settings.format_options()),
+        _ => app.display_value.clone(),
+    }
+}
+
+/// `text` in the large font if it fits in `width` columns, or as it is otherwise.
+pub(crate) fn presentation_lines(text: &str, width: usize) -> Vec<String> {
+    let rows = big_text(text);
+    if rows[0].chars().count() <= width { rows.to_vec() } else { vec![text.to_string()] }
+}
+
+/// Draws presentation mode: the expression and value in large glyphs on a plain background, and nothing else.
+pub(crate) fn render_presentation(f: &mut Frame, app: &App) {
+    let theme = &app.theme;
+    f.render_widget(Block::default().bg(theme.display_bg), f.size());
+    let area = f.size().inner(&Margin { horizontal: 2, vertical: 1 });
+    let width = area.width as usize;
+    let mut lines: Vec<Line> = Vec::new();
+    if app.mode() == Mode::ResultShown && let Some(expression) = &app.last_expression {
+        lines.extend(presentation_lines(&format!("{} =", expression), width).into_iter().map(|row| Line::styled(row, Style::default().fg(theme.border))));
+        lines.push(Line::raw(""));
+    }
+    let bold = Style::default().fg(theme.text).add_modifier(Modifier::BOLD);
+    lines.extend(presentation_lines(&shown_value(app), width).into_iter().map(|row| Line::styled(row, bold)));
+    if let Some(error) = &app.error_message {
+        lines.push(Line::raw(""));
+        lines.push(Line::styled(format!("⚠ {}", error), Style::default().fg(theme.error)));
+    }
+    let height = (lines.len() as u16).min(area.height);
+    let top = area.y + (area.height - height) / 2;
+    f.render_widget(Paragraph::new(lines).alignment(Alignment::Right), Rect::new(area.x, top, area.width, height));
+}
+
+/// Draws the screensaver: a dim clock in the configured time zone with the last result below it.
+/// It moves a little every minute so nothing stays lit in one place.
+pub(crate) fn render_screensaver(f: &mut Frame, app: &App) {
+    let theme = &app.theme;
+    let dim = Style::default().fg(theme.border);
+    f.render_widget(Block::default().bg(Color::Black), f.size());
+    let now = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map_or(0, |elapsed| elapsed.as_secs() as i64);
+    let local = now + app.settings.utc_offset_minutes as i64 * 60;
+    let clock = format!("{:02}:{:02}", local.rem_euclid(86_400) / 3600, local / 60 % 60);
+    let mut lines: Vec<Line> = big_text(&clock).into_iter().map(|row| Line::styled(row, dim)).collect();
+    if let Some(value) = app.last_result {
+        lines.push(Line::raw(""));
+        lines.push(Line::styled(format!("= {}", format_display(value, app.last_exact.as_deref(), app.settings.backend, &app.settings.format_options())), dim));
+    }
+    let size = f.size();
+    let (width, height) = (30.min(size.width), (lines.len() as u16).min(size.height));
+    let minute = (now / 60) as u16;
+    let x = size.x + minute.wrapping_mul(7) % (size.width - width + 1);
+    let y = size.y + minute.wrapping_mul(3) % (size.height - height + 1);
+    f.render_widget(Paragraph::new(lines).alignment(Alignment::Center), Rect::new(x, y, width, height));
+}
+
+/// Returns a rectangle of the given size centered inside `area`.
+pub(crate) fn centered_rect(width: u16, height: u16, area: Rect) -> Rect {
+    let width = width.min(area.width);
+    let height = height.min(area.height);
+    Rect::new(area.x + (area.width - width) / 2, area.y + (area.height - height) / 2, width, height)
+}
+
+/// How many bits fit on one row of the bit field, `width` columns wide: whole nibbles, each followed by a gap.
+pub(crate) fn bits_per_row(width: u16, word_bits: u32) -> u32 {
+    [32, 16, 8, 4].into_iter().find(|&bits| (bits / 4 * 5 - 1) as u16 <= width).unwrap_or(4).min(word_bits)
+}
+
+/// Draws the current value as a row of bit cells, most significant first, with an index over each nibble.
+/// Returns the click target of each cell.
+pub(crate) fn render_bit_field(f: &mut Frame, app: &App, area: Rect) -> Vec<(Rect, String)> {
+    let theme = &app.theme;
+    let bits = app.settings.word_bits;
+    let word = current_value(app).and_then(|value| to_word(value, bits));
+    let title = if word.is_some() { format!(" Bits · {}-bit (click to toggle) ", bits) } else { " Bits · out of range ".to_string() };
+    let block = Block::default().title(title).borders(Borders::ALL).border_style(Style::default().fg(theme.border))
+        .style(Style::default().fg(theme.text).bg(theme.display_bg));
+    let inner = block.inner(area);
+    f.render_widget(block, area);
+    let per_row = bits_per_row(inner.width, bits);
+    let mut cells = Vec::new();
+    for (row, y) in (0..bits / per_row).zip((inner.y..inner.bottom()).step_by(2)) {
+        let top = bits - 1 - row * per_row;
+        let mut labels = Vec::new();
+        let mut digits = Vec::new();
+        for i in 0..per_row {
+            let bit = top - i;
+            if i % 4 == 0 { labels.push(Span::styled(format!("{:<5}", bit), Style::default().fg(theme.border))); }
+            let set = word.is_some_and(|w| w >> bit & 1 == 1);
+            digits.push(Span::styled(
+                if set { "1" } else { "0" },
+                if set { Style::default().fg(theme.op_button_bg).add_modifier(Modifier::BOLD) } else { Style::default().fg(theme.text) }
+            ));
+            if i % 4 == 3 { digits.push(Span::raw(" ")); }
+            let x = inner.x + (i + i / 4) as u16;
+            if y + 1 < inner.bottom() && x < inner.right() { cells.push((Rect::new(x, y + 1, 1, 1), format!("bit:{}", bit))); }
+        }
+        f.render_widget(Paragraph::new(vec![Line::from(labels), Line::from(digits)]), Rect { y, height: 2.min(inner.bottom() - y), ..inner });
+    }
+    cells
+}
+
+/// Draws the current value in decimal, hexadecimal, binary and octal.
+pub(crate) fn render_base_converter(f: &mut Frame, app: &App, area: Rect) {
+    let theme = &app.theme;
+    let dim = Style::default().fg(theme.border);
+    let mut lines = Vec::new();
+    match current_value(app).map(|value| (value, base_representations(value, app.settings.word_bits))) {
+        Some((value, Some(rows))) => {
+            if value.fract() != 0.0 { lines.push(Line::styled(" integer part:", dim)); }
+            // Long binary numbers continue under the value column, breaking between groups.
+            let width = (area.width as usize).saturating_sub(7).max(5);
+            for (label, text) in rows {
+                let mut rows = vec![String::new()];
+                for group in group_base_digits(&text).split(' ') {
+                    let last = rows.last_mut().unwrap();
+                    if last.is_empty() { last.push_str(group); }
+                    else if last.chars().count() + 1 + group.len() <= width { last.push(' '); last.push_str(group); }
+                    else { rows.push(group.to_string()); }
+                }
+                for (i, row) in rows.into_iter().enumerate() {
+                    lines.push(Line::from(vec![Span::styled(format!(" {:<4}", if i == 0 { label } else { "" }), dim), Span::raw(row)]));
+                }
+            }
+        }
+        Some((_, None)) => lines.push(Line::styled(format!(" Out of range for {} bits", app.settings.word_bits), dim)),
+        None => lines.push(Line::styled(" Enter a number", dim)),
+    }
+    // A valid code point also gets its character and UTF-8 bytes.
+    if let Some(c) = current_value(app).and_then(code_point) {
+        let shown = if c.is_control() { String::new() } else { format!("\"{}\" ", c) };
+        lines.push(Line::from(vec![Span::styled(" utf8", dim), Span::raw(shown + &utf8_bytes(c))]));
+    }
+    lines.push(Line::raw(""));
+    lines.push(Line::styled(" Alt+D/H/B/O copies", dim));
+    lines.push(Line::styled(" Alt+W changes word size", dim));
+    f.render_widget(Paragraph::new(lines).style(Style::default().fg(theme.text).bg(theme.display_bg))
+        .block(Block::default().title(format!(" Bases · {}-bit (F9) ", app.settings.word_bits)).borders(Borders::ALL).border_style(dim)), area);
+}
+
+/// Draws the IEEE-754 breakdown of the current value: its fields, exact value and neighbours.
+pub(crate) fn render_inspector(f: &mut Frame, app: &App) {
+    let theme = &app.theme;
+    let area = centered_rect(72, 16, f.size());
+    let dim = Style::default().fg(theme.border);
+    let row = |label: &str, text: String| Line::from(vec![Span::styled(format!(" {:<11}", label), dim), Span::raw(text)]);
+    let mut lines = Vec::new();
+    match current_value(app) {
+        Some(value) => {
+            let bits = value.to_bits();
+            let (sign, biased, fraction) = (bits >> 63, (bits >> 52) & 0x7FF, bits & ((1 << 52) - 1));
+            let scale = match biased { 0 => "subnormal, 2^-1022".to_string(), 0x7FF => "infinity or NaN".to_string(), e => format!("2^{}", e as i64 - 1023) };
+            lines.push(row("value", format!("{}  (shortest: {})", format_result(value, &app.settings.format_options()), value)));
+            lines.push(row("bits", format!("0x{:016X}", bits)));
+            lines.push(row("sign", format!("{} ({})", sign, if sign == 1 { "−" } else { "+" })));
+            lines.push(row("exponent", format!("{:011b}  {}", biased, scale)));
+            lines.push(row("mantissa", format!("{:052b}", fraction)));
+            if value.is_finite() {
+                let exact = exact_decimal(value);
+                lines.push(row("exact", exact.clone()));
+                lines.push(row("next down", value.next_down().to_string()));
+                lines.push(row("next up", value.next_up().to_string()));
+                if format_result(value, &app.settings.format_options()) != exact {
+                    lines.push(Line::raw(""));
+                    lines.push(Line::styled(
+                        format!(" ⚠ {} is not exactly representable as a double", format_result(value, &app.settings.format_options())),
+                        Style::default().fg(theme.error)
+                    ));
+                }
+            }
+        }
+        None => lines.push(Line::styled(" Enter a number", dim)),
+    }
+    f.render_widget(Clear, area);
+    f.render_widget(Paragraph::new(lines).wrap(Wrap { trim: false }).style(Style::default().fg(theme.text).bg(theme.display_bg))
+        .block(Block::default().title(" IEEE-754 double (F10 to close) ").borders(Borders::ALL).border_style(dim)), area);
+}
+
+/// Draws the practice score: the level, the share answered correctly, and the current and best streaks.
+pub(crate) fn render_quiz(f: &mut Frame, app: &App, quiz: &Quiz, area: Rect) {
+    let theme = &app.theme;
+    let dim = Style::default().fg(theme.border);
+    let percent = (quiz.correct * 100).checked_div(quiz.asked).map_or(String::new(), |percent| format!(" ({}%)", percent));
+    let rows = [
+        ("Level", format!("{} of {}", quiz.level, Quiz::LEVELS)),
+        ("Score", format!("{}/{}{}", quiz.correct, quiz.asked, percent)),
+        ("Streak", quiz.streak.to_string()),
+        ("Best streak", quiz.best_streak.to_string()),
+    ];
+    let mut lines: Vec<Line> = rows.into_iter().map(|(label, value)| Line::from(vec![
+        Span::styled(format!(" {:<12}", label), dim), Span::raw(format!("{:>14}", value)),
+    ])).collect();
+    lines.push(Line::raw(""));
+    lines.push(Line::styled(" Type the answer, then =", dim));
+    lines.push(Line::styled(" PgUp/PgDn change the level", dim));
+    f.render_widget(Paragraph::new(lines).style(Style::default().fg(theme.text).bg(theme.display_bg))
+        .block(Block::default().title(" Practice ").borders(Borders::ALL).border_style(dim)), area);
+}
+
+/// Draws the tutor's account of the last evaluation, or a prompt while an expression is being typed.
+pub(crate) fn render_tutor(f: &mut Frame, app: &App, area: Rect) {
+    let theme = &app.theme;
+    let dim = Style::default().fg(theme.border);
+    // Each step starts a line of its own.
+    let text: Vec<Line> = match (&app.tutor_text, app.mode() == Mode::ResultShown) {
+        (Some(text), true) => text.split_inclusive("; ").map(|step| Line::raw(step.to_string())).collect(),
+        _ => vec![Line::styled("Press = to see the order the expression is worked out in.", dim)],
+    };
+    f.render_widget(Paragraph::new(text).wrap(Wrap { trim: true }).style(Style::default().fg(theme.text).bg(theme.display_bg))
+        .block(Block::default().title(" Tutor ").borders(Borders::ALL).border_style(dim)), area);
+}
+
+/// Draws the parse tree of the expression being typed, or of the last one after `=`.
+pub(crate) fn render_parse_tree(f: &mut Frame, app: &App, area: Rect) {
+    let theme = &app.theme;
+    let dim = Style::default().fg(theme.border);
+    let expression = match (&app.last_expression, app.mode() == Mode::ResultShown) {
+        (Some(expression), true) => expression.as_str(),
+        _ => app.display_value.as_str(),
+    };
+    let tree = tokenize(expression, app.settings.si_suffixes).and_then(|tokens| parse_tokens(tokens, expression.len(), app.settings.max_depth));
+    let lines: Vec<Line> = match tree {
+        Ok(tree) => tree_lines(&tree).into_iter().map(|line| Line::raw(format!(" {}", line))).collect(),
+        Err(e) => vec![Line::styled(format!(" {}", e.message), dim)],
+    };
+    f.render_widget(Paragraph::new(lines).style(Style::default().fg(theme.text).bg(theme.display_bg))
+        .block(Block::default().title(" Parse tree (Alt+T) ").borders(Borders::ALL).border_style(dim)), area);
+}
+
+/// Draws the RPN stack: the X, Y, Z and T registers, X at the bottom, and how many values lie deeper.
+pub(crate) fn render_rpn_stack(f: &mut Frame, app: &App, area: Rect) {
+    let theme = &app.theme;
+    let dim = Style::default().fg(theme.border);
+    let stack = &app.rpn_stack;
+    let mut lines = vec![Line::styled(format!(" {} more below", stack.len().saturating_sub(4)), dim)];
+    lines.extend(["T", "Z", "Y", "X"].iter().enumerate().map(|(i, register)| {
+        let value = (stack.len() + i).checked_sub(4).and_then(|index| stack.get(index))
+            .map_or(String::new(), |&x| format_result(x, &app.settings.format_options()));
+        Line::from(vec![Span::styled(format!(" {}:", register), dim), Span::raw(format!("{:>24}", value))])
+    }));
+    lines.push(Line::raw(""));
+    lines.push(Line::styled(" = push · Tab swap", dim));
+    lines.push(Line::styled(" Del drop · PgDn roll", dim));
+    f.render_widget(Paragraph::new(lines).style(Style::default().fg(theme.text).bg(theme.display_bg))
+        .block(Block::default().title(" RPN stack (^R) ").borders(Borders::ALL).border_style(dim)), area);
+}
+
+/// Draws the statistics-mode summary of the entered data points.
+pub(crate) fn render_data_summary(f: &mut Frame, app: &App, area: Rect) {
+    let theme = &app.theme;
+    let dim = Style::default().fg(theme.border);
+    let mut lines = Vec::new();
+    let values: Vec<f64> = app.data_points.iter().map(|p| p.1).collect();
+    match summarize(&values) {
+        Some(summary) => {
+            let rows = [
+                ("n", summary.n as f64), ("sum", summary.sum), ("mean", summary.mean), ("median", summary.median),
+                ("min", summary.min), ("max", summary.max), ("variance", summary.variance),
+                ("stddev", summary.stddev),
+            ];
+            lines.extend(rows.iter().map(|(label, value)| Line::from(vec![
+                Span::styled(format!(" {:<9}", label), dim),
+                Span::raw(format!("{:>18}", format_result(*value, &app.settings.format_options()))),
+            ])));
+            lines.push(Line::raw(""));
+            let recent: Vec<String> = app.data_points.iter().rev().take(5).map(|&(x, y)| match x {
+                Some(x) => format!("({}, {})", format_result(x, &app.settings.format_options()), format_result(y, &app.settings.format_options())),
+                None => format_result(y, &app.settings.format_options()),
+            }).collect();
+            lines.push(Line::styled(format!(" last: {}", recent.join(", ")), dim));
+        }
+        None => lines.push(Line::styled(" Enter a value and press =", dim)),
+    }
+    let pairs = data_pairs(app);
+    let fit = linear_fit(&pairs);
+    if let Some(fit) = fit {
+        lines.push(Line::raw(""));
+        let rows = [("pairs", pairs.len() as f64), ("slope", fit.slope), ("intercept", fit.intercept), ("r²", fit.r_squared)];
+        lines.extend(rows.iter().map(|(label, value)| Line::from(vec![
+            Span::styled(format!(" {:<9}", label), dim),
+            Span::raw(format!("{:>18}", format_result(*value, &app.settings.format_options()))),
+        ])));
+    }
+    lines.push(Line::styled(" Del drops last, ^K clears", dim));
+    let block = Block::default().title(" Statistics (F8) ").borders(Borders::ALL).border_style(dim).style(Style::default().fg(theme.text).bg(theme.display_bg));
+    let inner = block.inner(area);
+    f.render_widget(block, area);
+    let text_height = (lines.len() as u16).min(inner.height);
+    f.render_widget(Paragraph::new(lines), Rect { height: text_height, ..inner });
+    let chart_area = Rect { y: inner.y + text_height, height: inner.height - text_height, ..inner };
+    if let Some(fit) = fit && chart_area.height >= 6 { render_regression_chart(f, app, &pairs, fit, chart_area); }
+}
+
+/// Plots the entered pairs with their fitted line.
+pub(crate) fn render_regression_chart(f: &mut Frame, app: &App, pairs: &[(f64, f64)], fit: LinearFit, area: Rect) {
+    let theme = &app.theme;
+    let bounds = |values: &mut dyn Iterator<Item = f64>| values.fold((f64::INFINITY, f64::NEG_INFINITY), |(lo, hi), v| (lo.min(v), hi.max(v)));
+    let (x_min, x_max) = bounds(&mut pairs.iter().map(|p| p.0));
+    let line = [(x_min, fit.intercept + fit.slope * x_min), (x_max, fit.intercept + fit.slope * x_max)];
+    let (y_min, y_max) = bounds(&mut pairs.iter().map(|p| p.1).chain(line.iter().map(|p| p.1)));
+    let pad = if y_max > y_min { (y_max - y_min) * 0.05 } else { 1.0 };
+    let datasets = vec![
+        Dataset::default().marker(symbols::Marker::Braille).graph_type(GraphType::Line).style(Style::default().fg(theme.border)).data(&line),
+        Dataset::default().marker(symbols::Marker::Dot).graph_type(GraphType::Scatter).style(Style::default().fg(theme.text)).data(pairs),
+    ];
+    let label = |v: f64| Span::raw(format_result(v, &app.settings.format_options()));
+    f.render_widget(Chart::new(datasets).style(Style::default().bg(theme.display_bg))
+        .x_axis(Axis::default().bounds([x_min, x_max]).labels(vec![label(x_min), label(x_max)]).style(Style::default().fg(theme.border)))
+        .y_axis(Axis::default().bounds([y_min - pad, y_max + pad]).labels(vec![label(y_min), label(y_max)]).style(Style::default().fg(theme.border))), area);
+}
+
+/// Draws every finite result of the history in order, numbered like `@N`, with the highlighted one marked and
+/// its expression underneath.
+pub(crate) fn render_results_chart(f: &mut Frame, app: &App, index: usize) {
+    let theme = &app.theme;
+    let dim = Style::default().fg(theme.border);
+    let Some(entry) = app.history.get(index) else { return };
+    let points: Vec<(f64, f64)> = chart_entries(&app.history).into_iter().map(|i| ((i + 1) as f64, app.history[i].value)).collect();
+    let highlight = [((index + 1) as f64, entry.value)];
+    let bounds = |values: &mut dyn Iterator<Item = f64>| values.fold((f64::INFINITY, f64::NEG_INFINITY), |(lo, hi), v| (lo.min(v), hi.max(v)));
+    let (x_min, x_max) = bounds(&mut points.iter().map(|p| p.0));
+    let (y_min, y_max) = bounds(&mut points.iter().map(|p| p.1));
+    let pad = if y_max > y_min { (y_max - y_min) * 0.05 } else { 1.0 };
+    let datasets = vec![
+        Dataset::default().marker(symbols::Marker::Braille).graph_type(GraphType::Line).style(dim).data(&points),
+        Dataset::default().marker(symbols::Marker::Dot).graph_type(GraphType::Scatter).style(Style::default().fg(theme.text)).data(&points),
+        Dataset::default().marker(symbols::Marker::Block).graph_type(GraphType::Scatter).style(Style::default().fg(theme.equal_button_bg)).data(&highlight),
+    ];
+    let area = centered_rect(f.size().width.saturating_sub(6), f.size().height.saturating_sub(4), f.size());
+    f.render_widget(Clear, area);
+    let block = Block::default().title(" Results · ←/→ move, Enter inserts, Esc closes ").borders(Borders::ALL)
+        .border_style(dim).style(Style::default().fg(theme.text).bg(theme.display_bg));
+    let inner = block.inner(area);
+    f.render_widget(block, area);
+    let [chart_area, caption_area] = [
+        Rect { height: inner.height.saturating_sub(1), ..inner },
+        Rect { y: inner.y + inner.height.saturating_sub(1), height: 1.min(inner.height), ..inner },
+    ];
+    let label = |v: f64| Span::raw(format_result(v, &app.settings.format_options()));
+    let at = |x: f64| Span::raw(format!("@{}", x));
+    f.render_widget(Chart::new(datasets).style(Style::default().bg(theme.display_bg))
+        .x_axis(Axis::default().bounds([x_min - 0.5, x_max + 0.5]).labels(vec![at(x_min), at(x_max)]).style(dim))
+        .y_axis(Axis::default().bounds([y_min - pad, y_max + pad]).labels(vec![label(y_min), label(y_max)]).style(dim)), chart_area);
+    let value = format_display(entry.value, entry.exact.as_deref(), entry.backend, &app.settings.format_options());
+    f.render_widget(
+        Paragraph::new(Line::from(vec![Span::styled(format!(" @{}  ", index + 1), dim), Span::raw(format!("{} = {}", entry.expression, value))])),
+        caption_area
+    );
+}
+
+/// Draws a matrix result as a grid with right-aligned columns.
+pub(crate) fn render_matrix(f: &mut Frame, app: &App, matrix: &Matrix, area: Rect) {
+    let theme = &app.theme;
+    let cells: Vec<Vec<String>> = (0..matrix.rows)
+        .map(|r| (0..matrix.cols).map(|c| format_result(matrix.get(r, c), &app.settings.format_options())).collect()).collect();
+    let widths: Vec<usize> = (0..matrix.cols).map(|c| cells.iter().map(|row| row[c].chars().count()).max().unwrap_or(0)).collect();
+    let lines: Vec<Line> = cells.iter().map(|row| {
+        Line::raw(format!(" {}", row.iter().zip(&widths).map(|(cell, &width)| format!("{:>width$}", cell)).collect::<Vec<_>>().join("  ")))
+    }).collect();
+    f.render_widget(Paragraph::new(lines).style(Style::default().fg(theme.text).bg(theme.display_bg))
+        .block(Block::default().title(format!(" Matrix {}×{} ", matrix.rows, matrix.cols)).borders(Borders::ALL)
+            .border_style(Style::default().fg(theme.border))), area);
+}
+
+/// Draws the history tape: pinned entries at the top, then the rest with the newest at the bottom, scrolled to keep
+/// the selected entry in view. Returns the row of each visible line with its entry, for right-clicks.
+pub(crate) fn render_history(f: &mut Frame, app: &App, area: Rect) -> Vec<(Rect, usize)> {
+    let theme = &app.theme;
+    let visible = area.height.saturating_sub(2) as usize;
+    let lines: Vec<(usize, Line)> = history_order(&app.history).into_iter().map(|i| (i, &app.history[i])).flat_map(|(i, entry)| {
+        let tag = if entry.backend == NumberBackend::Float { String::new() } else { format!("[{}] ", entry.backend.name()) };
+        let pin = if entry.pinned { "★ " } else { "" };
+        let expression = if app.settings.superscripts { superscript_powers(&entry.expression) } else { entry.expression.clone() };
+        let note = entry.note.as_ref().map(|note| (i, Line::styled(format!("“{}”", note), Style::default().fg(theme.op_button_bg))));
+        note.into_iter().chain([(i, Line::from(vec![
+            Span::styled(pin, Style::default().fg(theme.op_button_bg)),
+            Span::styled(format!("@{}  ", i + 1), Style::default().fg(theme.border).add_modifier(Modifier::DIM)),
+            Span::styled(format!("{}{} = ", tag, expression), Style::default().fg(theme.border)),
+            Span::styled(format_display(entry.value, entry.exact.as_deref(), entry.backend, &app.settings.format_options()), Style::default().fg(theme.text)),
+        ]))])
+    }).collect();
+    // Pinned entries stay in view; the others scroll, showing the newest unless the selection is further up.
+    let pinned = lines.iter().take_while(|(i, _)| app.history[*i].pinned).count().min(visible);
+    let room = visible - pinned;
+    let selected = app.history_cursor.and_then(|cursor| lines.iter().skip(pinned).position(|(i, _)| *i == cursor));
+    let start = pinned + match selected {
+        Some(line) if line + room < lines.len() - pinned => line,
+        _ => (lines.len() - pinned).saturating_sub(room),
+    };
+    let lines: Vec<(usize, Line)> = lines[..pinned].iter().chain(lines[start..].iter().take(room)).map(|(i, line)| {
+        if Some(*i) == app.history_cursor { (*i, line.clone().patch_style(Style::default().bg(theme.border))) } else { (*i, line.clone()) }
+    }).collect();
+    let title = if app.history_cursor.is_some() {  " History · J/K move, p pin, a name " } else { " History " };
+    let rows = lines.iter().enumerate().map(|(row, (i, _))| (Rect::new(area.x + 1, area.y + 1 + row as u16, area.width.saturating_sub(2), 1), *i)).collect();
+    let text: Vec<Line> = lines.iter().map(|(_, line)| line.clone()).collect();
+    f.render_widget(Paragraph::new(text).style(Style::default().bg(theme.display_bg)).alignment(Alignment::Right)
+        .block(Block::default().title(title).borders(Borders::ALL).border_style(Style::default().fg(theme.border))), area);
+    rows
+}
+
+/// Draws the history menu where it was opened, kept on screen, or centered when opened from the keyboard.
+/// Returns the click target of each item.
+pub(crate) fn render_history_menu(f: &mut Frame, app: &App, menu: &HistoryMenu) -> Vec<(Rect, String)> {
+    let theme = &app.theme;
+    let Some(entry) = app.history.get(menu.entry) else { return Vec::new() };
+    let size = f.size();
+    let (width, height) = (22.min(size.width), (HISTORY_MENU_ITEMS.len() as u16 + 2).min(size.height));
+    let area = match menu.at {
+        Some((x, y)) => Rect::new(x.min(size.width - width), y.min(size.height - height), width, height),
+        None => centered_rect(width, height, size),
+    };
+    let lines: Vec<Line> = HISTORY_MENU_ITEMS.iter().enumerate().map(|(i, &name)| {
+        let name = if i == 3 && entry.pinned { "Unpin" } else { name };
+        let style = if i == menu.cursor { Style::default().fg(theme.op_button_fg).bg(theme.op_button_bg) } else { Style::default().fg(theme.text) };
+        Line::styled(format!(" {:<width$}", name, width = width.saturating_sub(3) as usize), style)
+    }).collect();
+    let title: String = format!(" {} ", entry.expression).chars().take(width.saturating_sub(2) as usize).collect();
+    f.render_widget(Clear, area);
+    f.render_widget(Paragraph::new(lines).style(Style::default().bg(theme.display_bg))
+        .block(Block::default().title(title).borders(Borders::ALL).border_style(Style::default().fg(theme.border))), area);
+    (0..HISTORY_MENU_ITEMS.len()).map(|i| (Rect::new(area.x + 1, area.y + 1 + i as u16, width.saturating_sub(2), 1), format!("menu:{}", i))).collect()
+}
+
+/// Draws the timing statistics panel: the last evaluation by stage, session min/avg/max, and per-entry totals.
+pub(crate) fn render_stats(f: &mut Frame, app: &App) {
+    let theme = &app.theme;
+    let area = centered_rect(50, 18, f.size());
+    let dim = Style::default().fg(theme.border);
+    let mut lines = vec![Line::styled(" Last evaluation", dim)];
+    match app.last_timings {
+        Some(t) => for (stage, duration) in [("tokenize", t.tokenize), ("parse", t.parse), ("evaluate", t.evaluate), ("total", t.total())] {
+            lines.push(Line::raw(format!("   {:<10}{:>10} µs", stage, duration.as_micros())));
+        },
+        None => lines.push(Line::raw("   none yet")),
+    }
+    let totals: Vec<Duration> = app.history.iter().map(|entry| entry.timings.total()).collect();
+    lines.push(Line::styled(format!(" Session ({} evaluations)", totals.len()), dim));
+    if let (Some(min), Some(max)) = (totals.iter().min(), totals.iter().max()) {
+        let avg = totals.iter().sum::<Duration>() / totals.len() as u32;
+        lines.push(Line::raw(format!("   min {} µs · avg {} µs · max {} µs", min.as_micros(), avg.as_micros(), max.as_micros())));
+    }
+    lines.push(Line::styled(" Per entry", dim));
+    let room = (area.height as usize).saturating_sub(lines.len() + 2);
+    for entry in app.history.iter().rev().take(room) {
+        lines.push(Line::raw(format!("   {:<30}{:>8} µs", entry.expression.chars().take(30).collect::<String>(), entry.timings.total().as_micros())));
+    }
+    f.render_widget(Clear, area);
+    f.render_widget(Paragraph::new(lines).style(Style::default().fg(theme.text).bg(theme.display_bg))
+        .block(Block::default().title(" Timing (F3 to close) ").borders(Borders::ALL).border_style(dim)), area);
+}
+
+/// Draws the settings overlay on top of the calculator.
+pub(crate) fn render_settings(f: &mut Frame, app: &App) {
+    let theme = &app.theme;
+    let area = centered_rect(40, SETTING_LABELS.len() as u16 + 2, f.size());
+    let lines: Vec<Line> = SETTING_LABELS.iter().enumerate().map(|(i, label)| {
+        let style = if i == app.settings_cursor { Style::default().fg(theme.op_button_fg).bg(theme.op_button_bg) } else { Style::default().fg(theme.text) };
+        Line::styled(format!(" {:<24}◀ {} ▶", label, app.settings.value_text(i)), style)
+    }).collect();
+    f.render_widget(Clear, area);
+    f.render_widget(Paragraph::new(lines).style(Style::default().bg(theme.display_bg))
+        .block(Block::default().title(" Settings (↑↓ select, ←→ change) ").borders(Borders::ALL)
+            .border_style(Style::default().fg(theme.border))), area);
+}
+
+/// Draws the tip-split helper, recomputing the amounts on every frame.
+pub(crate) fn render_tip_split(f: &mut Frame, app: &App, tip: &TipSplit) {
+    let theme = &app.theme;
+    let area = centered_rect(40, 9, f.size());
+    let money = |amount: f64| format!("{}{:.2}", app.settings.currency, amount);
+    let (tip_amount, total, share) = tip.amounts();
+    let fields = [
+        ("Bill", format!("{}{}", app.settings.currency, tip.bill)), ("Tip", format!("◀ {}% ▶", tip.tip_percent)),
+        ("People", format!("◀ {} ▶", tip.people)),
+    ];
+    let mut lines: Vec<Line> = fields.into_iter().enumerate().map(|(i, (label, value))| {
+        let style = if i == tip.cursor { Style::default().fg(theme.op_button_fg).bg(theme.op_button_bg) } else { Style::default().fg(theme.text) };
+        Line::styled(format!(" {:<14}{:>20} ", label, value), style)
+    }).collect();
+    lines.push(Line::raw(""));
+    for (label, amount) in [("Tip", tip_amount), ("Total", total), ("Per person", share)] {
+        lines.push(Line::styled(format!(" {:<14}{:>20} ", label, money(amount)), Style::default().fg(theme.text)));
+    }
+    f.render_widget(Clear, area);
+    f.render_widget(Paragraph::new(lines).style(Style::default().bg(theme.display_bg))
+        .block(Block::default().title(" Tip split (↑↓ select, +/- change) ").borders(Borders::ALL).border_style(Style::default().fg(theme.border))), area);
+}
+
+/// Draws the amortization view: the form, a summary, and the visible part of the schedule.
+pub(crate) fn render_amortization(f: &mut Frame, app: &App, view: &Amortization) {
+    let theme = &app.theme;
+    // The form, the summary, the header and the borders take seven rows above and around a full page.
+    let area = centered_rect(64, Amortization::PAGE as u16 + 7, f.size());
+    let dim = Style::default().fg(theme.border);
+    let mut lines: Vec<Line> = Amortization::LABELS.iter().zip(&view.fields).enumerate().map(|(i, (label, value))| {
+        let style = if i == view.cursor { Style::default().fg(theme.op_button_fg).bg(theme.op_button_bg) } else { Style::default().fg(theme.text) };
+        Line::styled(format!(" {:<16}{:>16} ", label, value), style)
+    }).collect();
+    let rows = view.schedule();
+    let total_interest: f64 = rows.iter().map(|row| row.interest).sum();
+    match rows.first() {
+        Some(first) => lines.push(Line::styled(format!(" Payment {:.2} · Total interest {:.2}", first.payment, total_interest), dim)),
+        None => lines.push(Line::styled(" Enter a principal and a term", dim)),
+    }
+    lines.push(Line::styled(format!(" {:>6}{:>13}{:>13}{:>13}{:>13}", "Period", "Payment", "Interest", "Principal", "Balance"), dim));
+    let room = (area.height as usize).saturating_sub(lines.len() + 2);
+    let scroll = view.scroll.min(rows.len().saturating_sub(room));
+    for row in rows.iter().skip(scroll).take(room) {
+        lines.push(Line::raw(format!(" {:>6}{:>13.2}{:>13.2}{:>13.2}{:>13.2}", row.period, row.payment, row.interest, row.principal, row.balance)));
+    }
+    f.render_widget(Clear, area);
+    f.render_widget(Paragraph::new(lines).style(Style::default().fg(theme.text).bg(theme.display_bg))
+        .block(Block::default().title(" Amortization (↑↓ field, PgUp/PgDn scroll, e export CSV) ").borders(Borders::ALL).border_style(dim)), area);
+}
+
+/// Draws the mortgage wizard with its results.
+pub(crate) fn render_mortgage(f: &mut Frame, app: &App, wizard: &Mortgage) {
+    let theme = &app.theme;
+    let area = centered_rect(44, 12, f.size());
+    let dim = Style::default().fg(theme.border);
+    let mut lines: Vec<Line> = Mortgage::LABELS.iter().zip(&wizard.fields).enumerate().map(|(i, (label, value))| {
+        let style = if i == wizard.cursor { Style::default().fg(theme.op_button_fg).bg(theme.op_button_bg) } else { Style::default().fg(theme.text) };
+        Line::styled(format!(" {:<18}{:>22} ", label, value), style)
+    }).collect();
+    lines.push(Line::raw(""));
+    match wizard.summary() {
+        Some(summary) => {
+            lines.push(Line::raw(format!(" {:<18}{:>22.2} ", "Monthly payment", summary.monthly)));
+            lines.push(Line::raw(format!(" {:<18}{:>22.2} ", "Total interest", summary.total_interest)));
+            lines.push(Line::raw(format!(" {:<18}{:>22} ", "Paid off in", format!("{} months", summary.months))));
+            if summary.interest_saved > 0.005 { lines.push(Line::raw(format!(" {:<18}{:>22.2} ", "Interest saved", summary.interest_saved))); }
+        }
+        None => lines.push(Line::styled(" Enter an amount and a term", dim)),
+    }
+    f.render_widget(Clear, area);
+    f.render_widget(Paragraph::new(lines).style(Style::default().fg(theme.text).bg(theme.display_bg))
+        .block(Block::default().title(" Mortgage (↑↓ field, Enter to use payment) ").borders(Borders::ALL).border_style(dim)), area);
+}
+
+/// Draws the compare view: the history with the marked entries, then A and B side by side with how they differ.
+pub(crate) fn render_compare(f: &mut Frame, app: &App, compare: &Compare) {
+    let theme = &app.theme;
+    let area = centered_rect(64, 20, f.size());
+    let dim = Style::default().fg(theme.border);
+    let value = |i: usize| format_display(app.history[i].value, app.history[i].exact.as_deref(), app.history[i].backend, &app.settings.format_options());
+    let room = (area.height as usize).saturating_sub(9);
+    let first = (compare.cursor + 1).saturating_sub(room);
+    let mut lines: Vec<Line> = app.history.iter().enumerate().skip(first).take(room).map(|(i, entry)| {
+        let mark = match compare.marked.iter().position(|&m| m == i) { Some(0) => "A", Some(_) => "B", None => " " };
+        let style = if i == compare.cursor { Style::default().fg(theme.op_button_fg).bg(theme.op_button_bg) } else { Style::default().fg(theme.text) };
+        Line::styled(format!(" {} {:<58}", mark, format!("{} = {}", entry.expression, value(i))), style)
+    }).collect();
+    lines.push(Line::raw(""));
+    match compare.marked.as_slice() {
+        &[a, b] => {
+            let label = |i: usize| app.history[i].note.clone().unwrap_or_else(|| app.history[i].expression.clone());
+            lines.push(Line::styled(format!(" {:<30}{:<30}", format!("A: {}", label(a)), format!("B: {}", label(b))), dim));
+            lines.push(Line::raw(format!(" {:<30}{:<30}", value(a), value(b))));
+            let (difference, ratio, change) = compare_values(app.history[a].value, app.history[b].value);
+            let undefined = || "undefined (A is 0)".to_string();
+            lines.push(Line::raw(format!(" {:<16}{}", "Difference", format_result(difference, &app.settings.format_options()))));
+            lines.push(Line::raw(format!(" {:<16}{}", "Ratio B/A", ratio.map_or_else(undefined, |r| format_result(r, &app.settings.format_options())))));
+            lines.push(Line::raw(format!(
+                " {:<16}{}", "Change A→B",
+                change.map_or_else(undefined, |c| format!("{}{}%", if c > 0.0 { "+" } else { "" }, format_result(c, &app.settings.format_options())))
+            )));
+        }
+        _ => lines.push(Line::styled(" Mark two entries with Space", dim)),
+    }
+    f.render_widget(Clear, area);
+    f.render_widget(Paragraph::new(lines).style(Style::default().fg(theme.text).bg(theme.display_bg))
+        .block(Block::default().title(" Compare (↑↓ move, Space mark, Esc close) ").borders(Borders::ALL).border_style(dim)), area);
+}
+
+/// Draws the usage view: the most used functions and operators as bars, then suggestions drawn from them.
+pub(crate) fn render_usage(f: &mut Frame, app: &App) {
+    let theme = &app.theme;
+    let area = centered_rect(64, 20, f.size());
+    let dim = Style::default().fg(theme.border);
+    let mut lines: Vec<Line> = Vec::new();
+    let used = most_used(app, 10);
+    if !app.settings.usage_stats {
+        lines.push(Line::styled(" Usage stats are off; turn them on in settings (F2).", dim));
+        lines.push(Line::styled(" The counts never leave this machine.", dim));
+    } else if used.is_empty() {
+        lines.push(Line::styled(" Nothing counted yet.", dim));
+    }
+    let most = used.first().map_or(1, |&(_, uses)| uses);
+    for (name, uses) in used {
+        let bar = "█".repeat((uses as usize * 36).div_ceil(most as usize));
+        lines.push(Line::from(vec![
+            Span::raw(format!(" {:<14}", name)), Span::styled(bar, Style::default().fg(theme.op_button_bg)),
+            Span::styled(format!(" {}", uses), dim),
+        ]));
+    }
+    let suggestions = usage_suggestions(app);
+    if !suggestions.is_empty() { lines.push(Line::raw("")); }
+    lines.extend(suggestions.into_iter().map(|text| Line::styled(format!(" {}", text), Style::default().fg(theme.text))));
+    f.render_widget(Clear, area);
+    f.render_widget(Paragraph::new(lines).style(Style::default().fg(theme.text).bg(theme.display_bg)).wrap(Wrap { trim: false })
+        .block(Block::default().title(" Most used (Del forget, Esc close) ").borders(Borders::ALL).border_style(dim)), area);
+}
+
+/// The key hints at the bottom of the screen, from the key bindings in force; the ones that do not fit are cut off.
+pub(crate) fn footer_text(config: &Config) -> String {
+    let hints = config.key_bindings().into_iter().filter(|(_, _, hint)| !hint.is_empty()).map(|(key, _, hint)| format!("{} {}", key, hint));
+    format!(" {}", ["Ctrl+Q to quit".to_string(), "Ctrl+P for commands".to_string()].into_iter().chain(hints).collect::<Vec<_>>().join(", "))
+}
+
+/// Draws the sheet of every key: the bindings in force, the keys that type operators, and the fixed ones.
+pub(crate) fn render_key_help(f: &mut Frame, app: &App) {
+    let theme = &app.theme;
+    let dim = Style::default().fg(theme.border);
+    let row = |key: &str, action: &str| Line::from(vec![
+        Span::styled(format!(" {:<12}", key), Style::default().fg(theme.op_button_bg)),
+        Span::raw(action.to_string()),
+    ]);
+    let mut lines: Vec<Line> = app.config.key_bindings().iter().map(|(key, action, _)| row(key, action)).collect();
+    lines.extend(app.config.operator_keys.iter().map(|(key, operator)| row(&key_label(key), &format!("Type {}", operator))));
+    lines.push(Line::styled(" Always", dim));
+    lines.extend(FIXED_KEYS.iter().map(|(key, action)| row(key, action)));
+    let area = centered_rect(52, lines.len() as u16 + 2, f.size());
+    f.render_widget(Clear, area);
+    f.render_widget(Paragraph::new(lines).style(Style::default().fg(theme.text).bg(theme.display_bg))
+        .block(Block::default().title(" Keys (any key closes) ").borders(Borders::ALL).border_style(dim)), area);
+}
+
+/// Draws every color of the theme at once for theme authors: a sample display, an error, each kind of key,
+/// and a swatch with the value of every role.
+pub(crate) fn render_theme_preview(f: &mut Frame, app: &App) {
+    let theme = &app.theme;
+    let area = centered_rect(60, 19, f.size());
+    let dim = Style::default().fg(theme.border);
+    let display = Style::default().fg(theme.text).bg(theme.display_bg);
+    let key = |label: &str, fg: Color, bg: Color| Span::styled(format!("  {}  ", label), Style::default().fg(fg).bg(bg));
+    let mut sample = scrolled_line(" sqrt((2 + 3) * (4 - 1))) ", None, 40, 0, theme);
+    sample.spans.iter_mut().for_each(|span| span.style = display.patch(span.style));
+    sample.spans.insert(0, Span::styled(" Display    ", dim));
+    let mut lines = vec![
+        sample,
+        Line::from(vec![Span::styled(" Result     ", dim), Span::styled(" = 15 ", display.add_modifier(Modifier::BOLD))]),
+        Line::from(vec![Span::styled(" Error      ", dim), Span::styled(" Unmatched ')' ", display.fg(theme.error))]),
+        Line::from(vec![
+            Span::styled(" Keys       ", dim),
+            key("7", theme.num_button_fg, theme.num_button_bg), Span::raw(" "),
+            key("+", theme.op_button_fg, theme.op_button_bg), Span::raw(" "),
+            key("=", theme.op_button_fg, theme.equal_button_bg), Span::raw(" "),
+            key("5", theme.op_button_fg, theme.active_button_bg), Span::styled(" pressed", dim),
+        ]),
+        Line::raw(""),
+    ];
+    lines.extend(theme.roles().into_iter().map(|(role, color)| Line::from(vec![
+        Span::styled(" ████ ", Style::default().fg(color)), Span::raw(format!("{:<17}", role)),
+        Span::styled(color_text(color), dim),
+    ])));
+    let mut brackets: Vec<Span> = theme.brackets.iter().map(|&color| Span::styled("█", Style::default().fg(color))).collect();
+    brackets.insert(0, Span::raw(" "));
+    brackets.extend([Span::raw(" brackets         "), Span::styled(theme.brackets.map(color_text).join(" "), dim)]);
+    lines.push(Line::from(brackets));
+    let title = format!(" Theme {} (Esc close) ", app.config.theme_name(&app.profile));
+    f.render_widget(Clear, area);
+    f.render_widget(Paragraph::new(lines).style(Style::default().fg(theme.text).bg(theme.background))
+        .block(Block::default().title(title).borders(Borders::ALL).border_style(dim)), area);
+}
+
+/// Draws the note editor over the newest history entry.
+pub(crate) fn render_note_editor(f: &mut Frame, app: &App, note: &str) {
+    let theme = &app.theme;
+    let Some(entry) = app.history.last() else { return };
+    let area = centered_rect(NOTE_MAX_LENGTH as u16 + 6, 5, f.size());
+    let value = format_display(entry.value, entry.exact.as_deref(), entry.backend, &app.settings.format_options());
+    let lines = vec![
+        Line::styled(format!(" {} = {}", entry.expression, value), Style::default().fg(theme.border)),
+        Line::raw(""),
+        Line::styled(format!(" > {}▏", note), Style::default().fg(theme.text)),
+    ];
+    f.render_widget(Clear, area);
+    f.render_widget(Paragraph::new(lines).style(Style::default().bg(theme.display_bg))
+        .block(Block::default().title(" Note (Enter to save) ").borders(Borders::ALL).border_style(Style::default().fg(theme.border))), area);
+}
+
+/// Draws the prompt for the name of a history result, over the entry being named.
+pub(crate) fn render_variable_prompt(f: &mut Frame, app: &App, entry: usize, name: &str) {
+    let theme = &app.theme;
+    let Some(entry) = app.history.get(entry) else { return };
+    let area = centered_rect(NOTE_MAX_LENGTH as u16 + 6, 5, f.size());
+    let value = format_display(entry.value, entry.exact.as_deref(), entry.backend, &app.settings.format_options());
+    let lines = vec![
+        Line::styled(format!(" {} = {}", entry.expression, value), Style::default().fg(theme.border)),
+        Line::raw(""),
+        Line::styled(format!(" > {}▏", name), Style::default().fg(theme.text)),
+    ];
+    f.render_widget(Clear, area);
+    f.render_widget(Paragraph::new(lines).style(Style::default().bg(theme.display_bg))
+        .block(Block::default().title(" Variable name (Enter to bind) ").borders(Borders::ALL).border_style(Style::default().fg(theme.border))), area);
+}
+
+/// Draws the named results in the side panel, in name order.
+pub(crate) fn render_variables(f: &mut Frame, app: &App, area: Rect) {
+    let theme = &app.theme;
+    let dim = Style::default().fg(theme.border);
+    let mut lines: Vec<Line> = app.variables.iter().map(|(name, &value)| {
+        Line::from(vec![Span::styled(format!(" {}", name), dim), Span::raw(format!(" = {}", format_result(value, &app.settings.format_options())))])
+    }).collect();
+    if lines.is_empty() { lines.push(Line::styled(" none yet · a in the history", dim)); }
+    f.render_widget(Paragraph::new(lines).style(Style::default().fg(theme.text).bg(theme.display_bg))
+        .block(Block::default().title(" Variables ").borders(Borders::ALL).border_style(dim)), area);
+}
+
+/// Draws the snippet menu: each template by name, with its formula.
+pub(crate) fn render_snippet_menu(f: &mut Frame, app: &App, cursor: usize) {
+    let theme = &app.theme;
+    let area = centered_rect(52, SNIPPETS.len() as u16 + 2, f.size());
+    let lines: Vec<Line> = SNIPPETS.iter().enumerate().map(|(i, (name, template))| {
+        let style = if i == cursor { Style::default().fg(theme.op_button_fg).bg(theme.op_button_bg) } else { Style::default().fg(theme.text) };
+        Line::styled(format!(" {:<22}{:<27}", name, template.replace(['{', '}'], "")), style)
+    }).collect();
+    f.render_widget(Clear, area);
+    f.render_widget(Paragraph::new(lines).style(Style::default().bg(theme.display_bg))
+        .block(Block::default().title(" Snippets ").borders(Borders::ALL).border_style(Style::default().fg(theme.border))), area);
+}
+
+/// Draws the function picker: the functions under their section headings, scrolled to keep the cursor in view.
+pub(crate) fn render_function_picker(f: &mut Frame, app: &App, cursor: usize) {
+    let theme = &app.theme;
+    let (functions, mut lines, mut cursor_line) = (picker_functions(), Vec::new(), 0);
+    for (i, &(group, name)) in functions.iter().enumerate() {
+        if i == 0 || functions[i - 1].0 != group {
+            lines.push(Line::styled(format!(" {}", group), Style::default().fg(theme.border).add_modifier(Modifier::BOLD)));
+        }
+        let hint = COMPLETIONS.iter().find(|(function, _)| *function == name).map_or("", |(_, hint)| hint);
+        let style = if i == cursor {
+            cursor_line = lines.len();
+            Style::default().fg(theme.op_button_fg).bg(theme.op_button_bg)
+        } else { Style::default().fg(theme.text) };
+        lines.push(Line::styled(format!("   {:<15}{:<26}", name, hint), style));
+    }
+    let area = centered_rect(46, lines.len() as u16 + 2, f.size());
+    let scroll = (cursor_line + 1).saturating_sub(area.height.saturating_sub(2) as usize) as u16;
+    f.render_widget(Clear, area);
+    f.render_widget(Paragraph::new(lines).scroll((scroll, 0)).style(Style::default().bg(theme.display_bg))
+        .block(Block::default().title(" Functions ").borders(Borders::ALL).border_style(Style::default().fg(theme.border))), area);
+}
+
+/// Draws the command palette: the filter line and the matching actions.
+pub(crate) fn render_palette(f: &mut Frame, app: &App, palette: &Palette) {
+    let theme = &app.theme;
+    let matches = palette_matches(&palette.query);
+    let area = centered_rect(40, matches.len() as u16 + 4, f.size());
+    let mut lines = vec![Line::styled(format!(" > {}▏", palette.query), Style::default().fg(theme.text)), Line::raw("")];
+    lines.extend(matches.iter().enumerate().map(|(i, &action)| {
+        let style = if i == palette.cursor { Style::default().fg(theme.op_button_fg).bg(theme.op_button_bg) } else { Style::default().fg(theme.text) };
+        Line::styled(format!(" {:<37}", PALETTE_ACTIONS[action].0), style)
+    }));
+    f.render_widget(Clear, area);
+    f.render_widget(Paragraph::new(lines).style(Style::default().bg(theme.display_bg))
+        .block(Block::default().title(" Commands ").borders(Borders::ALL).border_style(Style::default().fg(theme.border))), area);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::input::on_key;
+    use crossterm::event::{KeyCode, KeyModifiers};
+    use std::time::Instant;
+    #[test]
+    fn test_display_cursor() {
+        let mut app = App::new();
+        press(&mut app, &["1", "2"]);
+        assert!(shows_cursor(&app));
+        let mut terminal = Terminal::new(ratatui::backend::TestBackend::new(60, 24)).unwrap();
+        terminal.draw(|f| ui(f, &mut app)).unwrap();
+        assert_eq!(terminal.backend_mut().get_cursor().unwrap(), (57, 4));
+        assert_eq!(terminal.backend().buffer().get(56, 4).symbol(), "2");
+        press(&mut app, &["="]);
+        assert!(!shows_cursor(&app));
+        press(&mut app, &["3"]);
+        app.settings.adjust(Settings::CURSOR, true);
+        assert_eq!((app.settings.value_text(Settings::CURSOR), shows_cursor(&app)), ("Off".to_string(), false));
+        assert_eq!(toml::from_str::<Config>("cursor = \"steady\"").unwrap().cursor, CursorMode::Steady);
+    }
+    #[test]
+    fn test_bracket_depths() {
+        let depths = bracket_depths("3 + 4 * 2 / ( 1 - 5 ) ^ 2");
+        assert_eq!((depths.len(), depths[&12], depths[&20]), (2, Some(0), Some(0)));
+        let depths = bracket_depths("((1) + [2; \")\"]) )");
+        let mut sorted: Vec<_> = depths.into_iter().collect();
+        sorted.sort();
+        assert_eq!(sorted, [(0, Some(0)), (1, Some(1)), (3, Some(1)), (7, Some(1)), (14, Some(1)), (15, Some(0)), (17, None)]);
+        let theme = Theme::default();
+        let line = scrolled_line("(1)", None, 10, 0, &theme);
+        assert_eq!(line.spans[0].style.fg, Some(theme.brackets[0]));
+    }
+    #[test]
+    fn test_presentation_mode() {
+        assert_eq!(presentation_lines("12", 7).len(), 5);
+        assert_eq!(presentation_lines("12", 6), ["12"]);
+        let mut app = App::new();
+        press(&mut app, &["1", "2", "*", "3"]);
+        assert_eq!(shown_value(&app), "12 * 3");
+        press(&mut app, &["="]);
+        assert_eq!(shown_value(&app), "36");
+        palette_action("Presentation mode").unwrap()(&mut app);
+        assert!(app.presenting);
+    }
+    #[test]
+    fn test_key_help() {
+        let mut app = App::new();
+        app.config = toml::from_str("[keys]\n\"f5\" = \"Copy result\"\n\"ctrl+e\" = \"Tip split\"\n[operator_keys]\n\"alt+6\" = \"^\"").unwrap();
+        let bindings = app.config.key_bindings();
+        assert_eq!(
+            bindings[..2].iter().map(|(key, action, _)| (key.as_str(), action.as_str())).collect::<Vec<_>>(),
+            [("Ctrl+E", "Tip split"), ("F5", "Copy result")]
+        );
+        assert!(!bindings.iter().any(|(_, action, _)| action == "Finance keypad"));
+        let footer = footer_text(&app.config);
+        assert!(footer.starts_with(" Ctrl+Q to quit, Ctrl+P for commands, Ctrl+E for tip split, F5 for copy result, F1 for all keys, F2 for settings"));
+        assert!(!footer.contains("finance"));
+        on_key(&mut app, KeyCode::F(2), KeyModifiers::NONE);
+        assert_eq!(app.focus(), Focus::Settings);
+        app.overlay = None;
+        on_key(&mut app, KeyCode::F(1), KeyModifiers::NONE);
+        assert_eq!(app.focus(), Focus::KeyHelp);
+        let mut terminal = Terminal::new(ratatui::backend::TestBackend::new(80, 40)).unwrap();
+        terminal.draw(|f| ui(f, &mut app)).unwrap();
+        let screen: String = terminal.backend().buffer().content.iter().map(|cell| cell.symbol()).collect();
+        assert!(screen.contains("F5          Copy result") && screen.contains("Alt+6       Type ^") && screen.contains("Ctrl+Q      Quit"));
+        on_key(&mut app, KeyCode::Char('x'), KeyModifiers::NONE);
+        assert_eq!(app.focus(), Focus::Display(Mode::Editing));
+    }
+    #[test]
+    fn test_function_picker() {
+        let mut names: Vec<&str> = picker_functions().into_iter().map(|(_, name)| name).collect();
+        names.sort_unstable();
+        assert_eq!(names, COMPLETIONS.map(|(name, _)| name));
+        let mut app = App::new();
+        press(&mut app, &["2", "*"]);
+        palette_action("Function picker").unwrap()(&mut app);
+        let mut terminal = Terminal::new(ratatui::backend::TestBackend::new(80, 30)).unwrap();
+        let mut screen = |app: &mut App| {
+            terminal.draw(|f| ui(f, app)).unwrap();
+            terminal.backend().buffer().content.iter().map(|cell| cell.symbol()).collect::<String>()
+        };
+        let text = screen(&mut app);
+        assert!(text.contains(" Math ") && !text.contains(" Text and dates "));
+        for code in [KeyCode::PageDown; 6] { on_key(&mut app, code, KeyModifiers::NONE); }
+        let text = screen(&mut app);
+        assert!(!text.contains(" Math ") && text.contains(" Text and dates "));
+        for code in [KeyCode::PageUp, KeyCode::PageUp, KeyCode::PageUp, KeyCode::Up, KeyCode::Up] { on_key(&mut app, code, KeyModifiers::NONE); }
+        let text = screen(&mut app);
+        assert!(text.contains(" Geometry ") && text.contains("spherevol      (r)"));
+        on_key(&mut app, KeyCode::Enter, KeyModifiers::NONE);
+        press(&mut app, &["3", ")", "="]);
+        assert_eq!((app.focus(), app.last_expression.as_deref()), (Focus::Display(Mode::ResultShown), Some("2 * spherevol(3)")));
+    }
+    #[test]
+    fn test_results_chart() {
+        let mut app = App::new();
+        let open = palette_action("Chart of results").unwrap();
+        open(&mut app);
+        assert_eq!((app.focus(), app.notice.as_deref()), (Focus::Display(Mode::Editing), Some("No results to chart yet")));
+        for expression in ["2 + 2", "10", "3 * 3"] { app.display_value = expression.to_string(); press(&mut app, &["="]); }
+        app.history[1].value = f64::INFINITY;
+        open(&mut app);
+        assert!(matches!(app.overlay, Some(Overlay::Chart(2))));
+        on_key(&mut app, KeyCode::Left, KeyModifiers::NONE);
+        assert!(matches!(app.overlay, Some(Overlay::Chart(0))));
+        let mut terminal = Terminal::new(ratatui::backend::TestBackend::new(80, 30)).unwrap();
+        terminal.draw(|f| ui(f, &mut app)).unwrap();
+        let screen: String = terminal.backend().buffer().content.iter().map(|cell| cell.symbol()).collect();
+        assert!(screen.contains(" Results · ←/→ move") && screen.contains("@1  2 + 2 = 4") && screen.contains("@3"));
+        on_key(&mut app, KeyCode::End, KeyModifiers::NONE);
+        on_key(&mut app, KeyCode::Enter, KeyModifiers::NONE);
+        assert!(app.overlay.is_none() && app.display_value == "9");
+    }
+    #[test]
+    fn test_screensaver() {
+        assert_eq!(big_text("1:0"), [" █    ███", "██  █ █ █", " █    █ █", " █  █ █ █", "███   ███"].map(str::to_string));
+        assert_eq!(big_text("x")[2], " x ");
+        let mut app = App::new();
+        assert!(!is_idle(&app));
+        app.settings.adjust(Settings::SCREENSAVER, true);
+        assert_eq!(app.settings.value_text(Settings::SCREENSAVER), "After 1 min");
+        app.last_input = Instant::now() - Duration::from_secs(61);
+        assert!(is_idle(&app));
+        assert_eq!(toml::from_str::<Config>("idle_minutes = 5").unwrap().idle_minutes, 5);
+    }
+    #[test]
+    fn test_scrolled_line_marks_clipped_text() {
+        let theme = Theme::default();
+        let text = |line: Line| line.spans.iter().map(|span| span.content.to_string()).collect::<String>();
+        assert_eq!(text(scrolled_line("12345", None, 10, 0, &theme)), "12345");
+        assert_eq!(text(scrolled_line("1234567890", None, 5, 0, &theme)), "…7890");
+        assert_eq!(text(scrolled_line("1234567890", None, 5, 2, &theme)), "…678…");
+    }
+    #[test]
+    fn test_theme_files() {
+        let theme = Theme::parse("base = \"light\"\nop_button_bg = \"#ff8800\"\nerror = \"red\"\nbrackets = [\"#010203\", \"blue\"]").unwrap();
+        assert_eq!((theme.text, theme.op_button_bg, theme.error), (Color::Black, Color::Rgb(255, 136, 0), Color::Red));
+        assert_eq!(theme.brackets, [Color::Rgb(1, 2, 3), Color::Blue, Color::Rgb(1, 2, 3), Color::Blue]);
+        assert_eq!(Theme::parse("txt = \"red\""), Err("unknown theme role `txt`".to_string()));
+        assert_eq!(Theme::parse("text = \"reddish\""), Err("`text` is not a color like \"#ff9f43\" or \"red\"".to_string()));
+        assert_eq!(Theme::parse("base = \"solarized\""), Err("`base` must be \"dark\", \"light\" or \"mono\"".to_string()));
+        assert_eq!((color_text(Color::Rgb(255, 136, 0)), color_text(Color::LightRed)), ("#ff8800".to_string(), "lightred".to_string()));
+        let mut app = App::new();
+        app.theme = theme;
+        palette_action("Theme preview").unwrap()(&mut app);
+        assert_eq!(app.focus(), Focus::ThemePreview);
+        let mut terminal = Terminal::new(backend::TestBackend::new(80, 30)).unwrap();
+        terminal.draw(|f| ui(f, &mut app)).unwrap();
+        let buffer = terminal.backend().buffer();
+        let text: String = buffer.content.iter().map(|cell| cell.symbol()).collect();
+        assert!(text.contains("Theme dark (Esc close)") && text.contains("op_button_bg     #ff8800"));
+        assert!(text.contains("brackets         #010203 blue #010203 blue"));
+    }
+    #[test]
+    fn test_capabilities() {
+        let env = |vars: &'static [(&'static str, &'static str)]| move |name: &str| vars.iter()
+            .find(|(var, _)| *var == name).map(|(_, value)| value.to_string());
+        assert_eq!(Capabilities::detect(env(&[]), true), Capabilities { truecolor: false, escapes: false });
+        assert_eq!(Capabilities::detect(env(&[("WT_SESSION", "3f2a")]), true), Capabilities::FULL);
+        assert_eq!(Capabilities::detect(env(&[("TERM", "xterm-256color")]), true), Capabilities { truecolor: false, escapes: false });
+        assert_eq!(Capabilities::detect(env(&[("TERM", "xterm-256color")]), false), Capabilities::FULL);
+        assert_eq!(Capabilities::detect(env(&[("TERM", "linux"), ("COLORTERM", "truecolor")]), false), Capabilities { truecolor: true, escapes: false });
+        assert_eq!(
+            (basic_color(Color::Rgb(255, 159, 67)), basic_color(Color::Rgb(20, 20, 30)), basic_color(Color::Indexed(196))),
+            (Color::Yellow, Color::Black, Color::LightRed)
+        );
+        let mut app = App::new();
+        app.capabilities = Capabilities { truecolor: false, escapes: false };
+        let mut terminal = Terminal::new(backend::TestBackend::new(60, 24)).unwrap();
+        terminal.draw(|f| ui(f, &mut app)).unwrap();
+        assert!(terminal.backend().buffer().content.iter().all(|cell| !matches!(cell.fg, Color::Rgb(..)) && !matches!(cell.bg, Color::Rgb(..))));
+    }
+    /// Counts the bytes a backend writes to the terminal.
+    #[derive(Clone, Default)]
+    struct ByteCount(std::rc::Rc<std::cell::Cell<usize>>);
+
+    impl std::io::Write for ByteCount {
+        fn write(&mut self, bytes: &[u8]) -> std::io::Result<usize> { self.0.set(self.0.get() + bytes.len()); Ok(bytes.len()) }
+        fn flush(&mut self) -> std::io::Result<()> { Ok(()) }
+    }
+    #[test]
+    fn test_low_bandwidth() {
+        // The bytes sent for the frame after a key press, which highlights the key unless bandwidth is low.
+        let bytes_after_key = |low_bandwidth: bool| {
+            let mut app = App::new();
+            (app.low_bandwidth, app.capabilities.truecolor) = (low_bandwidth, !low_bandwidth);
+            let viewport = Viewport::Fixed(Rect::new(0, 0, 60, 24));
+            let count = ByteCount::default();
+            let mut terminal = Terminal::with_options(CrosstermBackend::new(count.clone()), TerminalOptions { viewport }).unwrap();
+            terminal.draw(|f| ui(f, &mut app)).unwrap();
+            let before = count.0.get();
+            press(&mut app, &["7"]);
+            app.set_active_button("7");
+            terminal.draw(|f| ui(f, &mut app)).unwrap();
+            (count.0.get() - before, app.active_button.is_some())
+        };
+        let ((full, highlighted), (low, _)) = (bytes_after_key(false), bytes_after_key(true));
+        assert!(highlighted && low * 3 < full, "{} bytes, {} with low bandwidth", full, low);
+        assert!(toml::from_str::<Config>("low_bandwidth = true").unwrap().low_bandwidth);
+    }
+    #[test]
+    fn test_monochrome() {
+        let config: Config = toml::from_str("color = false\ntheme = \"light\"").unwrap();
+        assert_eq!((config.theme_name("default"), config.theme("default") == Theme::mono()), ("mono", true));
+        let mut app = App::new();
+        app.theme = Theme::mono();
+        press(&mut app, &["1", "/"]);
+        app.error_message = Some("Division by zero".to_string());
+        let mut terminal = Terminal::new(backend::TestBackend::new(60, 24)).unwrap();
+        terminal.draw(|f| ui(f, &mut app)).unwrap();
+        let buffer = terminal.backend().buffer().clone();
+        assert!(buffer.content.iter().all(|cell| cell.fg == Color::Reset && cell.bg == Color::Reset));
+        let (pressed, number) = (
+            app.button_rects.iter().find(|(_, value)| value == "/").unwrap().0,
+            app.button_rects.iter().find(|(_, value)| value == "7").unwrap().0
+        );
+        let modifier = |rect: Rect| buffer.get(rect.x + rect.width / 2, rect.y + rect.height / 2).modifier;
+        assert!(modifier(pressed).contains(Modifier::REVERSED) && modifier(number).is_empty());
+        let warning = buffer.content.iter().position(|cell| cell.symbol() == "⚠").unwrap() as u16;
+        assert!(buffer.get(warning % 60 + 2, warning / 60).modifier.contains(Modifier::BOLD | Modifier::UNDERLINED));
+    }
+}
@@ -0,0 +1,30 @@
+//! The physical units a quantity can be written in, and the dimensions they measure.
+
+/// Powers of the base units metre, kilogram, second and ampere.
+pub(crate) type Dimension = [i32; 4];
+
+pub(crate) const DIMENSIONLESS: Dimension = [0; 4];
+pub(crate) const TIME: Dimension = [0, 0, 1, 0];
+
+/// The unit names, their size in base units, and their dimension.
+pub(crate) const UNITS: [(&str, f64, Dimension); 24] = [
+    ("m", 1.0, [1, 0, 0, 0]), ("km", 1000.0, [1, 0, 0, 0]), ("cm", 0.01, [1, 0, 0, 0]), ("mm", 0.001, [1, 0, 0, 0]),
+    ("ft", 0.3048, [1, 0, 0, 0]), ("mi", 1609.344, [1, 0, 0, 0]), ("L", 0.001, [3, 0, 0, 0]),
+    ("g", 0.001, [0, 1, 0, 0]), ("kg", 1.0, [0, 1, 0, 0]), ("mg", 1e-6, [0, 1, 0, 0]), ("lb", 0.453_592_37, [0, 1, 0, 0]),
+    ("s", 1.0, TIME), ("ms", 0.001, TIME), ("min", 60.0, TIME), ("h", 3600.0, TIME),
+    ("Hz", 1.0, [0, 0, -1, 0]), ("N", 1.0, [1, 1, -2, 0]), ("Pa", 1.0, [-1, 1, -2, 0]), ("J", 1.0, [2, 1, -2, 0]), ("kJ", 1000.0, [2, 1, -2, 0]),
+    ("W", 1.0, [2, 1, -3, 0]), ("kW", 1000.0, [2, 1, -3, 0]), ("A", 1.0, [0, 0, 0, 1]), ("V", 1.0, [2, 1, -3, -1]),
+];
+
+pub(crate) fn unit(name: &str) -> Option<(f64, Dimension)> { UNITS.iter().find(|u| u.0 == name).map(|u| (u.1, u.2)) }
+
+/// Writes a dimension as units that can be typed back in, like `m/s^2`; derived units get their own name.
+pub(crate) fn unit_text(dimension: Dimension) -> String {
+    const DERIVED: [&str; 6] = ["N", "J", "W", "Pa", "V", "Hz"];
+    if let Some(name) = DERIVED.iter().find(|name| unit(name).is_some_and(|u| u.1 == dimension)) { return name.to_string(); }
+    const BASE: [&str; 4] = ["m", "kg", "s", "A"];
+    let power = |name: &str, p: i32| if p == 1 { name.to_string() } else { format!("{}^{}", name, p) };
+    let above: Vec<String> = BASE.iter().zip(dimension).filter(|(_, p)| *p > 0).map(|(name, p)| power(name, p)).collect();
+    let below: String = BASE.iter().zip(dimension).filter(|(_, p)| *p < 0).map(|(name, p)| format!("/{}", power(name, -p))).collect();
+    if above.is_empty() { format!("1{}", below) } else { format!("{}{}", above.join("*"), below) }
+}